@@ -0,0 +1,295 @@
+use actix_web::{http, App, Error, HttpRequest, HttpResponse};
+use aws_s3_webdav::metrics;
+use aws_s3_webdav::retry::error_code;
+use env::AppEnv;
+use futures::{future, Future};
+use rusoto_s3::{HeadBucketError, HeadBucketRequest, HeadObjectError, HeadObjectRequest, S3};
+use s3::Sse;
+use std::time::{Duration, Instant};
+use tokio_timer::Timeout;
+
+/// Readiness check is failed if S3 doesn't respond within this time
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Process is alive and serving requests
+pub fn healthz(_req: &HttpRequest<AppEnv>) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+/// Failure of HEAD request, which has no body, so it comes with one standing for its status
+/// (see `StatusDispatcher`)
+fn head_failure(target: &str, unknown: &str) -> String {
+    match error_code(unknown) {
+        Some("NotFound") => format!("{} doesn't exist", target),
+        Some("AccessDenied") => format!("Access to {} is denied", target),
+        Some(code) => format!("HEAD of {} failed: {}", target, code),
+        None => format!("HEAD of {} failed: {}", target, unknown),
+    }
+}
+
+fn head_bucket_failure(bucket: &str, e: HeadBucketError) -> String {
+    match e {
+        HeadBucketError::HttpDispatch(e) => format!("S3 is unreachable: {}", e),
+        HeadBucketError::Credentials(e) => format!("AWS credentials are failing: {}", e),
+        HeadBucketError::NoSuchBucket(_) => format!("Bucket {} doesn't exist", bucket),
+        HeadBucketError::Validation(e) | HeadBucketError::Unknown(e) => {
+            head_failure(&format!("bucket {}", bucket), &e)
+        }
+    }
+}
+
+fn head_object_failure(key: &str, e: HeadObjectError) -> String {
+    match e {
+        HeadObjectError::HttpDispatch(e) => format!("S3 is unreachable: {}", e),
+        HeadObjectError::Credentials(e) => format!("AWS credentials are failing: {}", e),
+        HeadObjectError::NoSuchKey(_) => format!("Probe key {} doesn't exist", key),
+        HeadObjectError::Validation(e) | HeadObjectError::Unknown(e) => {
+            head_failure(&format!("probe key {}", key), &e)
+        }
+    }
+}
+
+/// Check S3 is reachable with configured credentials: HEAD of bucket, or of probe key if set,
+/// made once, without retries. Checks are counted in metrics like other S3 requests.
+fn probe(env: &AppEnv) -> Box<Future<Item = (), Error = String>> {
+    let bucket = env.config.s3.bucket.to_owned();
+    let (operation, check): (&'static str, Box<Future<Item = (), Error = String>>) =
+        match env.config.readiness.probe_key {
+            Some(ref key) => {
+                let key = match env.config.s3.prefix {
+                    Some(ref prefix) => format!("{}{}", prefix, key),
+                    None => key.to_owned(),
+                };
+                let sse = Sse::for_key(env, &key);
+                let request = HeadObjectRequest {
+                    bucket: bucket,
+                    key: key.to_owned(),
+                    sse_customer_algorithm: sse.customer_algorithm,
+                    sse_customer_key: sse.customer_key,
+                    sse_customer_key_md5: sse.customer_key_md5,
+                    ..HeadObjectRequest::default()
+                };
+
+                let check = env.s3.head_object(request);
+                ("head_object", Box::new(check.map(|_| ()).map_err(move |e| {
+                    head_object_failure(&key, e)
+                })))
+            }
+            None => {
+                let request = HeadBucketRequest {
+                    bucket: bucket.to_owned(),
+                };
+
+                let check = env.s3.head_bucket(request);
+                ("head_bucket", Box::new(check.map_err(move |e| {
+                    head_bucket_failure(&bucket, e)
+                })))
+            }
+        };
+
+    let metrics = env.metrics.clone();
+    let started = Instant::now();
+
+    Box::new(Timeout::new(check, PROBE_TIMEOUT).then(move |r| {
+        let r = r.map_err(|e| match e.into_inner() {
+            Some(e) => e,
+            None => format!("S3 didn't respond within {} seconds", PROBE_TIMEOUT.as_secs()),
+        });
+        metrics.s3_request(operation, started.elapsed(), r.is_err());
+
+        r
+    }))
+}
+
+/// Service is ready when S3 is reachable with configured credentials. Results of checks are
+/// cached, so probes don't hit S3 on every request.
+pub fn readyz(req: &HttpRequest<AppEnv>) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let env = req.state().clone();
+    let respond = |result: Result<(), String>| match result {
+        Ok(()) => HttpResponse::Ok().content_type("text/plain").body("ok"),
+        Err(e) => HttpResponse::ServiceUnavailable()
+            .content_type("text/plain")
+            .body(format!("degraded: {}", e)),
+    };
+
+    let cache_ttl = env.config.readiness.cache_ttl;
+    if let Some(result) = env.readiness.cached(cache_ttl, Instant::now()) {
+        return Box::new(future::ok(respond(result)));
+    }
+
+    Box::new(probe(&env).then(move |result| {
+        if let Err(ref e) = result {
+            warn!("Readiness check failed: {}", e);
+        }
+        env.readiness.record(result.clone(), Instant::now());
+
+        Ok(respond(result))
+    }))
+}
+
+/// Metrics of all workers in Prometheus text format
+pub fn metrics(req: &HttpRequest<AppEnv>) -> HttpResponse {
+    let env = req.state();
+
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(env.metrics.render(&env.in_flight))
+}
+
+/// Build admin application, served on its own address so its paths don't collide with
+/// object keys. Its state shares metrics and requests in flight with WebDAV server workers.
+pub fn app(state: AppEnv) -> App<AppEnv> {
+    App::with_state(state)
+        .resource("/metrics", |r| r.method(http::Method::GET).f(metrics))
+        .resource("/healthz", |r| r.method(http::Method::GET).f(healthz))
+        .resource("/readyz", |r| r.method(http::Method::GET).f(readyz))
+}
+
+#[cfg(test)]
+mod tests {
+    mod admin {
+        use actix_web::test::TestServer;
+        use admin;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+        use testing::{self, Failure, Response, StoredObject, Stub, StubState};
+
+        fn get(admin: &TestServer, path: &str) -> Response {
+            testing::request(admin.addr(), "GET", path, &[], b"")
+        }
+
+        fn metrics(admin: &TestServer) -> Response {
+            get(admin, "/metrics")
+        }
+
+        fn body(response: &Response) -> String {
+            String::from_utf8_lossy(&response.body).into_owned()
+        }
+
+        #[test]
+        fn test_metrics() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let env = testing::state(s3.addr());
+            let proxy_env = env.clone();
+            let proxy = TestServer::with_factory(move || ::app(proxy_env.clone()));
+            let admin = TestServer::with_factory(move || admin::app(env.clone()));
+
+            let put = testing::request(proxy.addr(), "PUT", "/a.txt", &[], b"Hello");
+            assert_eq!(put.status, 201);
+            assert_eq!(testing::request(proxy.addr(), "GET", "/a.txt", &[], b"").status, 200);
+
+            // request is recorded once its response is sent
+            let expected = "webdav_requests_total{method=\"GET\",status=\"200\"} 1";
+            let mut response = metrics(&admin);
+            for _ in 0..50 {
+                if String::from_utf8_lossy(&response.body).contains(expected) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+                response = metrics(&admin);
+            }
+
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("text/plain; version=0.0.4"));
+
+            let body = String::from_utf8(response.body).unwrap();
+            let lines: Vec<&str> = body.lines().collect();
+            for line in &[
+                expected,
+                "webdav_requests_total{method=\"PUT\",status=\"201\"} 1",
+                "webdav_received_bytes_total 5",
+                "webdav_sent_bytes_total 5",
+                "webdav_s3_requests_total{operation=\"get_object\"} 1",
+                "webdav_s3_request_errors_total{operation=\"get_object\"} 0",
+            ] {
+                assert!(lines.contains(line), "missing {} in\n{}", line, body);
+            }
+
+            assert_eq!(testing::request(proxy.addr(), "GET", "/metrics", &[], b"").status, 404);
+        }
+
+        #[test]
+        fn test_healthz() {
+            let env = testing::state("127.0.0.1:1".parse().unwrap());
+            let admin = TestServer::with_factory(move || admin::app(env.clone()));
+            let response = get(&admin, "/healthz");
+
+            assert_eq!(response.status, 200);
+            assert_eq!(body(&response), "ok");
+        }
+
+        #[test]
+        fn test_readyz() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let mut env = testing::state(s3.addr());
+            let ttl = Duration::from_millis(300);
+            Arc::get_mut(&mut env).unwrap().config.readiness.cache_ttl = ttl;
+            let admin_env = env.clone();
+            let admin = TestServer::with_factory(move || admin::app(admin_env.clone()));
+
+            let response = get(&admin, "/readyz");
+            assert_eq!((response.status, body(&response)), (200, "ok".to_owned()));
+            assert_eq!(stub.lock().unwrap().calls, vec!["head_bucket"]);
+
+            // failures are reported once cached result expires
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "head_bucket",
+                status: 403,
+                code: "AccessDenied",
+            });
+            assert_eq!(get(&admin, "/readyz").status, 200);
+            assert_eq!(stub.lock().unwrap().calls.len(), 1);
+
+            thread::sleep(Duration::from_millis(400));
+            let response = get(&admin, "/readyz");
+            assert_eq!(response.status, 503);
+            assert_eq!(body(&response), "degraded: Access to bucket bucket is denied");
+            assert_eq!(get(&admin, "/readyz").status, 503);
+            assert_eq!(stub.lock().unwrap().calls.len(), 2);
+
+            let metrics = body(&metrics(&admin));
+            let errors = "webdav_s3_request_errors_total{operation=\"head_bucket\"} 1";
+            assert!(metrics.lines().any(|l| l == errors), "missing {} in\n{}", errors, metrics);
+        }
+
+        #[test]
+        fn test_readyz_probe_key() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let mut env = testing::state(s3.addr());
+            {
+                let config = &mut Arc::get_mut(&mut env).unwrap().config;
+                config.s3.prefix = Some("prefix/".to_owned());
+                config.readiness.probe_key = Some("probe".to_owned());
+                config.readiness.cache_ttl = Duration::from_secs(0);
+            }
+            let admin = TestServer::with_factory(move || admin::app(env.clone()));
+
+            let response = get(&admin, "/readyz");
+            assert_eq!(response.status, 503);
+            assert!(body(&response).contains("probe key prefix/probe"), "{}", body(&response));
+
+            stub.lock()
+                .unwrap()
+                .objects
+                .insert("prefix/probe".to_owned(), StoredObject::default());
+            assert_eq!(get(&admin, "/readyz").status, 200);
+            assert_eq!(stub.lock().unwrap().calls, vec!["head_object", "head_object"]);
+        }
+
+        #[test]
+        fn test_readyz_unreachable() {
+            // nothing listens on port 1
+            let env = testing::state("127.0.0.1:1".parse().unwrap());
+            let admin = TestServer::with_factory(move || admin::app(env.clone()));
+            let response = get(&admin, "/readyz");
+            let body = body(&response);
+
+            assert_eq!(response.status, 503);
+            assert!(body.starts_with("degraded: S3 is unreachable"), "{}", body);
+        }
+    }
+}
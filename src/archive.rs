@@ -0,0 +1,655 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::{Compression, Crc};
+use std::cmp;
+use std::io::Write;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+/// Archive format of collection downloads and extracted uploads
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Format {
+    /// Parse `archive` or `extract` query parameter value
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "zip" => Ok(Format::Zip),
+            "tar" => Ok(Format::Tar),
+            "tar.gz" | "tgz" => Ok(Format::TarGz),
+            format => Err(format!("Unsupported archive format \"{}\"", format)),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            Format::Zip => "application/zip",
+            Format::Tar => "application/x-tar",
+            Format::TarGz => "application/gzip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Zip => "zip",
+            Format::Tar => "tar",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    /// New writer of archives in this format
+    pub fn writer(&self) -> Box<ArchiveWriter> {
+        match *self {
+            Format::Zip => Box::new(ZipWriter::new()),
+            Format::Tar => Box::new(TarWriter::new()),
+            Format::TarGz => Box::new(Gzip::new(TarWriter::new())),
+        }
+    }
+}
+
+/// Normalized relative path of archive entry: empty and `.` segments are dropped, `None` if
+/// the path is empty, absolute, or has `..` segments, so it can't escape the directory it's
+/// extracted to
+pub fn safe_path(path: &str) -> Option<String> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in path.split(&['/', '\\'][..]) {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment if segment.contains(':') && segments.is_empty() => return None,
+            segment => segments.push(segment),
+        }
+    }
+
+    if segments.is_empty() {
+        None
+    } else {
+        Some(segments.join("/"))
+    }
+}
+
+/// Name of object in archive of collection: its key relative to collection prefix, percent
+/// decoded. `None` for "folder" objects and keys which aren't safe to extract.
+pub fn entry_name(prefix: &str, key: &str) -> Option<String> {
+    if !key.starts_with(prefix) || key.ends_with('/') {
+        return None;
+    }
+
+    safe_path(&percent_decode(&key.as_bytes()[prefix.len()..]).decode_utf8_lossy())
+}
+
+/// Path of extracted archive entry relative to target collection: safe entry name with
+/// segments percent encoded, as they are in object keys
+pub fn entry_path(name: &str) -> String {
+    name.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Archive written on the fly: every method returns bytes to be sent next. Entry data must be
+/// passed through `write`, in between of `start_entry` and `finish_entry`.
+pub trait ArchiveWriter {
+    fn start_entry(&mut self, name: &str, size: u64, modified: &DateTime<Utc>)
+        -> Result<Vec<u8>, String>;
+
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, String>;
+
+    fn finish_entry(&mut self) -> Result<Vec<u8>, String>;
+
+    /// Trailer of the archive, no entries can be added after it
+    fn finish(&mut self) -> Result<Vec<u8>, String>;
+}
+
+/// Entry of ZIP archive, kept for central directory
+struct ZipEntry {
+    name: String,
+    modified: (u16, u16),
+    offset: u64,
+    crc: u32,
+    size: u64,
+}
+
+/// ZIP64 archive writer, entries are stored uncompressed with sizes and CRC-32 in data
+/// descriptors, as they are known only after entry data is written
+pub struct ZipWriter {
+    offset: u64,
+    entries: Vec<ZipEntry>,
+    crc: Crc,
+    written: u64,
+}
+
+const ZIP_VERSION: u16 = 45;
+/// Data descriptor is used (bit 3), names are UTF-8 (bit 11)
+const ZIP_FLAGS: u16 = 0x0808;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push(value as u8);
+    buffer.push((value >> 8) as u8);
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    put_u16(buffer, value as u16);
+    put_u16(buffer, (value >> 16) as u16);
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    put_u32(buffer, value as u32);
+    put_u32(buffer, (value >> 32) as u32);
+}
+
+/// MS-DOS time and date, as used by ZIP, times before 1980 are clamped
+fn dos_time(time: &DateTime<Utc>) -> (u16, u16) {
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+
+    let year = cmp::min(time.year() - 1980, 127) as u16;
+    let dos_time = ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16;
+    let dos_date = (year << 9) | ((time.month() << 5) | time.day()) as u16;
+
+    (dos_time, dos_date)
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter {
+            offset: 0,
+            entries: Vec::new(),
+            crc: Crc::new(),
+            written: 0,
+        }
+    }
+
+    fn emit(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.offset += bytes.len() as u64;
+        bytes
+    }
+}
+
+impl Default for ZipWriter {
+    fn default() -> ZipWriter {
+        ZipWriter::new()
+    }
+}
+
+impl ArchiveWriter for ZipWriter {
+    fn start_entry(
+        &mut self,
+        name: &str,
+        _size: u64,
+        modified: &DateTime<Utc>,
+    ) -> Result<Vec<u8>, String> {
+        if name.len() > u16::max_value() as usize {
+            return Err(format!("Entry name is too long: {}", name));
+        }
+
+        let entry = ZipEntry {
+            name: name.to_owned(),
+            modified: dos_time(modified),
+            offset: self.offset,
+            crc: 0,
+            size: 0,
+        };
+        let mut header = Vec::with_capacity(50 + name.len());
+
+        put_u32(&mut header, 0x0403_4b50);
+        put_u16(&mut header, ZIP_VERSION);
+        put_u16(&mut header, ZIP_FLAGS);
+        put_u16(&mut header, 0); // stored
+        put_u16(&mut header, entry.modified.0);
+        put_u16(&mut header, entry.modified.1);
+        put_u32(&mut header, 0); // CRC-32, in data descriptor
+        put_u32(&mut header, 0xffff_ffff); // sizes, in ZIP64 extra field
+        put_u32(&mut header, 0xffff_ffff);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 20);
+        header.extend_from_slice(name.as_bytes());
+        put_u16(&mut header, ZIP64_EXTRA_ID);
+        put_u16(&mut header, 16);
+        put_u64(&mut header, 0);
+        put_u64(&mut header, 0);
+
+        self.entries.push(entry);
+        self.crc.reset();
+        self.written = 0;
+
+        Ok(self.emit(header))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.crc.update(data);
+        self.written += data.len() as u64;
+
+        Ok(self.emit(data.to_vec()))
+    }
+
+    fn finish_entry(&mut self) -> Result<Vec<u8>, String> {
+        let (crc, size) = (self.crc.sum(), self.written);
+        let entry = self.entries.last_mut().ok_or("No entry started")?;
+        let mut descriptor = Vec::with_capacity(24);
+
+        entry.crc = crc;
+        entry.size = size;
+        put_u32(&mut descriptor, 0x0807_4b50);
+        put_u32(&mut descriptor, crc);
+        put_u64(&mut descriptor, size);
+        put_u64(&mut descriptor, size);
+
+        Ok(self.emit(descriptor))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        let start = self.offset;
+        let mut trailer = Vec::new();
+
+        for entry in &self.entries {
+            put_u32(&mut trailer, 0x0201_4b50);
+            put_u16(&mut trailer, ZIP_VERSION);
+            put_u16(&mut trailer, ZIP_VERSION);
+            put_u16(&mut trailer, ZIP_FLAGS);
+            put_u16(&mut trailer, 0);
+            put_u16(&mut trailer, entry.modified.0);
+            put_u16(&mut trailer, entry.modified.1);
+            put_u32(&mut trailer, entry.crc);
+            put_u32(&mut trailer, 0xffff_ffff);
+            put_u32(&mut trailer, 0xffff_ffff);
+            put_u16(&mut trailer, entry.name.len() as u16);
+            put_u16(&mut trailer, 28);
+            put_u16(&mut trailer, 0); // comment length
+            put_u16(&mut trailer, 0); // disk number
+            put_u16(&mut trailer, 0); // internal attributes
+            put_u32(&mut trailer, 0); // external attributes
+            put_u32(&mut trailer, 0xffff_ffff); // offset, in ZIP64 extra field
+            trailer.extend_from_slice(entry.name.as_bytes());
+            put_u16(&mut trailer, ZIP64_EXTRA_ID);
+            put_u16(&mut trailer, 24);
+            put_u64(&mut trailer, entry.size);
+            put_u64(&mut trailer, entry.size);
+            put_u64(&mut trailer, entry.offset);
+        }
+
+        let directory_size = trailer.len() as u64;
+        let end_offset = start + directory_size;
+        let entries = self.entries.len() as u64;
+
+        // ZIP64 end of central directory record and its locator
+        put_u32(&mut trailer, 0x0606_4b50);
+        put_u64(&mut trailer, 44);
+        put_u16(&mut trailer, ZIP_VERSION);
+        put_u16(&mut trailer, ZIP_VERSION);
+        put_u32(&mut trailer, 0);
+        put_u32(&mut trailer, 0);
+        put_u64(&mut trailer, entries);
+        put_u64(&mut trailer, entries);
+        put_u64(&mut trailer, directory_size);
+        put_u64(&mut trailer, start);
+        put_u32(&mut trailer, 0x0706_4b50);
+        put_u32(&mut trailer, 0);
+        put_u64(&mut trailer, end_offset);
+        put_u32(&mut trailer, 1);
+
+        // end of central directory record, values are in ZIP64 one
+        put_u32(&mut trailer, 0x0605_4b50);
+        put_u16(&mut trailer, 0);
+        put_u16(&mut trailer, 0);
+        put_u16(&mut trailer, 0xffff);
+        put_u16(&mut trailer, 0xffff);
+        put_u32(&mut trailer, 0xffff_ffff);
+        put_u32(&mut trailer, 0xffff_ffff);
+        put_u16(&mut trailer, 0);
+
+        self.entries.clear();
+
+        Ok(self.emit(trailer))
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+
+/// Write numeric tar header field as zero padded octal with trailing NUL, values which don't
+/// fit are written in GNU base-256 encoding
+fn tar_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let octal = format!("{:0width$o}", value, width = digits);
+
+    if octal.len() == digits {
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        let len = field.len();
+        for (i, byte) in field.iter_mut().enumerate() {
+            let shift = 8 * (len - 1 - i);
+            *byte = if shift < 64 { (value >> shift) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+/// Tar header block of regular file or PAX extended header
+fn tar_header(name: &str, size: u64, mtime: u64, kind: u8) -> Vec<u8> {
+    let mut header = vec![0; TAR_BLOCK];
+    let name = name.as_bytes();
+    let name_len = if name.len() > 100 { 100 } else { name.len() };
+
+    header[..name_len].copy_from_slice(&name[..name_len]);
+    tar_number(&mut header[100..108], 0o644);
+    tar_number(&mut header[108..116], 0);
+    tar_number(&mut header[116..124], 0);
+    tar_number(&mut header[124..136], size);
+    tar_number(&mut header[136..148], mtime);
+    header[148..156].copy_from_slice(b"        ");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..154].copy_from_slice(format!("{:06o}", checksum).as_bytes());
+    header[154] = 0;
+
+    header
+}
+
+/// PAX extended header record: `<length> <key>=<value>\n`, length includes itself
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len.to_string().len() + rest != len {
+        len = len.to_string().len() + rest;
+    }
+
+    format!("{} {}={}\n", len, key, value)
+}
+
+fn tar_padding(size: u64) -> Vec<u8> {
+    let rest = (size % TAR_BLOCK as u64) as usize;
+    vec![0; if rest == 0 { 0 } else { TAR_BLOCK - rest }]
+}
+
+/// POSIX (ustar) tar archive writer, names longer than 100 bytes are stored in PAX headers
+pub struct TarWriter {
+    size: u64,
+    written: u64,
+}
+
+impl TarWriter {
+    pub fn new() -> TarWriter {
+        TarWriter {
+            size: 0,
+            written: 0,
+        }
+    }
+}
+
+impl Default for TarWriter {
+    fn default() -> TarWriter {
+        TarWriter::new()
+    }
+}
+
+impl ArchiveWriter for TarWriter {
+    fn start_entry(
+        &mut self,
+        name: &str,
+        size: u64,
+        modified: &DateTime<Utc>,
+    ) -> Result<Vec<u8>, String> {
+        let mtime = if modified.timestamp() > 0 { modified.timestamp() as u64 } else { 0 };
+        let mut header = Vec::with_capacity(TAR_BLOCK);
+
+        if name.len() > 100 {
+            let record = pax_record("path", name);
+            header.extend(tar_header("././@PaxHeader", record.len() as u64, mtime, b'x'));
+            header.extend_from_slice(record.as_bytes());
+            header.extend(tar_padding(record.len() as u64));
+        }
+
+        header.extend(tar_header(name, size, mtime, b'0'));
+        self.size = size;
+        self.written = 0;
+
+        Ok(header)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.written += data.len() as u64;
+
+        if self.written > self.size {
+            return Err(format!("Entry is larger than {} bytes", self.size));
+        }
+
+        Ok(data.to_vec())
+    }
+
+    fn finish_entry(&mut self) -> Result<Vec<u8>, String> {
+        if self.written != self.size {
+            return Err(format!("Entry has {} of {} bytes", self.written, self.size));
+        }
+
+        Ok(tar_padding(self.size))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        Ok(vec![0; 2 * TAR_BLOCK])
+    }
+}
+
+/// Gzip compression of archive written by inner writer
+pub struct Gzip<W> {
+    inner: W,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+}
+
+impl<W: ArchiveWriter> Gzip<W> {
+    pub fn new(inner: W) -> Gzip<W> {
+        Gzip {
+            inner: inner,
+            encoder: Some(GzEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    /// Compress bytes, returns compressed output available so far
+    fn compress(&mut self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let encoder = self.encoder.as_mut().ok_or("Archive is finished")?;
+        encoder.write_all(bytes).map_err(|e| e.to_string())?;
+
+        Ok(encoder.get_mut().split_off(0))
+    }
+}
+
+impl<W: ArchiveWriter> ArchiveWriter for Gzip<W> {
+    fn start_entry(
+        &mut self,
+        name: &str,
+        size: u64,
+        modified: &DateTime<Utc>,
+    ) -> Result<Vec<u8>, String> {
+        let header = self.inner.start_entry(name, size, modified)?;
+        self.compress(&header)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let data = self.inner.write(data)?;
+        self.compress(&data)
+    }
+
+    fn finish_entry(&mut self) -> Result<Vec<u8>, String> {
+        let padding = self.inner.finish_entry()?;
+        self.compress(&padding)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        let trailer = self.inner.finish()?;
+        let mut output = self.compress(&trailer)?;
+        let encoder = self.encoder.take().ok_or("Archive is finished")?;
+        output.extend(encoder.finish().map_err(|e| e.to_string())?);
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod archive {
+        use archive::*;
+        use chrono::{TimeZone, Utc};
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        fn write_archive(writer: &mut ArchiveWriter, entries: &[(&str, &[u8])]) -> Vec<u8> {
+            let modified = Utc.ymd(2018, 10, 1).and_hms(12, 30, 4);
+            let mut archive = Vec::new();
+
+            for &(name, data) in entries {
+                archive.extend(writer.start_entry(name, data.len() as u64, &modified).unwrap());
+                for chunk in data.chunks(3) {
+                    archive.extend(writer.write(chunk).unwrap());
+                }
+                archive.extend(writer.finish_entry().unwrap());
+            }
+            archive.extend(writer.finish().unwrap());
+
+            archive
+        }
+
+        fn u16_at(bytes: &[u8], at: usize) -> u16 {
+            u16::from(bytes[at]) | u16::from(bytes[at + 1]) << 8
+        }
+
+        fn u32_at(bytes: &[u8], at: usize) -> u32 {
+            u32::from(u16_at(bytes, at)) | u32::from(u16_at(bytes, at + 2)) << 16
+        }
+
+        fn u64_at(bytes: &[u8], at: usize) -> u64 {
+            u64::from(u32_at(bytes, at)) | u64::from(u32_at(bytes, at + 4)) << 32
+        }
+
+        #[test]
+        fn test_format() {
+            assert_eq!(Format::parse("zip"), Ok(Format::Zip));
+            assert_eq!(Format::parse("tar"), Ok(Format::Tar));
+            assert_eq!(Format::parse("tar.gz"), Ok(Format::TarGz));
+            assert!(Format::parse("rar").is_err());
+        }
+
+        #[test]
+        fn test_safe_path() {
+            assert_eq!(safe_path("docs/a.txt"), Some("docs/a.txt".to_owned()));
+            assert_eq!(safe_path("docs//./a.txt"), Some("docs/a.txt".to_owned()));
+            assert_eq!(safe_path("docs\\a.txt"), Some("docs/a.txt".to_owned()));
+            assert_eq!(safe_path("docs/../../etc/passwd"), None);
+            assert_eq!(safe_path("/etc/passwd"), None);
+            assert_eq!(safe_path("C:/Windows"), None);
+            assert_eq!(safe_path("./"), None);
+        }
+
+        #[test]
+        fn test_entry_name() {
+            assert_eq!(entry_name("docs/", "docs/a%20b.txt"), Some("a b.txt".to_owned()));
+            assert_eq!(entry_name("docs/", "docs/2018/c.txt"), Some("2018/c.txt".to_owned()));
+            assert_eq!(entry_name("docs/", "docs/2018/"), None);
+            assert_eq!(entry_name("docs/", "docs/%2E%2E/x"), None);
+            assert_eq!(entry_name("docs/", "music/a.mp3"), None);
+        }
+
+        #[test]
+        fn test_entry_path() {
+            assert_eq!(entry_path("2018/a b%.txt"), "2018/a%20b%25.txt");
+            let key = format!("docs/{}", entry_path("a b%.txt"));
+            assert_eq!(entry_name("docs/", &key), Some("a b%.txt".to_owned()));
+        }
+
+        #[test]
+        fn test_zip() {
+            let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b/c", b"")];
+            let archive = write_archive(&mut ZipWriter::new(), entries);
+
+            // local header, data and data descriptor of the first entry
+            assert_eq!(u32_at(&archive, 0), 0x0403_4b50);
+            assert_eq!(u16_at(&archive, 10), (12 << 11) | (30 << 5) | 2);
+            assert_eq!(u16_at(&archive, 12), (38 << 9) | (10 << 5) | 1);
+            assert_eq!(&archive[30..35], b"a.txt");
+            assert_eq!(&archive[55..60], b"hello");
+            assert_eq!(u32_at(&archive, 60), 0x0807_4b50);
+            assert_eq!(u32_at(&archive, 64), 0x3610_a686);
+            assert_eq!(u64_at(&archive, 68), 5);
+
+            // end of central directory points to ZIP64 one, which points to central directory
+            let end = archive.len() - 22;
+            assert_eq!(u32_at(&archive, end), 0x0605_4b50);
+            assert_eq!(u32_at(&archive, end - 20), 0x0706_4b50);
+            let zip64_end = u64_at(&archive, end - 12) as usize;
+            assert_eq!(u32_at(&archive, zip64_end), 0x0606_4b50);
+            assert_eq!(u64_at(&archive, zip64_end + 32), 2);
+
+            let directory = u64_at(&archive, zip64_end + 48) as usize;
+            assert_eq!(u32_at(&archive, directory), 0x0201_4b50);
+            assert_eq!(u32_at(&archive, directory + 16), 0x3610_a686);
+            assert_eq!(&archive[directory + 46..directory + 51], b"a.txt");
+            assert_eq!(u64_at(&archive, directory + 55), 5);
+            assert_eq!(u64_at(&archive, directory + 71), 0);
+
+            let second = directory + 46 + 5 + 28;
+            assert_eq!(&archive[second + 46..second + 49], b"b/c");
+            assert_eq!(u64_at(&archive, second + 46 + 3 + 20), 84);
+            assert_eq!(u32_at(&archive, 84), 0x0403_4b50);
+        }
+
+        #[test]
+        fn test_tar_gz() {
+            let long = format!("{}/{}", "d".repeat(60), "f".repeat(60));
+            let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), (&long, b"data")];
+            let mut tar = Vec::new();
+            let compressed = write_archive(&mut Gzip::new(TarWriter::new()), entries);
+            GzDecoder::new(&compressed[..]).read_to_end(&mut tar).unwrap();
+
+            assert_eq!(tar.len(), 512 * 8);
+            assert_eq!(&tar[0..6], b"a.txt\0");
+            assert_eq!(&tar[124..136], b"00000000005\0");
+            assert_eq!(&tar[136..148], b"13354411514\0");
+            assert_eq!(&tar[257..263], b"ustar\0");
+            assert_eq!(&tar[512..517], b"hello");
+
+            let checksum: u32 = tar[..512]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| if (148..156).contains(&i) { 32 } else { u32::from(*b) })
+                .sum();
+            assert_eq!(&tar[148..156], format!("{:06o}\0 ", checksum).as_bytes());
+
+            // PAX header with full name, followed by the entry itself
+            assert_eq!(tar[1024 + 156], b'x');
+            let record = format!("131 path={}\n", long);
+            assert_eq!(&tar[1536..1536 + record.len()], record.as_bytes());
+            assert_eq!(tar[2048 + 156], b'0');
+            assert_eq!(&tar[2048 + 512..2048 + 516], b"data");
+            assert!(tar[3072..].iter().all(|b| *b == 0));
+        }
+
+        #[test]
+        fn test_tar_size_mismatch() {
+            let modified = Utc.ymd(2018, 10, 1).and_hms(0, 0, 0);
+            let mut writer = TarWriter::new();
+
+            writer.start_entry("a.txt", 3, &modified).unwrap();
+            assert!(writer.write(b"ab").is_ok());
+            assert!(writer.finish_entry().is_err());
+            assert!(writer.write(b"cd").is_err());
+        }
+
+        #[test]
+        fn test_tar_number() {
+            let mut field = [0; 12];
+            tar_number(&mut field, 8 * 1024 * 1024 * 1024);
+            assert_eq!(field, [0x80, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+        }
+    }
+}
@@ -0,0 +1,816 @@
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError};
+use actix_web::Error;
+use aws_s3_webdav::envelope;
+use aws_s3_webdav::integrity::SHA256_METADATA_KEY;
+use aws_s3_webdav::multipart::{part_ranges, PartSize};
+use aws_s3_webdav::storage_class::RestoreStatus;
+use env::AppEnv;
+use futures::future;
+use futures::{stream, Future, Stream};
+use rusoto_s3::*;
+use s3::{
+    self, abort_upload, complete_upload, complete_upload_error, delete_object, head_object,
+    head_object_version, list_parts_error, not_restored, restore_status, with_retry, AbortOnDrop,
+    Sse,
+};
+use std::collections::HashMap;
+use url::form_urlencoded;
+
+/// Successful copy
+#[derive(Debug)]
+pub struct Copied {
+    /// Source object, as seen before copy
+    pub source: HeadObjectOutput,
+    /// Expected destination ETag, if it can be predicted from the source
+    pub e_tag: Option<String>,
+    /// Destination content was checked against source by `verify`, with ETag or SHA-256.
+    /// Only size can be checked if neither is known.
+    pub verified: bool,
+}
+
+/// Destination encryption: configured one if any, otherwise same as source
+fn dest_encryption(sse: &Sse, source: &HeadObjectOutput) -> (Option<String>, Option<String>) {
+    if sse.customer_key.is_some() {
+        (None, None)
+    } else if sse.server_side_encryption.is_some() {
+        (sse.server_side_encryption.clone(), sse.ssekms_key_id.clone())
+    } else {
+        (source.server_side_encryption.clone(), source.ssekms_key_id.clone())
+    }
+}
+
+/// `CopyObject` keeps ETag unless source is a multipart upload, or source or destination is
+/// encrypted with KMS or customer key (ETag is not MD5 of the data then)
+fn predict_e_tag(
+    source: &HeadObjectOutput,
+    dest_encryption: &Option<String>,
+    sse: &Sse,
+) -> Option<String> {
+    let kms = |a: &Option<String>| a.as_ref().map(|a| a == "aws:kms") == Some(true);
+    let encrypted = kms(&source.server_side_encryption)
+        || kms(dest_encryption)
+        || source.sse_customer_algorithm.is_some()
+        || sse.customer_key.is_some();
+
+    match source.e_tag {
+        Some(ref e_tag) if !e_tag.contains('-') && !encrypted => Some(e_tag.to_owned()),
+        _ => None,
+    }
+}
+
+fn copy_single(env: &AppEnv, request: CopyObjectRequest) -> Box<Future<Item=(), Error=Error>> {
+    let state = env.clone();
+
+    Box::new(
+        with_retry(env, "copy_object", move || state.s3.copy_object(request.clone()))
+            .map_err(|e| match e {
+                // http://rusoto.github.io/rusoto/rusoto_s3/enum.CopyObjectError.html
+                CopyObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
+                CopyObjectError::Credentials(e) => ErrorForbidden(e),
+                CopyObjectError::Validation(e) => ErrorBadRequest(e),
+                CopyObjectError::ObjectNotInActiveTierError(_) => {
+                    not_restored(&RestoreStatus::Archived)
+                }
+                CopyObjectError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .map(|_| ()),
+    )
+}
+
+fn copy_part(
+    env: &AppEnv,
+    request: UploadPartCopyRequest,
+) -> Box<Future<Item=(), Error=UploadPartCopyError>> {
+    let state = env.clone();
+
+    Box::new(
+        with_retry(env, "upload_part_copy", move || {
+            state.s3.upload_part_copy(request.clone())
+        }).map(|_| ()),
+    )
+}
+
+/// List all uploaded parts, ordered by part number.
+/// rusoto doesn't parse `UploadPartCopy` response body (`CopyPartResult` is expected to be
+/// nested), so ETags of copied parts have to be fetched separately.
+fn list_parts(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+) -> Box<Future<Item=Vec<CompletedPart>, Error=ListPartsError>> {
+    Box::new(s3::list_parts(env, upload).map(|parts| {
+        parts
+            .into_iter()
+            .map(|p| CompletedPart {
+                e_tag: p.e_tag,
+                part_number: p.part_number,
+            })
+            .collect()
+    }))
+}
+
+fn upload_part_copy_error(e: UploadPartCopyError) -> Error {
+    match e {
+        UploadPartCopyError::HttpDispatch(e) => ErrorInternalServerError(e),
+        UploadPartCopyError::Credentials(e) => ErrorForbidden(e),
+        UploadPartCopyError::Validation(e) => ErrorBadRequest(e),
+        UploadPartCopyError::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Complete multipart copy, once all parts were copied
+fn complete_copy(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+    expected_parts: usize,
+) -> Box<Future<Item=(), Error=Error>> {
+    let state = env.clone();
+    let upload = upload.clone();
+
+    Box::new(list_parts(env, &upload).map_err(list_parts_error).and_then(
+        move |parts| -> Box<Future<Item=(), Error=Error>> {
+            if parts.len() != expected_parts {
+                return Box::new(future::err(ErrorInternalServerError(format!(
+                    "Expected {} copied parts, found {}",
+                    expected_parts,
+                    parts.len()
+                ))));
+            }
+
+            Box::new(
+                complete_upload(&state, &upload, parts)
+                    .map(|_| ())
+                    .map_err(complete_upload_error),
+            )
+        },
+    ))
+}
+
+/// Copy source with multipart upload, source ranges are copied in parallel by S3 itself.
+/// `part` holds copy source and encryption fields for every part copy request.
+fn copy_multipart(
+    env: &AppEnv,
+    create_request: CreateMultipartUploadRequest,
+    part: UploadPartCopyRequest,
+    length: u64,
+) -> Box<Future<Item=(), Error=Error>> {
+    let state = env.clone();
+    let s = env.clone();
+
+    Box::new(
+        with_retry(env, "create_multipart_upload", move || {
+            s.s3.create_multipart_upload(create_request.clone())
+        }).map_err(|e| match e {
+                CreateMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
+                CreateMultipartUploadError::Credentials(e) => ErrorForbidden(e),
+                CreateMultipartUploadError::Validation(e) => ErrorBadRequest(e),
+                CreateMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .and_then(move |upload| {
+                let mut guard = AbortOnDrop::new(&state, &upload);
+                let sizes = PartSize::new(state.config.copy.part_size, Some(length));
+                let template = UploadPartCopyRequest {
+                    bucket: upload.bucket.to_owned().unwrap(),
+                    key: upload.key.to_owned().unwrap(),
+                    upload_id: upload.upload_id.to_owned().unwrap(),
+                    ..part
+                };
+                let ranges = part_ranges(length, sizes);
+                let expected_parts = ranges.len();
+                let env = state.clone();
+                let s = state.clone();
+                let u = upload.clone();
+
+                stream::iter_ok(ranges)
+                    .map(move |(part_number, first, last)| {
+                        copy_part(
+                            &env,
+                            UploadPartCopyRequest {
+                                part_number: part_number,
+                                copy_source_range: Some(format!("bytes={}-{}", first, last)),
+                                ..template.clone()
+                            },
+                        )
+                    })
+                    .buffer_unordered(state.config.copy.concurrency)
+                    .for_each(|_| Ok(()))
+                    .map_err(upload_part_copy_error)
+                    .and_then(move |_| complete_copy(&s, &u, expected_parts))
+                    .or_else(move |e| {
+                        // do not leave copied parts behind
+                        abort_upload(&state, &upload).then(move |_| Err(e))
+                    })
+                    .then(move |r| {
+                        guard.disarm();
+                        r
+                    })
+            }),
+    )
+}
+
+/// Content headers to set on copy destination, replacing source ones
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContentHeaders {
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_language: Option<String>,
+    pub content_type: Option<String>,
+    pub expires: Option<String>,
+}
+
+impl ContentHeaders {
+    pub fn is_empty(&self) -> bool {
+        *self == ContentHeaders::default()
+    }
+
+    /// Source object headers with overrides applied
+    fn merge(source: &HeadObjectOutput, overrides: &ContentHeaders) -> ContentHeaders {
+        let pick = |o: &Option<String>, s: &Option<String>| o.clone().or_else(|| s.clone());
+
+        ContentHeaders {
+            cache_control: pick(&overrides.cache_control, &source.cache_control),
+            content_disposition: pick(&overrides.content_disposition, &source.content_disposition),
+            content_encoding: pick(&overrides.content_encoding, &source.content_encoding),
+            content_language: pick(&overrides.content_language, &source.content_language),
+            content_type: pick(&overrides.content_type, &source.content_type),
+            expires: pick(&overrides.expires, &source.expires),
+        }
+    }
+}
+
+/// Source object tags, encoded as `x-amz-tagging` header value
+fn get_tagging(
+    env: &AppEnv,
+    bucket: &str,
+    key: &str,
+) -> Box<Future<Item=Option<String>, Error=Error>> {
+    let state = env.clone();
+    let request = GetObjectTaggingRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..GetObjectTaggingRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "get_object_tagging", move || {
+            state.s3.get_object_tagging(request.clone())
+        }).map_err(|e| match e {
+                GetObjectTaggingError::HttpDispatch(e) => ErrorInternalServerError(e),
+                GetObjectTaggingError::Credentials(e) => ErrorForbidden(e),
+                GetObjectTaggingError::Validation(e) => ErrorBadRequest(e),
+                GetObjectTaggingError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .map(|output| {
+                if output.tag_set.is_empty() {
+                    None
+                } else {
+                    let mut tagging = form_urlencoded::Serializer::new(String::new());
+
+                    for tag in output.tag_set {
+                        tagging.append_pair(&tag.key, &tag.value);
+                    }
+
+                    Some(tagging.finish())
+                }
+            }),
+    )
+}
+
+/// User metadata entries to set (`Some`) or remove (`None`) on copy destination
+pub type MetadataChanges = HashMap<String, Option<String>>;
+
+/// User metadata for copy destination. Data key of client-side encrypted source is rewrapped
+/// with current keyring key, so copying objects in place rotates keys.
+fn dest_metadata(
+    env: &AppEnv,
+    source: &HeadObjectOutput,
+    changes: &MetadataChanges,
+) -> Result<Option<HashMap<String, String>>, Error> {
+    let mut metadata = source.metadata.clone();
+    let rewrapped = match (metadata.as_ref(), env.config.keyring.as_ref()) {
+        (Some(m), Some(keyring)) => match m.get(envelope::METADATA_KEY) {
+            Some(wrapped) => keyring.rewrap(wrapped).map_err(|e| {
+                error!("Failed to rewrap object data key: {}", e);
+                ErrorInternalServerError("Object can't be decrypted")
+            })?,
+            None => None,
+        },
+        _ => None,
+    };
+
+    if let (Some(m), Some(wrapped)) = (metadata.as_mut(), rewrapped) {
+        m.insert(envelope::METADATA_KEY.to_owned(), wrapped);
+    }
+
+    for (name, value) in changes {
+        match *value {
+            Some(ref value) => {
+                let m = metadata.get_or_insert_with(HashMap::new);
+                m.insert(name.to_owned(), value.to_owned());
+            }
+            None => if let Some(m) = metadata.as_mut() {
+                m.remove(name);
+            },
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Server-side copy within the bucket, sources larger than single `CopyObject` allows are
+/// copied part by part with `UploadPartCopy`. User metadata, tags, storage class and
+/// encryption are preserved, content headers are replaced by non empty `overrides`.
+pub fn copy(
+    env: &AppEnv,
+    bucket: &str,
+    source_key: &str,
+    dest_key: &str,
+    overrides: &ContentHeaders,
+) -> Box<Future<Item=Copied, Error=Error>> {
+    copy_version(env, bucket, source_key, None, dest_key, overrides, &MetadataChanges::new())
+}
+
+/// Server-side copy of given source version, latest one if `source_version_id` is `None`,
+/// with user metadata `changes` applied
+pub fn copy_version(
+    env: &AppEnv,
+    bucket: &str,
+    source_key: &str,
+    source_version_id: Option<&str>,
+    dest_key: &str,
+    overrides: &ContentHeaders,
+    changes: &MetadataChanges,
+) -> Box<Future<Item=Copied, Error=Error>> {
+    let state = env.clone();
+    let bucket = bucket.to_owned();
+    let source_key = source_key.to_owned();
+    let dest_key = dest_key.to_owned();
+    let source_version_id = source_version_id.map(|v| v.to_owned());
+    let copy_source = match source_version_id {
+        Some(ref version_id) => format!(
+            "{}?versionId={}",
+            util::encode_key(format!("{}/{}", bucket, source_key)),
+            form_urlencoded::byte_serialize(version_id.as_bytes()).collect::<String>()
+        ),
+        None => util::encode_key(format!("{}/{}", bucket, source_key)),
+    };
+    let overrides = overrides.clone();
+    let changes = changes.clone();
+    let source_sse = Sse::for_key(env, &source_key);
+    let sse = Sse::for_key(env, &dest_key);
+
+    let s = env.clone();
+    let source = head_object_version(env, &bucket, &source_key, source_version_id);
+    let source = source.and_then(move |source| {
+        let status = restore_status(&source);
+        if !status.is_readable() {
+            return Err(not_restored(&status));
+        }
+
+        dest_metadata(&s, &source, &changes).map(|metadata| (source, metadata))
+    });
+
+    Box::new(source.and_then(move |(source, metadata)| {
+        let length = source.content_length.unwrap_or(0) as u64;
+        let headers = ContentHeaders::merge(&source, &overrides);
+        let (encryption, kms_key_id) = dest_encryption(&sse, &source);
+
+        let copied: Box<Future<Item=Option<String>, Error=Error>> =
+            if length > state.config.copy.multipart_threshold {
+                let s = state.clone();
+                let source = source.clone();
+
+                Box::new(get_tagging(&state, &bucket, &source_key).and_then(move |tagging| {
+                    // unlike CopyObject, multipart upload doesn't copy anything from the source
+                    let request = CreateMultipartUploadRequest {
+                        bucket: bucket,
+                        key: dest_key,
+                        cache_control: headers.cache_control,
+                        content_disposition: headers.content_disposition,
+                        content_encoding: headers.content_encoding,
+                        content_language: headers.content_language,
+                        content_type: headers.content_type,
+                        expires: headers.expires,
+                        metadata: metadata,
+                        server_side_encryption: encryption,
+                        ssekms_key_id: kms_key_id,
+                        sse_customer_algorithm: sse.customer_algorithm.clone(),
+                        sse_customer_key: sse.customer_key.clone(),
+                        sse_customer_key_md5: sse.customer_key_md5.clone(),
+                        storage_class: source.storage_class,
+                        tagging: tagging,
+                        website_redirect_location: source.website_redirect_location,
+                        ..CreateMultipartUploadRequest::default()
+                    };
+                    let part = UploadPartCopyRequest {
+                        copy_source: copy_source,
+                        copy_source_sse_customer_algorithm: source_sse.customer_algorithm,
+                        copy_source_sse_customer_key: source_sse.customer_key,
+                        copy_source_sse_customer_key_md5: source_sse.customer_key_md5,
+                        sse_customer_algorithm: sse.customer_algorithm,
+                        sse_customer_key: sse.customer_key,
+                        sse_customer_key_md5: sse.customer_key_md5,
+                        ..UploadPartCopyRequest::default()
+                    };
+
+                    // ETag of multipart copy is its own, so it tells nothing about the source
+                    copy_multipart(&s, request, part, length).map(|_| None)
+                }))
+            } else {
+                // metadata can only be changed by replacing all of it
+                let replace = !overrides.is_empty() || metadata != source.metadata;
+                let request = CopyObjectRequest {
+                    bucket: bucket,
+                    copy_source: copy_source,
+                    key: dest_key,
+                    metadata_directive: Some(if replace { "REPLACE" } else { "COPY" }.to_owned()),
+                    tagging_directive: Some("COPY".to_owned()),
+                    server_side_encryption: encryption.clone(),
+                    ssekms_key_id: kms_key_id,
+                    copy_source_sse_customer_algorithm: source_sse.customer_algorithm,
+                    copy_source_sse_customer_key: source_sse.customer_key,
+                    copy_source_sse_customer_key_md5: source_sse.customer_key_md5,
+                    sse_customer_algorithm: sse.customer_algorithm.clone(),
+                    sse_customer_key: sse.customer_key.clone(),
+                    sse_customer_key_md5: sse.customer_key_md5.clone(),
+                    storage_class: source.storage_class.clone(),
+                    ..CopyObjectRequest::default()
+                };
+                let request = if replace {
+                    CopyObjectRequest {
+                        cache_control: headers.cache_control,
+                        content_disposition: headers.content_disposition,
+                        content_encoding: headers.content_encoding,
+                        content_language: headers.content_language,
+                        content_type: headers.content_type,
+                        expires: headers.expires,
+                        metadata: metadata,
+                        website_redirect_location: source.website_redirect_location.clone(),
+                        ..request
+                    }
+                } else {
+                    request
+                };
+                let e_tag = predict_e_tag(&source, &encryption, &sse);
+
+                Box::new(copy_single(&state, request).map(|_| e_tag))
+            };
+
+        copied.map(move |e_tag| Copied {
+            source: source,
+            e_tag: e_tag,
+            verified: false,
+        })
+    }))
+}
+
+fn sha256(object: &HeadObjectOutput) -> Option<&String> {
+    object.metadata.as_ref().and_then(|m| m.get(SHA256_METADATA_KEY))
+}
+
+/// Check destination object looks like a copy of the source, resolves to whether its content
+/// was checked. Without predictable ETag (multipart copies and uploads, KMS or customer key
+/// encryption) only stored SHA-256 can be checked, otherwise just size is.
+fn check_copy(copied: &Copied, dest: &HeadObjectOutput) -> Result<bool, String> {
+    if dest.content_length != copied.source.content_length {
+        return Err(format!(
+            "size {:?} doesn't match source size {:?}",
+            dest.content_length, copied.source.content_length
+        ));
+    }
+
+    if copied.e_tag.is_some() && dest.e_tag != copied.e_tag {
+        return Err(format!("ETag {:?} doesn't match expected {:?}", dest.e_tag, copied.e_tag));
+    }
+
+    let source_sha256 = sha256(&copied.source);
+    if source_sha256.is_some() && sha256(dest) != source_sha256 {
+        return Err("SHA-256 doesn't match source".to_owned());
+    }
+
+    Ok(copied.e_tag.is_some() || source_sha256.is_some())
+}
+
+/// Verify destination of completed copy, S3 may report success for failed `CopyObject`
+/// (200 response with an error body), so destination is checked before source is removed.
+/// Copies whose content couldn't be checked are reported as not `verified`.
+pub fn verify(
+    env: &AppEnv,
+    bucket: &str,
+    dest_key: &str,
+    copied: Copied,
+) -> Box<Future<Item=Copied, Error=Error>> {
+    let dest_key = dest_key.to_owned();
+
+    Box::new(head_object(env, bucket, &dest_key).then(move |r| match r {
+        Ok(dest) => match check_copy(&copied, &dest) {
+            Ok(verified) => {
+                if !verified {
+                    warn!(
+                        "Copy to {} has no MD5 ETag or SHA-256 to check, only size matches",
+                        dest_key
+                    );
+                }
+
+                Ok(Copied {
+                    verified: verified,
+                    ..copied
+                })
+            }
+            Err(reason) => {
+                error!("Copy to {} could not be verified: {}", dest_key, reason);
+                Err(ErrorInternalServerError(format!("Copy could not be verified: {}", reason)))
+            }
+        },
+        Err(e) => {
+            error!("Copy to {} could not be verified: {}", dest_key, e);
+            Err(ErrorInternalServerError("Copy could not be verified"))
+        }
+    }))
+}
+
+/// Move object: copy, verify destination, then delete source. Source is kept whenever
+/// the copy can't be confirmed, even if only its content can't be checked (409).
+pub fn move_object(
+    env: &AppEnv,
+    bucket: &str,
+    source_key: &str,
+    dest_key: &str,
+    overrides: &ContentHeaders,
+    changes: &MetadataChanges,
+) -> Box<Future<Item=Copied, Error=Error>> {
+    let state = env.clone();
+    let bucket = bucket.to_owned();
+    let source_key = source_key.to_owned();
+    let dest_key = dest_key.to_owned();
+    let s = state.clone();
+    let b = bucket.clone();
+    let d = dest_key.clone();
+
+    Box::new(
+        copy_version(env, &bucket, &source_key, None, &dest_key, overrides, changes)
+            .and_then(move |copied| verify(&s, &b, &d, copied))
+            .and_then(move |copied| -> Box<Future<Item=Copied, Error=Error>> {
+                if !copied.verified {
+                    error!(
+                        "Copied {} to {}, but kept source as only size of the copy matches",
+                        source_key, dest_key
+                    );
+
+                    return Box::new(future::err(ErrorConflict(
+                        "Destination was written, but source was kept as the copy can't be \
+                         verified",
+                    )));
+                }
+
+                Box::new(delete_object(&state, &bucket, &source_key)
+                    .map_err(move |e| {
+                        // copy is complete, so nothing is lost, but both objects now exist
+                        error!(
+                            "Moved {} to {}, but failed to delete source, both objects exist: {}",
+                            source_key, dest_key, e
+                        );
+
+                        e
+                    })
+                    .map(|_| copied))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    mod copy {
+        use actix;
+        use actix_web::Error;
+        use copy::*;
+        use env::Encryption;
+        use std::collections::BTreeMap;
+        use std::sync::{Arc, Mutex};
+        use testing::{self, Failure, StoredObject, Stub, StubState};
+
+        const MIB: usize = 1024 * 1024;
+
+        fn stub_with(key: &str, data: Vec<u8>) -> Stub {
+            let mut metadata = BTreeMap::new();
+            metadata.insert("origin".to_owned(), "camera".to_owned());
+            let mut tags = BTreeMap::new();
+            tags.insert("project".to_owned(), "a b&c".to_owned());
+
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            stub.lock().unwrap().objects.insert(
+                key.to_owned(),
+                StoredObject {
+                    e_tag: testing::e_tag(&data),
+                    data: data,
+                    content_type: Some("video/mp4".to_owned()),
+                    metadata: metadata,
+                    tags: tags,
+                    storage_class: Some("STANDARD_IA".to_owned()),
+                    ..StoredObject::default()
+                },
+            );
+
+            stub
+        }
+
+        fn run(stub: &Stub, threshold: u64) -> Result<Copied, Error> {
+            run_with(stub, threshold, ContentHeaders::default())
+        }
+
+        fn run_with(
+            stub: &Stub,
+            threshold: u64,
+            overrides: ContentHeaders,
+        ) -> Result<Copied, Error> {
+            run_config(stub, threshold, overrides, Encryption::Default)
+        }
+
+        fn run_config(
+            stub: &Stub,
+            threshold: u64,
+            overrides: ContentHeaders,
+            encryption: Encryption,
+        ) -> Result<Copied, Error> {
+            let s3 = testing::start(stub.clone());
+            let mut state = testing::state(s3.addr());
+            {
+                let config = &mut Arc::get_mut(&mut state).unwrap().config;
+                config.copy.multipart_threshold = threshold;
+                config.encryption = encryption;
+            }
+
+            let copied = copy(&state, "bucket", "video.mp4", "copy.mp4", &overrides);
+
+            actix::System::new("test").block_on(copied)
+        }
+
+        fn video() -> Vec<u8> {
+            (0..12 * MIB).map(|i| (i % 251) as u8).collect()
+        }
+
+        #[test]
+        fn test_small_object_single_copy() {
+            let stub = stub_with("video.mp4", b"small".to_vec());
+
+            let copied = run(&stub, 5 * MIB as u64).unwrap();
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(copied.e_tag, Some(stub.objects["video.mp4"].e_tag.clone()));
+            assert!(stub.calls.contains(&"copy_object"));
+            assert!(!stub.calls.contains(&"upload_part_copy"));
+            assert_eq!(stub.objects["copy.mp4"].data, b"small".to_vec());
+            assert_eq!(stub.objects["copy.mp4"].tags["project"], "a b&c");
+            assert_eq!(stub.objects["copy.mp4"].storage_class, Some("STANDARD_IA".to_owned()));
+        }
+
+        #[test]
+        fn test_content_headers_override() {
+            let stub = stub_with("video.mp4", b"small".to_vec());
+            let overrides = ContentHeaders {
+                content_type: Some("video/webm".to_owned()),
+                ..ContentHeaders::default()
+            };
+
+            run_with(&stub, 5 * MIB as u64, overrides).unwrap();
+
+            let stub = stub.lock().unwrap();
+            let copy = &stub.objects["copy.mp4"];
+            assert_eq!(copy.content_type, Some("video/webm".to_owned()));
+            assert_eq!(copy.metadata["origin"], "camera");
+            assert_eq!(copy.tags["project"], "a b&c");
+            assert_eq!(stub.objects["video.mp4"].content_type, Some("video/mp4".to_owned()));
+        }
+
+        #[test]
+        fn test_large_object_multipart_copy() {
+            let stub = stub_with("video.mp4", video());
+
+            let copied = run(&stub, 5 * MIB as u64).unwrap();
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(copied.e_tag, None);
+            assert_eq!(stub.calls.iter().filter(|c| **c == "upload_part_copy").count(), 3);
+            assert!(!stub.calls.contains(&"copy_object"));
+
+            let copy = &stub.objects["copy.mp4"];
+            assert!(copy.data == stub.objects["video.mp4"].data);
+            assert_eq!(copy.content_type, Some("video/mp4".to_owned()));
+            assert_eq!(copy.metadata["origin"], "camera");
+            assert_eq!(copy.tags["project"], "a b&c");
+            assert_eq!(copy.storage_class, Some("STANDARD_IA".to_owned()));
+        }
+
+        #[test]
+        fn test_customer_key_copy() {
+            // single and multipart copy
+            for data in &[b"small".to_vec(), video()] {
+                let stub = stub_with("video.mp4", data.clone());
+                stub.lock().unwrap().objects.get_mut("video.mp4").unwrap().customer_key_md5 =
+                    Some(testing::CUSTOMER_KEY_MD5.to_owned());
+
+                run_config(
+                    &stub,
+                    5 * MIB as u64,
+                    ContentHeaders::default(),
+                    testing::customer_keys(),
+                ).unwrap();
+
+                let stub = stub.lock().unwrap();
+                let copy = &stub.objects["copy.mp4"];
+                assert!(copy.data == *data);
+                assert_eq!(copy.customer_key_md5, Some(testing::CUSTOMER_KEY_MD5.to_owned()));
+            }
+        }
+
+        #[test]
+        fn test_configured_encryption_copy() {
+            let stub = stub_with("video.mp4", b"small".to_vec());
+            let encryption = Encryption::Managed {
+                algorithm: "aws:kms".to_owned(),
+                kms_key_id: Some("alias/proxy".to_owned()),
+            };
+
+            let copied = run_config(&stub, 5 * MIB as u64, ContentHeaders::default(), encryption)
+                .unwrap();
+
+            assert_eq!(copied.e_tag, None);
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.objects["copy.mp4"].encryption, Some("aws:kms".to_owned()));
+        }
+
+        #[test]
+        fn test_copy_rewraps_data_key() {
+            let (_, wrapped) = testing::keyring("old").new_data_key().unwrap();
+
+            // single and multipart copy
+            for data in &[b"small".to_vec(), video()] {
+                let stub = stub_with("video.mp4", data.clone());
+                stub.lock().unwrap().objects.get_mut("video.mp4").unwrap().metadata.insert(
+                    envelope::METADATA_KEY.to_owned(),
+                    wrapped.clone(),
+                );
+                let s3 = testing::start(stub.clone());
+                let mut state = testing::state(s3.addr());
+                {
+                    let config = &mut Arc::get_mut(&mut state).unwrap().config;
+                    config.copy.multipart_threshold = 5 * MIB as u64;
+                    config.keyring = Some(testing::keyring("new"));
+                }
+
+                let overrides = ContentHeaders::default();
+                let copied = copy(&state, "bucket", "video.mp4", "copy.mp4", &overrides);
+                actix::System::new("test").block_on(copied).unwrap();
+
+                let stub = stub.lock().unwrap();
+                let copy = &stub.objects["copy.mp4"];
+                assert!(copy.data == *data);
+                assert_eq!(copy.metadata["origin"], "camera");
+                assert_eq!(copy.content_type, Some("video/mp4".to_owned()));
+                let rewrapped = &copy.metadata[envelope::METADATA_KEY];
+                assert!(rewrapped.starts_with("v1:new:"));
+                assert!(testing::keyring("new").data_key(rewrapped).is_ok());
+            }
+        }
+
+        #[test]
+        fn test_archived_source() {
+            let stub = stub_with("video.mp4", b"small".to_vec());
+            stub.lock().unwrap().objects.get_mut("video.mp4").unwrap().storage_class =
+                Some("GLACIER".to_owned());
+
+            let e = run(&stub, 5 * MIB as u64).unwrap_err();
+
+            assert_eq!(e.as_response_error().error_response().status(), 409);
+            let stub = stub.lock().unwrap();
+            assert!(!stub.calls.contains(&"copy_object"));
+            assert!(!stub.objects.contains_key("copy.mp4"));
+        }
+
+        #[test]
+        fn test_failed_part_aborts_copy() {
+            let stub = stub_with("video.mp4", video());
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "upload_part_copy",
+                status: 403,
+                code: "AccessDenied",
+            });
+
+            assert!(run(&stub, 5 * MIB as u64).is_err());
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.aborted, 1);
+            assert!(stub.uploads.is_empty());
+            assert!(!stub.objects.contains_key("copy.mp4"));
+        }
+
+        #[test]
+        fn test_missing_source() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+
+            let e = run(&stub, 5 * MIB as u64).unwrap_err();
+
+            assert_eq!(e.as_response_error().error_response().status(), 404);
+        }
+    }
+}
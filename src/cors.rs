@@ -0,0 +1,258 @@
+/// Methods allowed by default: all the proxy handles and the rest of WebDAV ones, which clients
+/// send from browsers too
+pub const DEFAULT_METHODS: &str = "GET, HEAD, PUT, POST, PATCH, DELETE, OPTIONS, COPY, MOVE, \
+    PROPFIND, PROPPATCH, MKCOL, LOCK, UNLOCK";
+
+/// Response headers exposed to scripts by default, besides CORS-safelisted ones
+pub const DEFAULT_EXPOSED_HEADERS: &str = "ETag, Content-Range, Content-Length, \
+    Content-Disposition, Location, Repr-Digest, x-amz-version-id, Upload-Offset, \
+    Upload-Length, Upload-Metadata, Tus-Resumable, Tus-Version, Tus-Extension, Tus-Max-Size";
+
+/// Origins allowed to make cross-origin requests
+#[derive(Clone, Debug, PartialEq)]
+pub enum Origins {
+    Any,
+    /// Exact origins, e.g. `https://app.example.com`
+    List(Vec<String>),
+}
+
+/// Cross-origin resource sharing policy
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub origins: Origins,
+    /// Upper case method names
+    pub methods: Vec<String>,
+    /// Lower case request header names, any requested headers are allowed if not set
+    pub headers: Option<Vec<String>>,
+    pub exposed_headers: Vec<String>,
+    /// Cookies and HTTP authentication are sent with requests
+    pub credentials: bool,
+    /// Seconds browsers may cache preflight responses for
+    pub max_age: Option<u64>,
+}
+
+/// Comma separated list items, `None` for `*`
+fn parse_list(value: &str) -> Option<Vec<String>> {
+    if value.trim() == "*" {
+        return None;
+    }
+
+    Some(
+        value
+            .split(',')
+            .map(|item| item.trim().to_owned())
+            .filter(|item| !item.is_empty())
+            .collect(),
+    )
+}
+
+impl Policy {
+    /// Policy from comma separated lists of origins, methods, request and exposed headers,
+    /// `*` allows any origin or request header. Credentials need origins to be listed.
+    pub fn new(
+        origins: &str,
+        methods: &str,
+        headers: &str,
+        exposed_headers: &str,
+        credentials: bool,
+        max_age: Option<u64>,
+    ) -> Result<Policy, String> {
+        let origins = match parse_list(origins) {
+            None => Origins::Any,
+            Some(ref origins) if origins.is_empty() => return Err("No origins allowed".to_owned()),
+            Some(origins) => {
+                if let Some(origin) = origins.iter().find(|o| !o.contains("://")) {
+                    return Err(format!("Invalid origin \"{}\"", origin));
+                }
+
+                let origins = origins.iter().map(|o| o.trim_right_matches('/').to_owned());
+
+                Origins::List(origins.collect())
+            }
+        };
+        if origins == Origins::Any && credentials {
+            let error = "Credentials can't be allowed for any origin, origins must be listed";
+            return Err(error.to_owned());
+        }
+
+        let methods = parse_list(methods)
+            .ok_or_else(|| "Allowed methods must be listed".to_owned())?
+            .into_iter()
+            .map(|m| m.to_uppercase())
+            .collect();
+        let headers =
+            parse_list(headers).map(|h| h.into_iter().map(|h| h.to_lowercase()).collect());
+        let exposed_headers = parse_list(exposed_headers)
+            .ok_or_else(|| "Exposed headers must be listed".to_owned())?;
+
+        Ok(Policy {
+            origins: origins,
+            methods: methods,
+            headers: headers,
+            exposed_headers: exposed_headers,
+            credentials: credentials,
+            max_age: max_age,
+        })
+    }
+
+    /// `Access-Control-Allow-Origin` value for request origin, `None` if it's not allowed
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        match self.origins {
+            Origins::Any => Some("*".to_owned()),
+            Origins::List(ref origins) if origins.iter().any(|o| o == origin) => {
+                Some(origin.to_owned())
+            }
+            Origins::List(_) => None,
+        }
+    }
+
+    /// Responses depend on `Origin` request header, so caches must keep them apart
+    pub fn varies_by_origin(&self) -> bool {
+        self.origins != Origins::Any
+    }
+
+    /// Headers of response to actual (not preflight) request from allowed origin
+    pub fn response_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+
+        if let Some(allowed) = self.allow_origin(origin) {
+            headers.push(("Access-Control-Allow-Origin", allowed));
+
+            if self.credentials {
+                headers.push(("Access-Control-Allow-Credentials", "true".to_owned()));
+            }
+            if !self.exposed_headers.is_empty() {
+                headers.push(("Access-Control-Expose-Headers", self.exposed_headers.join(", ")));
+            }
+        }
+
+        headers
+    }
+
+    /// Headers of response to preflight request, given `Access-Control-Request-Method` and
+    /// `Access-Control-Request-Headers`. Error tells why the request isn't allowed.
+    pub fn preflight_headers(
+        &self,
+        origin: &str,
+        method: &str,
+        request_headers: Option<&str>,
+    ) -> Result<Vec<(&'static str, String)>, String> {
+        let allowed = self
+            .allow_origin(origin)
+            .ok_or_else(|| format!("Origin {} is not allowed", origin))?;
+
+        if !self.methods.iter().any(|m| m == method) {
+            return Err(format!("Method {} is not allowed", method));
+        }
+
+        let requested = request_headers
+            .map(|h| parse_list(&h.to_lowercase()).unwrap_or_default())
+            .unwrap_or_default();
+        let allowed_headers = match self.headers {
+            Some(ref headers) => {
+                if let Some(header) = requested.iter().find(|h| !headers.contains(h)) {
+                    return Err(format!("Header {} is not allowed", header));
+                }
+
+                headers.join(", ")
+            }
+            None => requested.join(", "),
+        };
+
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin", allowed),
+            ("Access-Control-Allow-Methods", self.methods.join(", ")),
+        ];
+
+        if !allowed_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", allowed_headers));
+        }
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_owned()));
+        }
+        if let Some(max_age) = self.max_age {
+            headers.push(("Access-Control-Max-Age", max_age.to_string()));
+        }
+
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod cors {
+        use cors::*;
+
+        fn policy(origins: &str, headers: &str, credentials: bool) -> Policy {
+            Policy::new(origins, DEFAULT_METHODS, headers, "ETag", credentials, Some(600)).unwrap()
+        }
+
+        #[test]
+        fn test_new() {
+            let policy = policy("https://a.example.com/, https://b.example.com", "*", false);
+            assert_eq!(
+                policy.origins,
+                Origins::List(vec![
+                    "https://a.example.com".to_owned(),
+                    "https://b.example.com".to_owned(),
+                ])
+            );
+            for method in &["COPY", "PROPFIND", "PROPPATCH", "MKCOL", "LOCK", "UNLOCK"] {
+                assert!(policy.methods.contains(&method.to_string()), "{}", method);
+            }
+            assert_eq!(policy.headers, None);
+
+            assert!(Policy::new("", "GET", "*", "", false, None).is_err());
+            assert!(Policy::new("example.com", "GET", "*", "", false, None).is_err());
+            assert!(Policy::new("*", "*", "*", "", false, None).is_err());
+        }
+
+        #[test]
+        fn test_allow_origin() {
+            let any = policy("*", "*", false);
+            assert_eq!(any.allow_origin("https://a.example.com"), Some("*".to_owned()));
+            assert!(!any.varies_by_origin());
+
+            // browsers would send credentials to any site otherwise
+            assert!(Policy::new("*", DEFAULT_METHODS, "*", "", true, None).is_err());
+
+            let origin = Some("https://a.example.com".to_owned());
+            let list = policy("https://a.example.com,https://b.example.com", "*", false);
+            assert_eq!(list.allow_origin("https://a.example.com"), origin);
+            assert_eq!(list.allow_origin("https://c.example.com"), None);
+            assert!(list.response_headers("https://c.example.com").is_empty());
+            assert_eq!(
+                list.response_headers("https://a.example.com"),
+                vec![
+                    ("Access-Control-Allow-Origin", "https://a.example.com".to_owned()),
+                    ("Access-Control-Expose-Headers", "ETag".to_owned()),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_preflight_headers() {
+            let policy = policy("https://a.example.com", "Content-Type, Depth", true);
+            let origin = "https://a.example.com";
+
+            assert_eq!(
+                policy.preflight_headers(origin, "MOVE", Some("depth,content-type")),
+                Ok(vec![
+                    ("Access-Control-Allow-Origin", origin.to_owned()),
+                    ("Access-Control-Allow-Methods", DEFAULT_METHODS.to_owned()),
+                    ("Access-Control-Allow-Headers", "content-type, depth".to_owned()),
+                    ("Access-Control-Allow-Credentials", "true".to_owned()),
+                    ("Access-Control-Max-Age", "600".to_owned()),
+                ])
+            );
+            assert!(policy.preflight_headers(origin, "PROPFIND", None).is_ok());
+            assert!(policy.preflight_headers(origin, "TRACE", None).is_err());
+            assert!(policy.preflight_headers(origin, "PUT", Some("x-custom")).is_err());
+            assert!(policy.preflight_headers("https://c.example.com", "GET", None).is_err());
+
+            let any = Policy::new("*", "GET", "*", "", false, None).unwrap();
+            let headers = any.preflight_headers(origin, "GET", Some("X-Custom")).unwrap();
+            assert!(headers.contains(&("Access-Control-Allow-Headers", "x-custom".to_owned())));
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use aws_s3_webdav::retry::status_error_body;
+use futures::{future, stream, Future, Stream};
+use rusoto_core::request::HttpResponse;
+use rusoto_core::{DispatchSignedRequest, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use std::time::Duration;
+
+/// Dispatches S3 requests with inner dispatcher, replacing bodies of error responses without
+/// S3 error code by ones with code standing for their status. Rusoto passes only bodies to
+/// errors, so transient failures of HEAD requests, or pages of proxies, couldn't be retried,
+/// and missing objects couldn't be told from denied access.
+pub struct StatusDispatcher<D>(pub D);
+
+impl<D> DispatchSignedRequest for StatusDispatcher<D>
+where
+    D: DispatchSignedRequest,
+    D::Future: Send,
+{
+    type Future = Box<Future<Item = HttpResponse, Error = HttpDispatchError> + Send>;
+
+    fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        Box::new(self.0.dispatch(request, timeout).and_then(
+            |response| -> Box<Future<Item = HttpResponse, Error = HttpDispatchError> + Send> {
+                if !response.status.is_client_error() && !response.status.is_server_error() {
+                    return Box::new(future::ok(response));
+                }
+
+                let HttpResponse {
+                    status,
+                    body,
+                    headers,
+                } = response;
+
+                // error bodies are small
+                Box::new(body.concat2().from_err().map(move |body| {
+                    let text = String::from_utf8_lossy(&body).into_owned();
+                    let body = match status_error_body(status.as_u16(), &text) {
+                        Some(replaced) => replaced.into_bytes(),
+                        None => body,
+                    };
+
+                    HttpResponse {
+                        status: status,
+                        body: Box::new(stream::once(Ok(body))),
+                        headers: headers,
+                    }
+                }))
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod dispatcher {
+        use actix_web::test::TestServer;
+        use aws_s3_webdav::retry::RetryPolicy;
+        use std::time::Duration;
+        use testing::{self, Failure, StoredObject, Stub};
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let stub = Stub::default();
+            stub.lock().unwrap().objects.insert(
+                "a.txt".to_owned(),
+                StoredObject {
+                    data: b"Hello".to_vec(),
+                    e_tag: testing::e_tag(b"Hello"),
+                    ..StoredObject::default()
+                },
+            );
+
+            testing::proxy(stub, |config| {
+                config.retry =
+                    RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1), false)
+            })
+        }
+
+        /// Failure with HTML page of load balancer instead of S3 error
+        fn html_failure(operation: &'static str, status: u16) -> Failure {
+            Failure {
+                operation: operation,
+                status: status,
+                code: "",
+            }
+        }
+
+        #[test]
+        fn test_retries_server_errors_without_s3_error() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.extend(vec![
+                html_failure("get_object", 502),
+                html_failure("head_object", 503),
+                html_failure("head_object", 504),
+            ]);
+
+            let get = testing::request(proxy.addr(), "GET", "/a.txt", &[], b"");
+            assert_eq!((get.status, get.body), (200, b"Hello".to_vec()));
+            assert_eq!(testing::request(proxy.addr(), "HEAD", "/a.txt", &[], b"").status, 200);
+
+            let calls = stub.lock().unwrap().calls.clone();
+            assert_eq!(calls.iter().filter(|c| **c == "get_object").count(), 2);
+            assert_eq!(calls.iter().filter(|c| **c == "head_object").count(), 3);
+        }
+
+        #[test]
+        fn test_head_errors() {
+            let (stub, _s3, proxy) = start();
+            let head = |path: &str| testing::request(proxy.addr(), "HEAD", path, &[], b"").status;
+
+            assert_eq!(head("/missing.txt"), 404);
+
+            stub.lock().unwrap().failures.push(html_failure("head_object", 403));
+            assert_eq!(head("/a.txt"), 403);
+        }
+
+        #[test]
+        fn test_client_errors_are_kept() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.push(html_failure("get_object", 501));
+
+            assert_eq!(testing::request(proxy.addr(), "GET", "/a.txt", &[], b"").status, 500);
+            assert_eq!(stub.lock().unwrap().calls, vec!["get_object"]);
+        }
+    }
+}
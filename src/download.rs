@@ -0,0 +1,173 @@
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound};
+use actix_web::Error;
+use aws_s3_webdav::archive::{self, ArchiveWriter, Format};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use env::AppEnv;
+use futures::{future, stream, Future, Stream};
+use rusoto_s3::*;
+use s3::{data_key, get_object, list_objects, plaintext_body, plaintext_length};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Number of objects requested ahead of the one being archived, their bodies are read only
+/// when it's their turn
+const READ_AHEAD: usize = 2;
+
+/// Archive body, chunks are sent as soon as they are written
+pub type ArchiveStream = Box<Stream<Item=Bytes, Error=Error>>;
+
+type Writer = Rc<RefCell<Box<ArchiveWriter>>>;
+
+/// Object to archive
+struct Member {
+    key: String,
+    /// Entry name, relative to archived collection
+    name: String,
+    modified: DateTime<Utc>,
+}
+
+/// Object key of path relative to key prefix
+fn key(env: &AppEnv, path: &str) -> String {
+    match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, path),
+        None => path.to_owned(),
+    }
+}
+
+fn archive_error(e: String) -> Error {
+    error!("Failed to write archive: {}", e);
+    ErrorInternalServerError(e)
+}
+
+/// Objects under collection prefix, in key order. Objects which names aren't safe to extract
+/// are skipped, archive limits are checked against sizes of stored objects.
+fn members(env: &AppEnv, path: &str) -> Box<Future<Item=Vec<Member>, Error=Error>> {
+    let env = env.clone();
+    let prefix = key(&env, path);
+    let root = path.is_empty();
+
+    Box::new(list_objects(&env, &prefix).and_then(move |objects| {
+        if objects.is_empty() && !root {
+            return Err(ErrorNotFound("Collection not found"));
+        }
+
+        let limits = &env.config.archive;
+        let mut members = Vec::new();
+        let mut size = 0;
+
+        for object in objects {
+            let key = match object.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let name = match archive::entry_name(&prefix, &key) {
+                Some(name) => name,
+                None => {
+                    if !key.ends_with('/') {
+                        warn!("Not archiving {}, its name is not safe to extract", key);
+                    }
+                    continue;
+                }
+            };
+            let modified = object
+                .last_modified
+                .and_then(|m| DateTime::parse_from_rfc3339(&m).ok())
+                .map_or_else(Utc::now, |m| m.with_timezone(&Utc));
+
+            size += object.size.unwrap_or(0) as u64;
+            members.push(Member {
+                key: key,
+                name: name,
+                modified: modified,
+            });
+        }
+
+        if members.len() > limits.max_entries {
+            return Err(ErrorForbidden(format!(
+                "Collection has more than {} objects, it can't be archived",
+                limits.max_entries
+            )));
+        }
+
+        if size > limits.max_size {
+            return Err(ErrorForbidden(format!(
+                "Collection is larger than {} bytes, it can't be archived",
+                limits.max_size
+            )));
+        }
+
+        Ok(members)
+    }))
+}
+
+/// Archive entry of fetched object: header, body passed through writer and entry trailer
+fn entry(env: &AppEnv, writer: &Writer, member: Member, output: GetObjectOutput) -> ArchiveStream {
+    let key = match data_key(env, &output.metadata) {
+        Ok(key) => key,
+        Err(e) => return Box::new(stream::once(Err(e))),
+    };
+    let size = plaintext_length(output.content_length.unwrap_or(0), &output.metadata) as u64;
+    let body: Box<Stream<Item=Vec<u8>, Error=Error>> = match output.body {
+        Some(body) => plaintext_body(body, key),
+        None => Box::new(stream::empty()),
+    };
+    let (header_writer, body_writer) = (writer.clone(), writer.clone());
+    let trailer_writer = writer.clone();
+
+    let header = future::lazy(move || {
+        header_writer.borrow_mut().start_entry(&member.name, size, &member.modified)
+    });
+    let body = body.and_then(move |chunk| {
+        body_writer.borrow_mut().write(&chunk).map_err(archive_error)
+    });
+    let trailer = future::lazy(move || trailer_writer.borrow_mut().finish_entry());
+
+    Box::new(
+        header
+            .map_err(archive_error)
+            .into_stream()
+            .chain(body)
+            .chain(trailer.map_err(archive_error).into_stream())
+            .map(Bytes::from),
+    )
+}
+
+/// Archive of all objects under collection path (relative to key prefix, empty or ending with
+/// `/`). Objects are fetched one by one, with `READ_AHEAD` objects requested in advance, so
+/// memory use doesn't depend on collection size. Failures after streaming started abort it.
+pub fn archive(
+    env: &AppEnv,
+    path: &str,
+    format: Format,
+) -> Box<Future<Item=ArchiveStream, Error=Error>> {
+    let env = env.clone();
+
+    Box::new(members(&env, path).map(move |members| -> ArchiveStream {
+        let writer: Writer = Rc::new(RefCell::new(format.writer()));
+        let finish_writer = writer.clone();
+        let s = env.clone();
+
+        let entries = stream::iter_ok::<_, Error>(members)
+            .map(move |member| get_object(&s, &member.key, None).map(|output| (member, output)))
+            .buffered(READ_AHEAD + 1)
+            .map(move |(member, output)| -> ArchiveStream {
+                match output {
+                    Some(output) => entry(&env, &writer, member, output),
+                    None => {
+                        warn!("Not archiving {}, it was deleted", member.key);
+                        Box::new(stream::empty())
+                    }
+                }
+            })
+            .flatten();
+        let trailer = future::lazy(move || finish_writer.borrow_mut().finish());
+
+        // compressed output is buffered, empty chunks would end chunked response early
+        Box::new(
+            entries
+                .chain(trailer.map(Bytes::from).map_err(archive_error).into_stream())
+                .filter(|chunk| !chunk.is_empty()),
+        )
+    }))
+}
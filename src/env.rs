@@ -1,5 +1,20 @@
-use rusoto_core::Region;
+use aws_s3_webdav::cors::Policy;
+use aws_s3_webdav::envelope::Keyring;
+use aws_s3_webdav::in_flight::InFlight;
+use aws_s3_webdav::metrics::Metrics;
+use aws_s3_webdav::metadata::MAX_METADATA_SIZE;
+use aws_s3_webdav::multipart::MAX_COPY_OBJECT_SIZE;
+use aws_s3_webdav::readiness::Readiness;
+use aws_s3_webdav::retry::RetryPolicy;
+use aws_s3_webdav::sse::CustomerKeys;
+use aws_s3_webdav::storage_class::StorageClassRules;
+use aws_s3_webdav::tus::CompletedUploads;
+use dispatcher::StatusDispatcher;
+use rusoto_core::{DefaultCredentialsProvider, HttpClient, Region};
 use rusoto_s3::*;
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct AwsConfig {
     pub region: Region,
@@ -30,22 +45,222 @@ impl S3Config {
     }
 }
 
+pub struct UploadConfig {
+    /// Base multipart upload part size, in bytes
+    pub part_size: u64,
+    /// Largest part size, parts are buffered in memory whole, in bytes
+    pub max_part_size: u64,
+    /// Max time to wait for next chunk of request body
+    pub idle_timeout: Duration,
+}
+
+impl UploadConfig {
+    pub fn new(part_size: u64, max_part_size: u64, idle_timeout: Duration) -> UploadConfig {
+        UploadConfig {
+            part_size: part_size,
+            max_part_size: max_part_size,
+            idle_timeout: idle_timeout,
+        }
+    }
+}
+
+pub struct CopyConfig {
+    /// Sources larger than this are copied with multipart upload, in bytes
+    pub multipart_threshold: u64,
+    /// Base part size for multipart copy, in bytes
+    pub part_size: u64,
+    /// Number of parts copied in parallel
+    pub concurrency: usize,
+}
+
+impl CopyConfig {
+    pub fn new(part_size: u64, concurrency: usize) -> CopyConfig {
+        CopyConfig {
+            multipart_threshold: MAX_COPY_OBJECT_SIZE,
+            part_size: part_size,
+            concurrency: cmp::max(concurrency, 1),
+        }
+    }
+}
+
+pub struct MetadataConfig {
+    /// Lowercased prefix of request headers stored as object user metadata, empty disables it
+    pub header_prefix: String,
+    /// Max size of user metadata, in bytes
+    pub max_size: usize,
+}
+
+impl MetadataConfig {
+    pub fn new<P>(header_prefix: P, max_size: usize) -> MetadataConfig
+    where
+        P: Into<String>,
+    {
+        MetadataConfig {
+            header_prefix: header_prefix.into().to_lowercase(),
+            max_size: cmp::min(max_size, MAX_METADATA_SIZE),
+        }
+    }
+}
+
+pub struct TrashConfig {
+    /// Prefix deleted objects are moved under, relative to key prefix, ends with `/`
+    pub prefix: String,
+    /// Deleted objects are purged from trash after this time
+    pub retention: Duration,
+}
+
+impl TrashConfig {
+    pub fn new<P>(prefix: P, retention: Duration) -> TrashConfig
+    where
+        P: Into<String>,
+    {
+        let mut prefix = prefix.into();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        TrashConfig {
+            prefix: prefix,
+            retention: retention,
+        }
+    }
+}
+
+/// Resumable uploads with tus protocol
+pub struct TusConfig {
+    /// Prefix data of unfinished uploads, which doesn't fill a whole part yet, is staged
+    /// under, relative to key prefix, ends with `/`
+    pub staging_prefix: String,
+}
+
+impl TusConfig {
+    pub fn new<P>(staging_prefix: P) -> TusConfig
+    where
+        P: Into<String>,
+    {
+        let mut staging_prefix = staging_prefix.into();
+        if !staging_prefix.ends_with('/') {
+            staging_prefix.push('/');
+        }
+
+        TusConfig {
+            staging_prefix: staging_prefix,
+        }
+    }
+}
+
+/// Limits of downloaded and extracted collection archives
+pub struct ArchiveConfig {
+    /// Max number of objects in archive, or extracted from it
+    pub max_entries: usize,
+    /// Max total size of archived objects or extracted files, in bytes
+    pub max_size: u64,
+}
+
+impl ArchiveConfig {
+    pub fn new(max_entries: usize, max_size: u64) -> ArchiveConfig {
+        ArchiveConfig {
+            max_entries: max_entries,
+            max_size: max_size,
+        }
+    }
+}
+
+/// Static website hosting, GETs are served like S3 website endpoints do
+pub struct WebsiteConfig {
+    /// Document served for collection GETs, relative to collection path
+    pub index_document: String,
+    /// Document served with 404 status for missing objects, relative to key prefix
+    pub error_document: Option<String>,
+    /// Document served for all missing objects instead of error document, for single page apps
+    pub spa_fallback: Option<String>,
+}
+
+/// Readiness checks of S3 reachability
+pub struct ReadinessConfig {
+    /// Key checked with HEAD instead of bucket, relative to key prefix, for credentials
+    /// allowed to access objects only
+    pub probe_key: Option<String>,
+    /// Result of last check is reported for this time
+    pub cache_ttl: Duration,
+}
+
+impl ReadinessConfig {
+    pub fn new(probe_key: Option<String>, cache_ttl: Duration) -> ReadinessConfig {
+        ReadinessConfig {
+            probe_key: probe_key,
+            cache_ttl: cache_ttl,
+        }
+    }
+}
+
+/// Server-side encryption applied to stored objects
+pub enum Encryption {
+    /// Bucket default encryption applies
+    Default,
+    /// S3 (`AES256`) or KMS (`aws:kms`, optionally with own key) managed keys
+    Managed {
+        algorithm: String,
+        kms_key_id: Option<String>,
+    },
+    /// Customer provided keys (SSE-C) held by proxy, sent with every object request
+    Customer(CustomerKeys),
+}
+
 pub struct AppConfig {
     pub aws: AwsConfig,
     pub s3: S3Config,
+    pub upload: UploadConfig,
+    pub copy: CopyConfig,
+    pub metadata: MetadataConfig,
+    pub encryption: Encryption,
+    /// Storage class of uploaded objects by key prefix, unless requested by client
+    pub storage_class: StorageClassRules,
+    /// Keys for client-side encryption done by proxy, objects are stored as is if not set
+    pub keyring: Option<Keyring>,
+    /// Deleted objects are moved to trash if set, otherwise they're deleted immediately
+    pub trash: Option<TrashConfig>,
+    /// Serve collections with index documents instead of listings if set
+    pub website: Option<WebsiteConfig>,
+    pub archive: ArchiveConfig,
+    /// Resumable uploads are accepted if set
+    pub tus: Option<TusConfig>,
+    /// Browsers may make cross-origin requests if set
+    pub cors: Option<Policy>,
+    pub retry: RetryPolicy,
+    pub readiness: ReadinessConfig,
 }
 
+/// Alias for application environment, shared between handlers
+pub type AppEnv = Arc<AppState>;
+
 /// Application State (environment)
 pub struct AppState {
     pub s3: S3Client,
     pub config: AppConfig,
+    /// Requests and uploads in flight, shared by workers of the same server
+    pub in_flight: InFlight,
+    /// Metrics, shared by workers of the same server
+    pub metrics: Metrics,
+    /// Last readiness check
+    pub readiness: Readiness,
+    /// Recently completed resumable uploads, shared by workers of the same server
+    pub completed_uploads: CompletedUploads,
 }
 
 impl AppState {
     pub fn new(config: AppConfig) -> AppState {
         AppState {
-            s3: S3Client::new(config.aws.region.to_owned()),
+            s3: S3Client::new_with(
+                StatusDispatcher(HttpClient::new().expect("Cannot build HTTP client")),
+                DefaultCredentialsProvider::new().expect("Cannot build AWS credentials provider"),
+                config.aws.region.to_owned(),
+            ),
             config: config,
+            in_flight: InFlight::default(),
+            metrics: Metrics::default(),
+            readiness: Readiness::default(),
+            completed_uploads: CompletedUploads::default(),
         }
     }
 }
@@ -0,0 +1,483 @@
+//! Client-side envelope encryption: every object is encrypted with its own random data key,
+//! which is stored in object metadata wrapped (encrypted) with a keyring key.
+//!
+//! Data is encrypted with AES-256-GCM in chunks of `CHUNK_SIZE` bytes, each followed by its
+//! authentication tag, so any chunk can be decrypted on its own. Chunk nonce is the chunk index
+//! with a flag marking the last chunk, which protects against reordered and truncated chunks.
+
+use base64;
+use futures::{Async, Poll, Stream};
+use ring::aead::{self, OpeningKey, SealingKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cmp;
+use std::collections::HashMap;
+use toml;
+
+/// Plaintext size of a single chunk, last chunk may be shorter
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Authentication tag appended to every chunk
+pub const TAG_LEN: usize = 16;
+
+/// Object metadata key of wrapped data key, stored as `v1:<key id>:<base64 wrapped key>`
+pub const METADATA_KEY: &str = "cse";
+
+/// Longest keyring key id
+pub const MAX_KEY_ID_LEN: usize = 32;
+
+/// Max size of the metadata entry, key included
+pub const METADATA_SIZE: usize = 3 + 3 + MAX_KEY_ID_LEN + 1 + 80;
+
+const VERSION: &str = "v1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Encrypted size of plaintext of given size, empty plaintext is stored as single empty chunk
+pub fn encrypted_size(plaintext: u64) -> u64 {
+    let chunks = cmp::max((plaintext + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64, 1);
+
+    plaintext + chunks * TAG_LEN as u64
+}
+
+/// Plaintext size of encrypted data of given size, `None` if it's not a valid encrypted size
+pub fn plaintext_size(encrypted: u64) -> Option<u64> {
+    let chunk = (CHUNK_SIZE + TAG_LEN) as u64;
+    let chunks = cmp::max((encrypted + chunk - 1) / chunk, 1);
+    let last = encrypted.checked_sub((chunks - 1) * chunk)?;
+
+    if last < TAG_LEN as u64 {
+        return None;
+    }
+
+    Some(encrypted - chunks * TAG_LEN as u64)
+}
+
+fn nonce(index: u64, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+
+    for (i, byte) in nonce.iter_mut().take(8).enumerate() {
+        *byte = (index >> (56 - i * 8)) as u8;
+    }
+
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/// Random per-object key
+pub struct DataKey {
+    key: Vec<u8>,
+}
+
+impl DataKey {
+    fn generate() -> Result<DataKey, String> {
+        let mut key = vec![0u8; KEY_LEN];
+        SystemRandom::new().fill(&mut key).map_err(|_| "Failed to generate data key".to_owned())?;
+
+        Ok(DataKey { key: key })
+    }
+
+    /// Encrypt chunk in place, appending authentication tag
+    fn seal(&self, index: u64, last: bool, chunk: &mut Vec<u8>) {
+        let key = SealingKey::new(&AES_256_GCM, &self.key).expect("Valid AES-256 key");
+        let len = chunk.len();
+        chunk.resize(len + TAG_LEN, 0);
+
+        aead::seal_in_place(&key, &nonce(index, last), &[], chunk, TAG_LEN)
+            .expect("Chunk is within AES-GCM limits");
+    }
+
+    /// Decrypt chunk with authentication tag in place
+    fn open(&self, index: u64, last: bool, chunk: &mut Vec<u8>) -> Result<(), String> {
+        let key = OpeningKey::new(&AES_256_GCM, &self.key).expect("Valid AES-256 key");
+        let len = aead::open_in_place(&key, &nonce(index, last), &[], 0, chunk)
+            .map_err(|_| format!("Chunk {} failed authentication", index))?
+            .len();
+
+        chunk.truncate(len);
+        Ok(())
+    }
+}
+
+/// Keys used to wrap data keys, new objects use `current` one, others are kept to read
+/// objects written before rotation
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl Keyring {
+    /// Parse keyring file with base64 encoded 256 bit keys by key id:
+    ///
+    /// ```toml
+    /// current = "2024-06"
+    ///
+    /// [keys]
+    /// "2023-01" = "old key"
+    /// "2024-06" = "current key"
+    /// ```
+    pub fn parse(content: &str) -> Result<Keyring, String> {
+        let value = content.parse::<toml::Value>().map_err(|e| e.to_string())?;
+        let current = value
+            .get("current")
+            .and_then(|c| c.as_str())
+            .ok_or("Missing current key id")?;
+        let table = value.get("keys").and_then(|k| k.as_table()).ok_or("Missing [keys] table")?;
+
+        let mut keys = HashMap::new();
+
+        for (id, key) in table {
+            let valid_id = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+
+            if id.is_empty() || id.len() > MAX_KEY_ID_LEN || !id.chars().all(valid_id) {
+                return Err(format!("Invalid key id \"{}\"", id));
+            }
+
+            let key = key
+                .as_str()
+                .and_then(|k| base64::decode(k.trim()).ok())
+                .filter(|k| k.len() == KEY_LEN)
+                .ok_or_else(|| format!("Key \"{}\" must be base64 encoded 32 bytes", id))?;
+
+            keys.insert(id.to_owned(), key);
+        }
+
+        if !keys.contains_key(current) {
+            return Err(format!("Current key \"{}\" not found in [keys]", current));
+        }
+
+        Ok(Keyring {
+            current: current.to_owned(),
+            keys: keys,
+        })
+    }
+
+    fn wrap(&self, key: &DataKey) -> Result<String, String> {
+        let wrapping = SealingKey::new(&AES_256_GCM, &self.keys[&self.current])
+            .map_err(|_| "Invalid keyring key".to_owned())?;
+        let aad = format!("{}:{}", VERSION, self.current);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| "Failed to generate nonce")?;
+
+        let mut sealed = key.key.clone();
+        sealed.resize(KEY_LEN + TAG_LEN, 0);
+        aead::seal_in_place(&wrapping, &nonce, aad.as_bytes(), &mut sealed, TAG_LEN)
+            .map_err(|_| "Failed to wrap data key".to_owned())?;
+
+        // random nonce, followed by wrapped key and tag
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&sealed);
+
+        Ok(format!("{}:{}", aad, base64::encode(&wrapped)))
+    }
+
+    /// Generate data key for a new object, returned with metadata value to store
+    pub fn new_data_key(&self) -> Result<(DataKey, String), String> {
+        let key = DataKey::generate()?;
+        let metadata = self.wrap(&key)?;
+
+        Ok((key, metadata))
+    }
+
+    /// Unwrap data key stored in object metadata
+    pub fn data_key(&self, metadata: &str) -> Result<DataKey, String> {
+        let mut fields = metadata.splitn(3, ':');
+
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(VERSION), Some(id), Some(wrapped)) => {
+                let key = self.keys.get(id).ok_or_else(|| format!("Unknown key id \"{}\"", id))?;
+                let opening = OpeningKey::new(&AES_256_GCM, key)
+                    .map_err(|_| "Invalid keyring key".to_owned())?;
+                let mut wrapped = base64::decode(wrapped)
+                    .ok()
+                    .filter(|w| w.len() == NONCE_LEN + KEY_LEN + TAG_LEN)
+                    .ok_or("Invalid wrapped data key")?;
+                let mut sealed = wrapped.split_off(NONCE_LEN);
+                let aad = format!("{}:{}", VERSION, id);
+
+                let key = aead::open_in_place(&opening, &wrapped, aad.as_bytes(), 0, &mut sealed)
+                    .map_err(|_| "Data key failed authentication".to_owned())?;
+
+                Ok(DataKey { key: key.to_vec() })
+            }
+            _ => Err("Unsupported encryption metadata".to_owned()),
+        }
+    }
+
+    /// Wrap data key with current keyring key, `None` if it's wrapped with current key already
+    pub fn rewrap(&self, metadata: &str) -> Result<Option<String>, String> {
+        if metadata.starts_with(&format!("{}:{}:", VERSION, self.current)) {
+            return Ok(None);
+        }
+
+        self.data_key(metadata).and_then(|key| self.wrap(&key)).map(Some)
+    }
+}
+
+/// Stream adapter encrypting input into chunks, one chunk per item
+pub struct EncryptChunks<S> {
+    inner: S,
+    key: DataKey,
+    index: u64,
+    buffer: Vec<u8>,
+    done: bool,
+    finished: bool,
+}
+
+impl<S> EncryptChunks<S> {
+    pub fn new(inner: S, key: DataKey) -> EncryptChunks<S> {
+        EncryptChunks {
+            inner: inner,
+            key: key,
+            index: 0,
+            buffer: Vec::new(),
+            done: false,
+            finished: false,
+        }
+    }
+
+    fn next_chunk(&mut self, size: usize, last: bool) -> Vec<u8> {
+        let rest = self.buffer.split_off(size);
+        let mut chunk = ::std::mem::replace(&mut self.buffer, rest);
+
+        self.key.seal(self.index, last, &mut chunk);
+        self.index += 1;
+        chunk
+    }
+}
+
+impl<S, B> Stream for EncryptChunks<S>
+where
+    S: Stream<Item = B>,
+    B: AsRef<[u8]>,
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        loop {
+            // full chunk is only known not to be the last one once more data arrives
+            if self.buffer.len() > CHUNK_SIZE {
+                return Ok(Async::Ready(Some(self.next_chunk(CHUNK_SIZE, false))));
+            }
+
+            if self.done {
+                if self.finished {
+                    return Ok(Async::Ready(None));
+                }
+
+                self.finished = true;
+                let size = self.buffer.len();
+                return Ok(Async::Ready(Some(self.next_chunk(size, true))));
+            }
+
+            match self.inner.poll()? {
+                Async::Ready(Some(data)) => self.buffer.extend_from_slice(data.as_ref()),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Stream adapter decrypting chunks produced by `EncryptChunks`, input may be split
+/// arbitrarily. Fails with error built by `on_error` if data was tampered with.
+pub struct DecryptChunks<S, F> {
+    inner: S,
+    key: DataKey,
+    on_error: F,
+    index: u64,
+    buffer: Vec<u8>,
+    done: bool,
+    finished: bool,
+}
+
+impl<S, F> DecryptChunks<S, F>
+where
+    S: Stream,
+    F: Fn(String) -> S::Error,
+{
+    pub fn new(inner: S, key: DataKey, on_error: F) -> DecryptChunks<S, F> {
+        DecryptChunks {
+            inner: inner,
+            key: key,
+            on_error: on_error,
+            index: 0,
+            buffer: Vec::new(),
+            done: false,
+            finished: false,
+        }
+    }
+
+    fn next_chunk(&mut self, size: usize, last: bool) -> Result<Vec<u8>, S::Error> {
+        let rest = self.buffer.split_off(size);
+        let mut chunk = ::std::mem::replace(&mut self.buffer, rest);
+
+        self.key.open(self.index, last, &mut chunk).map_err(&self.on_error)?;
+        self.index += 1;
+        Ok(chunk)
+    }
+}
+
+impl<S, B, F> Stream for DecryptChunks<S, F>
+where
+    S: Stream<Item = B>,
+    B: AsRef<[u8]>,
+    F: Fn(String) -> S::Error,
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        loop {
+            if self.buffer.len() > CHUNK_SIZE + TAG_LEN {
+                let chunk = self.next_chunk(CHUNK_SIZE + TAG_LEN, false)?;
+                return Ok(Async::Ready(Some(chunk)));
+            }
+
+            if self.done {
+                if self.finished {
+                    return Ok(Async::Ready(None));
+                }
+
+                self.finished = true;
+                let size = self.buffer.len();
+                return Ok(Async::Ready(Some(self.next_chunk(size, true)?)));
+            }
+
+            match self.inner.poll()? {
+                Async::Ready(Some(data)) => self.buffer.extend_from_slice(data.as_ref()),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod sizes {
+        use envelope::*;
+
+        #[test]
+        fn test_sizes() {
+            let sizes = [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE];
+
+            for &size in &sizes {
+                let encrypted = encrypted_size(size as u64);
+
+                assert_eq!(plaintext_size(encrypted), Some(size as u64));
+            }
+
+            assert_eq!(encrypted_size(0), TAG_LEN as u64);
+            assert_eq!(encrypted_size(CHUNK_SIZE as u64 + 1), CHUNK_SIZE as u64 + 1 + 32);
+        }
+
+        #[test]
+        fn test_invalid_size() {
+            assert_eq!(plaintext_size(0), None);
+            assert_eq!(plaintext_size(TAG_LEN as u64 - 1), None);
+            assert_eq!(plaintext_size((CHUNK_SIZE + TAG_LEN + 1) as u64), None);
+        }
+    }
+
+    mod chunks {
+        use envelope::*;
+        use futures::{stream, Future, Stream};
+
+        const OLD: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        const NEW: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+        fn keyring(current: &str) -> Keyring {
+            Keyring::parse(&format!(
+                "current = \"{}\"\n[keys]\nold = \"{}\"\nnew = \"{}\"\n",
+                current, OLD, NEW
+            )).unwrap()
+        }
+
+        fn encrypt(keyring: &Keyring, data: &[u8], split: usize) -> (Vec<u8>, String) {
+            let (key, metadata) = keyring.new_data_key().unwrap();
+            let input: Vec<Vec<u8>> = data.chunks(split).map(|c| c.to_vec()).collect();
+            let chunks = EncryptChunks::new(stream::iter_ok::<_, String>(input), key);
+
+            (chunks.concat2().wait().unwrap(), metadata)
+        }
+
+        fn decrypt(keyring: &Keyring, data: &[u8], metadata: &str) -> Result<Vec<u8>, String> {
+            let key = keyring.data_key(metadata)?;
+            let input: Vec<Vec<u8>> = data.chunks(1000).map(|c| c.to_vec()).collect();
+
+            DecryptChunks::new(stream::iter_ok::<_, String>(input), key, |e| e)
+                .concat2()
+                .wait()
+        }
+
+        #[test]
+        fn test_round_trip() {
+            let keyring = keyring("new");
+
+            for &size in &[0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 5] {
+                let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                let (encrypted, metadata) = encrypt(&keyring, &data, 7000);
+
+                assert_eq!(encrypted.len() as u64, encrypted_size(size as u64));
+                assert!(size < 2 || encrypted[..size / 2] != data[..size / 2]);
+                assert!(decrypt(&keyring, &encrypted, &metadata).unwrap() == data);
+            }
+        }
+
+        #[test]
+        fn test_tampered() {
+            let keyring = keyring("new");
+            let data = vec![7u8; 2 * CHUNK_SIZE + 10];
+            let (encrypted, metadata) = encrypt(&keyring, &data, CHUNK_SIZE);
+
+            let mut flipped = encrypted.clone();
+            flipped[10] ^= 1;
+            assert!(decrypt(&keyring, &flipped, &metadata).is_err());
+
+            // dropping last chunk
+            let truncated = &encrypted[..2 * (CHUNK_SIZE + TAG_LEN)];
+            assert!(decrypt(&keyring, truncated, &metadata).is_err());
+
+            // swapping chunks
+            let chunk = CHUNK_SIZE + TAG_LEN;
+            let mut swapped = encrypted[chunk..2 * chunk].to_vec();
+            swapped.extend_from_slice(&encrypted[..chunk]);
+            swapped.extend_from_slice(&encrypted[2 * chunk..]);
+            assert!(decrypt(&keyring, &swapped, &metadata).is_err());
+        }
+
+        #[test]
+        fn test_rotation() {
+            let (encrypted, metadata) = encrypt(&keyring("old"), b"secret", 100);
+            let keyring = keyring("new");
+
+            // old objects are still readable
+            assert_eq!(decrypt(&keyring, &encrypted, &metadata).unwrap(), b"secret".to_vec());
+
+            let rewrapped = keyring.rewrap(&metadata).unwrap().unwrap();
+            assert!(rewrapped.starts_with("v1:new:"));
+            assert!(rewrapped.len() + METADATA_KEY.len() <= METADATA_SIZE);
+            assert_eq!(decrypt(&keyring, &encrypted, &rewrapped).unwrap(), b"secret".to_vec());
+            assert_eq!(keyring.rewrap(&rewrapped).unwrap(), None);
+        }
+
+        #[test]
+        fn test_unknown_key() {
+            let (encrypted, metadata) = encrypt(&keyring("old"), b"secret", 100);
+            let other = Keyring::parse(&format!("current = \"k\"\n[keys]\nk = \"{}\"\n", OLD))
+                .unwrap();
+
+            assert!(decrypt(&other, &encrypted, &metadata).is_err());
+            assert!(other.data_key("v2:k:abc").is_err());
+        }
+
+        #[test]
+        fn test_invalid_keyring() {
+            assert!(Keyring::parse("[keys]\n").is_err());
+            assert!(Keyring::parse("current = \"a\"\n[keys]\nb = \"c2hvcnQ=\"\n").is_err());
+            assert!(Keyring::parse(&format!("current = \"a\"\n[keys]\nb = \"{}\"\n", OLD)).is_err());
+        }
+    }
+}
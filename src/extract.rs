@@ -0,0 +1,987 @@
+use archive::Format;
+use flate2::write::GzDecoder;
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use futures::{Async, Poll, Stream};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::mem;
+use std::str;
+
+/// Max size of tar extension header (PAX records or GNU long name), they are kept in memory
+const MAX_EXTENSION_SIZE: u64 = 1024 * 1024;
+
+/// Decompressed data is read in chunks of this size
+const INFLATE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Links, devices and encrypted files, which can't be extracted
+    Unsupported,
+}
+
+/// Archive reading progress, every entry is reported as `Entry`, followed by its data (only
+/// for files) and `EntryEnd` once data is read and verified
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Start of entry, with its name as stored in archive
+    Entry(String, EntryKind),
+    Data(Vec<u8>),
+    EntryEnd,
+}
+
+/// Archive read on the fly, from chunks of any size
+pub trait ArchiveReader {
+    /// Read next chunk of archive, returns events it completes
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>, String>;
+
+    /// End of archive, fails if it's truncated
+    fn finish(&mut self) -> Result<Vec<Event>, String>;
+}
+
+/// New reader of archives in given format
+pub fn reader(format: Format) -> Box<ArchiveReader> {
+    match format {
+        Format::Zip => Box::new(ZipReader::new()),
+        Format::Tar => Box::new(TarReader::new()),
+        Format::TarGz => Box::new(Gunzip::new(TarReader::new())),
+    }
+}
+
+/// Little-endian number
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, b| n << 8 | u64::from(*b))
+}
+
+/// Copy of running CRC, `Crc` isn't `Clone`
+fn copy_crc(crc: &Crc) -> Crc {
+    let mut copy = Crc::new();
+    copy.combine(crc);
+    copy
+}
+
+const TAR_BLOCK: u64 = 512;
+
+fn tar_padding(size: u64) -> u64 {
+    (TAR_BLOCK - size % TAR_BLOCK) % TAR_BLOCK
+}
+
+/// NUL terminated string of tar header field
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Numeric tar header field, octal or GNU base-256
+fn tar_number(field: &[u8]) -> Result<u64, String> {
+    if field[0] & 0x80 != 0 {
+        let first = u64::from(field[0] & 0x7f);
+        return Ok(field[1..].iter().fold(first, |n, b| n << 8 | u64::from(*b)));
+    }
+
+    let text = str::from_utf8(field).map_err(|_| "Invalid tar header number".to_owned())?;
+    let text = text.trim_matches(|c| c == '\0' || c == ' ');
+
+    if text.is_empty() {
+        return Ok(0);
+    }
+
+    u64::from_str_radix(text, 8).map_err(|_| format!("Invalid tar header number \"{}\"", text))
+}
+
+/// PAX extended header records: `<length> <key>=<value>\n`
+fn pax_records(mut data: &[u8]) -> Vec<(String, String)> {
+    let mut records = Vec::new();
+
+    while let Some(space) = data.iter().position(|b| *b == b' ') {
+        let len = match str::from_utf8(&data[..space]).ok().and_then(|l| l.parse().ok()) {
+            Some(len) if len > space + 1 && len <= data.len() => len,
+            _ => break,
+        };
+        let record = &data[space + 1..len - 1];
+
+        if let Some(eq) = record.iter().position(|b| *b == b'=') {
+            records.push((
+                String::from_utf8_lossy(&record[..eq]).into_owned(),
+                String::from_utf8_lossy(&record[eq + 1..]).into_owned(),
+            ));
+        }
+
+        data = &data[len..];
+    }
+
+    records
+}
+
+enum TarState {
+    Header,
+    /// Entry data, followed by padding to block size, data of unsupported entries is skipped
+    Data {
+        remaining: u64,
+        padding: u64,
+        emit: bool,
+    },
+    /// Extension header data: PAX records (`x`), GNU long name (`L`), others are ignored
+    Extension {
+        kind: u8,
+        remaining: u64,
+        padding: u64,
+        data: Vec<u8>,
+    },
+    Padding(u64),
+    /// End of archive blocks were read, anything after them is ignored
+    End,
+}
+
+/// POSIX (ustar, PAX) and GNU tar archive reader
+pub struct TarReader {
+    buffer: Vec<u8>,
+    offset: usize,
+    state: TarState,
+    /// Name of next entry from PAX or GNU long name header
+    long_name: Option<String>,
+    /// Size of next entry from PAX header
+    pax_size: Option<u64>,
+}
+
+impl TarReader {
+    pub fn new() -> TarReader {
+        TarReader {
+            buffer: Vec::new(),
+            offset: 0,
+            state: TarState::Header,
+            long_name: None,
+            pax_size: None,
+        }
+    }
+
+    fn available(&self) -> u64 {
+        (self.buffer.len() - self.offset) as u64
+    }
+
+    /// Take up to `max` bytes of buffered data
+    fn take(&mut self, max: u64) -> Vec<u8> {
+        let n = cmp::min(max, self.available()) as usize;
+        self.offset += n;
+        self.buffer[self.offset - n..self.offset].to_vec()
+    }
+
+    fn header(&mut self, block: &[u8], events: &mut Vec<Event>) -> Result<TarState, String> {
+        if block.iter().all(|b| *b == 0) {
+            return Ok(TarState::End);
+        }
+
+        let checksum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { 32 } else { u64::from(*b) })
+            .sum();
+        if tar_number(&block[148..156])? != checksum {
+            return Err("Invalid tar header checksum".to_owned());
+        }
+
+        let kind = block[156];
+        let size = tar_number(&block[124..136])?;
+
+        if kind == b'x' || kind == b'g' || kind == b'L' {
+            if size > MAX_EXTENSION_SIZE {
+                return Err("Tar extension header is too large".to_owned());
+            }
+
+            return Ok(TarState::Extension {
+                kind: kind,
+                remaining: size,
+                padding: tar_padding(size),
+                data: Vec::new(),
+            });
+        }
+
+        let mut name = c_string(&block[..100]);
+        let prefix = c_string(&block[345..500]);
+        if &block[257..262] == b"ustar" && !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let name = self.long_name.take().unwrap_or(name);
+        let size = self.pax_size.take().unwrap_or(size);
+        let entry_kind = match kind {
+            b'5' => EntryKind::Directory,
+            b'0' | 0 | b'7' if name.ends_with('/') => EntryKind::Directory,
+            b'0' | 0 | b'7' => EntryKind::File,
+            _ => EntryKind::Unsupported,
+        };
+
+        events.push(Event::Entry(name, entry_kind));
+
+        Ok(TarState::Data {
+            remaining: size,
+            padding: tar_padding(size),
+            emit: entry_kind == EntryKind::File,
+        })
+    }
+
+    /// Process buffered data in current state, returns `false` if more data is needed
+    fn step(&mut self, events: &mut Vec<Event>) -> Result<bool, String> {
+        let state = mem::replace(&mut self.state, TarState::End);
+
+        self.state = match state {
+            TarState::Header if self.available() < TAR_BLOCK => {
+                self.state = TarState::Header;
+                return Ok(false);
+            }
+            TarState::Header => {
+                let block = self.take(TAR_BLOCK);
+                self.header(&block, events)?
+            }
+            TarState::Data { remaining: 0, padding, .. } => {
+                events.push(Event::EntryEnd);
+                TarState::Padding(padding)
+            }
+            TarState::Data {
+                remaining,
+                padding,
+                emit,
+            } => {
+                let data = self.take(remaining);
+                let n = data.len() as u64;
+                if emit && n > 0 {
+                    events.push(Event::Data(data));
+                }
+
+                self.state = TarState::Data {
+                    remaining: remaining - n,
+                    padding: padding,
+                    emit: emit,
+                };
+                return Ok(n > 0);
+            }
+            TarState::Extension {
+                kind,
+                remaining: 0,
+                padding,
+                data,
+            } => {
+                match kind {
+                    b'x' => for (key, value) in pax_records(&data) {
+                        match key.as_str() {
+                            "path" => self.long_name = Some(value),
+                            "size" => self.pax_size = value.parse().ok(),
+                            _ => {}
+                        }
+                    },
+                    b'L' => self.long_name = Some(c_string(&data)),
+                    _ => {}
+                }
+
+                TarState::Padding(padding)
+            }
+            TarState::Extension {
+                kind,
+                remaining,
+                padding,
+                mut data,
+            } => {
+                let chunk = self.take(remaining);
+                let n = chunk.len() as u64;
+                data.extend(chunk);
+
+                self.state = TarState::Extension {
+                    kind: kind,
+                    remaining: remaining - n,
+                    padding: padding,
+                    data: data,
+                };
+                return Ok(n > 0);
+            }
+            TarState::Padding(0) => TarState::Header,
+            TarState::Padding(padding) => {
+                let n = self.take(padding).len() as u64;
+
+                self.state = TarState::Padding(padding - n);
+                return Ok(n > 0);
+            }
+            TarState::End => {
+                self.offset = self.buffer.len();
+                return Ok(false);
+            }
+        };
+
+        Ok(true)
+    }
+}
+
+impl Default for TarReader {
+    fn default() -> TarReader {
+        TarReader::new()
+    }
+}
+
+impl ArchiveReader for TarReader {
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>, String> {
+        let mut events = Vec::new();
+        self.buffer.extend_from_slice(data);
+
+        while self.step(&mut events)? {}
+
+        self.buffer.drain(..self.offset);
+        self.offset = 0;
+
+        Ok(events)
+    }
+
+    fn finish(&mut self) -> Result<Vec<Event>, String> {
+        match self.state {
+            TarState::End => Ok(Vec::new()),
+            // end of archive blocks are often missing
+            TarState::Header if self.buffer.is_empty() => Ok(Vec::new()),
+            _ => Err("Truncated tar archive".to_owned()),
+        }
+    }
+}
+
+/// Gzip decompression of archive read by inner reader
+pub struct Gunzip<R> {
+    decoder: Option<GzDecoder<Vec<u8>>>,
+    inner: R,
+}
+
+impl<R: ArchiveReader> Gunzip<R> {
+    pub fn new(inner: R) -> Gunzip<R> {
+        Gunzip {
+            decoder: Some(GzDecoder::new(Vec::new())),
+            inner: inner,
+        }
+    }
+}
+
+impl<R: ArchiveReader> ArchiveReader for Gunzip<R> {
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>, String> {
+        let decompressed = {
+            let decoder = self.decoder.as_mut().ok_or("Archive is finished")?;
+            decoder.write_all(data).map_err(|e| format!("Invalid gzip data: {}", e))?;
+            decoder.get_mut().split_off(0)
+        };
+
+        self.inner.feed(&decompressed)
+    }
+
+    fn finish(&mut self) -> Result<Vec<Event>, String> {
+        let decoder = self.decoder.take().ok_or("Archive is finished")?;
+        let decompressed = decoder.finish().map_err(|e| format!("Invalid gzip data: {}", e))?;
+        let mut events = self.inner.feed(&decompressed)?;
+        events.extend(self.inner.finish()?);
+
+        Ok(events)
+    }
+}
+
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_DATA_DESCRIPTOR: &[u8] = b"PK\x07\x08";
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+
+enum ZipState {
+    Header,
+    /// Stored data of known size
+    Stored(u64),
+    /// Stored data of unknown size, which ends with data descriptor
+    StoredUntilDescriptor,
+    Deflated(Box<Decompress>),
+    /// Data descriptor of deflated data
+    Descriptor,
+    /// Data of unsupported entry
+    Skip(u64),
+    /// Central directory was reached, entries are already read
+    End,
+}
+
+/// ZIP (and ZIP64) archive reader, entries are read from local headers as they come, so
+/// central directory isn't needed. Stored and deflated entries are supported.
+pub struct ZipReader {
+    buffer: Vec<u8>,
+    offset: usize,
+    state: ZipState,
+    /// Current entry: name, CRC-32 from local header, whether sizes are 64-bit and data
+    /// descriptor follows the data
+    name: String,
+    expected_crc: u32,
+    zip64: bool,
+    descriptor: bool,
+    crc: Crc,
+    written: u64,
+}
+
+impl ZipReader {
+    pub fn new() -> ZipReader {
+        ZipReader {
+            buffer: Vec::new(),
+            offset: 0,
+            state: ZipState::Header,
+            name: String::new(),
+            expected_crc: 0,
+            zip64: false,
+            descriptor: false,
+            crc: Crc::new(),
+            written: 0,
+        }
+    }
+
+    fn available(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    fn emit(&mut self, data: Vec<u8>, events: &mut Vec<Event>) {
+        if !data.is_empty() {
+            self.crc.update(&data);
+            self.written += data.len() as u64;
+            events.push(Event::Data(data));
+        }
+    }
+
+    /// Emit up to `max` bytes of buffered data
+    fn emit_buffered(&mut self, max: usize, events: &mut Vec<Event>) -> usize {
+        let n = cmp::min(max, self.available());
+        let data = self.buffer[self.offset..self.offset + n].to_vec();
+        self.offset += n;
+        self.emit(data, events);
+        n
+    }
+
+    fn end_entry(&mut self, crc: u32, events: &mut Vec<Event>) -> Result<ZipState, String> {
+        if self.crc.sum() != crc {
+            return Err(format!("CRC-32 of {} doesn't match", self.name));
+        }
+
+        events.push(Event::EntryEnd);
+        Ok(ZipState::Header)
+    }
+
+    /// Read local header, `None` if it's not fully buffered yet
+    fn header(&mut self, events: &mut Vec<Event>) -> Result<Option<ZipState>, String> {
+        let data = &self.buffer[self.offset..];
+        if data.len() < 4 {
+            return Ok(None);
+        }
+
+        match le(&data[..4]) as u32 {
+            ZIP_LOCAL_HEADER => {}
+            ZIP_CENTRAL_HEADER | ZIP_END_OF_CENTRAL_DIRECTORY | ZIP64_END_OF_CENTRAL_DIRECTORY => {
+                return Ok(Some(ZipState::End))
+            }
+            _ => return Err("Invalid ZIP archive".to_owned()),
+        }
+
+        if data.len() < 30 {
+            return Ok(None);
+        }
+
+        let name_len = le(&data[26..28]) as usize;
+        let header_len = 30 + name_len + le(&data[28..30]) as usize;
+        if data.len() < header_len {
+            return Ok(None);
+        }
+
+        let flags = le(&data[6..8]);
+        let method = le(&data[8..10]);
+        let mut compressed_size = le(&data[18..22]);
+        let mut size = le(&data[22..26]);
+        let mut extra = &data[30 + name_len..header_len];
+        self.zip64 = false;
+
+        while extra.len() >= 4 {
+            let len = cmp::min(le(&extra[2..4]) as usize, extra.len() - 4);
+            let mut field = &extra[4..4 + len];
+
+            if le(&extra[..2]) == 1 {
+                self.zip64 = true;
+
+                if size == 0xffff_ffff && field.len() >= 8 {
+                    size = le(&field[..8]);
+                    field = &field[8..];
+                }
+                if compressed_size == 0xffff_ffff && field.len() >= 8 {
+                    compressed_size = le(&field[..8]);
+                }
+            }
+
+            extra = &extra[4 + len..];
+        }
+
+        self.name = String::from_utf8_lossy(&data[30..30 + name_len]).into_owned();
+        self.expected_crc = le(&data[14..18]) as u32;
+        self.descriptor = flags & 0x08 != 0;
+        self.crc = Crc::new();
+        self.written = 0;
+        self.offset += header_len;
+
+        // encrypted or compressed with other methods than store and deflate
+        if flags & 0x01 != 0 || (method != 0 && method != 8) {
+            events.push(Event::Entry(self.name.to_owned(), EntryKind::Unsupported));
+
+            if self.descriptor {
+                return Err(format!("Entry {} of unknown size can't be skipped", self.name));
+            }
+
+            return Ok(Some(ZipState::Skip(compressed_size)));
+        }
+
+        let kind = if self.name.ends_with('/') { EntryKind::Directory } else { EntryKind::File };
+        events.push(Event::Entry(self.name.to_owned(), kind));
+
+        Ok(Some(match (method, self.descriptor) {
+            (0, false) if compressed_size != size => {
+                return Err(format!("Invalid size of stored entry {}", self.name));
+            }
+            (0, false) => ZipState::Stored(size),
+            (0, true) => ZipState::StoredUntilDescriptor,
+            _ => ZipState::Deflated(Box::new(Decompress::new(false))),
+        }))
+    }
+
+    /// Stored data until valid data descriptor: the first signature followed by CRC-32 and
+    /// sizes of data before it. Bytes which may be start of descriptor are kept buffered.
+    fn stored_until_descriptor(&mut self, events: &mut Vec<Event>) -> Result<ZipState, String> {
+        let len = if self.zip64 { 24 } else { 16 };
+        let mut search = 0;
+
+        loop {
+            let found = self.buffer[self.offset + search..]
+                .windows(4)
+                .position(|w| w == ZIP_DATA_DESCRIPTOR)
+                .map(|i| search + i);
+            let p = match found {
+                Some(p) => p,
+                None => {
+                    let safe = self.available().saturating_sub(3);
+                    self.emit_buffered(safe, events);
+                    return Ok(ZipState::StoredUntilDescriptor);
+                }
+            };
+
+            if self.available() < p + len {
+                self.emit_buffered(p, events);
+                return Ok(ZipState::StoredUntilDescriptor);
+            }
+
+            let data = &self.buffer[self.offset..];
+            let descriptor = &data[p + 4..p + len];
+            let mut crc = copy_crc(&self.crc);
+            crc.update(&data[..p]);
+            let size = self.written + p as u64;
+            let sizes = if self.zip64 {
+                (le(&descriptor[4..12]), le(&descriptor[12..20]))
+            } else {
+                (le(&descriptor[4..8]), le(&descriptor[8..12]))
+            };
+
+            if le(&descriptor[..4]) as u32 == crc.sum() && sizes == (size, size) {
+                self.emit_buffered(p, events);
+                self.offset += len;
+                events.push(Event::EntryEnd);
+
+                return Ok(ZipState::Header);
+            }
+
+            search = p + 1;
+        }
+    }
+
+    /// Read data descriptor after deflated data, signature is optional
+    fn descriptor(&mut self, events: &mut Vec<Event>) -> Result<Option<ZipState>, String> {
+        let data = &self.buffer[self.offset..];
+        let signature = if data.len() >= 4 && &data[..4] == ZIP_DATA_DESCRIPTOR { 4 } else { 0 };
+        let len = signature + if self.zip64 { 20 } else { 12 };
+
+        if data.len() < cmp::max(len, 4) {
+            return Ok(None);
+        }
+
+        let descriptor = &data[signature..len];
+        let crc = le(&descriptor[..4]) as u32;
+        let size = if self.zip64 { le(&descriptor[12..20]) } else { le(&descriptor[8..12]) };
+
+        if size != self.written {
+            return Err(format!("Size of {} doesn't match", self.name));
+        }
+
+        self.offset += len;
+        self.end_entry(crc, events).map(Some)
+    }
+
+    /// Process buffered data in current state, returns `false` if more data is needed
+    fn step(&mut self, events: &mut Vec<Event>) -> Result<bool, String> {
+        let state = mem::replace(&mut self.state, ZipState::End);
+        let available = self.available();
+
+        self.state = match state {
+            ZipState::Header => match self.header(events)? {
+                Some(state) => state,
+                None => {
+                    self.state = ZipState::Header;
+                    return Ok(false);
+                }
+            },
+            ZipState::Stored(0) => {
+                let crc = self.expected_crc;
+                self.end_entry(crc, events)?
+            }
+            ZipState::Stored(remaining) => {
+                let n = self.emit_buffered(cmp::min(remaining, available as u64) as usize, events);
+
+                self.state = ZipState::Stored(remaining - n as u64);
+                return Ok(n > 0);
+            }
+            ZipState::StoredUntilDescriptor => {
+                let state = self.stored_until_descriptor(events)?;
+                let progress = available != self.available();
+
+                self.state = state;
+                return Ok(progress);
+            }
+            ZipState::Deflated(mut decompress) => {
+                let before = decompress.total_in();
+                let mut output = Vec::with_capacity(INFLATE_CHUNK_SIZE);
+                let input = &self.buffer[self.offset..];
+                let status = decompress
+                    .decompress_vec(input, &mut output, FlushDecompress::None)
+                    .map_err(|e| format!("Invalid compressed data of {}: {}", self.name, e))?;
+                let consumed = (decompress.total_in() - before) as usize;
+                let produced = !output.is_empty();
+
+                self.offset += consumed;
+                self.emit(output, events);
+
+                match status {
+                    Status::StreamEnd if self.descriptor => ZipState::Descriptor,
+                    Status::StreamEnd => {
+                        let crc = self.expected_crc;
+                        self.end_entry(crc, events)?
+                    }
+                    _ => {
+                        self.state = ZipState::Deflated(decompress);
+                        return Ok(consumed > 0 || produced);
+                    }
+                }
+            }
+            ZipState::Descriptor => match self.descriptor(events)? {
+                Some(state) => state,
+                None => {
+                    self.state = ZipState::Descriptor;
+                    return Ok(false);
+                }
+            },
+            ZipState::Skip(0) => {
+                events.push(Event::EntryEnd);
+                ZipState::Header
+            }
+            ZipState::Skip(remaining) => {
+                let n = cmp::min(remaining, available as u64);
+                self.offset += n as usize;
+
+                self.state = ZipState::Skip(remaining - n);
+                return Ok(n > 0);
+            }
+            ZipState::End => {
+                self.offset = self.buffer.len();
+                return Ok(false);
+            }
+        };
+
+        Ok(true)
+    }
+}
+
+impl Default for ZipReader {
+    fn default() -> ZipReader {
+        ZipReader::new()
+    }
+}
+
+impl ArchiveReader for ZipReader {
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<Event>, String> {
+        let mut events = Vec::new();
+        self.buffer.extend_from_slice(data);
+
+        while self.step(&mut events)? {}
+
+        self.buffer.drain(..self.offset);
+        self.offset = 0;
+
+        Ok(events)
+    }
+
+    fn finish(&mut self) -> Result<Vec<Event>, String> {
+        match self.state {
+            ZipState::End => Ok(Vec::new()),
+            _ => Err("Truncated ZIP archive".to_owned()),
+        }
+    }
+}
+
+/// Stream of events of archive read from stream of its chunks, it ends after the first error
+pub struct Entries<S, F> {
+    inner: S,
+    reader: Box<ArchiveReader>,
+    on_error: F,
+    events: VecDeque<Event>,
+    done: bool,
+}
+
+impl<S, F> Entries<S, F>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    F: Fn(String) -> S::Error,
+{
+    pub fn new(inner: S, format: Format, on_error: F) -> Entries<S, F> {
+        Entries {
+            inner: inner,
+            reader: reader(format),
+            on_error: on_error,
+            events: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<S, F> Stream for Entries<S, F>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    F: Fn(String) -> S::Error,
+{
+    type Item = Event;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, S::Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+
+            let events = match self.inner.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(chunk))) => self.reader.feed(chunk.as_ref()),
+                Ok(Async::Ready(None)) => {
+                    self.done = true;
+                    self.reader.finish()
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Err(e);
+                }
+            };
+
+            match events {
+                Ok(events) => self.events.extend(events),
+                Err(e) => {
+                    self.done = true;
+                    return Err((self.on_error)(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod extract {
+        use archive::{ArchiveWriter, Format, Gzip, TarWriter, ZipWriter};
+        use base64;
+        use chrono::{TimeZone, Utc};
+        use extract::*;
+        use futures::{stream, Future};
+
+        /// Read archive in chunks of given size
+        fn read(
+            reader: &mut ArchiveReader,
+            archive: &[u8],
+            chunk: usize,
+        ) -> Result<Vec<Event>, String> {
+            let mut events = Vec::new();
+            for data in archive.chunks(chunk) {
+                events.extend(reader.feed(data)?);
+            }
+            events.extend(reader.finish()?);
+
+            Ok(events)
+        }
+
+        /// Files with their content, as read from events
+        fn files(events: Vec<Event>) -> Vec<(String, EntryKind, Vec<u8>)> {
+            let mut files: Vec<(String, EntryKind, Vec<u8>)> = Vec::new();
+            let mut open = false;
+
+            for event in events {
+                match event {
+                    Event::Entry(name, kind) => {
+                        assert!(!open);
+                        open = true;
+                        files.push((name, kind, Vec::new()));
+                    }
+                    Event::Data(data) => files.last_mut().unwrap().2.extend(data),
+                    Event::EntryEnd => {
+                        assert!(open);
+                        open = false;
+                    }
+                }
+            }
+            assert!(!open);
+
+            files
+        }
+
+        fn write(writer: &mut ArchiveWriter, entries: &[(&str, &[u8])]) -> Vec<u8> {
+            let modified = Utc.ymd(2018, 10, 1).and_hms(0, 0, 0);
+            let mut archive = Vec::new();
+
+            for &(name, data) in entries {
+                archive.extend(writer.start_entry(name, data.len() as u64, &modified).unwrap());
+                archive.extend(writer.write(data).unwrap());
+                archive.extend(writer.finish_entry().unwrap());
+            }
+            archive.extend(writer.finish().unwrap());
+
+            archive
+        }
+
+        fn file(name: &str, data: &[u8]) -> (String, EntryKind, Vec<u8>) {
+            (name.to_owned(), EntryKind::File, data.to_vec())
+        }
+
+        #[test]
+        fn test_zip_with_descriptors() {
+            // "PK\x07\x08" in data isn't mistaken for data descriptor
+            let tricky = b"PK\x07\x08\x00\x00\x00\x00\x00\x00\x00\x00PK\x07";
+            let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b", tricky), ("c", b"")];
+            let archive = write(&mut ZipWriter::new(), entries);
+
+            for chunk in &[1, 5, 64, 4096] {
+                let events = read(&mut ZipReader::new(), &archive, *chunk).unwrap();
+                assert_eq!(
+                    files(events),
+                    vec![file("a.txt", b"hello"), file("b", tricky), file("c", b"")]
+                );
+            }
+        }
+
+        #[test]
+        fn test_zip_deflated() {
+            // deflated "hello hello hello" with sizes in local header, stored "world" and
+            // directory, written by Python's zipfile
+            let archive = base64::decode(
+                "UEsDBBQAAAAIAAAAQU2AiPnlCgAAABEAAAAFAAAAYS50eHTLSM3JyVfIQJAAUEsDBBQAAAAAAAAAQU1D\
+                 EXc6BQAAAAUAAAAFAAAAYi50eHR3b3JsZFBLAwQUAAAAAAAAAEFNAAAAAAAAAAAAAAAABAAAAGRpci9Q\
+                 SwECFAMUAAAACAAAAEFNgIj55QoAAAARAAAABQAAAAAAAAAAAAAAgAEAAAAAYS50eHRQSwECFAMUAAAA\
+                 AAAAAEFNQxF3OgUAAAAFAAAABQAAAAAAAAAAAAAAgAEtAAAAYi50eHRQSwECFAMUAAAAAAAAAEFNAAAA\
+                 AAAAAAAAAAAABAAAAAAAAAAAAAAAgAFVAAAAZGlyL1BLBQYAAAAAAwADAJgAAAB3AAAAAAA=",
+            ).unwrap();
+
+            for chunk in &[1, 7, 4096] {
+                let events = read(&mut ZipReader::new(), &archive, *chunk).unwrap();
+                assert_eq!(
+                    files(events),
+                    vec![
+                        file("a.txt", b"hello hello hello"),
+                        file("b.txt", b"world"),
+                        ("dir/".to_owned(), EntryKind::Directory, vec![]),
+                    ]
+                );
+            }
+        }
+
+        #[test]
+        fn test_zip_deflated_with_descriptor() {
+            // deflated "hello hello hello" followed by data descriptor, as written to pipe
+            let archive = base64::decode(
+                "UEsDBBQACAAIAAAAQU0AAAAAAAAAAAAAAAAFAAAAYS50eHTLSM3JyVfIQJAAUEsHCICI+eUKAAAAEQAA\
+                 AFBLAQIUAxQACAAIAAAAQU2AiPnlCgAAABEAAAAFAAAAAAAAAAAAAACAAQAAAABhLnR4dFBLBQYAAAAA\
+                 AQABADMAAAA9AAAAAAA=",
+            ).unwrap();
+
+            for chunk in &[1, 7, 4096] {
+                let events = read(&mut ZipReader::new(), &archive, *chunk).unwrap();
+                assert_eq!(files(events), vec![file("a.txt", b"hello hello hello")]);
+            }
+        }
+
+        #[test]
+        fn test_invalid_zip() {
+            let mut archive = write(&mut ZipWriter::new(), &[("a.txt", b"hello")]);
+            assert!(read(&mut ZipReader::new(), &archive[..40], 4096).is_err());
+            assert!(read(&mut ZipReader::new(), b"not a zip", 4096).is_err());
+
+            // corrupted data doesn't match CRC-32 of any data descriptor
+            archive[56] = b'a';
+            assert!(read(&mut ZipReader::new(), &archive, 4096).is_err());
+        }
+
+        #[test]
+        fn test_tar_gz() {
+            let long = format!("{}/{}", "d".repeat(60), "f".repeat(60));
+            let data = vec![7; 1500];
+            let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), (&long, &data), ("e", b"")];
+            let archive = write(&mut Gzip::new(TarWriter::new()), entries);
+
+            for chunk in &[1, 100, 4096] {
+                let events = read(&mut Gunzip::new(TarReader::new()), &archive, *chunk).unwrap();
+                assert_eq!(
+                    files(events),
+                    vec![file("a.txt", b"hello"), file(&long, &data), file("e", b"")]
+                );
+            }
+        }
+
+        #[test]
+        fn test_tar_entry_kinds() {
+            let mut archive = write(&mut TarWriter::new(), &[("dir/", b""), ("link", b"")]);
+            // turn the second entry into symlink, fixing header checksum
+            archive[512 + 156] = b'2';
+            let checksum: u32 = archive[512..1024]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| if (148..156).contains(&i) { 32 } else { u32::from(*b) })
+                .sum();
+            archive[512 + 148..512 + 154].copy_from_slice(format!("{:06o}", checksum).as_bytes());
+
+            let events = read(&mut TarReader::new(), &archive, 4096).unwrap();
+            assert_eq!(
+                files(events),
+                vec![
+                    ("dir/".to_owned(), EntryKind::Directory, vec![]),
+                    ("link".to_owned(), EntryKind::Unsupported, vec![]),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_invalid_tar() {
+            let mut archive = write(&mut TarWriter::new(), &[("a.txt", b"hello")]);
+            assert!(read(&mut TarReader::new(), &archive[..600], 4096).is_err());
+
+            archive[0] = b'b';
+            assert!(read(&mut TarReader::new(), &archive, 4096).is_err());
+        }
+
+        #[test]
+        fn test_entries() {
+            let archive = write(&mut TarWriter::new(), &[("a.txt", b"hello")]);
+            let chunks: Vec<Result<Vec<u8>, String>> =
+                archive.chunks(100).map(|c| Ok(c.to_vec())).collect();
+            let events = Entries::new(stream::iter_result(chunks), Format::Tar, |e| e);
+
+            assert_eq!(files(events.collect().wait().unwrap()), vec![file("a.txt", b"hello")]);
+
+            let truncated = stream::iter_ok::<_, String>(vec![archive[..600].to_vec()]);
+            let events = Entries::new(truncated, Format::Tar, |e| format!("invalid: {}", e));
+
+            assert_eq!(events.collect().wait(), Err("invalid: Truncated tar archive".to_owned()));
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorBadRequest, ErrorRequestTimeout};
+use actix_web::http::header;
+use actix_web::multipart::{Field, Multipart, MultipartItem};
+use actix_web::Error;
+use aws_s3_webdav::archive;
+use aws_s3_webdav::integrity::ExpectedDigests;
+use aws_s3_webdav::timeout::IdleTimeout;
+use env::AppEnv;
+use futures::future::{self, Loop};
+use futures::{Future, Stream};
+use s3::object_exists;
+use std::cell::Cell;
+use std::rc::Rc;
+use upload::{upload_object, BodyStream, ObjectHeaders};
+
+/// File uploaded from form, reported in JSON response
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadedFile {
+    /// Name of form field the file was sent in
+    pub field: String,
+    pub name: String,
+    /// Absolute path of the object
+    pub path: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// Object didn't exist before, it wasn't overwritten
+    pub created: bool,
+    pub e_tag: Option<String>,
+}
+
+type Form = Box<Stream<Item=MultipartItem<Payload>, Error=Error>>;
+
+/// Rest of the form and files uploaded so far
+type FormState = (Form, Vec<UploadedFile>);
+
+type FormLoop = Box<Future<Item=Loop<Vec<UploadedFile>, FormState>, Error=Error>>;
+
+/// Field name and file name from `Content-Disposition` of form part, file name is `None` for
+/// plain fields and file inputs with no file chosen
+fn field_names(field: &Field<Payload>) -> (String, Option<String>) {
+    let disposition = field.content_disposition();
+    let name = disposition.as_ref().and_then(|d| d.get_name()).unwrap_or_default();
+    let filename = disposition.as_ref().and_then(|d| {
+        d.get_filename()
+            .map(|f| f.to_owned())
+            .or_else(|| d.get_filename_ext().map(|f| String::from_utf8_lossy(&f.value).into()))
+    });
+
+    (name.to_owned(), filename.filter(|f| !f.is_empty()))
+}
+
+/// Upload file sent in form part into object at `key`
+fn upload_file(
+    env: &AppEnv,
+    field: Field<Payload>,
+    key: String,
+    file: UploadedFile,
+) -> Box<Future<Item=UploadedFile, Error=Error>> {
+    let env = env.clone();
+    let size = Rc::new(Cell::new(0));
+    let received = size.clone();
+    let metrics = env.metrics.clone();
+    let body: BodyStream = Box::new(
+        IdleTimeout::new(
+            field.map_err(Error::from),
+            env.config.upload.idle_timeout,
+            || ErrorRequestTimeout("Timed out waiting for request body"),
+        ).inspect(move |chunk| {
+            received.set(received.get() + chunk.len() as u64);
+            metrics.received(chunk.len() as u64);
+        }),
+    );
+    let headers = ObjectHeaders {
+        content_type: file.content_type.clone(),
+        storage_class: env.config.storage_class.for_key(&key).map(|c| c.to_owned()),
+        ..ObjectHeaders::default()
+    };
+
+    Box::new(object_exists(&env, &env.config.s3.bucket, &key).and_then(move |existed| {
+        upload_object(&env, &key, headers, body, None, ExpectedDigests::default()).map(
+            move |(e_tag, _)| UploadedFile {
+                size: size.get(),
+                created: !existed,
+                e_tag: e_tag,
+                ..file
+            },
+        )
+    }))
+}
+
+/// Upload files of `multipart/form-data` form streamed in request body into collection at
+/// `path` (relative to key prefix, empty or ending with `/`), `href` is its absolute path.
+/// Files are uploaded one after another as they're read, named after their file names, which
+/// may have directories but mustn't escape the collection. Other fields are ignored. First
+/// failure ends the upload, files uploaded before it are kept.
+pub fn upload_files(
+    env: &AppEnv,
+    path: &str,
+    href: &str,
+    form: Multipart<Payload>,
+) -> Box<Future<Item=Vec<UploadedFile>, Error=Error>> {
+    let env = env.clone();
+    let prefix = match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, path),
+        None => path.to_owned(),
+    };
+    let href = href.to_owned();
+    let form: Form = Box::new(IdleTimeout::new(
+        form.map_err(Error::from),
+        env.config.upload.idle_timeout,
+        || ErrorRequestTimeout("Timed out waiting for request body"),
+    ));
+
+    Box::new(future::loop_fn((form, Vec::new()), move |(form, mut files)| {
+        let (env, prefix, href) = (env.clone(), prefix.clone(), href.clone());
+
+        form.into_future().map_err(|(e, _)| e).and_then(move |(item, form)| -> FormLoop {
+            let field = match item {
+                Some(MultipartItem::Field(field)) => field,
+                Some(MultipartItem::Nested(_)) => {
+                    return Box::new(future::err(ErrorBadRequest(
+                        "Nested multipart forms aren't supported",
+                    )))
+                }
+                None => return Box::new(future::ok(Loop::Break(files))),
+            };
+            let (field_name, filename) = field_names(&field);
+            let filename = match filename {
+                Some(filename) => filename,
+                None => {
+                    return Box::new(
+                        field
+                            .map_err(Error::from)
+                            .for_each(|_| Ok(()))
+                            .map(|_| Loop::Continue((form, files))),
+                    )
+                }
+            };
+            let name = match archive::safe_path(&filename) {
+                Some(name) => name,
+                None => {
+                    return Box::new(future::err(ErrorBadRequest(format!(
+                        "File name \"{}\" escapes target collection",
+                        filename
+                    ))))
+                }
+            };
+            let file_path = archive::entry_path(&name);
+            let content_type = field
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|t| t.to_str().ok())
+                .map(|t| t.to_owned());
+            let file = UploadedFile {
+                field: field_name,
+                name: name,
+                path: format!("{}{}", href, file_path),
+                size: 0,
+                content_type: content_type,
+                created: false,
+                e_tag: None,
+            };
+
+            Box::new(
+                upload_file(&env, field, format!("{}{}", prefix, file_path), file).map(
+                    move |file| {
+                        files.push(file);
+                        Loop::Continue((form, files))
+                    },
+                ),
+            )
+        })
+    }))
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Multipart upload opened by request, as needed to abort it
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenUpload {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+}
+
+/// Requests being handled and multipart uploads they opened, shared by all workers, so
+/// shutdown can wait for them and abort uploads left open
+#[derive(Clone, Debug, Default)]
+pub struct InFlight {
+    requests: Arc<AtomicUsize>,
+    uploads: Arc<Mutex<HashMap<String, OpenUpload>>>,
+}
+
+/// Request counted as in flight until dropped
+#[derive(Debug)]
+pub struct RequestGuard(Arc<AtomicUsize>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    /// Count request in flight while returned guard lives
+    pub fn request(&self) -> RequestGuard {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+
+        RequestGuard(self.requests.clone())
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    pub fn upload_opened(&self, upload: OpenUpload) {
+        self.uploads.lock().unwrap().insert(upload.upload_id.clone(), upload);
+    }
+
+    /// Upload was completed or aborted
+    pub fn upload_closed(&self, upload_id: &str) {
+        self.uploads.lock().unwrap().remove(upload_id);
+    }
+
+    /// Uploads still open, in no particular order
+    pub fn uploads(&self) -> Vec<OpenUpload> {
+        self.uploads.lock().unwrap().values().cloned().collect()
+    }
+
+    /// No requests are handled and no uploads are open
+    pub fn is_idle(&self) -> bool {
+        self.requests() == 0 && self.uploads.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod in_flight {
+        use in_flight::*;
+
+        #[test]
+        fn test_requests() {
+            let in_flight = InFlight::default();
+            let shared = in_flight.clone();
+            assert!(in_flight.is_idle());
+
+            let first = in_flight.request();
+            let second = shared.request();
+            assert_eq!(in_flight.requests(), 2);
+
+            drop(first);
+            assert_eq!(shared.requests(), 1);
+            assert!(!in_flight.is_idle());
+
+            drop(second);
+            assert!(in_flight.is_idle());
+        }
+
+        #[test]
+        fn test_uploads() {
+            let in_flight = InFlight::default();
+            let upload = OpenUpload {
+                bucket: "bucket".to_owned(),
+                key: "a.txt".to_owned(),
+                upload_id: "1".to_owned(),
+            };
+
+            in_flight.upload_opened(upload.clone());
+            assert!(!in_flight.is_idle());
+            assert_eq!(in_flight.clone().uploads(), vec![upload]);
+
+            in_flight.upload_closed("2");
+            assert_eq!(in_flight.uploads().len(), 1);
+
+            in_flight.upload_closed("1");
+            assert!(in_flight.is_idle());
+        }
+    }
+}
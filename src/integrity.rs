@@ -0,0 +1,267 @@
+use base64;
+use hex;
+use md5;
+use sha2::{Digest, Sha256};
+
+/// Object metadata key used to store hex encoded SHA-256 of object contents
+pub const SHA256_METADATA_KEY: &str = "sha256";
+
+/// Digests computed over uploaded data
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digests {
+    pub md5: Vec<u8>,
+    pub sha256: Vec<u8>,
+}
+
+/// Incrementally computes MD5 and SHA-256 of data passed through it
+pub struct Hasher {
+    md5: md5::Context,
+    sha256: Sha256,
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher {
+            md5: md5::Context::new(),
+            sha256: Sha256::default(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.md5.consume(data);
+        self.sha256.input(data);
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests {
+            md5: self.md5.compute().to_vec(),
+            sha256: self.sha256.result().to_vec(),
+        }
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+/// Digests of the whole request body announced by client
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpectedDigests {
+    pub md5: Option<Vec<u8>>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+impl ExpectedDigests {
+    /// Parse `Content-MD5`, `Digest` (RFC 3230) and `Repr-Digest` (RFC 9530) header values,
+    /// only MD5 and SHA-256 are supported, other algorithms are ignored
+    pub fn from_headers(
+        content_md5: Option<&str>,
+        digest: Option<&str>,
+        repr_digest: Option<&str>,
+    ) -> Result<ExpectedDigests, String> {
+        let mut expected = ExpectedDigests::default();
+
+        if let Some(value) = content_md5 {
+            expected.set_md5(decode(value.trim(), "Content-MD5")?)?;
+        }
+
+        for (algorithm, value) in digest.map(parse_list).unwrap_or_default() {
+            expected.set(&algorithm, decode(value, "Digest")?)?;
+        }
+
+        for (algorithm, value) in repr_digest.map(parse_list).unwrap_or_default() {
+            // structured field byte sequence: `:base64:`
+            if value.len() < 2 || !value.starts_with(':') || !value.ends_with(':') {
+                return Err(format!("Invalid Repr-Digest value for {}", algorithm));
+            }
+
+            expected.set(&algorithm, decode(&value[1..value.len() - 1], "Repr-Digest")?)?;
+        }
+
+        Ok(expected)
+    }
+
+    fn set(&mut self, algorithm: &str, value: Vec<u8>) -> Result<(), String> {
+        match algorithm {
+            "md5" => self.set_md5(value),
+            _ => self.set_sha256(value),
+        }
+    }
+
+    fn set_md5(&mut self, value: Vec<u8>) -> Result<(), String> {
+        if value.len() != 16 {
+            return Err("Invalid MD5 digest length".to_owned());
+        }
+
+        set_once(&mut self.md5, value, "MD5")
+    }
+
+    fn set_sha256(&mut self, value: Vec<u8>) -> Result<(), String> {
+        if value.len() != 32 {
+            return Err("Invalid SHA-256 digest length".to_owned());
+        }
+
+        set_once(&mut self.sha256, value, "SHA-256")
+    }
+
+    /// Check computed digests match expected ones
+    pub fn verify(&self, computed: &Digests) -> Result<(), String> {
+        if self.md5.as_ref().map(|d| *d != computed.md5).unwrap_or(false) {
+            return Err("MD5 digest of request body doesn't match".to_owned());
+        }
+
+        if self.sha256.as_ref().map(|d| *d != computed.sha256).unwrap_or(false) {
+            return Err("SHA-256 digest of request body doesn't match".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+/// Same algorithm may be given by several headers, all values must agree
+fn set_once(slot: &mut Option<Vec<u8>>, value: Vec<u8>, algorithm: &str) -> Result<(), String> {
+    match *slot {
+        Some(ref existing) if *existing != value => {
+            Err(format!("Conflicting {} digests provided", algorithm))
+        }
+        _ => {
+            *slot = Some(value);
+            Ok(())
+        }
+    }
+}
+
+/// Split `alg=value, alg=value` list, algorithm names are lowercased,
+/// unsupported algorithms are skipped
+fn parse_list(header: &str) -> Vec<(String, &str)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut kv = item.trim().splitn(2, '=');
+            let algorithm = kv.next()?.trim().to_lowercase();
+            let value = kv.next()?.trim();
+
+            match algorithm.as_str() {
+                "md5" | "sha-256" => Some((algorithm, value)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn decode(value: &str, header: &str) -> Result<Vec<u8>, String> {
+    base64::decode(value).map_err(|_| format!("Invalid base64 value in {} header", header))
+}
+
+/// Base64 encoded MD5 of data, as expected in `Content-MD5` header
+pub fn content_md5(data: &[u8]) -> String {
+    base64::encode(&md5::compute(data).0)
+}
+
+/// `Repr-Digest` header value for given SHA-256 digest
+pub fn repr_digest(sha256: &[u8]) -> String {
+    format!("sha-256=:{}:", base64::encode(sha256))
+}
+
+/// Hex encoded SHA-256 digest, as stored in object metadata
+pub fn sha256_hex(sha256: &[u8]) -> String {
+    hex::encode(sha256)
+}
+
+/// Decode SHA-256 digest stored in object metadata
+pub fn sha256_from_hex(value: &str) -> Option<Vec<u8>> {
+    hex::decode(value).ok().and_then(|d| if d.len() == 32 { Some(d) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    mod hasher {
+        use integrity::*;
+
+        #[test]
+        fn test_digests() {
+            let mut hasher = Hasher::new();
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            let digests = hasher.finish();
+
+            assert_eq!(hex::encode(&digests.md5), "900150983cd24fb0d6963f7d28e17f72");
+            assert_eq!(
+                sha256_hex(&digests.sha256),
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            );
+        }
+
+        #[test]
+        fn test_content_md5() {
+            assert_eq!(content_md5(b""), "1B2M2Y8AsgTpgAmY7PhCfg==");
+        }
+    }
+
+    mod expected_digests {
+        use integrity::*;
+
+        const EMPTY_SHA256: &str = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+        fn empty() -> Digests {
+            Hasher::new().finish()
+        }
+
+        #[test]
+        fn test_none() {
+            let expected = ExpectedDigests::from_headers(None, None, None).unwrap();
+
+            assert_eq!(expected, ExpectedDigests::default());
+            assert!(expected.verify(&empty()).is_ok());
+        }
+
+        #[test]
+        fn test_content_md5() {
+            let expected =
+                ExpectedDigests::from_headers(Some("1B2M2Y8AsgTpgAmY7PhCfg=="), None, None).unwrap();
+
+            assert!(expected.verify(&empty()).is_ok());
+
+            let mut hasher = Hasher::new();
+            hasher.update(b"abc");
+            assert!(expected.verify(&hasher.finish()).is_err());
+        }
+
+        #[test]
+        fn test_digest() {
+            let header = format!("SHA-256={}, UNIXsum=30637", EMPTY_SHA256);
+            let expected = ExpectedDigests::from_headers(None, Some(&header), None).unwrap();
+
+            assert_eq!(expected.sha256, Some(empty().sha256));
+            assert_eq!(expected.md5, None);
+        }
+
+        #[test]
+        fn test_repr_digest() {
+            let header = format!("sha-256=:{}:", EMPTY_SHA256);
+            let expected = ExpectedDigests::from_headers(None, None, Some(&header)).unwrap();
+
+            assert_eq!(expected.sha256, Some(empty().sha256));
+            assert_eq!(repr_digest(&empty().sha256), header);
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!(ExpectedDigests::from_headers(Some("not base64!"), None, None).is_err());
+            assert!(ExpectedDigests::from_headers(Some("YWJj"), None, None).is_err());
+            assert!(ExpectedDigests::from_headers(None, None, Some("sha-256=abc")).is_err());
+        }
+
+        #[test]
+        fn test_conflicting() {
+            let digest = format!("SHA-256={}", EMPTY_SHA256);
+            let repr_digest = format!("sha-256=:{}:", base64::encode(&[0u8; 32]));
+
+            assert!(
+                ExpectedDigests::from_headers(None, Some(&digest), Some(&repr_digest)).is_err()
+            );
+        }
+    }
+}
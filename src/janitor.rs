@@ -0,0 +1,267 @@
+use actix;
+use aws_s3_webdav::multipart::is_stale;
+use chrono::Utc;
+use env::AppEnv;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use resumable::staging_key;
+use rusoto_s3::*;
+use s3::{delete_object, with_retry};
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
+
+/// Uploads listing page, with markers for the next page if listing is truncated
+type UploadsPage = (Vec<MultipartUpload>, Option<(Option<String>, Option<String>)>);
+
+/// Markers of next page of uploads listing, `None` on last page. Truncated listing without
+/// markers is ended, as asking for it again would return the same page.
+fn next_markers(output: &ListMultipartUploadsOutput) -> Option<(Option<String>, Option<String>)> {
+    if output.is_truncated != Some(true) {
+        return None;
+    }
+
+    match (&output.next_key_marker, &output.next_upload_id_marker) {
+        (&None, &None) => {
+            warn!("Multipart uploads listing is truncated without next markers, ending it");
+            None
+        }
+        (key_marker, upload_id_marker) => Some((key_marker.clone(), upload_id_marker.clone())),
+    }
+}
+
+fn list_uploads_page(
+    env: &AppEnv,
+    key_marker: Option<String>,
+    upload_id_marker: Option<String>,
+) -> Box<Future<Item = UploadsPage, Error = ListMultipartUploadsError>> {
+    let state = env.clone();
+    let request = ListMultipartUploadsRequest {
+        bucket: env.config.s3.bucket.to_owned(),
+        prefix: env.config.s3.prefix.to_owned(),
+        key_marker: key_marker,
+        upload_id_marker: upload_id_marker,
+        ..ListMultipartUploadsRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "list_multipart_uploads", move || {
+            state.s3.list_multipart_uploads(request.clone())
+        }).map(|output| {
+            let next = next_markers(&output);
+
+            (output.uploads.unwrap_or_default(), next)
+        }),
+    )
+}
+
+/// Staged data of resumable upload is deleted along with it, if resumable uploads are enabled
+fn delete_staged(env: &AppEnv, upload_id: &str) -> Box<Future<Item = (), Error = ()>> {
+    if env.config.tus.is_none() {
+        return Box::new(future::ok(()));
+    }
+
+    let staging_key = staging_key(env, upload_id);
+
+    Box::new(delete_object(env, &env.config.s3.bucket, &staging_key).then(move |r| {
+        if let Err(e) = r {
+            error!("Failed to delete staged upload data {}: {}", staging_key, e);
+        }
+
+        Ok(())
+    }))
+}
+
+fn abort(env: &AppEnv, upload: MultipartUpload) -> Box<Future<Item = bool, Error = ()>> {
+    let state = env.clone();
+    let cleanup = env.clone();
+    let key = upload.key.unwrap_or_default();
+    let upload_id = upload.upload_id.unwrap_or_default();
+    let request = AbortMultipartUploadRequest {
+        bucket: env.config.s3.bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id: upload_id.to_owned(),
+        ..AbortMultipartUploadRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "abort_multipart_upload", move || {
+            state.s3.abort_multipart_upload(request.clone())
+        }).then(move |r| -> Box<Future<Item = bool, Error = ()>> {
+            match r {
+                Ok(_) => {
+                    info!("Aborted stale multipart upload of {}", key);
+                    Box::new(delete_staged(&cleanup, &upload_id).map(|_| true))
+                }
+                Err(e) => {
+                    error!("Failed to abort stale multipart upload of {}: {}", key, e);
+                    Box::new(future::ok(false))
+                }
+            }
+        }),
+    )
+}
+
+/// Abort multipart uploads under configured key prefix, initiated more than `max_age` ago,
+/// resolves to the number of aborted uploads
+pub fn abort_stale_uploads(
+    env: &AppEnv,
+    max_age: Duration,
+) -> Box<Future<Item = usize, Error = ListMultipartUploadsError>> {
+    let env = env.clone();
+    let now = Utc::now();
+
+    Box::new(future::loop_fn(
+        (0, None, None),
+        move |(aborted, key_marker, upload_id_marker)| {
+            let env = env.clone();
+
+            list_uploads_page(&env, key_marker, upload_id_marker).and_then(move |(uploads, next)| {
+                let stale: Vec<MultipartUpload> = uploads
+                    .into_iter()
+                    .filter(|u| {
+                        u.initiated
+                            .as_ref()
+                            .map(|i| is_stale(i, now, max_age))
+                            .unwrap_or(false)
+                    })
+                    .collect();
+
+                // abort sequentially to not flood S3 with requests
+                stream::iter_ok::<_, ()>(stale)
+                    .and_then(move |upload| abort(&env, upload))
+                    .fold(aborted, |n, ok| Ok::<_, ()>(if ok { n + 1 } else { n }))
+                    .then(move |r| {
+                        let aborted = r.unwrap_or(aborted);
+
+                        Ok(match next {
+                            Some((key_marker, upload_id_marker)) => {
+                                Loop::Continue((aborted, key_marker, upload_id_marker))
+                            }
+                            None => Loop::Break(aborted),
+                        })
+                    })
+            })
+        },
+    ))
+}
+
+/// Periodically abort stale multipart uploads, must be called within a running actix system
+pub fn spawn(env: AppEnv, interval: Duration, max_age: Duration) {
+    actix::spawn(
+        Interval::new(Instant::now(), interval)
+            .map_err(|e| error!("Uploads janitor timer failed: {}", e))
+            .for_each(move |_| {
+                abort_stale_uploads(&env, max_age).then(|r| {
+                    match r {
+                        Ok(aborted) => debug!("Uploads janitor aborted {} uploads", aborted),
+                        Err(e) => error!("Uploads janitor failed to list uploads: {}", e),
+                    }
+
+                    Ok(())
+                })
+            }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    mod next_markers {
+        use janitor::*;
+
+        #[test]
+        fn test_next_markers() {
+            let mut output = ListMultipartUploadsOutput {
+                is_truncated: Some(true),
+                next_key_marker: Some("b.txt".to_owned()),
+                ..ListMultipartUploadsOutput::default()
+            };
+            assert_eq!(next_markers(&output), Some((Some("b.txt".to_owned()), None)));
+
+            output.next_key_marker = None;
+            assert_eq!(next_markers(&output), None);
+
+            output.next_key_marker = Some("b.txt".to_owned());
+            output.is_truncated = Some(false);
+            assert_eq!(next_markers(&output), None);
+        }
+    }
+
+    mod abort_stale_uploads {
+        use actix;
+        use janitor::*;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use env::TusConfig;
+        use resumable::staging_key;
+        use testing::{self, StoredObject, Stub, StubState, Upload};
+
+        #[test]
+        fn test_aborts_only_stale_uploads() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+
+            {
+                let mut stub = stub.lock().unwrap();
+                stub.uploads.insert(
+                    "old".to_owned(),
+                    Upload {
+                        key: "old.txt".to_owned(),
+                        initiated: "2018-09-01T00:00:00.000Z".to_owned(),
+                        ..Upload::default()
+                    },
+                );
+                stub.uploads.insert(
+                    "new".to_owned(),
+                    Upload {
+                        key: "new.txt".to_owned(),
+                        initiated: Utc::now().to_rfc3339(),
+                        ..Upload::default()
+                    },
+                );
+            }
+
+            let state = testing::state(s3.addr());
+            let aborted = actix::System::new("test")
+                .block_on(abort_stale_uploads(&state, Duration::from_secs(3600)))
+                .unwrap();
+
+            assert_eq!(aborted, 1);
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.uploads.contains_key("new"));
+            assert!(!stub.uploads.contains_key("old"));
+        }
+
+        #[test]
+        fn test_deletes_staged_upload_data() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let mut state = testing::state(s3.addr());
+            Arc::get_mut(&mut state).unwrap().config.tus = Some(TusConfig::new(".uploads"));
+
+            {
+                let mut stub = stub.lock().unwrap();
+                stub.uploads.insert(
+                    "old".to_owned(),
+                    Upload {
+                        key: "old.txt".to_owned(),
+                        initiated: "2018-09-01T00:00:00.000Z".to_owned(),
+                        ..Upload::default()
+                    },
+                );
+                stub.objects.insert(staging_key(&state, "old"), StoredObject::default());
+                stub.objects.insert(staging_key(&state, "other"), StoredObject::default());
+            }
+
+            let aborted = actix::System::new("test")
+                .block_on(abort_stale_uploads(&state, Duration::from_secs(3600)))
+                .unwrap();
+
+            assert_eq!(aborted, 1);
+
+            let stub = stub.lock().unwrap();
+            assert!(!stub.objects.contains_key(&staging_key(&state, "old")));
+            assert!(stub.objects.contains_key(&staging_key(&state, "other")));
+        }
+    }
+}
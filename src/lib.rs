@@ -1,4 +1,39 @@
+extern crate base64;
+extern crate chrono;
+extern crate flate2;
 extern crate futures;
+extern crate hex;
+extern crate md5;
+extern crate rand;
+extern crate ring;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha2;
+#[cfg(test)]
+extern crate tokio;
+extern crate tokio_timer;
+extern crate toml;
+extern crate url;
+
+pub mod archive;
+pub mod cors;
+pub mod envelope;
+pub mod extract;
+pub mod in_flight;
+pub mod integrity;
+pub mod listing;
+pub mod metadata;
+pub mod metrics;
+pub mod multipart;
+pub mod propfind;
+pub mod readiness;
+pub mod retry;
+pub mod sse;
+pub mod storage_class;
+pub mod timeout;
+pub mod trash;
+pub mod tus;
+pub mod x509;
 
 pub mod stream_utils {
     use futures::{stream, Async, Stream};
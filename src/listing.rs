@@ -0,0 +1,357 @@
+use chrono::DateTime;
+use std::cmp::Ordering;
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+/// Collection member: object or nested collection (common prefix)
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub name: String,
+    /// Absolute path, ends with `/` for collections
+    pub path: String,
+    pub collection: bool,
+    pub size: Option<i64>,
+    pub last_modified: Option<String>,
+}
+
+/// Page of collection listing
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Listing {
+    /// Absolute path of the collection, ends with `/`
+    pub path: String,
+    pub entries: Vec<Entry>,
+    /// Token of the next page, if there are more entries
+    pub next_page: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortBy {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SortBy::Name => "name",
+            SortBy::Size => "size",
+            SortBy::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sort {
+    pub by: SortBy,
+    pub descending: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Sort {
+        Sort {
+            by: SortBy::Name,
+            descending: false,
+        }
+    }
+}
+
+impl Sort {
+    /// Parse `sort` (`name`, `size` or `modified`) and `order` (`asc` or `desc`) query parameters
+    pub fn parse(by: Option<&str>, order: Option<&str>) -> Result<Sort, String> {
+        let by = match by {
+            None | Some("name") => SortBy::Name,
+            Some("size") => SortBy::Size,
+            Some("modified") => SortBy::Modified,
+            Some(by) => return Err(format!("Unsupported sort column \"{}\"", by)),
+        };
+        let descending = match order {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(order) => return Err(format!("Unsupported sort order \"{}\"", order)),
+        };
+
+        Ok(Sort {
+            by: by,
+            descending: descending,
+        })
+    }
+
+    /// Sort entries, collections always go first
+    pub fn apply(&self, entries: &mut [Entry]) {
+        let by = self.by;
+        let descending = self.descending;
+
+        entries.sort_by(|a, b| {
+            let ordering = match by {
+                SortBy::Name => Ordering::Equal,
+                SortBy::Size => a.size.cmp(&b.size),
+                SortBy::Modified => a.last_modified.cmp(&b.last_modified),
+            }.then_with(|| a.name.cmp(&b.name));
+
+            b.collection
+                .cmp(&a.collection)
+                .then(if descending { ordering.reverse() } else { ordering })
+        });
+    }
+}
+
+/// Links to collection and all its parents: name and absolute path, root first
+pub fn breadcrumbs(path: &str) -> Vec<(String, String)> {
+    let mut crumbs = vec![("/".to_owned(), "/".to_owned())];
+    let mut href = "/".to_owned();
+
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        href.push_str(name);
+        href.push('/');
+        crumbs.push((name.to_owned(), href.clone()));
+    }
+
+    crumbs
+}
+
+/// Size in bytes, or in binary units with single decimal for larger ones
+pub fn format_size(size: i64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// S3 timestamp as `YYYY-MM-DD HH:MM:SS`, unparseable ones are shown as is
+fn format_modified(modified: &str) -> String {
+    match DateTime::parse_from_rfc3339(modified) {
+        Ok(modified) => modified.format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => modified.to_owned(),
+    }
+}
+
+/// Escape text for HTML and XML
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Path with every segment percent encoded, to be used in URLs
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Query string of listing link, `limit` is kept when given
+fn query(sort: &Sort, limit: Option<usize>, page: Option<&str>) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.append_pair("sort", sort.by.as_str());
+    query.append_pair("order", if sort.descending { "desc" } else { "asc" });
+
+    if let Some(limit) = limit {
+        query.append_pair("limit", &limit.to_string());
+    }
+
+    if let Some(page) = page {
+        query.append_pair("page", page);
+    }
+
+    query.finish()
+}
+
+/// Render listing page as HTML, column headers sort entries of the page, clicking on current
+/// sort column reverses the order
+pub fn render_html(listing: &Listing, sort: &Sort, limit: Option<usize>, first: bool) -> String {
+    let mut html = String::new();
+    let title = escape(&listing.path);
+
+    html.push_str(&format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {}</title>\n\
+         <style>body{{font-family:sans-serif}}td,th{{padding:2px 12px;text-align:left}}\
+         td.size{{text-align:right}}</style>\n</head>\n<body>\n<h1>",
+        title
+    ));
+
+    let crumbs: Vec<String> = breadcrumbs(&listing.path)
+        .into_iter()
+        .map(|(name, href)| {
+            format!("<a href=\"{}\">{}</a>", escape(&encode_path(&href)), escape(&name))
+        })
+        .collect();
+    html.push_str(&crumbs.join(" "));
+    html.push_str("</h1>\n<table>\n<tr>");
+
+    let columns = [(SortBy::Name, "Name"), (SortBy::Size, "Size"), (SortBy::Modified, "Modified")];
+    for &(by, label) in &columns {
+        let link = Sort {
+            by: by,
+            descending: sort.by == by && !sort.descending,
+        };
+        let arrow = match (sort.by == by, sort.descending) {
+            (true, false) => " &#9650;",
+            (true, true) => " &#9660;",
+            (false, _) => "",
+        };
+
+        html.push_str(&format!(
+            "<th><a href=\"?{}\">{}</a>{}</th>",
+            escape(&query(&link, limit, None)),
+            label,
+            arrow
+        ));
+    }
+    html.push_str("</tr>\n");
+
+    if listing.path != "/" {
+        html.push_str("<tr><td><a href=\"..\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in &listing.entries {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+            escape(&encode_path(&entry.path)),
+            escape(&entry.name),
+            if entry.collection { "/" } else { "" },
+            entry.size.map(format_size).unwrap_or_default(),
+            entry.last_modified.as_ref().map(|m| format_modified(m)).unwrap_or_default()
+        ));
+    }
+    html.push_str("</table>\n");
+
+    if !first || listing.next_page.is_some() {
+        html.push_str("<p>");
+
+        if !first {
+            html.push_str(&format!(
+                "<a href=\"?{}\">First page</a> ",
+                escape(&query(sort, limit, None))
+            ));
+        }
+
+        if let Some(ref next) = listing.next_page {
+            html.push_str(&format!(
+                "<a href=\"?{}\">Next page</a>",
+                escape(&query(sort, limit, Some(next)))
+            ));
+        }
+
+        html.push_str("</p>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    mod listing {
+        use listing::*;
+
+        fn entry(name: &str, collection: bool, size: Option<i64>, modified: &str) -> Entry {
+            Entry {
+                name: name.to_owned(),
+                path: format!("/docs/{}{}", name, if collection { "/" } else { "" }),
+                collection: collection,
+                size: size,
+                last_modified: if modified.is_empty() { None } else { Some(modified.to_owned()) },
+            }
+        }
+
+        fn names(entries: &[Entry]) -> Vec<&str> {
+            entries.iter().map(|e| e.name.as_str()).collect()
+        }
+
+        #[test]
+        fn test_sort() {
+            let mut entries = vec![
+                entry("b.txt", false, Some(1), "2018-10-02T00:00:00.000Z"),
+                entry("photos", true, None, ""),
+                entry("a.txt", false, Some(5), "2018-10-01T00:00:00.000Z"),
+                entry("c.txt", false, Some(3), "2018-10-03T00:00:00.000Z"),
+                entry("music", true, None, ""),
+            ];
+
+            Sort::default().apply(&mut entries);
+            assert_eq!(names(&entries), vec!["music", "photos", "a.txt", "b.txt", "c.txt"]);
+
+            Sort::parse(Some("size"), Some("desc")).unwrap().apply(&mut entries);
+            assert_eq!(names(&entries), vec!["photos", "music", "a.txt", "c.txt", "b.txt"]);
+
+            Sort::parse(Some("modified"), None).unwrap().apply(&mut entries);
+            assert_eq!(names(&entries), vec!["music", "photos", "a.txt", "b.txt", "c.txt"]);
+
+            assert!(Sort::parse(Some("owner"), None).is_err());
+            assert!(Sort::parse(None, Some("up")).is_err());
+        }
+
+        #[test]
+        fn test_breadcrumbs() {
+            assert_eq!(breadcrumbs("/"), vec![("/".to_owned(), "/".to_owned())]);
+            assert_eq!(
+                breadcrumbs("/docs/2018/"),
+                vec![
+                    ("/".to_owned(), "/".to_owned()),
+                    ("docs".to_owned(), "/docs/".to_owned()),
+                    ("2018".to_owned(), "/docs/2018/".to_owned()),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_format_size() {
+            assert_eq!(format_size(0), "0 B");
+            assert_eq!(format_size(1023), "1023 B");
+            assert_eq!(format_size(1536), "1.5 KiB");
+            assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        }
+
+        #[test]
+        fn test_render_html() {
+            let listing = Listing {
+                path: "/docs/".to_owned(),
+                entries: vec![
+                    entry("<script>", true, None, ""),
+                    entry("a.txt", false, Some(2048), "2018-10-01T12:30:00.000Z"),
+                ],
+                next_page: Some("token+/=".to_owned()),
+            };
+            let html = render_html(&listing, &Sort::default(), Some(2), true);
+
+            assert!(html.contains("<title>Index of /docs/</title>"));
+            assert!(html.contains("<a href=\"/docs/\">docs</a>"));
+            assert!(html.contains("&lt;script&gt;/</a>"));
+            assert!(!html.contains("<script>"));
+            assert!(html.contains("<td class=\"size\">2.0 KiB</td><td>2018-10-01 12:30:00</td>"));
+            assert!(html.contains("?sort=name&amp;order=desc&amp;limit=2\">Name</a> &#9650;"));
+            assert!(html.contains("page=token%2B%2F%3D\">Next page</a>"));
+            assert!(!html.contains("First page"));
+        }
+
+        #[test]
+        fn test_render_html_encodes_paths() {
+            let listing = Listing {
+                path: "/a#b?c%d/".to_owned(),
+                entries: vec![entry("a#b?c%d", false, None, ""), entry("x&y", true, None, "")],
+                next_page: None,
+            };
+            let html = render_html(&listing, &Sort::default(), None, true);
+
+            assert!(html.contains("<a href=\"/a%23b%3Fc%25d/\">a#b?c%d</a></h1>"), "{}", html);
+            assert!(html.contains("<a href=\"/docs/a%23b%3Fc%25d\">a#b?c%d</a>"));
+            assert!(html.contains("<a href=\"/docs/x&amp;y/\">x&amp;y/</a>"));
+        }
+    }
+}
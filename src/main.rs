@@ -2,23 +2,333 @@ extern crate actix;
 extern crate actix_web;
 extern crate aws_s3_webdav;
 extern crate bytes;
+extern crate chrono;
 extern crate clap;
 extern crate env_logger;
+#[cfg(test)]
+extern crate flate2;
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate rusoto_core;
 extern crate rusoto_credential;
 extern crate rusoto_s3;
+extern crate rustls;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio_current_thread;
+extern crate tokio_io;
+extern crate tokio_timer;
 extern crate toml;
+extern crate url;
+extern crate webpki;
 
 mod routes;
+mod admin;
+mod copy;
+mod dispatcher;
+mod download;
 mod env;
+mod form;
+mod janitor;
+mod middleware;
+mod resumable;
+mod s3;
+mod shutdown;
+mod soft_delete;
+mod tls;
+mod unpack;
+mod upload;
+mod versions;
+#[cfg(test)]
+mod testing;
 
+use actix::actors::signal;
+use actix::Actor;
 use actix_web::{http, server, App};
+use aws_s3_webdav::cors::{self, Policy};
+use aws_s3_webdav::envelope::Keyring;
+use aws_s3_webdav::in_flight::InFlight;
+use aws_s3_webdav::metrics::Metrics;
+use aws_s3_webdav::readiness::Readiness;
+use aws_s3_webdav::tus::CompletedUploads;
+use aws_s3_webdav::storage_class::StorageClassRules;
+use aws_s3_webdav::metadata::DEFAULT_HEADER_PREFIX;
+use aws_s3_webdav::retry::RetryPolicy;
+use aws_s3_webdav::sse::CustomerKeys;
 use rusoto_core::Region;
 use std::sync::Arc;
 use std::borrow::ToOwned;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+/// Build application with all routes registered
+fn app(state: env::AppEnv) -> App<env::AppEnv> {
+    App::with_state(state)
+        .middleware(middleware::InFlightRequests)
+        .middleware(middleware::RequestMetrics)
+        .middleware(middleware::Cors)
+        .middleware(middleware::ClientPrincipal)
+        .resource("/", |r| r.f(routes::index))
+        .default_resource(move |r| {
+            r.method(http::Method::GET).f(routes::get_object);
+            r.method(http::Method::HEAD).f(routes::head_object);
+            r.method(http::Method::PUT).f(routes::put_object);
+            r.method(http::Method::POST).f(routes::post_object);
+            r.method(http::Method::PATCH).f(routes::patch_object);
+            r.method(http::Method::DELETE).f(routes::delete_object);
+            r.method(http::Method::OPTIONS).f(routes::options);
+            r.method(http::Method::from_bytes(b"COPY").unwrap())
+                .f(routes::copy_object);
+            r.method(http::Method::from_bytes(b"MOVE").unwrap())
+                .f(routes::move_object);
+            r.method(http::Method::from_bytes(b"PROPFIND").unwrap())
+                .f(routes::propfind);
+        })
+}
+
+/// Server-side encryption from command line arguments
+fn encryption(args: &clap::ArgMatches) -> env::Encryption {
+    if let Some(path) = args.value_of("sse_customer_key_file") {
+        let content = fs::read_to_string(path)
+            .expect(&format!("Cannot read SSE-C key file {}", path));
+
+        return env::Encryption::Customer(
+            CustomerKeys::parse(&content).expect("SSE-C key file must be valid"),
+        );
+    }
+
+    let kms_key_id = args.value_of("sse_kms_key_id").map(|k| k.to_owned());
+
+    match args.value_of("server_side_encryption") {
+        Some("AES256") if kms_key_id.is_some() => {
+            panic!("SSE KMS key ID can only be used with aws:kms encryption")
+        }
+        Some(algorithm) => env::Encryption::Managed {
+            algorithm: algorithm.to_owned(),
+            kms_key_id: kms_key_id,
+        },
+        None => env::Encryption::Default,
+    }
+}
+
+/// Client-side encryption keyring from command line arguments
+fn keyring(args: &clap::ArgMatches) -> Option<Keyring> {
+    args.value_of("encryption_keyring").map(|path| {
+        let content = fs::read_to_string(path)
+            .expect(&format!("Cannot read encryption keyring {}", path));
+
+        Keyring::parse(&content).expect("Encryption keyring must be valid")
+    })
+}
+
+/// Trash config from command line arguments, trash is disabled without prefix
+fn trash(args: &clap::ArgMatches) -> Option<env::TrashConfig> {
+    args.value_of("trash_prefix").filter(|p| !p.is_empty()).map(|prefix| {
+        let days = args.value_of("trash_retention")
+            .unwrap_or_default()
+            .parse::<u64>()
+            .expect("Trash retention must be a number of days");
+
+        env::TrashConfig::new(prefix, Duration::from_secs(days * 24 * 3600))
+    })
+}
+
+/// Website config from command line arguments, website mode is enabled by index document
+fn website(args: &clap::ArgMatches) -> Option<env::WebsiteConfig> {
+    let document = |name| args.value_of(name).filter(|d| !d.is_empty()).map(|d| d.to_owned());
+
+    document("index_document").map(|index_document| env::WebsiteConfig {
+        index_document: index_document,
+        error_document: document("error_document"),
+        spa_fallback: document("spa_fallback"),
+    })
+}
+
+/// Resumable uploads config from command line arguments, they're disabled without prefix
+fn tus(args: &clap::ArgMatches) -> Option<env::TusConfig> {
+    let tus = args.value_of("tus_staging_prefix")
+        .filter(|p| !p.is_empty())
+        .map(env::TusConfig::new);
+
+    // staged data would have to be encrypted, and encryption resumed mid-chunk
+    if tus.is_some() && args.value_of("encryption_keyring").is_some() {
+        panic!("Resumable uploads can't be used with client-side encryption");
+    }
+
+    tus
+}
+
+/// CORS policy from command line arguments, cross-origin requests are disabled without
+/// allowed origins
+fn cors(args: &clap::ArgMatches) -> Option<Policy> {
+    let value = |name| args.value_of(name).unwrap_or_default();
+
+    args.value_of("cors_allowed_origins").filter(|o| !o.is_empty()).map(|origins| {
+        let max_age = value("cors_max_age")
+            .parse::<u64>()
+            .expect("CORS max age must be a number of seconds");
+
+        Policy::new(
+            origins,
+            value("cors_allowed_methods"),
+            value("cors_allowed_headers"),
+            value("cors_exposed_headers"),
+            value("cors_allow_credentials") == "true",
+            Some(max_age).filter(|a| *a > 0),
+        ).expect("CORS policy must be valid")
+    })
+}
+
+/// TLS config from command line arguments, HTTPS is disabled without certificate
+fn tls(args: &clap::ArgMatches) -> Option<tls::Tls> {
+    args.value_of("tls_cert").filter(|c| !c.is_empty()).map(|cert| {
+        let allowed_clients = args.value_of("tls_allowed_clients").map(|clients| {
+            clients.split(',').map(|c| c.trim().to_owned()).filter(|c| !c.is_empty()).collect()
+        });
+
+        tls::Tls::new(
+            cert,
+            args.value_of("tls_key").expect("TLS key argument required"),
+            args.value_of("tls_client_ca").filter(|c| !c.is_empty()),
+            allowed_clients,
+        ).expect("TLS certificates must be valid")
+    })
+}
+
+/// Build application config from command line arguments
+fn app_config(args: &clap::ArgMatches) -> env::AppConfig {
+    let aws_region_name: String = args.value_of("aws_region")
+        .expect("AWS Region argument required")
+        .to_owned();
+
+    let aws_region: Region = aws_region_name.parse().expect("Is a valid AWS region id");
+
+    env::AppConfig {
+        aws: env::AwsConfig::new(&Region::Custom {
+            name: aws_region.name().to_owned(),
+            endpoint: format!("http://s3.{}.amazonaws.com", aws_region.name()).to_owned(),
+        }),
+        s3: env::S3Config::new(
+            args.value_of("aws_bucket")
+                .expect("AWS Bucket name argument required"),
+            args.value_of("aws_key_prefix").and_then(|s| {
+                if s.is_empty() {
+                    None
+                } else {
+                    Some(s)
+                }
+            }),
+        ),
+        upload: env::UploadConfig::new(
+            args.value_of("upload_part_size")
+                .unwrap_or_default()
+                .parse::<u64>()
+                .expect("Upload part size must be a number of MiB")
+                * 1024 * 1024,
+            args.value_of("upload_max_part_size")
+                .unwrap_or_default()
+                .parse::<u64>()
+                .expect("Upload max part size must be a number of MiB")
+                * 1024 * 1024,
+            Duration::from_secs(
+                args.value_of("upload_idle_timeout")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                    .expect("Upload idle timeout must be a number of seconds"),
+            ),
+        ),
+        copy: env::CopyConfig::new(
+            args.value_of("copy_part_size")
+                .unwrap_or_default()
+                .parse::<u64>()
+                .expect("Copy part size must be a number of MiB")
+                * 1024 * 1024,
+            args.value_of("copy_concurrency")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .expect("Copy concurrency must be a number"),
+        ),
+        metadata: env::MetadataConfig::new(
+            args.value_of("metadata_header_prefix").unwrap_or_default(),
+            args.value_of("metadata_max_size")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .expect("Metadata max size must be a number of bytes"),
+        ),
+        encryption: encryption(args),
+        storage_class: StorageClassRules::parse(
+            args.values_of("storage_class_rule").into_iter().flatten(),
+        ).expect("Storage class rules must be prefix=CLASS"),
+        keyring: keyring(args),
+        trash: trash(args),
+        website: website(args),
+        archive: env::ArchiveConfig::new(
+            args.value_of("archive_max_entries")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .expect("Archive max entries must be a number"),
+            args.value_of("archive_max_size")
+                .unwrap_or_default()
+                .parse::<u64>()
+                .expect("Archive max size must be a number of MiB")
+                * 1024 * 1024,
+        ),
+        tus: tus(args),
+        cors: cors(args),
+        retry: RetryPolicy::new(
+            args.value_of("s3_max_attempts")
+                .unwrap_or_default()
+                .parse::<u32>()
+                .expect("S3 max attempts must be a number"),
+            Duration::from_millis(
+                args.value_of("s3_retry_base_delay")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                    .expect("S3 retry base delay must be a number of milliseconds"),
+            ),
+            Duration::from_millis(
+                args.value_of("s3_retry_max_delay")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                    .expect("S3 retry max delay must be a number of milliseconds"),
+            ),
+            args.value_of("s3_retry_jitter") == Some("true"),
+        ),
+        readiness: env::ReadinessConfig::new(
+            args.value_of("readiness_probe_key").filter(|k| !k.is_empty()).map(|k| k.to_owned()),
+            Duration::from_secs(
+                args.value_of("readiness_cache_ttl")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                    .expect("Readiness cache TTL must be a number of seconds"),
+            ),
+        ),
+    }
+}
+
+/// State shared by all servers and tasks, besides config and S3 client
+#[derive(Clone, Default)]
+struct Shared {
+    in_flight: InFlight,
+    metrics: Metrics,
+    readiness: Readiness,
+    completed_uploads: CompletedUploads,
+}
+
+/// Application state sharing requests in flight, metrics, readiness and completed resumable
+/// uploads with other servers and tasks
+fn shared_state(args: &clap::ArgMatches, shared: &Shared) -> env::AppEnv {
+    let mut state = env::AppState::new(app_config(args));
+    state.in_flight = shared.in_flight.clone();
+    state.metrics = shared.metrics.clone();
+    state.readiness = shared.readiness.clone();
+    state.completed_uploads = shared.completed_uploads.clone();
+
+    Arc::new(state)
+}
 
 fn main() {
     env_logger::init();
@@ -39,6 +349,88 @@ fn main() {
                 .default_value("0.0.0.0:8080")
                 .required(false),
         )
+        .arg(
+            clap::Arg::with_name("admin_bind")
+                .long("admin-bind")
+                .value_name("HOST")
+                .env("ADMIN_BIND")
+                .help(
+                    "Serve /metrics, /healthz and /readyz on this address, health checks are \
+                     only available there, empty disables it",
+                )
+                .takes_value(true)
+                .default_value("127.0.0.1:9090")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("readiness_probe_key")
+                .long("readiness-probe-key")
+                .value_name("KEY")
+                .env("READINESS_PROBE_KEY")
+                .help("Check readiness with HEAD of this key instead of bucket")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("readiness_cache_ttl")
+                .long("readiness-cache-ttl")
+                .value_name("SECONDS")
+                .env("READINESS_CACHE_TTL")
+                .help("Time result of readiness check is reported for before checking again")
+                .takes_value(true)
+                .default_value("10")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_name("SECONDS")
+                .env("SHUTDOWN_TIMEOUT")
+                .help("Time requests in flight have to finish on shutdown")
+                .takes_value(true)
+                .default_value("30")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .env("TLS_CERT")
+                .help("Serve HTTPS with this PEM certificate chain, server certificate first")
+                .takes_value(true)
+                .requires("tls_key")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("tls_key")
+                .long("tls-key")
+                .value_name("PATH")
+                .env("TLS_KEY")
+                .help("PEM private key (PKCS#8 or RSA) of TLS certificate")
+                .takes_value(true)
+                .requires("tls_cert")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("tls_client_ca")
+                .long("tls-client-ca")
+                .value_name("PATH")
+                .env("TLS_CLIENT_CA")
+                .help("Require client certificates issued by these PEM CA certificates")
+                .takes_value(true)
+                .requires("tls_cert")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("tls_allowed_clients")
+                .long("tls-allowed-clients")
+                .value_name("NAMES")
+                .env("TLS_ALLOWED_CLIENTS")
+                .help("Comma separated client certificate common names allowed to connect")
+                .takes_value(true)
+                .requires("tls_client_ca")
+                .required(false),
+        )
         .arg(
             clap::Arg::with_name("aws_bucket")
                 .long("aws-bucket")
@@ -66,54 +458,473 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            clap::Arg::with_name("upload_part_size")
+                .long("upload-part-size")
+                .value_name("MIB")
+                .env("UPLOAD_PART_SIZE")
+                .help("Multipart upload part size in MiB, min 5, grows for large uploads")
+                .takes_value(true)
+                .default_value("5")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("upload_max_part_size")
+                .long("upload-max-part-size")
+                .value_name("MIB")
+                .env("UPLOAD_MAX_PART_SIZE")
+                .help(
+                    "Largest multipart upload part size in MiB, max 5120, each upload buffers \
+                     a whole part in memory, objects up to 10000 parts of it can be uploaded",
+                )
+                .takes_value(true)
+                .default_value("5120")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("upload_idle_timeout")
+                .long("upload-idle-timeout")
+                .value_name("SECONDS")
+                .env("UPLOAD_IDLE_TIMEOUT")
+                .help("Fail and abort upload if no request body data received for given time")
+                .takes_value(true)
+                .default_value("60")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("copy_part_size")
+                .long("copy-part-size")
+                .value_name("MIB")
+                .env("COPY_PART_SIZE")
+                .help("Part size in MiB for server-side copy of objects larger than 5GiB")
+                .takes_value(true)
+                .default_value("512")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("copy_concurrency")
+                .long("copy-concurrency")
+                .value_name("N")
+                .env("COPY_CONCURRENCY")
+                .help("Number of parts copied in parallel for objects larger than 5GiB")
+                .takes_value(true)
+                .default_value("4")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("metadata_header_prefix")
+                .long("metadata-header-prefix")
+                .value_name("PREFIX")
+                .env("METADATA_HEADER_PREFIX")
+                .help("Prefix of request headers stored as object metadata, empty to disable")
+                .takes_value(true)
+                .default_value(DEFAULT_HEADER_PREFIX)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("metadata_max_size")
+                .long("metadata-max-size")
+                .value_name("BYTES")
+                .env("METADATA_MAX_SIZE")
+                .help("Max total size of object metadata keys and values, up to 2048")
+                .takes_value(true)
+                .default_value("2048")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("server_side_encryption")
+                .long("server-side-encryption")
+                .value_name("ALGORITHM")
+                .env("SERVER_SIDE_ENCRYPTION")
+                .help("Encrypt stored objects with S3 (AES256) or KMS (aws:kms) managed keys")
+                .takes_value(true)
+                .possible_values(&["AES256", "aws:kms"])
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("sse_kms_key_id")
+                .long("sse-kms-key-id")
+                .value_name("KEY_ID")
+                .env("SSE_KMS_KEY_ID")
+                .help("KMS key ID or ARN used with aws:kms encryption")
+                .takes_value(true)
+                .requires("server_side_encryption")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("sse_customer_key_file")
+                .long("sse-customer-key-file")
+                .value_name("PATH")
+                .env("SSE_CUSTOMER_KEY_FILE")
+                .help("TOML file with SSE-C keys by object key prefix")
+                .takes_value(true)
+                .conflicts_with("server_side_encryption")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("storage_class_rule")
+                .long("storage-class-rule")
+                .value_name("PREFIX=CLASS")
+                .env("STORAGE_CLASS_RULES")
+                .help(
+                    "Storage class of uploaded objects with key prefix, empty prefix matches all \
+                     (STANDARD, STANDARD_IA, ONEZONE_IA, INTELLIGENT_TIERING, GLACIER, \
+                     DEEP_ARCHIVE)",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("encryption_keyring")
+                .long("encryption-keyring")
+                .value_name("PATH")
+                .env("ENCRYPTION_KEYRING")
+                .help("TOML keyring file, enables client-side encryption of stored objects")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("s3_max_attempts")
+                .long("s3-max-attempts")
+                .value_name("N")
+                .env("S3_MAX_ATTEMPTS")
+                .help("Max attempts for S3 calls failing with transient errors, 1 to disable")
+                .takes_value(true)
+                .default_value("3")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("s3_retry_base_delay")
+                .long("s3-retry-base-delay")
+                .value_name("MILLIS")
+                .env("S3_RETRY_BASE_DELAY")
+                .help("Delay before first S3 retry, doubled for every next one")
+                .takes_value(true)
+                .default_value("100")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("s3_retry_max_delay")
+                .long("s3-retry-max-delay")
+                .value_name("MILLIS")
+                .env("S3_RETRY_MAX_DELAY")
+                .help("Max delay between S3 retries")
+                .takes_value(true)
+                .default_value("5000")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("s3_retry_jitter")
+                .long("s3-retry-jitter")
+                .value_name("BOOL")
+                .env("S3_RETRY_JITTER")
+                .help("Randomize delays between S3 retries")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .default_value("true")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("abort_uploads_older_than")
+                .long("abort-uploads-older-than")
+                .value_name("HOURS")
+                .env("ABORT_UPLOADS_OLDER_THAN")
+                .help("Age after which unfinished multipart uploads are aborted")
+                .takes_value(true)
+                .default_value("24")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("abort_uploads_interval")
+                .long("abort-uploads-interval")
+                .value_name("MINUTES")
+                .env("ABORT_UPLOADS_INTERVAL")
+                .help("How often to look for stale multipart uploads, 0 to disable")
+                .takes_value(true)
+                .default_value("60")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_allowed_origins")
+                .long("cors-allowed-origins")
+                .value_name("ORIGINS")
+                .env("CORS_ALLOWED_ORIGINS")
+                .help("Comma separated origins allowed to make cross-origin requests, or *")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_allowed_methods")
+                .long("cors-allowed-methods")
+                .value_name("METHODS")
+                .env("CORS_ALLOWED_METHODS")
+                .help("Comma separated methods allowed in cross-origin requests")
+                .takes_value(true)
+                .default_value(cors::DEFAULT_METHODS)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_allowed_headers")
+                .long("cors-allowed-headers")
+                .value_name("HEADERS")
+                .env("CORS_ALLOWED_HEADERS")
+                .help("Comma separated headers allowed in cross-origin requests, or * for any")
+                .takes_value(true)
+                .default_value("*")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_exposed_headers")
+                .long("cors-exposed-headers")
+                .value_name("HEADERS")
+                .env("CORS_EXPOSED_HEADERS")
+                .help("Comma separated response headers exposed to cross-origin scripts")
+                .takes_value(true)
+                .default_value(cors::DEFAULT_EXPOSED_HEADERS)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_allow_credentials")
+                .long("cors-allow-credentials")
+                .value_name("BOOL")
+                .env("CORS_ALLOW_CREDENTIALS")
+                .help("Allow cookies and HTTP authentication in cross-origin requests")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .default_value("false")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("cors_max_age")
+                .long("cors-max-age")
+                .value_name("SECONDS")
+                .env("CORS_MAX_AGE")
+                .help("Seconds browsers may cache preflight responses for, 0 to not send it")
+                .takes_value(true)
+                .default_value("3600")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("tus_staging_prefix")
+                .long("tus-staging-prefix")
+                .value_name("PREFIX")
+                .env("TUS_STAGING_PREFIX")
+                .help("Accept resumable uploads, staging their data under this prefix")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("trash_prefix")
+                .long("trash-prefix")
+                .value_name("PREFIX")
+                .env("TRASH_PREFIX")
+                .help("Move deleted objects under this prefix (e.g. .trash/) instead of deleting")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("trash_retention")
+                .long("trash-retention")
+                .value_name("DAYS")
+                .env("TRASH_RETENTION")
+                .help("Age after which deleted objects are purged from trash")
+                .takes_value(true)
+                .default_value("30")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("purge_trash_interval")
+                .long("purge-trash-interval")
+                .value_name("MINUTES")
+                .env("PURGE_TRASH_INTERVAL")
+                .help("How often to purge expired trash, 0 to disable")
+                .takes_value(true)
+                .default_value("60")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("index_document")
+                .long("index-document")
+                .value_name("NAME")
+                .env("INDEX_DOCUMENT")
+                .help("Serve collections with this document (e.g. index.html) instead of listing")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("error_document")
+                .long("error-document")
+                .value_name("PATH")
+                .env("ERROR_DOCUMENT")
+                .help("Document served with 404 status for missing objects in website mode")
+                .takes_value(true)
+                .requires("index_document")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("spa_fallback")
+                .long("spa-fallback")
+                .value_name("PATH")
+                .env("SPA_FALLBACK")
+                .help("Document served for any missing object in website mode (single page apps)")
+                .takes_value(true)
+                .requires("index_document")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("archive_max_entries")
+                .long("archive-max-entries")
+                .value_name("N")
+                .env("ARCHIVE_MAX_ENTRIES")
+                .help("Max number of objects in archive downloaded or extracted")
+                .takes_value(true)
+                .default_value("10000")
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("archive_max_size")
+                .long("archive-max-size")
+                .value_name("MIB")
+                .env("ARCHIVE_MAX_SIZE")
+                .help("Max total size in MiB of objects in archive downloaded or extracted")
+                .takes_value(true)
+                .default_value("10240")
+                .required(false),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("abort-uploads")
+                .about("Abort stale multipart uploads under key prefix and exit"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("purge-trash")
+                .about("Delete objects in trash older than retention and exit"),
+        )
         .get_matches();
 
+    let uploads_max_age = Duration::from_secs(
+        matches
+            .value_of("abort_uploads_older_than")
+            .unwrap_or_default()
+            .parse::<u64>()
+            .expect("Abort uploads age must be a number of hours") * 3600,
+    );
+
+    if matches.subcommand_matches("abort-uploads").is_some() {
+        let state = Arc::new(env::AppState::new(app_config(&matches)));
+        let mut sys = actix::System::new("abort-uploads");
+
+        match sys.block_on(janitor::abort_stale_uploads(&state, uploads_max_age)) {
+            Ok(aborted) => println!("Aborted {} stale multipart uploads", aborted),
+            Err(e) => {
+                error!("Failed to list multipart uploads: {}", e);
+                process::exit(1);
+            }
+        }
+
+        return;
+    }
+
+    if matches.subcommand_matches("purge-trash").is_some() {
+        let state = Arc::new(env::AppState::new(app_config(&matches)));
+        let mut sys = actix::System::new("purge-trash");
+
+        match sys.block_on(soft_delete::purge(&state)) {
+            Ok(purged) => println!("Purged {} objects from trash", purged),
+            Err(e) => {
+                error!("Failed to purge trash: {}", e);
+                process::exit(1);
+            }
+        }
+
+        return;
+    }
+
     let bind_port = matches.value_of("bind").unwrap_or_default().to_owned();
+    let uploads_interval = matches
+        .value_of("abort_uploads_interval")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .expect("Abort uploads interval must be a number of minutes");
+    let trash_interval = matches
+        .value_of("purge_trash_interval")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .expect("Purge trash interval must be a number of minutes");
+
+    let shutdown_timeout = matches
+        .value_of("shutdown_timeout")
+        .unwrap_or_default()
+        .parse::<u16>()
+        .expect("Shutdown timeout must be a number of seconds");
+    let tls = tls(&matches).map(Arc::new);
 
-    info!("Start server on {}", bind_port);
+    info!("Start server on {}{}", bind_port, if tls.is_some() { " with TLS" } else { "" });
 
-    // Start http server
-    server::HttpServer::new(move || {
+    let sys = actix::System::new("aws-s3-webdav");
+    let server_args = matches.clone();
+    let shared = Shared::default();
+    let server_shared = shared.clone();
+
+    // Start http server, signals are handled by shutdown, which stops it. Workers are given
+    // one more second, so uploads still open when grace period expires are aborted there.
+    let server = server::HttpServer::new(move || {
         info!("Building application");
-        let args = matches.clone();
 
-        let aws_region_name: String = args.value_of("aws_region")
-            .expect("AWS Region argument required")
-            .to_owned();
+        app(shared_state(&server_args, &server_shared))
+    }).disable_signals()
+        .shutdown_timeout(shutdown_timeout.saturating_add(1));
 
-        let aws_region: Region = aws_region_name.parse().expect("Is a valid AWS region id");
+    let server = match tls {
+        Some(ref tls) => server.bind_with(&bind_port, tls.acceptor()),
+        None => server.bind(&bind_port),
+    }.expect(&format!("Cannot bind to {}", &bind_port))
+        .start();
 
-        let state = env::AppState::new(env::AppConfig {
-            aws: env::AwsConfig::new(&Region::Custom {
-                name: aws_region.name().to_owned(),
-                endpoint: format!("http://s3.{}.amazonaws.com", aws_region.name()).to_owned(),
-            }),
-            s3: env::S3Config::new(
-                args.value_of("aws_bucket")
-                    .expect("AWS Bucket name argument required"),
-                args.value_of("aws_key_prefix").and_then(|s| {
-                    if s.is_empty() {
-                        None
-                    } else {
-                        Some(s)
-                    }
-                }),
-            ),
-        });
+    // Admin server keeps serving until system is stopped, so shutdown can be watched there
+    if let Some(admin_bind) = matches.value_of("admin_bind").filter(|b| !b.is_empty()) {
+        info!("Start admin server on {}", admin_bind);
+
+        let admin_args = matches.clone();
+        let admin_shared = shared.clone();
+
+        server::HttpServer::new(move || admin::app(shared_state(&admin_args, &admin_shared)))
+            .workers(1)
+            .disable_signals()
+            .bind(admin_bind)
+            .expect(&format!("Cannot bind to {}", admin_bind))
+            .start();
+    }
+
+    let shutdown = shutdown::Shutdown::new(
+        server,
+        shared_state(&matches, &shared),
+        Duration::from_secs(u64::from(shutdown_timeout)),
+    ).start();
+    let signals = actix::System::current().registry().get::<signal::ProcessSignals>();
+    signals.do_send(signal::Subscribe(shutdown.recipient()));
+
+    if let Some(tls) = tls {
+        let reloader = tls::Reloader(tls).start();
+        signals.do_send(signal::Subscribe(reloader.recipient()));
+    }
+
+    if uploads_interval > 0 {
+        janitor::spawn(
+            shared_state(&matches, &shared),
+            Duration::from_secs(uploads_interval * 60),
+            uploads_max_age,
+        );
+    }
+
+    if trash_interval > 0 && trash(&matches).is_some() {
+        soft_delete::spawn(
+            shared_state(&matches, &shared),
+            Duration::from_secs(trash_interval * 60),
+        );
+    }
 
-        App::with_state(Arc::new(state))
-            .resource("/", |r| r.f(routes::index))
-            .default_resource(move |r| {
-                r.method(http::Method::GET).f(routes::get_object);
-                r.method(http::Method::HEAD).f(routes::head_object);
-                r.method(http::Method::PUT).f(routes::put_object);
-                r.method(http::Method::DELETE).f(routes::delete_object);
-                r.method(http::Method::from_bytes(b"COPY").unwrap())
-                    .f(routes::copy_object);
-                r.method(http::Method::from_bytes(b"MOVE").unwrap())
-                    .f(routes::move_object);
-            })
-    }).bind(&bind_port)
-        .expect(&format!("Cannot bind to {}", &bind_port))
-        .run();
+    sys.run();
 }
@@ -0,0 +1,150 @@
+use envelope;
+use integrity::SHA256_METADATA_KEY;
+use std::collections::HashMap;
+
+/// S3 limit for user metadata size: sum of all keys and values, in bytes
+pub const MAX_METADATA_SIZE: usize = 2048;
+
+/// Default prefix of request headers forwarded as user metadata, same as S3 uses
+pub const DEFAULT_HEADER_PREFIX: &str = "x-amz-meta-";
+
+/// Space kept for SHA-256 digest and wrapped encryption key stored by proxy itself
+const RESERVED_SIZE: usize = 6 + 64 + envelope::METADATA_SIZE;
+
+/// Metadata keys managed by proxy, not settable by clients and not returned to them
+fn is_reserved(key: &str) -> bool {
+    key == SHA256_METADATA_KEY || key == envelope::METADATA_KEY
+}
+
+/// Size of metadata as counted by S3
+pub fn size(metadata: &HashMap<String, String>) -> usize {
+    metadata.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+/// Collect user metadata from request headers starting with `prefix` (case-insensitive),
+/// prefix is stripped and keys are lowercased. Fails if reserved key is used, value is not
+/// printable ASCII or total size exceeds `max_size`
+pub fn from_headers<'a, I>(
+    headers: I,
+    prefix: &str,
+    max_size: usize,
+) -> Result<HashMap<String, String>, String>
+where
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let prefix = prefix.to_lowercase();
+    let mut metadata = HashMap::new();
+
+    for (name, value) in headers {
+        let name = name.to_lowercase();
+
+        if !name.starts_with(&prefix) {
+            continue;
+        }
+
+        let key = &name[prefix.len()..];
+
+        if key.is_empty() {
+            return Err("Empty metadata key".to_owned());
+        }
+
+        if is_reserved(key) {
+            return Err(format!("Metadata key {} is reserved", key));
+        }
+
+        if !value.iter().all(|b| *b == b'\t' || (*b >= 0x20 && *b < 0x7f)) {
+            return Err(format!("Metadata {} value must be printable ASCII", key));
+        }
+
+        // same header repeated is joined like HTTP does for lists
+        let value = String::from_utf8_lossy(value).trim().to_owned();
+        let entry = metadata.entry(key.to_owned()).or_insert_with(String::new);
+
+        if !entry.is_empty() {
+            entry.push(',');
+        }
+
+        entry.push_str(&value);
+    }
+
+    if size(&metadata) + RESERVED_SIZE > max_size {
+        let available = max_size.saturating_sub(RESERVED_SIZE);
+        return Err(format!("Metadata size exceeds {} bytes", available));
+    }
+
+    Ok(metadata)
+}
+
+/// Response headers for stored user metadata, reserved keys are skipped
+pub fn to_headers(metadata: &HashMap<String, String>, prefix: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = metadata
+        .iter()
+        .filter(|&(k, _)| !is_reserved(k))
+        .map(|(k, v)| (format!("{}{}", prefix, k), v.to_owned()))
+        .collect();
+
+    headers.sort();
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    mod from_headers {
+        use metadata::*;
+
+        fn parse(headers: &[(&str, &str)]) -> Result<HashMap<String, String>, String> {
+            from_headers(
+                headers.iter().map(|&(n, v)| (n, v.as_bytes())),
+                "X-Meta-",
+                MAX_METADATA_SIZE,
+            )
+        }
+
+        #[test]
+        fn test_collects_prefixed() {
+            let metadata = parse(&[
+                ("content-type", "text/plain"),
+                ("x-meta-origin", " camera "),
+                ("X-Meta-Pipeline", "ingest"),
+                ("x-meta-pipeline", "thumbs"),
+            ]).unwrap();
+
+            assert_eq!(metadata.len(), 2);
+            assert_eq!(metadata["origin"], "camera");
+            assert_eq!(metadata["pipeline"], "ingest,thumbs");
+        }
+
+        #[test]
+        fn test_rejects_invalid() {
+            assert!(parse(&[("x-meta-", "value")]).is_err());
+            assert!(parse(&[("x-meta-sha256", "value")]).is_err());
+            assert!(parse(&[("x-meta-cse", "value")]).is_err());
+            assert!(parse(&[("x-meta-name", "caf\u{e9}")]).is_err());
+        }
+
+        #[test]
+        fn test_size_limit() {
+            // together with reserved entries makes exactly 2048 bytes
+            let value = "a".repeat(MAX_METADATA_SIZE - RESERVED_SIZE - 3);
+
+            assert!(parse(&[("x-meta-key", &value)]).is_ok());
+            assert!(parse(&[("x-meta-key2", &value)]).is_err());
+        }
+    }
+
+    mod to_headers {
+        use metadata::*;
+
+        #[test]
+        fn test_skips_reserved() {
+            let mut metadata = HashMap::new();
+            metadata.insert("origin".to_owned(), "camera".to_owned());
+            metadata.insert(SHA256_METADATA_KEY.to_owned(), "00".to_owned());
+
+            assert_eq!(
+                to_headers(&metadata, "x-meta-"),
+                vec![("x-meta-origin".to_owned(), "camera".to_owned())]
+            );
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use in_flight::InFlight;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Content type of metrics in Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// Methods reported by name, others are reported as `OTHER`, so clients can't make up series
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "PUT", "POST", "PATCH", "DELETE", "OPTIONS", "COPY", "MOVE", "PROPFIND",
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Cumulative counts of observations in `LATENCY_BUCKETS`
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (count, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// Requests made to S3 with given operation, each attempt counts
+#[derive(Clone, Debug, Default)]
+struct S3Operation {
+    latency: Histogram,
+    errors: u64,
+    retries: u64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Latency of handled requests by method and response status
+    requests: BTreeMap<(&'static str, u16), Histogram>,
+    received_bytes: u64,
+    sent_bytes: u64,
+    s3: BTreeMap<&'static str, S3Operation>,
+}
+
+/// Metrics of requests handled and S3 requests made, shared by all workers
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<Mutex<Registry>>);
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    /// Request was handled, `sent_bytes` is size of response body
+    pub fn request(&self, method: &str, status: u16, latency: Duration, sent_bytes: u64) {
+        let method = METHODS.iter().find(|m| **m == method).map_or("OTHER", |m| *m);
+        let mut registry = self.0.lock().unwrap();
+
+        let requests = registry.requests.entry((method, status));
+        requests.or_default().observe(latency);
+        registry.sent_bytes += sent_bytes;
+    }
+
+    /// Chunk of request body was received
+    pub fn received(&self, bytes: u64) {
+        self.0.lock().unwrap().received_bytes += bytes;
+    }
+
+    /// S3 request attempt finished
+    pub fn s3_request(&self, operation: &'static str, latency: Duration, failed: bool) {
+        let mut registry = self.0.lock().unwrap();
+        let stats = registry.s3.entry(operation).or_default();
+
+        stats.latency.observe(latency);
+        if failed {
+            stats.errors += 1;
+        }
+    }
+
+    /// Failed S3 request is retried
+    pub fn s3_retry(&self, operation: &'static str) {
+        let mut registry = self.0.lock().unwrap();
+
+        registry.s3.entry(operation).or_default().retries += 1;
+    }
+
+    /// Metrics in Prometheus text format, with requests and uploads `in_flight` now
+    pub fn render(&self, in_flight: &InFlight) -> String {
+        let registry = self.0.lock().unwrap();
+        let mut out = String::new();
+
+        family(&mut out, "webdav_requests_total", "counter", "Requests handled");
+        for (&(method, status), latency) in &registry.requests {
+            let _ = writeln!(
+                out,
+                "webdav_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, latency.count
+            );
+        }
+
+        let name = "webdav_request_duration_seconds";
+        family(&mut out, name, "histogram", "Time to handle request, until response is sent");
+        for (&(method, status), latency) in &registry.requests {
+            let labels = format!("method=\"{}\",status=\"{}\"", method, status);
+            latency.render(&mut out, name, &labels);
+        }
+
+        let help = "Request body bytes received";
+        family(&mut out, "webdav_received_bytes_total", "counter", help);
+        let _ = writeln!(out, "webdav_received_bytes_total {}", registry.received_bytes);
+
+        let help = "Response body bytes sent";
+        family(&mut out, "webdav_sent_bytes_total", "counter", help);
+        let _ = writeln!(out, "webdav_sent_bytes_total {}", registry.sent_bytes);
+
+        let help = "Requests being handled";
+        family(&mut out, "webdav_requests_in_flight", "gauge", help);
+        let _ = writeln!(out, "webdav_requests_in_flight {}", in_flight.requests());
+
+        let help = "Multipart uploads opened by requests being handled";
+        family(&mut out, "webdav_multipart_uploads_in_flight", "gauge", help);
+        let _ = writeln!(out, "webdav_multipart_uploads_in_flight {}", in_flight.uploads().len());
+
+        let help = "S3 requests made, including retries";
+        family(&mut out, "webdav_s3_requests_total", "counter", help);
+        for (operation, stats) in &registry.s3 {
+            let _ = writeln!(
+                out,
+                "webdav_s3_requests_total{{operation=\"{}\"}} {}",
+                operation, stats.latency.count
+            );
+        }
+
+        let help = "S3 requests failed, including ones retried";
+        family(&mut out, "webdav_s3_request_errors_total", "counter", help);
+        for (operation, stats) in &registry.s3 {
+            let _ = writeln!(
+                out,
+                "webdav_s3_request_errors_total{{operation=\"{}\"}} {}",
+                operation, stats.errors
+            );
+        }
+
+        let help = "S3 requests retried after transient failures";
+        family(&mut out, "webdav_s3_request_retries_total", "counter", help);
+        for (operation, stats) in &registry.s3 {
+            let _ = writeln!(
+                out,
+                "webdav_s3_request_retries_total{{operation=\"{}\"}} {}",
+                operation, stats.retries
+            );
+        }
+
+        let name = "webdav_s3_request_duration_seconds";
+        family(&mut out, name, "histogram", "Time S3 requests took");
+        for (operation, stats) in &registry.s3 {
+            stats.latency.render(&mut out, name, &format!("operation=\"{}\"", operation));
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod metrics {
+        use in_flight::InFlight;
+        use metrics::*;
+        use std::time::Duration;
+
+        fn lines(metrics: &Metrics, in_flight: &InFlight) -> Vec<String> {
+            metrics.render(in_flight).lines().map(|l| l.to_owned()).collect()
+        }
+
+        #[test]
+        fn test_requests() {
+            let metrics = Metrics::default();
+            let in_flight = InFlight::default();
+            let _request = in_flight.request();
+
+            metrics.request("GET", 200, Duration::from_millis(125), 100);
+            metrics.request("GET", 200, Duration::from_millis(500), 50);
+            metrics.request("LOCK", 405, Duration::from_millis(1), 0);
+            metrics.received(42);
+
+            let lines = lines(&metrics.clone(), &in_flight);
+            let get = "method=\"GET\",status=\"200\"";
+            let duration = |line: &str| format!("webdav_request_duration_seconds_{}", line);
+            let expected = [
+                "# TYPE webdav_requests_total counter".to_owned(),
+                format!("webdav_requests_total{{{}}} 2", get),
+                "webdav_requests_total{method=\"OTHER\",status=\"405\"} 1".to_owned(),
+                "# TYPE webdav_request_duration_seconds histogram".to_owned(),
+                duration(&format!("bucket{{{},le=\"0.1\"}} 0", get)),
+                duration(&format!("bucket{{{},le=\"0.25\"}} 1", get)),
+                duration(&format!("bucket{{{},le=\"0.5\"}} 2", get)),
+                duration(&format!("bucket{{{},le=\"+Inf\"}} 2", get)),
+                duration(&format!("sum{{{}}} 0.625", get)),
+                duration(&format!("count{{{}}} 2", get)),
+                "webdav_received_bytes_total 42".to_owned(),
+                "webdav_sent_bytes_total 150".to_owned(),
+                "webdav_requests_in_flight 1".to_owned(),
+                "webdav_multipart_uploads_in_flight 0".to_owned(),
+            ];
+
+            for line in expected.iter() {
+                assert!(lines.iter().any(|l| l == line), "missing {}", line);
+            }
+        }
+
+        #[test]
+        fn test_s3_requests() {
+            let metrics = Metrics::default();
+
+            metrics.s3_request("upload_part", Duration::from_secs(2), true);
+            metrics.s3_retry("upload_part");
+            metrics.s3_request("upload_part", Duration::from_secs(1), false);
+            metrics.s3_request("get_object", Duration::from_millis(30), false);
+
+            let lines = lines(&metrics, &InFlight::default());
+            let expected = [
+                "webdav_s3_requests_total{operation=\"get_object\"} 1",
+                "webdav_s3_requests_total{operation=\"upload_part\"} 2",
+                "webdav_s3_request_errors_total{operation=\"get_object\"} 0",
+                "webdav_s3_request_errors_total{operation=\"upload_part\"} 1",
+                "webdav_s3_request_retries_total{operation=\"upload_part\"} 1",
+                "webdav_s3_request_duration_seconds_bucket{operation=\"upload_part\",le=\"1\"} 1",
+                "webdav_s3_request_duration_seconds_sum{operation=\"upload_part\"} 3",
+            ];
+
+            for line in expected.iter() {
+                assert!(lines.iter().any(|l| l == line), "missing {}", line);
+            }
+        }
+    }
+}
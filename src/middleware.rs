@@ -0,0 +1,134 @@
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::{Finished, Middleware, Response, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use env::AppEnv;
+use std::time::Instant;
+use tls::Principal;
+
+fn header_str(req: &HttpRequest<AppEnv>, name: HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok())
+}
+
+/// Cross-origin resource sharing with configured policy: answers preflight requests and adds
+/// CORS headers to responses to allowed origins. Requests from other origins are handled
+/// as usual, just without CORS headers, so browsers don't let scripts read responses.
+pub struct Cors;
+
+impl Middleware<AppEnv> for Cors {
+    fn start(&self, req: &HttpRequest<AppEnv>) -> Result<Started> {
+        let policy = match req.state().config.cors {
+            Some(ref policy) => policy,
+            None => return Ok(Started::Done),
+        };
+        let origin = header_str(req, header::ORIGIN);
+        let method = header_str(req, header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        // other OPTIONS requests (e.g. of tus clients) are handled by routes
+        let (origin, method) = match (origin, method) {
+            (Some(origin), Some(method)) if req.method() == Method::OPTIONS => (origin, method),
+            _ => return Ok(Started::Done),
+        };
+        let request_headers = header_str(req, header::ACCESS_CONTROL_REQUEST_HEADERS);
+
+        let mut response = match policy.preflight_headers(origin, method, request_headers) {
+            Ok(headers) => {
+                let mut response = HttpResponse::NoContent();
+                for (name, value) in headers {
+                    response.header(name, value);
+                }
+                response
+            }
+            Err(e) => {
+                debug!("Preflight request rejected: {}", e);
+                HttpResponse::Forbidden()
+            }
+        };
+
+        // responses returned here don't go through `response`
+        if policy.varies_by_origin() {
+            response.header(header::VARY, "Origin");
+        }
+
+        Ok(Started::Response(response.finish()))
+    }
+
+    fn response(&self, req: &HttpRequest<AppEnv>, mut resp: HttpResponse) -> Result<Response> {
+        let policy = match req.state().config.cors {
+            Some(ref policy) => policy,
+            None => return Ok(Response::Done(resp)),
+        };
+
+        if let Some(origin) = header_str(req, header::ORIGIN) {
+            for (name, value) in policy.response_headers(origin) {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    resp.headers_mut().insert(name, value);
+                }
+            }
+        }
+
+        if policy.varies_by_origin() {
+            resp.headers_mut().append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+
+        Ok(Response::Done(resp))
+    }
+}
+
+/// Counts requests in flight, from when they're started until their response is sent or
+/// connection is closed, as shutdown waits for them
+pub struct InFlightRequests;
+
+impl Middleware<AppEnv> for InFlightRequests {
+    fn start(&self, req: &HttpRequest<AppEnv>) -> Result<Started> {
+        // request extensions are dropped with request, when it's done
+        let guard = req.state().in_flight.request();
+        req.extensions_mut().insert(guard);
+
+        Ok(Started::Done)
+    }
+}
+
+/// Principal of client certificate, verified when TLS connection was accepted, is put in
+/// request extensions, so handlers can tell who made the request
+pub struct ClientPrincipal;
+
+impl<S> Middleware<S> for ClientPrincipal {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let extensions = req.request().stream_extensions();
+
+        if let Some(principal) = extensions.and_then(|e| e.get::<Principal>()).cloned() {
+            debug!("{} {} by client {}", req.method(), req.path(), principal.0);
+            req.extensions_mut().insert(principal);
+        }
+
+        Ok(Started::Done)
+    }
+}
+
+/// When request was started, in request extensions
+struct RequestStarted(Instant);
+
+/// Records latency and size of responses by method and status, once response is sent
+pub struct RequestMetrics;
+
+impl Middleware<AppEnv> for RequestMetrics {
+    fn start(&self, req: &HttpRequest<AppEnv>) -> Result<Started> {
+        req.extensions_mut().insert(RequestStarted(Instant::now()));
+
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &HttpRequest<AppEnv>, resp: &HttpResponse) -> Finished {
+        if let Some(started) = req.extensions().get::<RequestStarted>() {
+            req.state().metrics.request(
+                req.method().as_str(),
+                resp.status().as_u16(),
+                started.0.elapsed(),
+                resp.response_size(),
+            );
+        }
+
+        Finished::Done
+    }
+}
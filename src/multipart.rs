@@ -0,0 +1,297 @@
+use chrono::{DateTime, Utc};
+use futures::{Async, Poll, Stream};
+use std::cmp;
+use std::mem;
+use std::time::Duration;
+
+/// Smallest part size accepted by S3, except for the last part
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Largest part size accepted by S3
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Largest object which can be copied with a single `CopyObject` request
+pub const MAX_COPY_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Maximum number of parts in a single multipart upload
+pub const MAX_PARTS: u64 = 10_000;
+
+/// When upload size is unknown - part size is doubled every `PARTS_PER_STEP` parts,
+/// with 5MiB base size this allows uploads close to 5TiB (S3 object size limit)
+pub const PARTS_PER_STEP: i64 = 1_000;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Part size strategy for a single multipart upload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartSize {
+    /// All parts have same size, used when upload size is known
+    Fixed(u64),
+    /// Part size grows progressively starting from given size up to max one, used when upload
+    /// size is unknown (chunked uploads)
+    Growing(u64, u64),
+}
+
+impl PartSize {
+    /// Select part size strategy for configured base part size and optional upload size
+    pub fn new(base: u64, content_length: Option<u64>) -> PartSize {
+        PartSize::with_max(base, MAX_PART_SIZE, content_length)
+    }
+
+    /// Select part size strategy with parts no larger than `max`. Uploads of known size
+    /// larger than `max_upload_size(max)` can't fit into parts of max size.
+    pub fn with_max(base: u64, max: u64, content_length: Option<u64>) -> PartSize {
+        let max = clamp_part_size(max);
+        let base = cmp::min(clamp_part_size(base), max);
+
+        match content_length {
+            Some(length) => {
+                let required = (length + MAX_PARTS - 1) / MAX_PARTS;
+                // round up to whole MiB
+                let required = (required + MIB - 1) / MIB * MIB;
+
+                PartSize::Fixed(cmp::min(cmp::max(base, required), max))
+            }
+            None => PartSize::Growing(base, max),
+        }
+    }
+
+    /// Size for given part, part numbers start from 1
+    pub fn for_part(&self, part_number: i64) -> u64 {
+        match *self {
+            PartSize::Fixed(size) => size,
+            PartSize::Growing(base, max) => {
+                let step = cmp::max(part_number - 1, 0) / PARTS_PER_STEP;
+
+                if step >= 32 {
+                    max
+                } else {
+                    cmp::min(base << step, max)
+                }
+            }
+        }
+    }
+}
+
+fn clamp_part_size(size: u64) -> u64 {
+    cmp::min(cmp::max(size, MIN_PART_SIZE), MAX_PART_SIZE)
+}
+
+/// Largest upload which fits into `MAX_PARTS` parts no larger than `max_part_size`
+pub fn max_upload_size(max_part_size: u64) -> u64 {
+    clamp_part_size(max_part_size) * MAX_PARTS
+}
+
+/// Stream adapter which buffers input chunks into numbered upload parts, sized by `PartSize`
+pub struct PartChunks<S> {
+    inner: S,
+    sizes: PartSize,
+    part_number: i64,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl<S> PartChunks<S> {
+    pub fn new(inner: S, sizes: PartSize) -> PartChunks<S> {
+        PartChunks {
+            inner: inner,
+            sizes: sizes,
+            part_number: 1,
+            buffer: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn next_part(&mut self, size: usize) -> (i64, Vec<u8>) {
+        let rest = self.buffer.split_off(size);
+        let part = mem::replace(&mut self.buffer, rest);
+        let part_number = self.part_number;
+        self.part_number += 1;
+
+        (part_number, part)
+    }
+}
+
+impl<S, B> Stream for PartChunks<S>
+where
+    S: Stream<Item = B>,
+    B: AsRef<[u8]>,
+{
+    type Item = (i64, Vec<u8>);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let size = self.sizes.for_part(self.part_number) as usize;
+
+            if self.buffer.len() >= size {
+                return Ok(Async::Ready(Some(self.next_part(size))));
+            }
+
+            if self.done {
+                if self.buffer.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+
+                let size = self.buffer.len();
+                return Ok(Async::Ready(Some(self.next_part(size))));
+            }
+
+            match self.inner.poll()? {
+                Async::Ready(Some(chunk)) => self.buffer.extend_from_slice(chunk.as_ref()),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Split object of given length into numbered parts, as `(part_number, first_byte, last_byte)`
+/// with inclusive byte positions, as used by `x-amz-copy-source-range`
+pub fn part_ranges(length: u64, sizes: PartSize) -> Vec<(i64, u64, u64)> {
+    let mut ranges = vec![];
+    let mut first = 0;
+
+    while first < length {
+        let part_number = ranges.len() as i64 + 1;
+        let last = cmp::min(first + sizes.for_part(part_number), length) - 1;
+
+        ranges.push((part_number, first, last));
+        first = last + 1;
+    }
+
+    ranges
+}
+
+/// Check if multipart upload initiated at given time (as returned by `ListMultipartUploads`)
+/// is older than `max_age`, uploads with unparseable time are never considered stale
+pub fn is_stale(initiated: &str, now: DateTime<Utc>, max_age: Duration) -> bool {
+    match (
+        DateTime::parse_from_rfc3339(initiated),
+        ::chrono::Duration::from_std(max_age),
+    ) {
+        (Ok(initiated), Ok(max_age)) => initiated.with_timezone(&Utc) + max_age < now,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod part_size {
+        use multipart::*;
+
+        #[test]
+        fn test_small_known_length_uses_base() {
+            assert_eq!(PartSize::new(MIN_PART_SIZE, Some(1024)), PartSize::Fixed(MIN_PART_SIZE));
+        }
+
+        #[test]
+        fn test_base_is_clamped() {
+            assert_eq!(PartSize::new(1, None), PartSize::Growing(MIN_PART_SIZE, MAX_PART_SIZE));
+            assert_eq!(
+                PartSize::new(u64::max_value(), None),
+                PartSize::Growing(MAX_PART_SIZE, MAX_PART_SIZE)
+            );
+        }
+
+        #[test]
+        fn test_large_known_length_fits_max_parts() {
+            let length = 100 * 1024 * 1024 * 1024;
+            let size = PartSize::new(MIN_PART_SIZE, Some(length)).for_part(1);
+
+            assert!(size * MAX_PARTS >= length);
+            assert_eq!(size % (1024 * 1024), 0);
+        }
+
+        #[test]
+        fn test_growing() {
+            let sizes = PartSize::new(MIN_PART_SIZE, None);
+
+            assert_eq!(sizes.for_part(1), MIN_PART_SIZE);
+            assert_eq!(sizes.for_part(PARTS_PER_STEP), MIN_PART_SIZE);
+            assert_eq!(sizes.for_part(PARTS_PER_STEP + 1), MIN_PART_SIZE * 2);
+            assert_eq!(sizes.for_part(MAX_PARTS as i64), MIN_PART_SIZE << 9);
+
+            let total: u64 = (1..(MAX_PARTS as i64 + 1)).map(|n| sizes.for_part(n)).sum();
+            assert!(total > 4 * 1024 * 1024 * 1024 * 1024);
+        }
+
+        #[test]
+        fn test_max() {
+            let max = 64 * 1024 * 1024;
+            let sizes = PartSize::with_max(MIN_PART_SIZE, max, None);
+            assert_eq!(sizes.for_part(1), MIN_PART_SIZE);
+            assert_eq!(sizes.for_part(MAX_PARTS as i64), max);
+
+            let length = 1024 * 1024 * 1024 * 1024;
+            assert_eq!(PartSize::with_max(MIN_PART_SIZE, max, Some(length)), PartSize::Fixed(max));
+            assert_eq!(PartSize::with_max(max * 2, max, None).for_part(1), max);
+            assert_eq!(max_upload_size(max), max * MAX_PARTS);
+            assert_eq!(max_upload_size(0), MIN_PART_SIZE * MAX_PARTS);
+        }
+    }
+
+    mod part_chunks {
+        use futures::{stream, Future, Stream};
+        use multipart::*;
+
+        fn chunks(input: Vec<&'static [u8]>, size: u64) -> Vec<(i64, Vec<u8>)> {
+            PartChunks::new(stream::iter_ok::<_, ()>(input), PartSize::Fixed(size))
+                .collect()
+                .wait()
+                .unwrap()
+        }
+
+        #[test]
+        fn test_empty() {
+            assert_eq!(chunks(vec![], 2), vec![]);
+        }
+
+        #[test]
+        fn test_split_and_merge() {
+            assert_eq!(
+                chunks(vec![b"abc", b"d", b"efghi"], 4),
+                vec![(1, b"abcd".to_vec()), (2, b"efgh".to_vec()), (3, b"i".to_vec())]
+            );
+        }
+    }
+
+    mod part_ranges {
+        use multipart::*;
+
+        #[test]
+        fn test_ranges() {
+            assert_eq!(part_ranges(0, PartSize::Fixed(4)), vec![]);
+            assert_eq!(part_ranges(4, PartSize::Fixed(4)), vec![(1, 0, 3)]);
+            assert_eq!(
+                part_ranges(9, PartSize::Fixed(4)),
+                vec![(1, 0, 3), (2, 4, 7), (3, 8, 8)]
+            );
+        }
+
+        #[test]
+        fn test_large_object_fits_max_parts() {
+            let length = 5 * 1024 * 1024 * 1024 * 1024;
+            let ranges = part_ranges(length, PartSize::new(MIN_PART_SIZE, Some(length)));
+
+            assert!(ranges.len() as u64 <= MAX_PARTS);
+            assert_eq!(ranges.last().map(|r| r.2), Some(length - 1));
+        }
+    }
+
+    mod stale {
+        use chrono::{TimeZone, Utc};
+        use multipart::*;
+        use std::time::Duration;
+
+        #[test]
+        fn test_is_stale() {
+            let now = Utc.ymd(2018, 9, 20).and_hms(12, 0, 0);
+            let day = Duration::from_secs(24 * 3600);
+
+            assert!(is_stale("2018-09-19T11:59:59.000Z", now, day));
+            assert!(!is_stale("2018-09-19T12:00:01.000Z", now, day));
+            assert!(!is_stale("not a date", now, day));
+        }
+    }
+}
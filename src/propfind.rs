@@ -0,0 +1,221 @@
+use chrono::DateTime;
+use listing::escape;
+use storage_class::RestoreStatus;
+
+/// Namespace of properties not defined by WebDAV
+pub const NAMESPACE: &str = "urn:aws-s3-webdav:";
+
+/// Body of `403 Forbidden` response to PROPFIND of collection with infinite depth
+pub const FINITE_DEPTH_ERROR: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                                      <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/>\
+                                      </D:error>\n";
+
+/// Depth of PROPFIND request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Depth {
+    /// Only the resource itself
+    Zero,
+    /// Resource and its immediate members
+    One,
+    Infinity,
+}
+
+impl Depth {
+    /// Parse `Depth` header value, requests without it have infinite depth
+    pub fn parse(value: Option<&str>) -> Result<Depth, String> {
+        match value.map(|v| v.trim()) {
+            Some("0") => Ok(Depth::Zero),
+            Some("1") => Ok(Depth::One),
+            Some(v) if v.eq_ignore_ascii_case("infinity") => Ok(Depth::Infinity),
+            None => Ok(Depth::Infinity),
+            Some(_) => Err("Depth can only be 0, 1 or infinity".to_owned()),
+        }
+    }
+}
+
+/// S3 listing timestamp in HTTP date format of `getlastmodified`, unparseable ones as is
+pub fn http_date(modified: &str) -> String {
+    match DateTime::parse_from_rfc3339(modified) {
+        Ok(modified) => modified.naive_utc().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        Err(_) => modified.to_owned(),
+    }
+}
+
+/// Live properties of an object or collection, reported by PROPFIND
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties {
+    /// Percent-encoded absolute path, ends with `/` for collections
+    pub href: String,
+    /// Collections have no other properties
+    pub collection: bool,
+    pub content_length: Option<i64>,
+    pub content_type: Option<String>,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<String>,
+    pub storage_class: Option<String>,
+    pub restore: Option<String>,
+    /// Hex encoded SHA-256 of the data, if it's stored
+    pub sha256: Option<String>,
+}
+
+/// Restore status as reported in `restore-status` property, with expiry date of restored copy
+fn restore_status(properties: &Properties) -> (&'static str, Option<String>) {
+    let status = RestoreStatus::new(
+        properties.storage_class.as_deref(),
+        properties.restore.as_deref(),
+    );
+
+    match status {
+        RestoreStatus::NotArchived => ("not-archived", None),
+        RestoreStatus::Archived => ("archived", None),
+        RestoreStatus::InProgress => ("in-progress", None),
+        RestoreStatus::Restored { expiry } => ("restored", expiry),
+    }
+}
+
+/// `207 Multi-Status` body with all properties of each object or collection. Objects without
+/// storage class are in `STANDARD` one.
+pub fn multistatus(objects: &[Properties]) -> String {
+    let mut body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\" \
+         xmlns:S=\"{}\">\n",
+        NAMESPACE
+    );
+
+    for object in objects {
+        if object.collection {
+            body.push_str(&format!(
+                "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:resourcetype>\
+                 <D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status>\
+                 </D:propstat></D:response>\n",
+                escape(&object.href)
+            ));
+            continue;
+        }
+
+        let mut props = String::from("<D:resourcetype/>");
+        let mut prop = |name: &str, value: &str| {
+            props.push_str(&format!("<{0}>{1}</{0}>", name, escape(value)));
+        };
+
+        if let Some(length) = object.content_length {
+            prop("D:getcontentlength", &length.to_string());
+        }
+
+        if let Some(ref content_type) = object.content_type {
+            prop("D:getcontenttype", content_type);
+        }
+
+        if let Some(ref e_tag) = object.e_tag {
+            prop("D:getetag", e_tag);
+        }
+
+        if let Some(ref last_modified) = object.last_modified {
+            prop("D:getlastmodified", last_modified);
+        }
+
+        let storage_class = object.storage_class.as_deref();
+        prop("S:storage-class", storage_class.unwrap_or("STANDARD"));
+
+        let (status, expiry) = restore_status(object);
+        prop("S:restore-status", status);
+
+        if let Some(expiry) = expiry {
+            prop("S:restore-expiry-date", &expiry);
+        }
+
+        if let Some(ref sha256) = object.sha256 {
+            prop("S:sha256", sha256);
+        }
+
+        body.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            escape(&object.href),
+            props
+        ));
+    }
+
+    body.push_str("</D:multistatus>\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    mod propfind {
+        use propfind::*;
+
+        #[test]
+        fn test_multistatus() {
+            let restored =
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"";
+            let objects = vec![
+                Properties {
+                    href: "/a%20b.txt".to_owned(),
+                    content_length: Some(5),
+                    content_type: Some("text/plain".to_owned()),
+                    e_tag: Some("\"abc\"".to_owned()),
+                    last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned()),
+                    sha256: Some("d2f2".to_owned()),
+                    ..Properties::default()
+                },
+                Properties {
+                    href: "/backup.tar".to_owned(),
+                    storage_class: Some("GLACIER".to_owned()),
+                    restore: Some(restored.to_owned()),
+                    ..Properties::default()
+                },
+            ];
+            let body = multistatus(&objects);
+
+            assert!(body.contains("xmlns:S=\"urn:aws-s3-webdav:\""), "{}", body);
+            assert!(body.contains(
+                "<D:href>/a%20b.txt</D:href><D:propstat><D:prop><D:resourcetype/>\
+                 <D:getcontentlength>5</D:getcontentlength>\
+                 <D:getcontenttype>text/plain</D:getcontenttype>\
+                 <D:getetag>&quot;abc&quot;</D:getetag>\
+                 <D:getlastmodified>Wed, 21 Oct 2015 07:28:00 GMT</D:getlastmodified>\
+                 <S:storage-class>STANDARD</S:storage-class>\
+                 <S:restore-status>not-archived</S:restore-status>\
+                 <S:sha256>d2f2</S:sha256></D:prop>"
+            ), "{}", body);
+            assert!(body.contains(
+                "<S:storage-class>GLACIER</S:storage-class>\
+                 <S:restore-status>restored</S:restore-status>\
+                 <S:restore-expiry-date>Fri, 21 Dec 2012 00:00:00 GMT</S:restore-expiry-date>"
+            ), "{}", body);
+            assert_eq!(body.matches("<D:response>").count(), 2);
+        }
+
+        #[test]
+        fn test_collection() {
+            let collection = Properties {
+                href: "/docs/".to_owned(),
+                collection: true,
+                ..Properties::default()
+            };
+            let body = multistatus(&[collection]);
+
+            assert!(body.contains(
+                "<D:href>/docs/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/>\
+                 </D:resourcetype></D:prop>"
+            ), "{}", body);
+            assert!(!body.contains("storage-class"), "{}", body);
+        }
+
+        #[test]
+        fn test_depth() {
+            assert_eq!(Depth::parse(Some("0")), Ok(Depth::Zero));
+            assert_eq!(Depth::parse(Some("1")), Ok(Depth::One));
+            assert_eq!(Depth::parse(Some("Infinity")), Ok(Depth::Infinity));
+            assert_eq!(Depth::parse(None), Ok(Depth::Infinity));
+            assert!(Depth::parse(Some("2")).is_err());
+        }
+
+        #[test]
+        fn test_http_date() {
+            assert_eq!(http_date("2015-10-21T07:28:00.000Z"), "Wed, 21 Oct 2015 07:28:00 GMT");
+            assert_eq!(http_date("yesterday"), "yesterday");
+        }
+    }
+}
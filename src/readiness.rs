@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Result of a readiness check and when it finished
+#[derive(Clone, Debug)]
+struct Check {
+    finished: Instant,
+    result: Result<(), String>,
+}
+
+/// Result of last readiness check, failures included, shared by all workers, so probes
+/// don't hit S3 on every request
+#[derive(Clone, Debug, Default)]
+pub struct Readiness(Arc<Mutex<Option<Check>>>);
+
+impl Readiness {
+    /// Result of last check, unless it's older than `max_age` at `now` or there's none yet
+    pub fn cached(&self, max_age: Duration, now: Instant) -> Option<Result<(), String>> {
+        match *self.0.lock().unwrap() {
+            Some(ref check) if now.duration_since(check.finished) <= max_age => {
+                Some(check.result.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Check finished at `now`, replacing older result
+    pub fn record(&self, result: Result<(), String>, now: Instant) {
+        let mut last = self.0.lock().unwrap();
+
+        // checks running concurrently may finish out of order
+        let newer = match *last {
+            Some(ref check) => check.finished <= now,
+            None => true,
+        };
+
+        if newer {
+            *last = Some(Check {
+                finished: now,
+                result: result,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod readiness {
+        use readiness::*;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn test_cached() {
+            let readiness = Readiness::default();
+            let ttl = Duration::from_secs(10);
+            let now = Instant::now();
+            assert_eq!(readiness.cached(ttl, now), None);
+
+            readiness.clone().record(Ok(()), now);
+            assert_eq!(readiness.cached(ttl, now + ttl), Some(Ok(())));
+            assert_eq!(readiness.cached(ttl, now + ttl + Duration::from_millis(1)), None);
+
+            let failed = Err("S3 unreachable".to_owned());
+            readiness.record(failed.clone(), now + ttl);
+            assert_eq!(readiness.cached(ttl, now + ttl), Some(failed.clone()));
+
+            // older result doesn't replace newer one
+            readiness.record(Ok(()), now + Duration::from_secs(5));
+            assert_eq!(readiness.cached(ttl, now + ttl), Some(failed));
+        }
+    }
+}
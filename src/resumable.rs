@@ -0,0 +1,433 @@
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, InternalError,
+};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use aws_s3_webdav::multipart::{PartChunks, PartSize};
+use aws_s3_webdav::tus::{self, LENGTH_METADATA_KEY, PARTS_METADATA_KEY, UPLOAD_METADATA_KEY};
+use bytes::Bytes;
+use env::AppEnv;
+use futures::{future, stream, Async, Future, Poll, Stream};
+use rusoto_s3::*;
+use s3::{
+    abort_upload, complete_upload, complete_upload_error, delete_object, get_object, list_parts,
+    list_parts_error, plaintext_body, with_retry, Sse,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+use upload::{upload_part, BodyStream};
+
+/// Resumable upload in progress, as stored in S3: parts of multipart upload and staging object
+/// with data which doesn't fill a whole part yet
+pub struct Upload {
+    /// Size of the whole upload
+    pub length: u64,
+    /// `Upload-Metadata` given on creation
+    pub metadata: Option<String>,
+    /// Uploaded parts with their sizes, in part number order
+    parts: Vec<(CompletedPart, u64)>,
+    /// Data received after the last part
+    tail: Vec<u8>,
+    /// All data was received and object is stored
+    pub completed: bool,
+}
+
+/// `ListParts` has no error variants, missing uploads are only told by error code
+fn no_such_upload(body: &str) -> bool {
+    body.contains("<Code>NoSuchUpload</Code>")
+}
+
+impl Upload {
+    /// Number of bytes received so far
+    pub fn offset(&self) -> u64 {
+        if self.completed {
+            return self.length;
+        }
+
+        self.parts.iter().map(|&(_, size)| size).sum::<u64>() + self.tail.len() as u64
+    }
+}
+
+/// Multipart upload of object `key`, as needed by S3 operations
+fn multipart_upload(env: &AppEnv, key: &str, upload_id: &str) -> CreateMultipartUploadOutput {
+    CreateMultipartUploadOutput {
+        bucket: Some(env.config.s3.bucket.to_owned()),
+        key: Some(key.to_owned()),
+        upload_id: Some(upload_id.to_owned()),
+        ..CreateMultipartUploadOutput::default()
+    }
+}
+
+/// Key of upload staging object, under staging prefix
+pub fn staging_key(env: &AppEnv, upload_id: &str) -> String {
+    let staging_prefix = env.config.tus.as_ref().map_or("", |t| t.staging_prefix.as_str());
+    let prefix = match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, staging_prefix),
+        None => staging_prefix.to_owned(),
+    };
+
+    tus::staging_key(&prefix, upload_id)
+}
+
+/// Store staging object with data after `parts` parts, replacing previous one
+fn stage(
+    env: &AppEnv,
+    upload_id: &str,
+    length: u64,
+    metadata: &Option<String>,
+    parts: i64,
+    data: Vec<u8>,
+) -> Box<Future<Item=(), Error=Error>> {
+    let state = env.clone();
+    let key = staging_key(env, upload_id);
+    let sse = Sse::for_key(env, &key);
+    let mut staging_metadata = HashMap::new();
+    staging_metadata.insert(LENGTH_METADATA_KEY.to_owned(), length.to_string());
+    staging_metadata.insert(PARTS_METADATA_KEY.to_owned(), parts.to_string());
+    if let Some(ref metadata) = *metadata {
+        staging_metadata.insert(UPLOAD_METADATA_KEY.to_owned(), metadata.to_owned());
+    }
+    let bucket = env.config.s3.bucket.to_owned();
+
+    Box::new(
+        with_retry(env, "put_object", move || {
+            state.s3.put_object(PutObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                body: Some(StreamingBody::from(data.clone())),
+                metadata: Some(staging_metadata.clone()),
+                server_side_encryption: sse.server_side_encryption.to_owned(),
+                ssekms_key_id: sse.ssekms_key_id.to_owned(),
+                sse_customer_algorithm: sse.customer_algorithm.to_owned(),
+                sse_customer_key: sse.customer_key.to_owned(),
+                sse_customer_key_md5: sse.customer_key_md5.to_owned(),
+                ..PutObjectRequest::default()
+            })
+        }).map_err(|e| match e {
+                PutObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
+                PutObjectError::Credentials(e) => ErrorForbidden(e),
+                PutObjectError::Validation(e) => ErrorBadRequest(e),
+                PutObjectError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .map(|_| ()),
+    )
+}
+
+/// Request body which ends instead of failing, so data received before the failure is kept,
+/// the failure is reported once it's stored. Data beyond upload length is refused.
+struct ReceivedBody {
+    inner: BodyStream,
+    remaining: u64,
+    error: Rc<RefCell<Option<Error>>>,
+    done: bool,
+}
+
+impl Stream for ReceivedBody {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(mut chunk))) => {
+                if chunk.len() as u64 > self.remaining {
+                    chunk.truncate(self.remaining as usize);
+                    self.done = true;
+                    *self.error.borrow_mut() = Some(
+                        InternalError::new(
+                            "Request body exceeds Upload-Length",
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                        ).into(),
+                    );
+                }
+
+                self.remaining -= chunk.len() as u64;
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Ok(Async::Ready(None)) => {
+                self.done = true;
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.done = true;
+                *self.error.borrow_mut() = Some(e);
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Complete upload once all parts are uploaded and delete its staging object. Upload with no
+/// parts is empty, it's completed with a single empty part. Completed upload is still
+/// reported by `status` for a while.
+fn complete(
+    env: &AppEnv,
+    key: &str,
+    upload_id: &str,
+    length: u64,
+    metadata: Option<String>,
+    mut parts: Vec<CompletedPart>,
+) -> Box<Future<Item=(), Error=Error>> {
+    let env = env.clone();
+    let upload = multipart_upload(&env, key, upload_id);
+    let staging_key = staging_key(&env, upload_id);
+    let upload_id = upload_id.to_owned();
+    let empty_part: Box<Future<Item=Option<CompletedPart>, Error=Error>> = if parts.is_empty() {
+        Box::new(upload_part(&env, &upload, 1, Vec::new()).map(Some))
+    } else {
+        Box::new(future::ok(None))
+    };
+
+    Box::new(
+        empty_part
+            .and_then(move |part| {
+                parts.extend(part);
+                complete_upload(&env, &upload, parts)
+                    .map_err(complete_upload_error)
+                    .and_then(move |_| {
+                        env.completed_uploads.record(&upload_id, length, metadata, Instant::now());
+                        delete_object(&env, &env.config.s3.bucket, &staging_key)
+                    })
+            }),
+    )
+}
+
+/// Start resumable upload of object at `key`, resolves to upload id. Upload length and
+/// metadata are kept with the staging object until upload completes, empty uploads are
+/// completed right away.
+pub fn create(
+    env: &AppEnv,
+    key: &str,
+    length: u64,
+    metadata: Option<String>,
+    content_type: Option<String>,
+) -> Box<Future<Item=String, Error=Error>> {
+    let state = env.clone();
+    let sse = Sse::for_key(env, key);
+    let request = CreateMultipartUploadRequest {
+        bucket: env.config.s3.bucket.to_owned(),
+        key: key.to_owned(),
+        content_type: content_type,
+        storage_class: env.config.storage_class.for_key(key).map(|c| c.to_owned()),
+        server_side_encryption: sse.server_side_encryption,
+        ssekms_key_id: sse.ssekms_key_id,
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..CreateMultipartUploadRequest::default()
+    };
+    let env = env.clone();
+    let key = key.to_owned();
+
+    Box::new(
+        with_retry(&env, "create_multipart_upload", move || {
+            state.s3.create_multipart_upload(request.clone())
+        }).map_err(|e| match e {
+                CreateMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
+                CreateMultipartUploadError::Credentials(e) => ErrorForbidden(e),
+                CreateMultipartUploadError::Validation(e) => ErrorBadRequest(e),
+                CreateMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .and_then(move |upload| {
+                let upload_id = upload.upload_id.to_owned().unwrap_or_default();
+                let staged = stage(&env, &upload_id, length, &metadata, 0, Vec::new());
+
+                staged
+                    .and_then(move |_| -> Box<Future<Item=(), Error=Error>> {
+                        if length == 0 {
+                            complete(&env, &key, &upload_id, length, metadata, Vec::new())
+                        } else {
+                            Box::new(future::ok(()))
+                        }
+                    })
+                    .map(move |_| upload.upload_id.unwrap_or_default())
+            }),
+    )
+}
+
+/// Upload completed within grace period, as reported to clients
+fn completed(env: &AppEnv, upload_id: &str) -> Option<Upload> {
+    env.completed_uploads.get(upload_id, Instant::now()).map(|c| Upload {
+        length: c.length,
+        metadata: c.metadata,
+        parts: Vec::new(),
+        tail: Vec::new(),
+        completed: true,
+    })
+}
+
+/// Upload in progress, or completed within grace period, `None` if there's no such upload
+pub fn status(
+    env: &AppEnv,
+    key: &str,
+    upload_id: &str,
+) -> Box<Future<Item=Option<Upload>, Error=Error>> {
+    let env = env.clone();
+    let upload = multipart_upload(&env, key, upload_id);
+    let s = env.clone();
+    let id = upload_id.to_owned();
+
+    let in_progress = get_object(&env, &staging_key(&env, upload_id), None).and_then(
+        move |output| -> Box<Future<Item=Option<Upload>, Error=Error>> {
+            let output = match output {
+                Some(output) => output,
+                None => return Box::new(future::ok(None)),
+            };
+            let staging_metadata = output.metadata.unwrap_or_default();
+            let length = staging_metadata.get(LENGTH_METADATA_KEY).and_then(|l| l.parse().ok());
+            let length = match length {
+                Some(length) => length,
+                None => {
+                    return Box::new(future::err(ErrorInternalServerError(
+                        "Upload staging object has no length",
+                    )))
+                }
+            };
+            let staged_after: i64 = staging_metadata
+                .get(PARTS_METADATA_KEY)
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(0);
+            let metadata = staging_metadata.get(UPLOAD_METADATA_KEY).cloned();
+            let tail: Box<Stream<Item=Vec<u8>, Error=Error>> = match output.body {
+                Some(body) => plaintext_body(body, None),
+                None => Box::new(stream::empty()),
+            };
+            let parts = list_parts(&env, &upload).then(|r| match r {
+                Ok(parts) => Ok(Some(parts)),
+                Err(ListPartsError::Unknown(ref body)) if no_such_upload(body) => Ok(None),
+                Err(e) => Err(list_parts_error(e)),
+            });
+
+            Box::new(tail.concat2().join(parts).map(move |(tail, parts)| {
+                parts.map(|parts| {
+                    let last_part = parts.last().and_then(|p| p.part_number).unwrap_or(0);
+
+                    Upload {
+                        length: length,
+                        metadata: metadata,
+                        parts: parts
+                            .into_iter()
+                            .map(|p| {
+                                let size = p.size.unwrap_or(0) as u64;
+                                let part = CompletedPart {
+                                    e_tag: p.e_tag,
+                                    part_number: p.part_number,
+                                };
+
+                                (part, size)
+                            })
+                            .collect(),
+                        // staged data was already uploaded with a part, but staging object
+                        // wasn't replaced yet
+                        tail: if last_part == staged_after { tail } else { Vec::new() },
+                        completed: false,
+                    }
+                })
+            }))
+        },
+    );
+
+    Box::new(in_progress.map(move |upload| upload.or_else(|| completed(&s, &id))))
+}
+
+/// Append request body to upload at its current offset, resolves to the new offset. Parts are
+/// uploaded as they fill up and the rest is staged, so data is kept if request fails midway.
+/// Upload is completed once all data is received.
+pub fn append(
+    env: &AppEnv,
+    key: &str,
+    upload_id: &str,
+    upload: Upload,
+    body: BodyStream,
+) -> Box<Future<Item=u64, Error=Error>> {
+    if upload.completed {
+        return Box::new(future::ok(upload.length));
+    }
+
+    let env = env.clone();
+    let key = key.to_owned();
+    let upload_id = upload_id.to_owned();
+    let offset = upload.offset();
+    let Upload {
+        length,
+        metadata,
+        parts,
+        tail,
+        ..
+    } = upload;
+    let uploaded = offset - tail.len() as u64;
+    let next_part = parts.last().and_then(|p| p.0.part_number).unwrap_or(0) + 1;
+    // upload length is known, so all parts have the same size
+    let part_size = PartSize::new(env.config.upload.part_size, Some(length)).for_part(1);
+    let error = Rc::new(RefCell::new(None));
+    let body = ReceivedBody {
+        inner: body,
+        remaining: length - offset,
+        error: error.clone(),
+        done: false,
+    };
+    let data = stream::once(Ok(Bytes::from(tail))).chain(body);
+    let parts: Vec<CompletedPart> = parts.into_iter().map(|(part, _)| part).collect();
+    let s = env.clone();
+    let multipart = multipart_upload(&env, &key, &upload_id);
+
+    Box::new(
+        PartChunks::new(data, PartSize::Fixed(part_size))
+            .fold(
+                (parts, uploaded, Vec::new()),
+                move |(mut parts, uploaded, _), (n, data)| -> Box<Future<Item=_, Error=Error>> {
+                    let end = uploaded + data.len() as u64;
+
+                    // the last chunk is staged, unless it's the last part of upload
+                    if data.len() as u64 != part_size && end != length {
+                        return Box::new(future::ok((parts, uploaded, data)));
+                    }
+
+                    Box::new(upload_part(&s, &multipart, next_part + n - 1, data).map(
+                        move |part| {
+                            parts.push(part);
+                            (parts, end, Vec::new())
+                        },
+                    ))
+                },
+            )
+            .and_then(move |(parts, uploaded, rest)| {
+                let received = uploaded + rest.len() as u64;
+                let stored = if received == length {
+                    complete(&env, &key, &upload_id, length, metadata, parts)
+                } else {
+                    let last_part = parts.last().and_then(|p| p.part_number).unwrap_or(0);
+                    stage(&env, &upload_id, length, &metadata, last_part, rest)
+                };
+
+                stored.and_then(move |_| match error.borrow_mut().take() {
+                    Some(e) => Err(e),
+                    None => Ok(received),
+                })
+            }),
+    )
+}
+
+/// Abort upload, deleting uploaded parts and staged data
+pub fn terminate(env: &AppEnv, key: &str, upload_id: &str) -> Box<Future<Item=(), Error=Error>> {
+    let env = env.clone();
+    let staging_key = staging_key(&env, upload_id);
+
+    Box::new(
+        abort_upload(&env, &multipart_upload(&env, key, upload_id))
+            .map_err(|e| match e {
+                AbortMultipartUploadError::NoSuchUpload(e) => ErrorNotFound(e),
+                AbortMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
+                AbortMultipartUploadError::Credentials(e) => ErrorForbidden(e),
+                AbortMultipartUploadError::Validation(e) => ErrorBadRequest(e),
+                AbortMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .and_then(move |_| delete_object(&env, &env.config.s3.bucket, &staging_key)),
+    )
+}
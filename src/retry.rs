@@ -0,0 +1,297 @@
+use futures::future::{self, Loop};
+use futures::{Future, IntoFuture};
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// S3 error codes which are safe to retry
+const RETRYABLE_ERROR_CODES: &[&str] = &[
+    "BadGateway",
+    "GatewayTimeout",
+    "InternalError",
+    "RequestTimeout",
+    "ServiceUnavailable",
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+];
+
+/// Retry policy with exponential backoff
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before first retry, doubled for every next retry
+    pub base_delay: Duration,
+    /// Upper bound for delay between attempts
+    pub max_delay: Duration,
+    /// Use random delay between zero and computed backoff ("full jitter")
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    pub fn new(
+        max_attempts: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+        jitter: bool,
+    ) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: cmp::max(max_attempts, 1),
+            base_delay: base_delay,
+            max_delay: max_delay,
+            jitter: jitter,
+        }
+    }
+
+    /// Policy which never retries
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(1, Duration::from_millis(0), Duration::from_millis(0), false)
+    }
+
+    /// Exponential backoff (without jitter) before given retry, retries are numbered from 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::max_value());
+
+        self.base_delay
+            .checked_mul(factor)
+            .map(|d| cmp::min(d, self.max_delay))
+            .unwrap_or(self.max_delay)
+    }
+
+    /// Delay before given retry, with jitter applied if enabled
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+
+        if self.jitter {
+            let millis = duration_millis(backoff);
+
+            Duration::from_millis(thread_rng().gen_range(0, millis + 1))
+        } else {
+            backoff
+        }
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
+type LoopStep<T, S, E> = Box<Future<Item = Loop<T, S>, Error = E>>;
+
+/// Run operation, retrying failures accepted by `should_retry` according to given policy.
+/// `should_retry` receives the error and the number of the retry about to be made, it is
+/// only called if policy allows more attempts.
+pub fn retry<F, R, P>(
+    policy: RetryPolicy,
+    operation: F,
+    should_retry: P,
+) -> Box<Future<Item = R::Item, Error = R::Error>>
+where
+    F: FnMut() -> R + 'static,
+    R: IntoFuture + 'static,
+    R::Future: 'static,
+    R::Item: 'static,
+    R::Error: 'static,
+    P: Fn(&R::Error, u32) -> bool + 'static,
+{
+    // operation is only started when returned future is polled
+    Box::new(future::lazy(move || {
+        future::loop_fn(
+            (operation, should_retry, 1),
+            move |(mut operation, should_retry, attempt)| {
+                operation().into_future().then(
+                    move |result| -> LoopStep<R::Item, (F, P, u32), R::Error> {
+                        match result {
+                            Ok(item) => Box::new(future::ok(Loop::Break(item))),
+                            Err(e) => {
+                                if attempt >= policy.max_attempts || !should_retry(&e, attempt) {
+                                    return Box::new(future::err(e));
+                                }
+
+                                Box::new(
+                                    Delay::new(Instant::now() + policy.delay(attempt)).then(
+                                        move |r| match r {
+                                            Ok(_) => Ok(Loop::Continue((
+                                                operation,
+                                                should_retry,
+                                                attempt + 1,
+                                            ))),
+                                            Err(_) => Err(e),
+                                        },
+                                    ),
+                                )
+                            }
+                        }
+                    },
+                )
+            },
+        )
+    }))
+}
+
+/// Extract `<Code>` value from S3 XML error body
+pub fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = body[start..].find("</Code>")? + start;
+
+    Some(body[start..end].trim())
+}
+
+/// Check if S3 error response body describes a transient failure
+pub fn is_retryable_error_body(body: &str) -> bool {
+    match error_code(body) {
+        Some(code) => RETRYABLE_ERROR_CODES.contains(&code),
+        None => false,
+    }
+}
+
+/// Error code standing for HTTP status of error response without S3 error code
+fn status_error_code(status: u16) -> Option<&'static str> {
+    match status {
+        400 => Some("BadRequest"),
+        403 => Some("AccessDenied"),
+        404 => Some("NotFound"),
+        500 => Some("InternalError"),
+        502 => Some("BadGateway"),
+        503 => Some("ServiceUnavailable"),
+        504 => Some("GatewayTimeout"),
+        _ => None,
+    }
+}
+
+/// S3 error body replacing body of error response without S3 error code (e.g. of HEAD
+/// request, or HTML page of load balancer), so it's classified by its status. `None` if body
+/// has error code, or status has no code standing for it.
+pub fn status_error_body(status: u16, body: &str) -> Option<String> {
+    if error_code(body).is_some() {
+        return None;
+    }
+
+    status_error_code(status).map(|code| {
+        format!(
+            "<Error><Code>{}</Code><Message>HTTP {} response without S3 error</Message></Error>",
+            code, status
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    mod retry_policy {
+        use retry::*;
+        use std::time::Duration;
+
+        fn policy(jitter: bool) -> RetryPolicy {
+            RetryPolicy::new(5, Duration::from_millis(100), Duration::from_secs(1), jitter)
+        }
+
+        #[test]
+        fn test_backoff() {
+            let p = policy(false);
+
+            assert_eq!(p.delay(1), Duration::from_millis(100));
+            assert_eq!(p.delay(2), Duration::from_millis(200));
+            assert_eq!(p.delay(4), Duration::from_millis(800));
+            assert_eq!(p.delay(5), Duration::from_secs(1));
+            assert_eq!(p.delay(100), Duration::from_secs(1));
+        }
+
+        #[test]
+        fn test_jitter_within_backoff() {
+            let p = policy(true);
+
+            for retry in 1..10 {
+                assert!(p.delay(retry) <= p.backoff(retry));
+            }
+        }
+
+        #[test]
+        fn test_none() {
+            assert_eq!(RetryPolicy::none().max_attempts, 1);
+        }
+    }
+
+    mod retry_fn {
+        use retry::*;
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::time::Duration;
+        use tokio::runtime::current_thread::Runtime;
+
+        fn run(policy: RetryPolicy, fail_times: u32) -> (Result<u32, u32>, u32) {
+            let calls = Rc::new(Cell::new(0));
+            let counter = calls.clone();
+
+            let f = retry(
+                policy,
+                move || {
+                    counter.set(counter.get() + 1);
+
+                    if counter.get() > fail_times {
+                        Ok(counter.get())
+                    } else {
+                        Err(counter.get())
+                    }
+                },
+                |_, _| true,
+            );
+
+            let result = Runtime::new().unwrap().block_on(f);
+            (result, calls.get())
+        }
+
+        #[test]
+        fn test_succeeds_after_retries() {
+            let p = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1), true);
+
+            assert_eq!(run(p, 2), (Ok(3), 3));
+        }
+
+        #[test]
+        fn test_gives_up() {
+            let p = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(1), false);
+
+            assert_eq!(run(p, 5), (Err(3), 3));
+        }
+    }
+
+    mod error_body {
+        use retry::*;
+
+        #[test]
+        fn test_error_code() {
+            let body = "<?xml version=\"1.0\"?><Error><Code>SlowDown</Code><Message>Reduce your \
+                        request rate.</Message></Error>";
+
+            assert_eq!(error_code(body), Some("SlowDown"));
+            assert!(is_retryable_error_body(body));
+        }
+
+        #[test]
+        fn test_not_retryable() {
+            assert!(!is_retryable_error_body("<Error><Code>AccessDenied</Code></Error>"));
+            assert!(!is_retryable_error_body(""));
+        }
+
+        #[test]
+        fn test_status_error_body() {
+            for status in &[500, 502, 503, 504] {
+                let body = status_error_body(*status, "").unwrap();
+                assert!(is_retryable_error_body(&body), "{} isn't retryable", status);
+            }
+
+            let html = status_error_body(502, "<html><body>Bad Gateway</body></html>").unwrap();
+            assert_eq!(error_code(&html), Some("BadGateway"));
+
+            let not_found = status_error_body(404, "").unwrap();
+            assert_eq!(error_code(&not_found), Some("NotFound"));
+            assert!(!is_retryable_error_body(&not_found));
+
+            let slow_down = "<Error><Code>SlowDown</Code></Error>";
+            assert_eq!(status_error_body(503, slow_down), None);
+            assert_eq!(status_error_body(501, ""), None);
+        }
+    }
+}
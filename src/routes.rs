@@ -1,115 +1,528 @@
 use actix_web::{AsyncResponder, Error, HttpRequest, HttpResponse, HttpMessage, error::ErrorBadRequest,
-                error::ErrorForbidden, error::ErrorInternalServerError, error::ErrorNotFound,
-                http::header, Responder};
+                error::ErrorConflict, error::ErrorForbidden, error::ErrorInternalServerError,
+                error::ErrorNotFound, error::ErrorRequestTimeout, error::InternalError,
+                http::header, http::Method, http::StatusCode, Responder};
+use actix_web::dev::HttpResponseBuilder;
 use rusoto_s3::*;
-use futures::{future, stream, Future, Stream};
+use futures::{future, Future, Stream};
 use bytes::Bytes;
+use copy;
+use download;
+use form;
+use resumable;
+use unpack;
+use upload::{upload_object, BodyStream, ObjectHeaders};
+use soft_delete;
+use versions;
 use env::*;
-use aws_s3_webdav::stream_utils;
-use std::sync::Arc;
-
-/// Alias for application environment, shared between handlers
-type AppEnv = Arc<AppState>;
+use aws_s3_webdav::archive::{self, Format};
+use aws_s3_webdav::integrity::{self, Digests, ExpectedDigests, SHA256_METADATA_KEY};
+use aws_s3_webdav::listing::{self, Entry, Listing, Sort};
+use aws_s3_webdav::metadata;
+use aws_s3_webdav::propfind;
+use aws_s3_webdav::storage_class::{self, RestoreStatus};
+use aws_s3_webdav::timeout::IdleTimeout;
+use aws_s3_webdav::trash;
+use aws_s3_webdav::tus;
+use s3::{
+    self, data_key, head_object_error, object_exists, plaintext_body, plaintext_length,
+    restore_status, with_retry, Sse, RESTORE_RETRY_AFTER,
+};
+use serde_json;
+use std::collections::HashMap;
+use url::form_urlencoded;
 
 fn extract_bucket(req: &HttpRequest<AppEnv>) -> String {
     req.state().config.s3.bucket.as_str().to_owned()
 }
 
+/// Path of request relative to key prefix
+fn extract_path(req: &HttpRequest<AppEnv>) -> String {
+    req.path().trim_left_matches("/").to_owned()
+}
+
 fn extract_object_key(req: &HttpRequest<AppEnv>) -> String {
-    match req.state().config.s3.prefix {
-        Some(ref prefix) => format!("{}{}", prefix, req.path().trim_left_matches("/")),
-        None => req.path().trim_left_matches("/").to_owned(),
+    object_key(req.state(), &extract_path(req))
+}
+
+/// Object key of path relative to key prefix
+fn object_key(env: &AppEnv, path: &str) -> String {
+    match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, path),
+        None => path.to_owned(),
     }
 }
 
+const REPR_DIGEST: &str = "Repr-Digest";
+const TAGGING: &str = "x-amz-tagging";
+const WEBSITE_REDIRECT_LOCATION: &str = "x-amz-website-redirect-location";
+const STORAGE_CLASS: &str = "x-amz-storage-class";
+const RESTORE: &str = "x-amz-restore";
+const VERSION_ID: &str = "x-amz-version-id";
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+
+/// Days restored copy of archived object is kept for, unless requested otherwise
+const DEFAULT_RESTORE_DAYS: i64 = 1;
+
+/// Max entries per collection listing page, as allowed by `ListObjectsV2`
+const LISTING_PAGE_SIZE: usize = 1000;
+
 fn header_string(h: &header::HeaderValue) -> Option<String> {
     h.to_str().map(|h| h.to_string()).ok()
 }
 
-pub fn index(_req: &HttpRequest<AppEnv>) -> impl Responder {
-    HttpResponse::NotImplemented()
+/// SHA-256 digest of the object, stored in metadata on upload
+fn stored_sha256(metadata: &Option<HashMap<String, String>>) -> Option<Vec<u8>> {
+    metadata
+        .as_ref()
+        .and_then(|m| m.get(SHA256_METADATA_KEY))
+        .and_then(|v| integrity::sha256_from_hex(v))
 }
 
-/// Get object from bucket
-pub fn get_object(req: &HttpRequest<AppEnv>) -> impl Responder {
-    req.state()
-        .s3
-        .get_object(GetObjectRequest {
-            bucket: extract_bucket(&req),
-            key: extract_object_key(&req),
-            ..GetObjectRequest::default()
-        })
-        .map_err(|e| match e {
-            // http://rusoto.github.io/rusoto/rusoto_s3/enum.GetObjectError.html
-            GetObjectError::NoSuchKey(e) => ErrorNotFound(e),
-            GetObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
-            GetObjectError::Credentials(e) => ErrorForbidden(e),
-            GetObjectError::Validation(e) => ErrorBadRequest(e),
-            GetObjectError::Unknown(e) => ErrorInternalServerError(e),
-        })
-        .map(|r| match r.body {
-            Some(body) => {
-                let mut response = HttpResponse::Ok();
+/// Add stored user metadata and website redirect to GET and HEAD responses
+fn metadata_headers(
+    response: &mut HttpResponseBuilder,
+    config: &MetadataConfig,
+    metadata: &Option<HashMap<String, String>>,
+    website_redirect_location: &Option<String>,
+) {
+    if let (false, &Some(ref metadata)) = (config.header_prefix.is_empty(), metadata) {
+        for (name, value) in metadata::to_headers(metadata, &config.header_prefix) {
+            response.header(name.as_str(), value.as_str());
+        }
+    }
 
-                if let Some(cache_control) = r.cache_control {
-                    response.header(header::CACHE_CONTROL, cache_control.as_str());
-                }
+    if let Some(ref location) = *website_redirect_location {
+        response.header(WEBSITE_REDIRECT_LOCATION, location.as_str());
+    }
+}
 
-                if let Some(content_disposition) = r.content_disposition {
-                    response.header(header::CONTENT_DISPOSITION, content_disposition.as_str());
-                }
+/// Add storage class and restore status of archived objects to GET and HEAD responses
+fn storage_class_headers(
+    response: &mut HttpResponseBuilder,
+    storage_class: &Option<String>,
+    restore: &Option<String>,
+) {
+    if let Some(ref storage_class) = *storage_class {
+        response.header(STORAGE_CLASS, storage_class.as_str());
+    }
 
-                if let Some(content_encoding) = r.content_encoding {
-                    response.header(header::CONTENT_ENCODING, content_encoding.as_str());
-                }
+    if let Some(ref restore) = *restore {
+        response.header(RESTORE, restore.as_str());
+    }
+}
 
-                if let Some(content_language) = r.content_language {
-                    response.header(header::CONTENT_LANGUAGE, content_language.as_str());
-                }
+/// Bucket root: GET lists root collection, `?trash` lists all trashed objects, PUT or POST
+/// with `?extract=` extracts archive into it, tus POST creates resumable upload in it, form
+/// POST uploads files into it
+pub fn index(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    if req.method() == Method::GET {
+        return get_object(req);
+    }
 
-                if let Some(content_type) = r.content_type {
-                    response.header(header::CONTENT_TYPE, content_type.as_str());
-                }
+    if req.method() == Method::OPTIONS {
+        return options(req);
+    }
 
-                if let Some(e_tag) = r.e_tag {
-                    response.header(header::ETAG, e_tag.as_str());
-                }
+    if req.method() == Method::PUT || req.method() == Method::POST {
+        if let Some(format) = req.query().get("extract") {
+            return extract_archive(req, format);
+        }
+    }
+
+    if req.method() == Method::POST && req.headers().contains_key(TUS_RESUMABLE) {
+        return create_upload(req);
+    }
+
+    if req.method() == Method::POST && is_form(req) {
+        return upload_form(req);
+    }
 
-                if let Some(expires) = r.expires {
-                    response.header(header::EXPIRES, expires.as_str());
+    if req.method().as_str() == "PROPFIND" {
+        return propfind(req);
+    }
+
+    Box::new(future::ok(HttpResponse::NotImplemented().finish()))
+}
+
+/// Client asked for JSON instead of HTML
+fn accepts_json(req: &HttpRequest<AppEnv>) -> bool {
+    let accept = req.headers().get(header::ACCEPT).and_then(|h| h.to_str().ok());
+
+    accept.map(|a| a.contains("application/json")) == Some(true)
+}
+
+/// Listing page size requested with `limit` query parameter
+fn listing_limit(req: &HttpRequest<AppEnv>) -> Result<Option<usize>, Error> {
+    match req.query().get("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .ok()
+            .filter(|l| *l > 0 && *l <= LISTING_PAGE_SIZE)
+            .map(Some)
+            .ok_or_else(|| {
+                ErrorBadRequest(format!("limit must be between 1 and {}", LISTING_PAGE_SIZE))
+            }),
+        None => Ok(None),
+    }
+}
+
+/// List collection at request path, which is root or ends with `/`, as HTML page or JSON if
+/// requested with `Accept: application/json`. Listing is paginated with `limit` and `page`
+/// query parameters, `sort` and `order` sort entries of the page.
+fn list_collection(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let query = req.query();
+    let sort = query.get("sort").map(|s| s.as_str());
+    let sort = match Sort::parse(sort, query.get("order").map(|o| o.as_str())) {
+        Ok(sort) => sort,
+        Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+    };
+    let limit = match listing_limit(req) {
+        Ok(limit) => limit,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let page = query.get("page").cloned();
+    let first = page.is_none();
+    let json = accepts_json(req);
+    let path = format!("/{}", extract_path(req));
+    let prefix = extract_object_key(req);
+    let max_keys = limit.unwrap_or(LISTING_PAGE_SIZE) as i64;
+
+    ::s3::list_objects_page(req.state(), &prefix, Some("/"), page, Some(max_keys))
+        .and_then(move |output| {
+            let name = |key: &str| key[prefix.len()..].trim_right_matches('/').to_owned();
+            let truncated = output.is_truncated == Some(true);
+            // empty collections exist only as "folder" objects with the prefix itself as key
+            let marker = output.contents.iter().flatten().any(|o| o.key.as_ref() == Some(&prefix));
+            let prefixes = output.common_prefixes.unwrap_or_default();
+            let collections = prefixes.into_iter().filter_map(|p| {
+                let name = name(&p.prefix?);
+
+                Some(Entry {
+                    path: format!("{}{}/", path, name),
+                    name: name,
+                    collection: true,
+                    size: None,
+                    last_modified: None,
+                })
+            });
+            let objects = output.contents.unwrap_or_default().into_iter().filter_map(|o| {
+                let name = name(o.key.as_ref()?);
+                if name.is_empty() {
+                    return None;
                 }
 
-                if let Some(last_modified) = r.last_modified {
-                    response.header(header::LAST_MODIFIED, last_modified.as_str());
+                Some(Entry {
+                    path: format!("{}{}", path, name),
+                    name: name,
+                    collection: false,
+                    size: o.size,
+                    last_modified: o.last_modified,
+                })
+            });
+            let mut entries: Vec<Entry> = collections.chain(objects).collect();
+
+            if entries.is_empty() && first && !marker && path != "/" {
+                return Err(ErrorNotFound("Collection not found"));
+            }
+
+            sort.apply(&mut entries);
+            let listing = Listing {
+                path: path,
+                entries: entries,
+                next_page: output.next_continuation_token.filter(|_| truncated),
+            };
+
+            if json {
+                serde_json::to_string(&listing)
+                    .map(|body| HttpResponse::Ok().content_type("application/json").body(body))
+                    .map_err(ErrorInternalServerError)
+            } else {
+                Ok(HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .body(listing::render_html(&listing, &sort, limit, first)))
+            }
+        })
+        .responder()
+}
+
+/// Redirect to collection path, for GETs of collections without trailing `/`
+fn collection_redirect(env: &AppEnv, key: &str, path: &str) -> Box<Future<Item=(), Error=Error>> {
+    let location = format!("/{}/", path);
+
+    Box::new(
+        ::s3::list_objects_page(env, &format!("{}/", key), None, None, Some(1)).and_then(
+            move |output| {
+                if output.contents.unwrap_or_default().is_empty() {
+                    return Ok(());
                 }
 
-                response.streaming(Box::new(body.map_err(|_e| {
-                    ErrorInternalServerError("Something went wrong with body stream")
-                }).map(Bytes::from)))
+                let response = HttpResponse::MovedPermanently()
+                    .header(header::LOCATION, location.as_str())
+                    .finish();
+
+                Err(InternalError::from_response("Collection moved", response).into())
+            },
+        ),
+    )
+}
+
+/// List objects in trash, deleted from request path or under it, as JSON
+fn list_trash(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    soft_delete::list(req.state(), &extract_path(req))
+        .and_then(|entries| {
+            serde_json::to_string(&entries)
+                .map(|body| HttpResponse::Ok().content_type("application/json").body(body))
+                .map_err(ErrorInternalServerError)
+        })
+        .responder()
+}
+
+/// Object version requested with `versionId` query parameter
+fn version_id(req: &HttpRequest<AppEnv>) -> Option<String> {
+    req.query().get("versionId").cloned()
+}
+
+/// List versions of object as JSON, newest first
+fn list_versions(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    versions::list_versions(req.state(), &extract_object_key(&req))
+        .and_then(|versions| {
+            if versions.is_empty() {
+                return Err(ErrorNotFound("Object not found"));
             }
-            None => HttpResponse::from_error(ErrorNotFound("Object Not Found")),
+
+            serde_json::to_string(&versions)
+                .map(|body| HttpResponse::Ok().content_type("application/json").body(body))
+                .map_err(ErrorInternalServerError)
         })
         .responder()
 }
 
-/// HEAD object from bucket
-pub fn head_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
-    req.state()
-        .s3
-        .head_object(HeadObjectRequest {
-            bucket: extract_bucket(&req),
-            key: extract_object_key(&req),
-            ..HeadObjectRequest::default()
+/// Response with headers and (decrypted) body of fetched object
+fn object_output_response(env: &AppEnv, r: GetObjectOutput) -> HttpResponse {
+    // like S3 website endpoints, serve redirect instead of the object itself
+    if let (Some(_), Some(location)) = (&env.config.website, &r.website_redirect_location) {
+        return HttpResponse::MovedPermanently()
+            .header(header::LOCATION, location.as_str())
+            .finish();
+    }
+
+    match r.body {
+        Some(body) => {
+            let key = match data_key(env, &r.metadata) {
+                Ok(key) => key,
+                Err(e) => return HttpResponse::from_error(e),
+            };
+            let mut response = HttpResponse::Ok();
+
+            if let Some(cache_control) = r.cache_control {
+                response.header(header::CACHE_CONTROL, cache_control.as_str());
+            }
+
+            if let Some(content_disposition) = r.content_disposition {
+                response.header(header::CONTENT_DISPOSITION, content_disposition.as_str());
+            }
+
+            if let Some(content_encoding) = r.content_encoding {
+                response.header(header::CONTENT_ENCODING, content_encoding.as_str());
+            }
+
+            if let Some(content_language) = r.content_language {
+                response.header(header::CONTENT_LANGUAGE, content_language.as_str());
+            }
+
+            if let Some(content_type) = r.content_type {
+                response.header(header::CONTENT_TYPE, content_type.as_str());
+            }
+
+            if let Some(e_tag) = r.e_tag {
+                response.header(header::ETAG, e_tag.as_str());
+            }
+
+            if let Some(expires) = r.expires {
+                response.header(header::EXPIRES, expires.as_str());
+            }
+
+            if let Some(last_modified) = r.last_modified {
+                response.header(header::LAST_MODIFIED, last_modified.as_str());
+            }
+
+            if let Some(sha256) = stored_sha256(&r.metadata) {
+                response.header(REPR_DIGEST, integrity::repr_digest(&sha256).as_str());
+            }
+
+            metadata_headers(
+                &mut response,
+                &env.config.metadata,
+                &r.metadata,
+                &r.website_redirect_location,
+            );
+            storage_class_headers(&mut response, &r.storage_class, &r.restore);
+
+            if let Some(version_id) = r.version_id {
+                response.header(VERSION_ID, version_id.as_str());
+            }
+
+            response.streaming(plaintext_body(body, key).map(Bytes::from))
+        }
+        None => HttpResponse::from_error(ErrorNotFound("Object Not Found")),
+    }
+}
+
+/// GET object and build response with its headers and body, `None` if there's no such object.
+/// In website mode objects with `x-amz-website-redirect-location` are served as redirects.
+fn object_response(
+    env: &AppEnv,
+    key: &str,
+    version: Option<String>,
+) -> Box<Future<Item=Option<HttpResponse>, Error=Error>> {
+    let env = env.clone();
+
+    Box::new(
+        ::s3::get_object(&env, key, version)
+            .map(move |r| r.map(|r| object_output_response(&env, r))),
+    )
+}
+
+/// Response for missing object: redirect to collection if there are objects under the path,
+/// otherwise SPA entry document or error document in website mode, or 404
+fn missing_object(
+    env: &AppEnv,
+    path: &str,
+    versioned: bool,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let env = env.clone();
+    let redirect: Box<Future<Item=(), Error=Error>> = if versioned || path.ends_with('/') {
+        Box::new(future::ok(()))
+    } else {
+        collection_redirect(&env, &object_key(&env, path), path)
+    };
+
+    Box::new(redirect.and_then(move |_| -> Box<Future<Item=HttpResponse, Error=Error>> {
+        let not_found = || ErrorNotFound("Object not found");
+        let website = match env.config.website {
+            Some(ref website) => website,
+            None => return Box::new(future::err(not_found())),
+        };
+
+        if let Some(ref document) = website.spa_fallback {
+            return Box::new(
+                object_response(&env, &object_key(&env, document), None)
+                    .and_then(move |r| r.ok_or_else(not_found)),
+            );
+        }
+
+        match website.error_document {
+            Some(ref document) => Box::new(
+                object_response(&env, &object_key(&env, document), None).and_then(move |r| {
+                    let mut response = r.ok_or_else(not_found)?;
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+
+                    Ok(response)
+                }),
+            ),
+            None => Box::new(future::err(not_found())),
+        }
+    }))
+}
+
+/// Download collection at request path as archive, streamed while objects are fetched
+fn download_archive(
+    req: &HttpRequest<AppEnv>,
+    format: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let format = match Format::parse(format) {
+        Ok(format) => format,
+        Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+    };
+    let path = extract_path(req);
+
+    if !path.is_empty() && !path.ends_with('/') {
+        return Box::new(future::err(ErrorBadRequest("Only collections can be archived")));
+    }
+
+    let name = match path.trim_right_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => extract_bucket(req),
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", name, format.extension());
+
+    download::archive(req.state(), &path, format)
+        .map(move |body| {
+            HttpResponse::Ok()
+                .content_type(format.content_type())
+                .header(header::CONTENT_DISPOSITION, disposition.as_str())
+                .streaming(body)
         })
-        .map_err(|e| match e {
-            // http://rusoto.github.io/rusoto/rusoto_s3/enum.HeadObjectError.html
-            HeadObjectError::NoSuchKey(e) => ErrorNotFound(e),
-            HeadObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
-            HeadObjectError::Credentials(e) => ErrorForbidden(e),
-            HeadObjectError::Validation(e) => ErrorBadRequest(e),
-            HeadObjectError::Unknown(e) => ErrorInternalServerError(e),
+        .responder()
+}
+
+/// Get object from bucket, or list collection if path ends with `/` (serve its index document
+/// in website mode). `?versions` lists versions of the object, `?trash` lists trashed objects
+/// deleted from the path, `?archive=zip|tar|tar.gz` downloads collection as archive.
+pub fn get_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    if let Some(format) = req.query().get("archive") {
+        return download_archive(req, format);
+    }
+
+    if req.query().contains_key("versions") {
+        return list_versions(req);
+    }
+
+    if req.query().contains_key("trash") {
+        return list_trash(req);
+    }
+
+    let env = req.state().clone();
+    let path = extract_path(req);
+    let collection = path.is_empty() || path.ends_with('/');
+    let key = match env.config.website {
+        Some(ref website) if collection => {
+            object_key(&env, &format!("{}{}", path, website.index_document))
+        }
+        _ if collection => return list_collection(req),
+        _ => extract_object_key(req),
+    };
+    let version = version_id(&req);
+    let versioned = version.is_some();
+
+    object_response(&env, &key, version)
+        .and_then(move |response| -> Box<Future<Item=HttpResponse, Error=Error>> {
+            match response {
+                Some(response) => Box::new(future::ok(response)),
+                None => missing_object(&env, &path, versioned),
+            }
         })
-        .map(|r| {
+        .responder()
+}
+
+/// HEAD object from bucket, or offset of resumable upload with `?tus=`
+pub fn head_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    if let Some(upload_id) = req.query().get("tus") {
+        return upload_status(req, upload_id);
+    }
+
+    let state = req.state().clone();
+    let key = extract_object_key(&req);
+    let sse = Sse::for_key(&state, &key);
+    let request = HeadObjectRequest {
+        bucket: extract_bucket(&req),
+        key: key,
+        version_id: version_id(&req),
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..HeadObjectRequest::default()
+    };
+
+    let env = req.state().clone();
+
+    with_retry(req.state(), "head_object", move || state.s3.head_object(request.clone()))
+        .map_err(|e| head_object_error(e).unwrap_or_else(|| ErrorNotFound("Object not found")))
+        .map(move |r| {
             let mut response = HttpResponse::Ok();
 
             // TODO add Accept-Ranges support
@@ -134,6 +547,7 @@ pub fn head_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, E
             }
 
             if let Some(content_length) = r.content_length {
+                let content_length = plaintext_length(content_length, &r.metadata);
                 response.header(header::CONTENT_LENGTH, content_length.to_string().as_str());
             }
 
@@ -153,98 +567,290 @@ pub fn head_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, E
                 response.header(header::LAST_MODIFIED, last_modified.as_str());
             }
 
+            if let Some(sha256) = stored_sha256(&r.metadata) {
+                response.header(REPR_DIGEST, integrity::repr_digest(&sha256).as_str());
+            }
+
+            metadata_headers(
+                &mut response,
+                &env.config.metadata,
+                &r.metadata,
+                &r.website_redirect_location,
+            );
+            storage_class_headers(&mut response, &r.storage_class, &r.restore);
+
+            if let Some(version_id) = r.version_id {
+                response.header(VERSION_ID, version_id.as_str());
+            }
+
             response.finish()
         })
         .responder()
 }
 
-fn upload_parts(
-    body_stream: Box<Stream<Item=Bytes, Error=Error>>,
-    state: AppEnv,
-    upload: &CreateMultipartUploadOutput,
-) -> Box<Future<Item=Vec<CompletedPart>, Error=UploadPartError>> {
-    let bucket: String = upload.bucket.to_owned().unwrap();
-    let key: String = upload.key.to_owned().unwrap();
-    let upload_id: String = upload.upload_id.to_owned().unwrap();
+/// PROPFIND of object or collection: WebDAV live properties, with storage class, restore
+/// status and SHA-256 in `urn:aws-s3-webdav:` namespace. All properties are returned whatever
+/// the request body asks for. Paths without trailing `/` are collections if there's no such
+/// object, collections can't be listed with infinite depth.
+pub fn propfind(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let depth = req.headers().get("depth").map(|d| d.to_str().unwrap_or(""));
+    let depth = match propfind::Depth::parse(depth) {
+        Ok(depth) => depth,
+        Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+    };
+    let env = req.state().clone();
+    // request path as sent, as keys in `req.path()` are already decoded
+    let href = req.uri().path().to_owned();
+    let key = extract_object_key(req);
 
-    Box::new(
-        stream_utils::numbers(1)
-            .map_err(|e| ErrorInternalServerError(e))
-            .zip(
-                // Buffer into 5Mb chunks, AWS doesn't allow parts smaller than 5Mb
-                body_stream
-                    // TODO optimize this
-                    .map(|b| stream::iter_ok(b.to_vec()))
-                    .flatten()
-                    .chunks(5 * 1024 * 1024),
-            )
-            .map_err(|_| {
-                UploadPartError::Unknown("Something went wrong with HttpRequest stream".to_owned())
-            })
-            .fold(
-                vec![],
-                move |mut parts,
-                      (part_number, data)|
-                      -> Box<
-                          future::Future<Item=Vec<CompletedPart>, Error=UploadPartError>,
-                      > {
-                    Box::new(
-                        state
-                            .s3
-                            .upload_part(UploadPartRequest {
-                                bucket: bucket.to_owned(),
-                                key: key.to_owned(),
-                                upload_id: upload_id.to_owned(),
-                                part_number: part_number.to_owned(),
-                                body: Some(StreamingBody::from(data)),
-                                ..UploadPartRequest::default()
-                            })
-                            .map(move |output| {
-                                parts.push(CompletedPart {
-                                    e_tag: output.e_tag,
-                                    part_number: Some(part_number),
-                                });
-
-                                parts
-                            }),
-                    )
-                },
-            ),
-    )
+    if href.ends_with('/') {
+        return propfind_collection(&env, href, key, depth);
+    }
+
+    s3::head_object(&env, &extract_bucket(req), &key)
+        .then(move |r| -> Box<Future<Item=HttpResponse, Error=Error>> {
+            let r = match r {
+                Ok(r) => r,
+                Err(ref e) if e.as_response_error().error_response().status() == 404 => {
+                    let href = format!("{}/", href);
+                    return propfind_collection(&env, href, format!("{}/", key), depth);
+                }
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let properties = propfind::Properties {
+                href: href,
+                content_length: r.content_length.map(|l| plaintext_length(l, &r.metadata)),
+                content_type: r.content_type,
+                e_tag: r.e_tag,
+                last_modified: r.last_modified,
+                storage_class: r.storage_class,
+                restore: r.restore,
+                sha256: stored_sha256(&r.metadata).map(|s| integrity::sha256_hex(&s)),
+                ..propfind::Properties::default()
+            };
+
+            Box::new(future::ok(multistatus(&[properties])))
+        })
+        .responder()
 }
 
-fn complete_upload(
-    env: &AppEnv,
-    upload: &CreateMultipartUploadOutput,
-    parts: Vec<CompletedPart>,
-) -> Box<Future<Item=CompleteMultipartUploadOutput, Error=CompleteMultipartUploadError>> {
-    Box::new(
-        env.s3
-            .complete_multipart_upload(CompleteMultipartUploadRequest {
-                bucket: upload.bucket.to_owned().unwrap(),
-                key: upload.key.to_owned().unwrap(),
-                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
-                request_payer: None,
-                upload_id: upload.upload_id.to_owned().unwrap(),
-            }),
-    )
+/// `207 Multi-Status` response with properties of objects and collections
+fn multistatus(properties: &[propfind::Properties]) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(propfind::multistatus(properties))
 }
 
-fn abort_upload(
+/// PROPFIND of collection with key `prefix`, at `href` ending with `/`. Its members are listed
+/// for depth 1, without properties known only to HEAD (content type, SHA-256, restore status).
+fn propfind_collection(
     env: &AppEnv,
-    upload: &CreateMultipartUploadOutput,
-) -> Box<Future<Item=AbortMultipartUploadOutput, Error=AbortMultipartUploadError>> {
-    Box::new(env.s3.abort_multipart_upload(AbortMultipartUploadRequest {
-        bucket: upload.bucket.to_owned().unwrap(),
-        key: upload.key.to_owned().unwrap(),
-        request_payer: None,
-        upload_id: upload.upload_id.to_owned().unwrap(),
-    }))
+    href: String,
+    prefix: String,
+    depth: propfind::Depth,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let members = match depth {
+        propfind::Depth::One => ::s3::list_collection(env, &prefix),
+        // any object with the prefix tells collection exists
+        _ => Box::new(
+            ::s3::list_objects_page(env, &prefix, None, None, Some(1))
+                .map(|output| (vec![], output.contents.unwrap_or_default())),
+        ),
+    };
+    let root = prefix == object_key(env, "");
+
+    members
+        .and_then(move |(prefixes, objects)| {
+            if prefixes.is_empty() && objects.is_empty() && !root {
+                return Err(ErrorNotFound("Collection not found"));
+            }
+
+            if depth == propfind::Depth::Infinity {
+                return Ok(HttpResponse::Forbidden()
+                    .content_type("application/xml; charset=utf-8")
+                    .body(propfind::FINITE_DEPTH_ERROR));
+            }
+
+            let mut properties = vec![propfind::Properties {
+                href: href.to_owned(),
+                collection: true,
+                ..propfind::Properties::default()
+            }];
+
+            if depth == propfind::Depth::One {
+                let name = |key: &str| listing::encode_path(&key[prefix.len()..]);
+
+                for collection in prefixes.into_iter().filter_map(|p| p.prefix) {
+                    properties.push(propfind::Properties {
+                        href: format!("{}{}", href, name(&collection)),
+                        collection: true,
+                        ..propfind::Properties::default()
+                    });
+                }
+
+                for object in objects {
+                    let key = object.key.unwrap_or_default();
+                    // empty collections exist only as "folder" objects with the prefix as key
+                    if key == prefix {
+                        continue;
+                    }
+
+                    properties.push(propfind::Properties {
+                        href: format!("{}{}", href, name(&key)),
+                        content_length: object.size,
+                        e_tag: object.e_tag,
+                        last_modified: object.last_modified.map(|m| propfind::http_date(&m)),
+                        storage_class: object.storage_class,
+                        ..propfind::Properties::default()
+                    });
+                }
+            }
+
+            Ok(multistatus(&properties))
+        })
+        .responder()
+}
+
+/// Request body, failing if client stops sending it. Actix doesn't fail request payload on
+/// client disconnect while handler is running, so stalled body is the only way to detect it.
+fn request_body(req: &HttpRequest<AppEnv>) -> BodyStream {
+    let metrics = req.state().metrics.clone();
+
+    Box::new(IdleTimeout::new(
+        req.payload()
+            .inspect(move |chunk| metrics.received(chunk.len() as u64))
+            .map_err(|_e| ErrorInternalServerError("Something went wrong while reading request stream")),
+        req.state().config.upload.idle_timeout,
+        || ErrorRequestTimeout("Timed out waiting for request body"),
+    ))
+}
+
+/// Build PUT response: `201 Created` for new resources and `204 No Content` for overwrites
+fn put_response(
+    existed: bool,
+    path: &str,
+    e_tag: Option<String>,
+    digests: &Digests,
+) -> HttpResponse {
+    let mut response = if existed {
+        HttpResponse::NoContent()
+    } else {
+        let mut created = HttpResponse::Created();
+        created.header(header::LOCATION, path);
+        created
+    };
+
+    if let Some(e_tag) = e_tag {
+        response.header(header::ETAG, e_tag.as_str());
+    }
+
+    response.header(REPR_DIGEST, integrity::repr_digest(&digests.sha256).as_str());
+    response.finish()
+}
+
+/// Expected body digests from `Content-MD5`, `Digest` and `Repr-Digest` headers
+fn expected_digests(req: &HttpRequest<AppEnv>) -> Result<ExpectedDigests, Error> {
+    let header = |name: &str| req.headers().get(name).and_then(|h| h.to_str().ok());
+
+    ExpectedDigests::from_headers(header("Content-MD5"), header("Digest"), header(REPR_DIGEST))
+        .map_err(ErrorBadRequest)
+}
+
+/// User metadata from request headers with configured prefix
+fn user_metadata(req: &HttpRequest<AppEnv>) -> Result<HashMap<String, String>, Error> {
+    let config = &req.state().config.metadata;
+
+    if config.header_prefix.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let headers = req.headers().iter().map(|(name, value)| (name.as_str(), value.as_bytes()));
+
+    metadata::from_headers(headers, &config.header_prefix, config.max_size)
+        .map_err(ErrorBadRequest)
+}
+
+/// Storage class requested with `x-amz-storage-class` header, or configured for the key
+fn storage_class(req: &HttpRequest<AppEnv>, key: &str) -> Result<Option<String>, Error> {
+    match req.headers().get(STORAGE_CLASS).and_then(header_string) {
+        Some(class) => storage_class::parse(&class).map(Some).map_err(ErrorBadRequest),
+        None => Ok(req.state().config.storage_class.for_key(key).map(|c| c.to_owned())),
+    }
+}
+
+/// Extract archive streamed in request body into collection at request path, results of
+/// extracted entries are reported as Multi-Status
+fn extract_archive(
+    req: &HttpRequest<AppEnv>,
+    format: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let format = match Format::parse(format) {
+        Ok(format) => format,
+        Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+    };
+    let path = extract_path(req);
+
+    if !path.is_empty() && !path.ends_with('/') {
+        return Box::new(future::err(ErrorBadRequest(
+            "Archives can only be extracted into collections",
+        )));
+    }
+
+    unpack::extract(req.state(), &path, req.path(), format, request_body(req))
+        .map(|results| unpack::multistatus(&results))
+        .responder()
 }
 
+/// Request body is `multipart/form-data` form
+fn is_form(req: &HttpRequest<AppEnv>) -> bool {
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|h| h.to_str().ok());
+
+    content_type.map(|t| t.to_lowercase().starts_with("multipart/form-data")) == Some(true)
+}
+
+/// Upload files of form streamed in request body into collection at request path. Responds
+/// with JSON list of uploaded files if asked for, browsers are redirected with `303 See Other`
+/// to path in `redirect` query parameter, or back to the collection.
+fn upload_form(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let path = extract_path(req);
+
+    if !path.is_empty() && !path.ends_with('/') {
+        return Box::new(future::err(ErrorBadRequest(
+            "Forms can only be uploaded to collections",
+        )));
+    }
+
+    // only local paths, so the form can't be used to redirect elsewhere (browsers treat `\`
+    // as `/`, so `/\host` would be another host too)
+    let local = |p: &str| p.starts_with('/') && !p[1..].starts_with(&['/', '\\'][..]);
+    let location = match req.query().get("redirect") {
+        Some(redirect) if local(redirect) => redirect.to_owned(),
+        Some(_) => return Box::new(future::err(ErrorBadRequest("Invalid redirect path"))),
+        None => req.path().to_owned(),
+    };
+    let json = accepts_json(req);
+
+    form::upload_files(req.state(), &path, req.path(), req.multipart())
+        .and_then(move |files| {
+            if json {
+                serde_json::to_string(&files)
+                    .map(|body| HttpResponse::Ok().content_type("application/json").body(body))
+                    .map_err(ErrorInternalServerError)
+            } else {
+                Ok(HttpResponse::SeeOther().header(header::LOCATION, location).finish())
+            }
+        })
+        .responder()
+}
+
+/// Upload object, `?extract=zip|tar|tar.gz` extracts archive into collection instead
 pub fn put_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
-    let bucket = extract_bucket(&req);
+    if let Some(format) = req.query().get("extract") {
+        return extract_archive(req, format);
+    }
+
     let key = extract_object_key(&req);
+    let path = req.path().to_owned();
 
     let state = req.state().clone();
     // select headers
@@ -254,141 +860,447 @@ pub fn put_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Er
     let content_language = req.headers().get(header::CONTENT_LANGUAGE).and_then(header_string);
     let content_type = req.headers().get(header::CONTENT_TYPE).and_then(header_string);
     let expires = req.headers().get(header::EXPIRES).and_then(header_string);
+    let content_length = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(header_string)
+        .and_then(|l| l.parse::<u64>().ok());
+    let tagging = req.headers().get(TAGGING).and_then(header_string);
+    let website_redirect_location =
+        req.headers().get(WEBSITE_REDIRECT_LOCATION).and_then(header_string);
+    let (expected, user_metadata, storage_class) =
+        match (expected_digests(&req), user_metadata(&req), storage_class(&req, &key)) {
+            (Ok(expected), Ok(user_metadata), Ok(storage_class)) => {
+                (expected, user_metadata, storage_class)
+            }
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return Box::new(future::err(e)),
+        };
 
     // TODO optimize upload - check request size then decide which upload method to
     // use (multipart_upload vs put_object)
 
-    let body_stream: Box<Stream<Item=Bytes, Error=Error>> = Box::new(
-        req.payload()
-            .map_err(|_e| ErrorInternalServerError("Something went wrong while reading request stream"))
-    );
-
-    return Box::new(
-        state
-            .s3
-            .create_multipart_upload(CreateMultipartUploadRequest {
-                bucket: bucket.to_owned(),
-                key: key.to_owned(),
-                cache_control: cache_control.to_owned(),
-                content_disposition: content_disposition.to_owned(),
-                content_encoding: content_encoding.to_owned(),
-                content_language: content_language.to_owned(),
-                content_type: content_type.to_owned(),
-                expires: expires.to_owned(),
-                ..CreateMultipartUploadRequest::default()
-            })
-            .map_err(|e| match e {
-                CreateMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
-                CreateMultipartUploadError::Credentials(e) => ErrorForbidden(e),
-                CreateMultipartUploadError::Validation(e) => ErrorBadRequest(e),
-                CreateMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
-            })
-            .and_then(move |upload| {
-                upload_parts(body_stream, state.to_owned(), &upload)
-                    .then(move |parts_r| match parts_r {
-                        Ok(parts) => {
-                            let c: Box<Future<Item=HttpResponse, Error=Error>>;
-
-                            if parts.is_empty() {
-                                // no parts upload - file is empty
-                                c = Box::new(
-                                    abort_upload(&state, &upload)
-                                        .then(move |_| {
-                                            state
-                                                .s3
-                                                .put_object(PutObjectRequest {
-                                                    bucket: bucket,
-                                                    key: key,
-                                                    body: Some(StreamingBody::from(vec![])),
-                                                    cache_control: cache_control.to_owned(),
-                                                    content_disposition: content_disposition.to_owned(),
-                                                    content_encoding: content_encoding.to_owned(),
-                                                    content_language: content_language.to_owned(),
-                                                    content_type: content_type.to_owned(),
-                                                    expires: expires.to_owned(),
-                                                    ..PutObjectRequest::default()
-                                                })
-                                                .map_err(|e| match e {
-                                                    PutObjectError::HttpDispatch(e) => {
-                                                        ErrorInternalServerError(e)
-                                                    }
-                                                    PutObjectError::Credentials(e) => {
-                                                        ErrorForbidden(e)
-                                                    }
-                                                    PutObjectError::Validation(e) => {
-                                                        ErrorBadRequest(e)
-                                                    }
-                                                    PutObjectError::Unknown(e) => {
-                                                        ErrorInternalServerError(e)
-                                                    }
-                                                })
-                                        })
-                                        .map(|_| HttpResponse::Ok().finish()),
-                                );
-                            } else {
-                                c = Box::new(
-                                    complete_upload(&state, &upload, parts)
-                                        .map_err(|e| match e {
-                                            CompleteMultipartUploadError::HttpDispatch(e) => {
-                                                ErrorInternalServerError(e)
-                                            }
-                                            CompleteMultipartUploadError::Credentials(e) => {
-                                                ErrorForbidden(e)
-                                            }
-                                            CompleteMultipartUploadError::Validation(e) => {
-                                                ErrorBadRequest(e)
-                                            }
-                                            CompleteMultipartUploadError::Unknown(e) => {
-                                                ErrorInternalServerError(e)
-                                            }
-                                        })
-                                        .map(|_| HttpResponse::Ok().finish()),
-                                );
-                            }
-
-                            c
-                        }
-                        Err(e) => {
-                            let c: Box<Future<Item=HttpResponse, Error=Error>> = Box::new(
-                                abort_upload(&state, &upload)
-                                    .map(|_| HttpResponse::Ok().finish())
-                                    .then(|_| {
-                                        Err(match e {
-                                            UploadPartError::HttpDispatch(e) => {
-                                                ErrorInternalServerError(e)
-                                            }
-                                            UploadPartError::Credentials(e) => ErrorForbidden(e),
-                                            UploadPartError::Validation(e) => ErrorBadRequest(e),
-                                            UploadPartError::Unknown(e) => ErrorInternalServerError(e),
-                                        })
-                                    }),
-                            );
-
-                            c
-                        }
-                    })
-            })
-            .map(|_| HttpResponse::Ok().finish()),
-    );
+    let body_stream = request_body(req);
+    let headers = ObjectHeaders {
+        cache_control: cache_control,
+        content_disposition: content_disposition,
+        content_encoding: content_encoding,
+        content_language: content_language,
+        content_type: content_type,
+        expires: expires,
+        storage_class: storage_class,
+        tagging: tagging,
+        website_redirect_location: website_redirect_location,
+        metadata: user_metadata,
+    };
+    let upload = upload_object(&state, &key, headers, body_stream, content_length, expected);
+
+    Box::new(
+        object_exists(&state, &extract_bucket(&req), &extract_object_key(&req)).and_then(
+            move |existed| {
+                upload.map(move |(e_tag, digests)| put_response(existed, &path, e_tag, &digests))
+            },
+        ),
+    )
 }
 
-pub fn delete_object(req: &HttpRequest<AppEnv>) -> impl Responder {
-    req.state()
-        .s3
-        .delete_object(DeleteObjectRequest {
-            bucket: extract_bucket(&req),
-            key: extract_object_key(&req),
-            ..DeleteObjectRequest::default()
-        })
-        .map_err(|e| match e {
-            // http://rusoto.github.io/rusoto/rusoto_s3/enum.DeleteObjectError.html
-            DeleteObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
-            DeleteObjectError::Credentials(e) => ErrorForbidden(e),
-            DeleteObjectError::Validation(e) => ErrorBadRequest(e),
-            DeleteObjectError::Unknown(e) => ErrorInternalServerError(e),
-        })
-        .map(|_| HttpResponse::Ok().finish())
-        .responder()
+/// Response to moves to or from trash: `207 Multi-Status` with result of every move if some
+/// of them failed, so objects moved before the failure can be found
+fn moved_response(moved: Vec<trash::Moved>) -> HttpResponse {
+    if moved.iter().all(|m| m.error.is_none()) {
+        return HttpResponse::Ok().finish();
+    }
+
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(trash::multistatus(&moved))
+}
+
+/// Delete object, or move it to trash if enabled. Collections are only deleted with trash,
+/// objects already in trash are deleted permanently. `?tus=` terminates resumable upload.
+pub fn delete_object(req: &HttpRequest<AppEnv>) -> impl Responder {
+    if let Some(upload_id) = req.query().get("tus") {
+        return terminate_upload(req, upload_id);
+    }
+
+    let path = extract_path(req);
+    let trash = req.state().config.trash.as_ref();
+
+    if trash.map(|t| !path.starts_with(t.prefix.as_str())) == Some(true) {
+        return soft_delete::move_to_trash(req.state(), &path)
+            .map(moved_response)
+            .responder();
+    }
+
+    ::s3::delete_object(req.state(), &extract_bucket(&req), &extract_object_key(&req))
+        .map(|_| HttpResponse::Ok().finish())
+        .responder()
+}
+
+/// POST to object: `?restore` restores archived object, `?versionId=` makes given version
+/// the latest one, `?untrash` moves trashed object back to its original path, `?extract=`
+/// extracts archive into collection, requests with `Tus-Resumable` create resumable uploads,
+/// `multipart/form-data` forms upload files into collection
+pub fn post_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    if let Some(format) = req.query().get("extract") {
+        extract_archive(req, format)
+    } else if req.headers().contains_key(TUS_RESUMABLE) {
+        create_upload(req)
+    } else if is_form(req) {
+        upload_form(req)
+    } else if req.query().contains_key("restore") {
+        restore_object(req)
+    } else if req.query().contains_key("untrash") {
+        soft_delete::restore(req.state(), &extract_path(req))
+            .map(moved_response)
+            .responder()
+    } else if let Some(version_id) = version_id(req) {
+        restore_version(req, &version_id)
+    } else {
+        Box::new(future::ok(HttpResponse::MethodNotAllowed().finish()))
+    }
+}
+
+/// Check tus request: resumable uploads are enabled and protocol version is supported
+fn tus_request(req: &HttpRequest<AppEnv>) -> Result<(), Error> {
+    if req.state().config.tus.is_none() {
+        return Err(ErrorNotFound("Resumable uploads are not enabled"));
+    }
+
+    match req.headers().get(TUS_RESUMABLE).and_then(header_string) {
+        Some(ref version) if version == tus::VERSION => Ok(()),
+        _ => {
+            let response = HttpResponse::PreconditionFailed()
+                .header("Tus-Version", tus::VERSION)
+                .finish();
+            Err(InternalError::from_response("Unsupported tus version", response).into())
+        }
+    }
+}
+
+/// Supported methods, and tus protocol capabilities if resumable uploads are enabled
+pub fn options(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let mut response = HttpResponse::NoContent();
+
+    if req.state().config.tus.is_some() {
+        response
+            .header(
+                header::ALLOW,
+                "OPTIONS, GET, HEAD, PUT, POST, PATCH, DELETE, COPY, MOVE, PROPFIND",
+            )
+            .header(TUS_RESUMABLE, tus::VERSION)
+            .header("Tus-Version", tus::VERSION)
+            .header("Tus-Extension", tus::EXTENSIONS)
+            .header("Tus-Max-Size", tus::MAX_SIZE.to_string());
+    } else {
+        response
+            .header(header::ALLOW, "OPTIONS, GET, HEAD, PUT, POST, DELETE, COPY, MOVE, PROPFIND");
+    }
+
+    Box::new(future::ok(response.finish()))
+}
+
+/// Response to tus request with `Tus-Resumable` header, which every one of them has to have,
+/// errors included
+fn tus_response(
+    response: Box<Future<Item=HttpResponse, Error=Error>>,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    Box::new(response.then(|r| {
+        let mut response = match r {
+            Ok(response) => response,
+            Err(e) => e.as_response_error().error_response(),
+        };
+        response
+            .headers_mut()
+            .insert(TUS_RESUMABLE, header::HeaderValue::from_static(tus::VERSION));
+
+        Ok(response)
+    }))
+}
+
+/// Create resumable upload (tus `creation` extension) of object at request path. Uploads to
+/// collections are named after `filename` metadata. Upload URL is object path with `?tus=`.
+fn create_upload(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    tus_response(start_upload(req))
+}
+
+fn start_upload(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    if let Err(e) = tus_request(req) {
+        return Box::new(future::err(e));
+    }
+
+    let header = |name: &str| req.headers().get(name).and_then(header_string);
+    let length = match header(UPLOAD_LENGTH).map(|l| tus::parse_size(&l, UPLOAD_LENGTH)) {
+        Some(Ok(length)) if length > tus::MAX_SIZE => {
+            let e = InternalError::new("Upload is too large", StatusCode::PAYLOAD_TOO_LARGE);
+            return Box::new(future::err(e.into()));
+        }
+        Some(Ok(length)) => length,
+        Some(Err(e)) => return Box::new(future::err(ErrorBadRequest(e))),
+        None => return Box::new(future::err(ErrorBadRequest("Upload-Length header is required"))),
+    };
+    let raw_metadata = header(UPLOAD_METADATA);
+    let metadata = match tus::parse_metadata(raw_metadata.as_ref().map_or("", |m| m.as_str())) {
+        Ok(metadata) => metadata,
+        Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+    };
+    let path = extract_path(req);
+    let (key, location) = if path.is_empty() || path.ends_with('/') {
+        let name = tus::metadata_value(&metadata, "filename").and_then(archive::safe_path);
+        let name = match name {
+            Some(name) => archive::entry_path(&name),
+            None => {
+                return Box::new(future::err(ErrorBadRequest(
+                    "Uploads to collections need valid filename metadata",
+                )))
+            }
+        };
+
+        (object_key(req.state(), &format!("{}{}", path, name)), format!("{}{}", req.path(), name))
+    } else {
+        (extract_object_key(req), req.path().to_owned())
+    };
+    let content_type = tus::metadata_value(&metadata, "filetype")
+        .filter(|t| header::HeaderValue::from_str(t).is_ok())
+        .map(|t| t.to_owned());
+
+    resumable::create(req.state(), &key, length, raw_metadata, content_type)
+        .map(move |upload_id| {
+            let upload_id: String =
+                form_urlencoded::byte_serialize(upload_id.as_bytes()).collect();
+
+            HttpResponse::Created()
+                .header(header::LOCATION, format!("{}?tus={}", location, upload_id))
+                .finish()
+        })
+        .responder()
+}
+
+/// Resumable upload at request path, 404 if there's no such upload
+fn resumable_upload(
+    req: &HttpRequest<AppEnv>,
+    upload_id: &str,
+) -> Box<Future<Item=resumable::Upload, Error=Error>> {
+    if let Err(e) = tus_request(req) {
+        return Box::new(future::err(e));
+    }
+
+    Box::new(
+        resumable::status(req.state(), &extract_object_key(req), upload_id)
+            .and_then(|upload| upload.ok_or_else(|| ErrorNotFound("Upload not found"))),
+    )
+}
+
+/// Offset and length of resumable upload, offset is upload length once it's completed
+fn upload_status(
+    req: &HttpRequest<AppEnv>,
+    upload_id: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    tus_response(
+        resumable_upload(req, upload_id)
+            .map(|upload| {
+                let mut response = HttpResponse::Ok();
+                response
+                    .header(UPLOAD_OFFSET, upload.offset().to_string())
+                    .header(UPLOAD_LENGTH, upload.length.to_string())
+                    .header(header::CACHE_CONTROL, "no-store");
+
+                if let Some(ref metadata) = upload.metadata {
+                    response.header(UPLOAD_METADATA, metadata.as_str());
+                }
+
+                response.finish()
+            })
+            .responder(),
+    )
+}
+
+/// Append request body to resumable upload, at offset given by client
+fn append_upload(
+    req: &HttpRequest<AppEnv>,
+    upload_id: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    tus_response(append_body(req, upload_id))
+}
+
+fn append_body(
+    req: &HttpRequest<AppEnv>,
+    upload_id: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    match req.headers().get(header::CONTENT_TYPE).and_then(header_string) {
+        Some(ref content_type) if content_type == tus::PATCH_CONTENT_TYPE => {}
+        _ => {
+            let e = InternalError::new(
+                "Content-Type must be application/offset+octet-stream",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            );
+            return Box::new(future::err(e.into()));
+        }
+    }
+
+    let offset = match req.headers().get(UPLOAD_OFFSET).and_then(header_string) {
+        Some(offset) => match tus::parse_size(&offset, UPLOAD_OFFSET) {
+            Ok(offset) => offset,
+            Err(e) => return Box::new(future::err(ErrorBadRequest(e))),
+        },
+        None => return Box::new(future::err(ErrorBadRequest("Upload-Offset header is required"))),
+    };
+    let content_length = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(header_string)
+        .and_then(|l| l.parse::<u64>().ok());
+    let env = req.state().clone();
+    let key = extract_object_key(req);
+    let upload_id = upload_id.to_owned();
+    let body = request_body(req);
+
+    resumable_upload(req, &upload_id)
+        .and_then(move |upload| -> Box<Future<Item=u64, Error=Error>> {
+            if upload.offset() != offset {
+                return Box::new(future::err(ErrorConflict("Upload-Offset doesn't match")));
+            }
+
+            if content_length.map(|l| offset + l > upload.length) == Some(true) {
+                let e = InternalError::new(
+                    "Request body exceeds Upload-Length",
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                );
+                return Box::new(future::err(e.into()));
+            }
+
+            resumable::append(&env, &key, &upload_id, upload, body)
+        })
+        .map(|offset| {
+            HttpResponse::NoContent().header(UPLOAD_OFFSET, offset.to_string()).finish()
+        })
+        .responder()
+}
+
+/// Terminate resumable upload (tus `termination` extension)
+fn terminate_upload(
+    req: &HttpRequest<AppEnv>,
+    upload_id: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let env = req.state().clone();
+    let key = extract_object_key(req);
+    let upload_id = upload_id.to_owned();
+
+    tus_response(
+        resumable_upload(req, &upload_id)
+            .and_then(move |_| resumable::terminate(&env, &key, &upload_id))
+            .map(|_| HttpResponse::NoContent().finish())
+            .responder(),
+    )
+}
+
+/// PATCH appends data to resumable upload with `?tus=`
+pub fn patch_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    match req.query().get("tus") {
+        Some(upload_id) => append_upload(req, upload_id),
+        None => Box::new(future::ok(HttpResponse::MethodNotAllowed().finish())),
+    }
+}
+
+/// Restore job parameters from `days` and `tier` query parameters
+fn restore_request(req: &HttpRequest<AppEnv>) -> Result<RestoreRequest, Error> {
+    let query = req.query();
+    let days = match query.get("days") {
+        Some(days) => days
+            .parse::<i64>()
+            .ok()
+            .filter(|d| *d > 0)
+            .ok_or_else(|| ErrorBadRequest("days must be a positive number"))?,
+        None => DEFAULT_RESTORE_DAYS,
+    };
+    let tier = query.get("tier").map(|t| t.as_str()).unwrap_or("Standard");
+    let tier = storage_class::parse_tier(tier).map_err(ErrorBadRequest)?;
+
+    Ok(RestoreRequest {
+        days: Some(days),
+        glacier_job_parameters: Some(GlacierJobParameters {
+            tier: tier.to_owned(),
+        }),
+        ..RestoreRequest::default()
+    })
+}
+
+/// 202 with `Retry-After` while restore is in progress, 200 once restored copy is readable
+fn restore_response(in_progress: bool) -> HttpResponse {
+    if in_progress {
+        HttpResponse::Accepted()
+            .header(RESTORE, "ongoing-request=\"true\"")
+            .header(header::RETRY_AFTER, RESTORE_RETRY_AFTER.to_string())
+            .finish()
+    } else {
+        HttpResponse::Ok().finish()
+    }
+}
+
+/// Restore archived object, expiry of already restored copy is extended. Status is checked
+/// first, so repeated requests don't start new restore jobs.
+fn restore_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let restore_request = match restore_request(req) {
+        Ok(restore_request) => restore_request,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let state = req.state().clone();
+    let bucket = extract_bucket(&req);
+    let key = extract_object_key(&req);
+
+    Box::new(::s3::head_object(&state, &bucket, &key).and_then(
+        move |object| -> Box<Future<Item=HttpResponse, Error=Error>> {
+            let in_progress = match restore_status(&object) {
+                RestoreStatus::NotArchived => {
+                    return Box::new(future::err(ErrorConflict("Object is not archived")))
+                }
+                RestoreStatus::InProgress => return Box::new(future::ok(restore_response(true))),
+                RestoreStatus::Archived => true,
+                RestoreStatus::Restored { .. } => false,
+            };
+            let s = state.clone();
+            let request = RestoreObjectRequest {
+                bucket: bucket,
+                key: key,
+                restore_request: Some(restore_request),
+                ..RestoreObjectRequest::default()
+            };
+
+            Box::new(
+                with_retry(&state, "restore_object", move || s.s3.restore_object(request.clone()))
+                    .then(move |r| match r {
+                        Ok(_) => Ok(restore_response(in_progress)),
+                        // http://rusoto.github.io/rusoto/rusoto_s3/enum.RestoreObjectError.html
+                        Err(RestoreObjectError::Unknown(ref e))
+                            if e.contains("<Code>RestoreAlreadyInProgress</Code>") =>
+                        {
+                            Ok(restore_response(true))
+                        }
+                        Err(RestoreObjectError::ObjectAlreadyInActiveTierError(e)) => {
+                            Err(ErrorConflict(e))
+                        }
+                        Err(RestoreObjectError::HttpDispatch(e)) => {
+                            Err(ErrorInternalServerError(e))
+                        }
+                        Err(RestoreObjectError::Credentials(e)) => Err(ErrorForbidden(e)),
+                        Err(RestoreObjectError::Validation(e)) => Err(ErrorBadRequest(e)),
+                        Err(RestoreObjectError::Unknown(e)) => Err(ErrorInternalServerError(e)),
+                    }),
+            )
+        },
+    ))
+}
+
+/// Restore previous version by copying it over the latest one, which is kept as a version
+fn restore_version(
+    req: &HttpRequest<AppEnv>,
+    version_id: &str,
+) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let bucket = extract_bucket(&req);
+    let key = extract_object_key(&req);
+    let overrides = copy::ContentHeaders::default();
+    let changes = copy::MetadataChanges::new();
+
+    copy::copy_version(req.state(), &bucket, &key, Some(version_id), &key, &overrides, &changes)
+        .map(|_| HttpResponse::Ok().finish())
+        .responder()
 }
 
 enum DestinationHeaderError {
@@ -408,29 +1320,46 @@ fn extract_destination_header(
     }
 }
 
+/// Content headers given with COPY or MOVE request, to be set on destination object
+fn content_headers(req: &HttpRequest<AppEnv>) -> copy::ContentHeaders {
+    copy::ContentHeaders {
+        cache_control: req.headers().get(header::CACHE_CONTROL).and_then(header_string),
+        content_disposition: req.headers().get(header::CONTENT_DISPOSITION).and_then(header_string),
+        content_encoding: req.headers().get(header::CONTENT_ENCODING).and_then(header_string),
+        content_language: req.headers().get(header::CONTENT_LANGUAGE).and_then(header_string),
+        content_type: req.headers().get(header::CONTENT_TYPE).and_then(header_string),
+        expires: req.headers().get(header::EXPIRES).and_then(header_string),
+    }
+}
+
 pub fn copy_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
     let state = req.state().clone();
     let bucket = extract_bucket(&req);
     let source_key = extract_object_key(&req);
 
     match extract_destination_header(req) {
+        Ok(dest) => copy::copy(&state, &bucket, &source_key, &dest, &content_headers(req))
+            .map(|_| HttpResponse::Ok().finish())
+            .responder(),
+        Err(_) => Box::new(future::err(ErrorBadRequest("Invalid Destination header"))),
+    }
+}
+
+/// Move object: copy, verify destination, then delete source. Source is kept whenever
+/// the copy can't be confirmed. Collections can't be moved, objects are moved one by one.
+pub fn move_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, Error=Error>> {
+    let bucket = extract_bucket(&req);
+    let source_key = extract_object_key(&req);
+
+    match extract_destination_header(req) {
+        Ok(ref dest) if req.path().ends_with('/') || dest.is_empty() || dest.ends_with('/') => {
+            Box::new(future::err(ErrorForbidden("Collections can't be moved, only objects")))
+        }
         Ok(dest) => {
-            state
-                .s3
-                .copy_object(CopyObjectRequest {
-                    bucket: bucket.clone(),
-                    copy_source: util::encode_key(format!("{}/{}", bucket, source_key)),
-                    key: dest,
-                    ..CopyObjectRequest::default()
-                })
-                .map_err(|e| match e {
-                    // http://rusoto.github.io/rusoto/rusoto_s3/enum.CopyObjectError.html
-                    CopyObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
-                    CopyObjectError::Credentials(e) => ErrorForbidden(e),
-                    CopyObjectError::Validation(e) => ErrorBadRequest(e),
-                    CopyObjectError::ObjectNotInActiveTierError(e) => ErrorForbidden(e),
-                    CopyObjectError::Unknown(e) => ErrorInternalServerError(e),
-                })
+            let overrides = content_headers(req);
+            let changes = copy::MetadataChanges::new();
+
+            copy::move_object(req.state(), &bucket, &source_key, &dest, &overrides, &changes)
                 .map(|_| HttpResponse::Ok().finish())
                 .responder()
         }
@@ -438,28 +1367,1868 @@ pub fn copy_object(req: &HttpRequest<AppEnv>) -> Box<Future<Item=HttpResponse, E
     }
 }
 
-pub fn move_object(req: &HttpRequest<AppEnv>) -> impl Responder {
-    let state = req.state().clone();
-    let bucket = extract_bucket(&req);
-    let source_key = extract_object_key(&req);
+#[cfg(test)]
+mod tests {
+    mod put_object {
+        use actix_web::test::TestServer;
+        use aws_s3_webdav::envelope;
+        use aws_s3_webdav::integrity::{self, Hasher};
+        use aws_s3_webdav::multipart;
+        use aws_s3_webdav::storage_class::StorageClassRules;
+        use std::io::{Read, Write};
+        use std::net::{SocketAddr, TcpStream};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+        use env::Encryption;
+        use testing::{self, Failure, Response, Stub, StubState};
 
-    copy_object(req)
-        .and_then(move |_| {
-            state
-                .s3
-                .delete_object(DeleteObjectRequest {
-                    bucket: bucket,
-                    key: source_key,
-                    ..DeleteObjectRequest::default()
-                })
-                .map_err(|e| match e {
-                    // http://rusoto.github.io/rusoto/rusoto_s3/enum.DeleteObjectError.html
-                    DeleteObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
-                    DeleteObjectError::Credentials(e) => ErrorForbidden(e),
-                    DeleteObjectError::Validation(e) => ErrorBadRequest(e),
-                    DeleteObjectError::Unknown(e) => ErrorInternalServerError(e),
-                })
-                .map(|_| HttpResponse::Ok().finish())
-        })
-        .responder()
+        fn start() -> (Stub, TestServer, TestServer) {
+            start_with(|| Encryption::Default)
+        }
+
+        fn start_with(encryption: fn() -> Encryption) -> (Stub, TestServer, TestServer) {
+            testing::proxy(Stub::default(), move |config| config.encryption = encryption())
+        }
+
+        fn put(addr: SocketAddr, path: &str, body: &[u8]) -> Response {
+            testing::request(addr, "PUT", path, &[], body)
+        }
+
+        #[test]
+        fn test_put_too_large_for_max_parts() {
+            let (stub, _s3, proxy) = testing::proxy(Stub::default(), |config| {
+                config.upload.max_part_size = multipart::MIN_PART_SIZE;
+            });
+
+            // rejected before body is read, so only request head is sent
+            let mut stream = TcpStream::connect(proxy.addr()).unwrap();
+            let length = multipart::max_upload_size(multipart::MIN_PART_SIZE) + 1;
+            let head = format!("PUT /big.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+            stream.write_all(head.as_bytes()).unwrap();
+            let mut status = [0; 12];
+            stream.read_exact(&mut status).unwrap();
+
+            assert_eq!(&status, b"HTTP/1.1 413");
+            assert!(!stub.lock().unwrap().calls.contains(&"create_multipart_upload"));
+        }
+
+        #[test]
+        fn test_put_new_object() {
+            let (stub, _s3, proxy) = start();
+
+            let response = put(proxy.addr(), "/hello.txt", b"Hello there!");
+
+            assert_eq!(response.status, 201);
+            assert_eq!(response.header("location"), Some("/hello.txt"));
+
+            let stub = stub.lock().unwrap();
+            let object = &stub.objects["hello.txt"];
+            assert_eq!(object.data, b"Hello there!".to_vec());
+            assert_eq!(response.header("etag"), Some(object.e_tag.as_str()));
+        }
+
+        #[test]
+        fn test_put_when_existence_unknown() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "head_object",
+                status: 403,
+                code: "AccessDenied",
+            });
+
+            let response = put(proxy.addr(), "/hello.txt", b"Hello there!");
+
+            assert_eq!(response.status, 403);
+            assert!(stub.lock().unwrap().objects.is_empty());
+        }
+
+        #[test]
+        fn test_put_existing_object() {
+            let (stub, _s3, proxy) = start();
+
+            put(proxy.addr(), "/hello.txt", b"Hello");
+            let response = put(proxy.addr(), "/hello.txt", b"Hello again");
+
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("location"), None);
+            assert_eq!(stub.lock().unwrap().objects["hello.txt"].data, b"Hello again".to_vec());
+        }
+
+        #[test]
+        fn test_put_empty_object() {
+            let (stub, _s3, proxy) = start();
+
+            let response = put(proxy.addr(), "/empty.txt", b"");
+
+            assert_eq!(response.status, 201);
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.objects["empty.txt"].data, Vec::<u8>::new());
+            assert!(stub.calls.contains(&"put_object"));
+            assert_eq!(
+                stub.objects["empty.txt"].metadata["sha256"],
+                integrity::sha256_hex(&sha256(b""))
+            );
+        }
+
+        #[test]
+        fn test_put_multiple_parts() {
+            let (stub, _s3, proxy) = start();
+            let data: Vec<u8> = (0..6 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+            let response = put(proxy.addr(), "/big.bin", &data);
+
+            assert_eq!(response.status, 201);
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.calls.iter().filter(|c| **c == "upload_part").count(), 2);
+            assert!(stub.objects["big.bin"].data == data);
+        }
+
+        #[test]
+        fn test_put_failed_part_aborts_upload() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "upload_part",
+                status: 500,
+                code: "InternalError",
+            });
+
+            let response = put(proxy.addr(), "/hello.txt", b"Hello there!");
+
+            assert_eq!(response.status, 500);
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.aborted, 1);
+            assert!(stub.uploads.is_empty());
+            assert!(!stub.objects.contains_key("hello.txt"));
+        }
+
+        #[test]
+        fn test_put_failed_complete_aborts_upload() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "complete_multipart_upload",
+                status: 400,
+                code: "InvalidPart",
+            });
+
+            let response = put(proxy.addr(), "/hello.txt", b"Hello there!");
+
+            assert_eq!(response.status, 500);
+            assert_eq!(stub.lock().unwrap().aborted, 1);
+        }
+
+        fn sha256(data: &[u8]) -> Vec<u8> {
+            let mut hasher = Hasher::new();
+            hasher.update(data);
+            hasher.finish().sha256
+        }
+
+        #[test]
+        fn test_put_returns_digest() {
+            let (stub, _s3, proxy) = start();
+
+            let response = put(proxy.addr(), "/hello.txt", b"Hello there!");
+
+            assert_eq!(response.status, 201);
+            assert_eq!(
+                response.header("repr-digest"),
+                Some(integrity::repr_digest(&sha256(b"Hello there!")).as_str())
+            );
+            // digest wasn't known before upload started, it's stored when upload completes
+            assert_eq!(
+                stub.lock().unwrap().objects["hello.txt"].metadata["sha256"],
+                integrity::sha256_hex(&sha256(b"Hello there!"))
+            );
+            assert_eq!(
+                stub.lock().unwrap().calls.iter().filter(|&&c| c == "copy_object").count(),
+                1
+            );
+
+            let response = testing::request(proxy.addr(), "HEAD", "/hello.txt", &[], b"");
+            assert_eq!(
+                response.header("repr-digest"),
+                Some(integrity::repr_digest(&sha256(b"Hello there!")).as_str())
+            );
+        }
+
+        #[test]
+        fn test_put_verified_digest_is_stored() {
+            let (stub, _s3, proxy) = start();
+            let digest = integrity::repr_digest(&sha256(b"Hello there!"));
+            let md5 = integrity::content_md5(b"Hello there!");
+
+            let response = testing::request(
+                proxy.addr(),
+                "PUT",
+                "/hello.txt",
+                &[("Repr-Digest", &digest), ("Content-MD5", &md5)],
+                b"Hello there!",
+            );
+
+            assert_eq!(response.status, 201);
+            assert_eq!(
+                stub.lock().unwrap().objects["hello.txt"].metadata["sha256"],
+                integrity::sha256_hex(&sha256(b"Hello there!"))
+            );
+
+            let response = testing::request(proxy.addr(), "HEAD", "/hello.txt", &[], b"");
+            assert_eq!(response.header("repr-digest"), Some(digest.as_str()));
+        }
+
+        #[test]
+        fn test_put_digest_mismatch_aborts_upload() {
+            let (stub, _s3, proxy) = start();
+            let md5 = integrity::content_md5(b"Hello there?");
+
+            let response = testing::request(
+                proxy.addr(),
+                "PUT",
+                "/hello.txt",
+                &[("Content-MD5", &md5)],
+                b"Hello there!",
+            );
+
+            assert_eq!(response.status, 400);
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.aborted, 1);
+            assert!(!stub.calls.contains(&"complete_multipart_upload"));
+            assert!(!stub.objects.contains_key("hello.txt"));
+        }
+
+        #[test]
+        fn test_put_invalid_digest_header() {
+            let (stub, _s3, proxy) = start();
+
+            let response = testing::request(
+                proxy.addr(),
+                "PUT",
+                "/hello.txt",
+                &[("Digest", "SHA-256=not-a-digest")],
+                b"Hello there!",
+            );
+
+            assert_eq!(response.status, 400);
+            assert!(!stub.lock().unwrap().calls.contains(&"create_multipart_upload"));
+        }
+
+        #[test]
+        fn test_put_with_customer_key() {
+            let (stub, _s3, proxy) = start_with(testing::customer_keys);
+            let body: Vec<u8> = (0..6 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+            assert_eq!(put(proxy.addr(), "/big.bin", &body).status, 201);
+            assert_eq!(put(proxy.addr(), "/empty.bin", b"").status, 201);
+
+            {
+                let stub = stub.lock().unwrap();
+                let md5 = Some(testing::CUSTOMER_KEY_MD5.to_owned());
+                assert_eq!(stub.objects["big.bin"].customer_key_md5, md5);
+                assert_eq!(stub.objects["empty.bin"].customer_key_md5, md5);
+            }
+
+            // stub rejects reads without the key
+            let response = testing::request(proxy.addr(), "GET", "/big.bin", &[], b"");
+            assert_eq!(response.status, 200);
+            assert!(response.body == body);
+            let response = testing::request(proxy.addr(), "HEAD", "/big.bin", &[], b"");
+            assert_eq!(response.status, 200);
+        }
+
+        #[test]
+        fn test_put_with_keyring() {
+            let (stub, _s3, proxy) = testing::proxy(Stub::default(), |config| {
+                config.keyring = Some(testing::keyring("new"))
+            });
+            let body: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+            let md5 = integrity::content_md5(&body);
+
+            let response =
+                testing::request(proxy.addr(), "PUT", "/big.bin", &[("Content-MD5", &md5)], &body);
+            assert_eq!(response.status, 201);
+            assert_eq!(put(proxy.addr(), "/empty.bin", b"").status, 201);
+
+            {
+                let stub = stub.lock().unwrap();
+                let stored = &stub.objects["big.bin"];
+                assert_eq!(stored.data.len() as u64, envelope::encrypted_size(body.len() as u64));
+                assert!(stored.data[..1024] != body[..1024]);
+                assert!(stored.metadata[envelope::METADATA_KEY].starts_with("v1:new:"));
+                assert_eq!(stub.objects["empty.bin"].data.len(), envelope::TAG_LEN);
+            }
+
+            let response = testing::request(proxy.addr(), "GET", "/big.bin", &[], b"");
+            assert_eq!(response.status, 200);
+            assert!(response.body == body);
+            assert_eq!(response.header("x-amz-meta-cse"), None);
+            let response = testing::request(proxy.addr(), "HEAD", "/big.bin", &[], b"");
+            assert_eq!(response.header("content-length"), Some("204800"));
+            let response = testing::request(proxy.addr(), "GET", "/empty.bin", &[], b"");
+            assert_eq!(response.status, 200);
+            assert!(response.body.is_empty());
+
+            // stored as is without keyring, but can't be read
+            let (_, _plain_s3, plain) = testing::proxy(stub.clone(), |_| ());
+            let response = testing::request(plain.addr(), "GET", "/big.bin", &[], b"");
+            assert_eq!(response.status, 500);
+        }
+
+        #[test]
+        fn test_put_storage_class() {
+            let (stub, _s3, proxy) = testing::proxy(Stub::default(), |config| {
+                config.storage_class = StorageClassRules::parse(vec!["backups/=GLACIER"]).unwrap()
+            });
+            let put_with = |path: &str, class: Option<&str>| {
+                let headers: Vec<_> = class.iter().map(|c| ("X-Amz-Storage-Class", *c)).collect();
+                testing::request(proxy.addr(), "PUT", path, &headers, b"Hello").status
+            };
+
+            assert_eq!(put_with("/backups/a.tar", None), 201);
+            assert_eq!(put_with("/backups/b.tar", Some("deep_archive")), 201);
+            assert_eq!(put_with("/hello.txt", None), 201);
+            assert_eq!(put_with("/empty.txt", Some("STANDARD_IA")), 201);
+            assert_eq!(put_with("/cold.txt", Some("COLD")), 400);
+
+            let stub = stub.lock().unwrap();
+            let class = |key: &str| stub.objects[key].storage_class.clone();
+            assert_eq!(class("backups/a.tar"), Some("GLACIER".to_owned()));
+            assert_eq!(class("backups/b.tar"), Some("DEEP_ARCHIVE".to_owned()));
+            assert_eq!(class("hello.txt"), None);
+            assert_eq!(class("empty.txt"), Some("STANDARD_IA".to_owned()));
+        }
+
+        #[test]
+        fn test_put_with_managed_encryption() {
+            let (stub, _s3, proxy) = start_with(|| Encryption::Managed {
+                algorithm: "aws:kms".to_owned(),
+                kms_key_id: Some("alias/proxy".to_owned()),
+            });
+
+            put(proxy.addr(), "/hello.txt", b"Hello there!");
+            put(proxy.addr(), "/empty.txt", b"");
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.objects["hello.txt"].encryption, Some("aws:kms".to_owned()));
+            assert_eq!(stub.objects["empty.txt"].encryption, Some("aws:kms".to_owned()));
+        }
+
+        #[test]
+        fn test_put_forwards_metadata() {
+            let (stub, _s3, proxy) = start();
+
+            let response = testing::request(
+                proxy.addr(),
+                "PUT",
+                "/hello.txt",
+                &[("X-Amz-Meta-Origin", "camera"), ("x-amz-meta-job", "42")],
+                b"Hello there!",
+            );
+
+            assert_eq!(response.status, 201);
+            {
+                let stub = stub.lock().unwrap();
+                let metadata = &stub.objects["hello.txt"].metadata;
+                assert_eq!(metadata["origin"], "camera");
+                assert_eq!(metadata["job"], "42");
+            }
+
+            for method in &["GET", "HEAD"] {
+                let response = testing::request(proxy.addr(), method, "/hello.txt", &[], b"");
+
+                assert_eq!(response.header("x-amz-meta-origin"), Some("camera"));
+                assert_eq!(response.header("x-amz-meta-job"), Some("42"));
+                assert_eq!(response.header("x-amz-meta-sha256"), None);
+            }
+        }
+
+        #[test]
+        fn test_put_metadata_too_large() {
+            let (stub, _s3, proxy) = start();
+            let value = "a".repeat(2048);
+
+            let response = testing::request(
+                proxy.addr(),
+                "PUT",
+                "/hello.txt",
+                &[("x-amz-meta-note", &value)],
+                b"Hello there!",
+            );
+
+            assert_eq!(response.status, 400);
+            assert!(!stub.lock().unwrap().calls.contains(&"create_multipart_upload"));
+        }
+
+        #[test]
+        fn test_client_disconnect_aborts_upload() {
+            use std::io::Write;
+            use std::net::TcpStream;
+
+            let (stub, _s3, proxy) = start();
+
+            {
+                let mut stream = TcpStream::connect(proxy.addr()).unwrap();
+                stream
+                    .write_all(b"PUT /hello.txt HTTP/1.1\r\nHost: proxy\r\nContent-Length: 100\r\n\r\nHello")
+                    .unwrap();
+
+                // wait for upload to be started
+                for _ in 0..50 {
+                    if !stub.lock().unwrap().uploads.is_empty() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+            }
+
+            for _ in 0..50 {
+                if stub.lock().unwrap().aborted > 0 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.aborted, 1);
+            assert!(stub.uploads.is_empty());
+        }
+
+        #[test]
+        fn test_uploads_in_flight() {
+            use aws_s3_webdav::in_flight::InFlight;
+            use std::io::Write;
+            use std::net::TcpStream;
+
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let s3_addr = s3.addr();
+            let in_flight = InFlight::default();
+            let server_in_flight = in_flight.clone();
+            let proxy = TestServer::with_factory(move || {
+                let mut state = testing::state(s3_addr);
+                Arc::get_mut(&mut state).unwrap().in_flight = server_in_flight.clone();
+                ::app(state)
+            });
+            let wait_for = |done: &Fn() -> bool| {
+                for _ in 0..50 {
+                    if done() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+            };
+
+            {
+                let head = "PUT /hello.txt HTTP/1.1\r\nHost: proxy\r\nContent-Length: 100\r\n\r\n";
+                let mut stream = TcpStream::connect(proxy.addr()).unwrap();
+                stream.write_all(format!("{}Hello", head).as_bytes()).unwrap();
+
+                wait_for(&|| !in_flight.uploads().is_empty());
+                assert_eq!(in_flight.requests(), 1);
+                assert_eq!(in_flight.uploads()[0].key, "hello.txt");
+            }
+
+            // upload aborted on disconnect isn't open anymore
+            wait_for(&|| in_flight.is_idle());
+            assert!(in_flight.is_idle());
+
+            assert_eq!(put(proxy.addr(), "/hello.txt", b"Hello").status, 201);
+            wait_for(&|| in_flight.is_idle());
+            assert!(in_flight.is_idle());
+        }
+    }
+    mod move_object {
+        use actix_web::test::TestServer;
+        use testing::{self, Failure, Response, StoredObject, Stub};
+
+        fn object(data: &[u8]) -> StoredObject {
+            StoredObject {
+                data: data.to_vec(),
+                e_tag: testing::e_tag(data),
+                ..StoredObject::default()
+            }
+        }
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let stub = Stub::default();
+            stub.lock().unwrap().objects.insert("a.txt".to_owned(), object(b"Hello"));
+
+            testing::proxy(stub, |_| ())
+        }
+
+        fn move_to(proxy: &TestServer, destination: &str) -> Response {
+            testing::request(proxy.addr(), "MOVE", "/a.txt", &[("Destination", destination)], b"")
+        }
+
+        #[test]
+        fn test_move() {
+            let (stub, _s3, proxy) = start();
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 200);
+
+            let stub = stub.lock().unwrap();
+            assert!(!stub.objects.contains_key("a.txt"));
+            assert_eq!(stub.objects["b.txt"].data, b"Hello".to_vec());
+        }
+
+        #[test]
+        fn test_unverified_move_keeps_source() {
+            let (stub, _s3, proxy) = start();
+            // ETag of multipart upload isn't MD5 of the data, and there's no SHA-256 stored
+            stub.lock().unwrap().objects.get_mut("a.txt").unwrap().e_tag =
+                "\"d41d8cd98f00b204e9800998ecf8427e-2\"".to_owned();
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 409);
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.objects.contains_key("a.txt"));
+            assert_eq!(stub.objects["b.txt"].data, b"Hello".to_vec());
+            assert!(!stub.calls.contains(&"delete_object"));
+        }
+
+        #[test]
+        fn test_move_verified_with_sha256() {
+            let (stub, _s3, proxy) = start();
+            {
+                let mut stub = stub.lock().unwrap();
+                let object = stub.objects.get_mut("a.txt").unwrap();
+                object.e_tag = "\"d41d8cd98f00b204e9800998ecf8427e-2\"".to_owned();
+                object.metadata.insert("sha256".to_owned(), "digest".to_owned());
+            }
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 200);
+            assert!(!stub.lock().unwrap().objects.contains_key("a.txt"));
+        }
+
+        #[test]
+        fn test_multipart_copy_move() {
+            // ETag of multipart copy is its own, only stored SHA-256 tells it's the same data
+            for &(sha256, status) in &[(false, 409), (true, 200)] {
+                let stub = Stub::default();
+                let mut a = object(b"Hello");
+                if sha256 {
+                    a.metadata.insert("sha256".to_owned(), "digest".to_owned());
+                }
+                stub.lock().unwrap().objects.insert("a.txt".to_owned(), a);
+                let (stub, _s3, proxy) =
+                    testing::proxy(stub, |config| config.copy.multipart_threshold = 1);
+
+                assert_eq!(move_to(&proxy, "/b.txt").status, status);
+                assert_eq!(stub.lock().unwrap().objects.contains_key("a.txt"), !sha256);
+            }
+        }
+
+        #[test]
+        fn test_collections_are_not_moved() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().objects.insert("docs/a.txt".to_owned(), object(b"Hello"));
+
+            let headers = [("Destination", "/archive/")];
+            let response = testing::request(proxy.addr(), "MOVE", "/docs/", &headers, b"");
+            assert_eq!(response.status, 403);
+            assert_eq!(move_to(&proxy, "/archive/").status, 403);
+            assert!(stub.lock().unwrap().objects.contains_key("docs/a.txt"));
+            assert!(stub.lock().unwrap().objects.contains_key("a.txt"));
+        }
+
+        #[test]
+        fn test_failed_copy_keeps_source() {
+            let (stub, _s3, proxy) = start();
+            // CopyObject may fail after sending 200 status, with error in response body
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "copy_object",
+                status: 200,
+                code: "InternalError",
+            });
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 500);
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.objects.contains_key("a.txt"));
+            assert!(!stub.calls.contains(&"delete_object"));
+        }
+
+        #[test]
+        fn test_stale_destination_keeps_source() {
+            let (stub, _s3, proxy) = start();
+            {
+                let mut stub = stub.lock().unwrap();
+                stub.objects.insert("b.txt".to_owned(), object(b"Bye!!"));
+                stub.failures.push(Failure {
+                    operation: "copy_object",
+                    status: 200,
+                    code: "InternalError",
+                });
+            }
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 500);
+
+            let stub = stub.lock().unwrap();
+            assert_eq!(stub.objects["a.txt"].data, b"Hello".to_vec());
+            assert!(!stub.calls.contains(&"delete_object"));
+        }
+
+        #[test]
+        fn test_failed_delete_keeps_both() {
+            let (stub, _s3, proxy) = start();
+            stub.lock().unwrap().failures.push(Failure {
+                operation: "delete_object",
+                status: 403,
+                code: "AccessDenied",
+            });
+
+            assert_eq!(move_to(&proxy, "/b.txt").status, 500);
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.objects.contains_key("a.txt"));
+            assert!(stub.objects.contains_key("b.txt"));
+        }
+    }
+
+    mod restore_object {
+        use actix_web::test::TestServer;
+        use testing::{self, StoredObject, Stub};
+
+        fn start(storage_class: &str) -> (Stub, TestServer, TestServer) {
+            let stub = Stub::default();
+            stub.lock().unwrap().objects.insert(
+                "backup.tar".to_owned(),
+                StoredObject {
+                    data: b"Hello".to_vec(),
+                    e_tag: testing::e_tag(b"Hello"),
+                    storage_class: Some(storage_class.to_owned()),
+                    ..StoredObject::default()
+                },
+            );
+
+            testing::proxy(stub, |_| ())
+        }
+
+        #[test]
+        fn test_propfind() {
+            let (stub, _s3, proxy) = start("GLACIER");
+            let propfind = |path: &str| {
+                let response = testing::request(proxy.addr(), "PROPFIND", path, &[], b"");
+                (response.status, String::from_utf8(response.body).unwrap())
+            };
+
+            let (status, body) = propfind("/backup.tar");
+            assert_eq!(status, 207);
+            assert!(body.contains("<D:href>/backup.tar</D:href>"), "{}", body);
+            assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"), "{}", body);
+            assert!(body.contains("<S:storage-class>GLACIER</S:storage-class>"), "{}", body);
+            assert!(body.contains("<S:restore-status>archived</S:restore-status>"), "{}", body);
+
+            stub.lock().unwrap().objects.get_mut("backup.tar").unwrap().restore =
+                Some("ongoing-request=\"true\"".to_owned());
+            let (_, body) = propfind("/backup.tar");
+            assert!(body.contains("<S:restore-status>in-progress</S:restore-status>"), "{}", body);
+
+            assert_eq!(propfind("/missing.tar").0, 404);
+        }
+
+        #[test]
+        fn test_restore_archived() {
+            let (stub, _s3, proxy) = start("GLACIER");
+            let get = || testing::request(proxy.addr(), "GET", "/backup.tar", &[], b"");
+            let restore = |query: &str| {
+                let path = format!("/backup.tar?restore{}", query);
+                testing::request(proxy.addr(), "POST", &path, &[], b"")
+            };
+
+            assert_eq!(get().status, 409);
+            assert_eq!(restore("&tier=Slow").status, 400);
+            assert_eq!(restore("&days=0").status, 400);
+
+            let response = restore("&days=7&tier=bulk");
+            assert_eq!(response.status, 202);
+            assert_eq!(response.header("retry-after"), Some("900"));
+
+            // in progress, no new restore job
+            assert_eq!(restore("").status, 202);
+            let calls = stub.lock().unwrap().calls.clone();
+            assert_eq!(calls.iter().filter(|c| **c == "restore_object").count(), 1);
+            let response = get();
+            assert_eq!(response.status, 503);
+            assert_eq!(response.header("retry-after"), Some("900"));
+            let response = testing::request(proxy.addr(), "HEAD", "/backup.tar", &[], b"");
+            assert_eq!(response.header("x-amz-storage-class"), Some("GLACIER"));
+            assert_eq!(response.header("x-amz-restore"), Some("ongoing-request=\"true\""));
+
+            let restored =
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"";
+            stub.lock().unwrap().objects.get_mut("backup.tar").unwrap().restore =
+                Some(restored.to_owned());
+
+            let response = get();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"Hello".to_vec());
+            assert_eq!(response.header("x-amz-restore"), Some(restored));
+            assert_eq!(restore("").status, 200);
+        }
+
+        #[test]
+        fn test_restore_not_archived() {
+            let (_stub, _s3, proxy) = start("STANDARD_IA");
+
+            let response = testing::request(proxy.addr(), "POST", "/backup.tar?restore", &[], b"");
+            assert_eq!(response.status, 409);
+            let response = testing::request(proxy.addr(), "GET", "/backup.tar", &[], b"");
+            assert_eq!(response.header("x-amz-storage-class"), Some("STANDARD_IA"));
+            let response = testing::request(proxy.addr(), "POST", "/backup.tar", &[], b"");
+            assert_eq!(response.status, 405);
+        }
+    }
+
+    mod propfind {
+        use actix_web::test::TestServer;
+        use aws_s3_webdav::integrity::{self, Hasher};
+        use testing::{self, Response, StoredObject, Stub};
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let (stub, s3, proxy) = testing::proxy(Stub::default(), |_| ());
+
+            for &(path, data) in &[("/a%20b.txt", "one"), ("/docs/c%25.txt", "two")] {
+                testing::request(proxy.addr(), "PUT", path, &[], data.as_bytes());
+            }
+            // empty collection
+            stub.lock().unwrap().objects.insert("empty/".to_owned(), StoredObject::default());
+
+            (stub, s3, proxy)
+        }
+
+        fn propfind(proxy: &TestServer, path: &str, depth: Option<&str>) -> (u16, String) {
+            let headers: Vec<(&str, &str)> = depth.into_iter().map(|d| ("Depth", d)).collect();
+            let Response { status, body, .. } =
+                testing::request(proxy.addr(), "PROPFIND", path, &headers, b"");
+
+            (status, String::from_utf8(body).unwrap())
+        }
+
+        fn hrefs(body: &str) -> Vec<&str> {
+            body.split("<D:href>").skip(1).map(|r| &r[..r.find("</D:href>").unwrap()]).collect()
+        }
+
+        #[test]
+        fn test_object() {
+            let (_stub, _s3, proxy) = start();
+            let mut hasher = Hasher::new();
+            hasher.update(b"one");
+            let sha256 = integrity::sha256_hex(&hasher.finish().sha256);
+
+            let (status, body) = propfind(&proxy, "/a%20b.txt", Some("1"));
+            assert_eq!(status, 207);
+            assert_eq!(hrefs(&body), vec!["/a%20b.txt"]);
+            assert!(body.contains("<D:resourcetype/>"), "{}", body);
+            assert!(body.contains(&format!("<S:sha256>{}</S:sha256>", sha256)), "{}", body);
+
+            // objects have no members, so depth doesn't matter
+            assert_eq!(propfind(&proxy, "/a%20b.txt", None).0, 207);
+            assert_eq!(propfind(&proxy, "/missing.txt", Some("0")).0, 404);
+        }
+
+        #[test]
+        fn test_collection() {
+            let (_stub, _s3, proxy) = start();
+
+            let (status, body) = propfind(&proxy, "/", Some("0"));
+            assert_eq!(status, 207);
+            assert_eq!(hrefs(&body), vec!["/"]);
+            assert!(body.contains("<D:collection/>"), "{}", body);
+
+            let (status, body) = propfind(&proxy, "/", Some("1"));
+            assert_eq!(status, 207);
+            assert_eq!(hrefs(&body), vec!["/", "/docs/", "/empty/", "/a%20b.txt"]);
+            assert!(body.contains("<D:getcontentlength>3</D:getcontentlength>"), "{}", body);
+            assert!(body.contains("GMT</D:getlastmodified>"), "{}", body);
+
+            // paths of collections without trailing `/` get one
+            let (status, body) = propfind(&proxy, "/docs", Some("1"));
+            assert_eq!(status, 207);
+            assert_eq!(hrefs(&body), vec!["/docs/", "/docs/c%25.txt"]);
+
+            assert_eq!(hrefs(&propfind(&proxy, "/empty/", Some("1")).1), vec!["/empty/"]);
+            assert_eq!(propfind(&proxy, "/missing/", Some("1")).0, 404);
+        }
+
+        #[test]
+        fn test_depth() {
+            let (_stub, _s3, proxy) = start();
+
+            let (status, body) = propfind(&proxy, "/docs/", Some("infinity"));
+            assert_eq!(status, 403);
+            assert!(body.contains("<D:propfind-finite-depth/>"), "{}", body);
+            assert_eq!(propfind(&proxy, "/docs/", None).0, 403);
+            assert_eq!(propfind(&proxy, "/docs/", Some("2")).0, 400);
+            assert_eq!(propfind(&proxy, "/missing/", None).0, 404);
+        }
+    }
+
+    mod versions {
+        use actix_web::test::TestServer;
+        use serde_json::{self, Value};
+        use testing::{self, Stub};
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let stub = Stub::default();
+            stub.lock().unwrap().versioning = true;
+
+            testing::proxy(stub, |_| ())
+        }
+
+        fn versions(proxy: &TestServer) -> Vec<Value> {
+            let response = testing::request(proxy.addr(), "GET", "/a.txt?versions", &[], b"");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("application/json"));
+
+            match serde_json::from_slice(&response.body).unwrap() {
+                Value::Array(versions) => versions,
+                other => panic!("Expected array, got {}", other),
+            }
+        }
+
+        #[test]
+        fn test_versions() {
+            let (_stub, _s3, proxy) = start();
+            let request = |method: &str, path: &str, body: &[u8]| {
+                testing::request(proxy.addr(), method, path, &[], body)
+            };
+
+            request("PUT", "/a.txt", b"one");
+            request("PUT", "/a.txt", b"two");
+            request("PUT", "/a.txt.bak", b"backup");
+
+            // uploads are copied in place to store their SHA-256, replacing uploaded version
+
+            let listed = versions(&proxy);
+            assert_eq!(listed.len(), 2);
+            assert_eq!(listed[0]["versionId"], "v4");
+            assert_eq!(listed[0]["isLatest"], true);
+            assert_eq!(listed[1]["versionId"], "v2");
+            assert_eq!(listed[1]["isLatest"], false);
+            assert_eq!(listed[1]["size"], 3);
+            assert_eq!(listed[1]["deleteMarker"], false);
+
+            let response = request("GET", "/a.txt?versionId=v2", b"");
+            assert_eq!(response.body, b"one".to_vec());
+            assert_eq!(response.header("x-amz-version-id"), Some("v2"));
+            let response = request("HEAD", "/a.txt?versionId=v2", b"");
+            assert_eq!(response.header("content-length"), Some("3"));
+            assert_eq!(request("GET", "/a.txt", b"").header("x-amz-version-id"), Some("v4"));
+
+            // restored version becomes the latest one, all versions are kept
+            assert_eq!(request("POST", "/a.txt?versionId=v2", b"").status, 200);
+            assert_eq!(request("GET", "/a.txt", b"").body, b"one".to_vec());
+            assert_eq!(versions(&proxy).len(), 3);
+            assert_eq!(request("POST", "/a.txt?versionId=v9", b"").status, 404);
+
+            request("DELETE", "/a.txt", b"");
+            let listed = versions(&proxy);
+            assert_eq!(listed.len(), 4);
+            assert_eq!(listed[0]["deleteMarker"], true);
+            assert_eq!(listed[0]["isLatest"], true);
+            assert_eq!(request("GET", "/b.txt?versions", b"").status, 404);
+        }
+    }
+
+    mod trash {
+        use actix_web::test::TestServer;
+        use env::TrashConfig;
+        use serde_json::{self, Value};
+        use std::time::Duration;
+        use testing::{self, Response, Stub};
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let (stub, s3, proxy) = testing::proxy(Stub::default(), |config| {
+                let retention = Duration::from_secs(30 * 24 * 3600);
+                config.trash = Some(TrashConfig::new(".trash", retention));
+            });
+
+            let objects = [("/docs/a.txt", "one"), ("/docs/b.txt", "two"), ("/c.txt", "3")];
+            for &(path, data) in &objects {
+                testing::request(proxy.addr(), "PUT", path, &[], data.as_bytes());
+            }
+
+            (stub, s3, proxy)
+        }
+
+        fn request(proxy: &TestServer, method: &str, path: &str) -> Response {
+            testing::request(proxy.addr(), method, path, &[], b"")
+        }
+
+        fn trash(proxy: &TestServer, path: &str) -> Vec<Value> {
+            let response = request(proxy, "GET", &format!("{}?trash", path));
+            assert_eq!(response.status, 200);
+
+            match serde_json::from_slice(&response.body).unwrap() {
+                Value::Array(entries) => entries,
+                other => panic!("Expected array, got {}", other),
+            }
+        }
+
+        fn trashed_keys(stub: &Stub) -> Vec<String> {
+            let stub = stub.lock().unwrap();
+            let mut keys: Vec<String> =
+                stub.objects.keys().filter(|k| k.starts_with(".trash/")).cloned().collect();
+            keys.sort();
+            keys
+        }
+
+        #[test]
+        fn test_delete_moves_to_trash() {
+            let (stub, _s3, proxy) = start();
+
+            assert_eq!(request(&proxy, "DELETE", "/c.txt").status, 200);
+            assert_eq!(request(&proxy, "GET", "/c.txt").status, 404);
+            let keys = trashed_keys(&stub);
+            assert_eq!(keys.len(), 1);
+            assert!(keys[0].ends_with("/c.txt"));
+            assert_eq!(
+                stub.lock().unwrap().objects[&keys[0]].metadata.get("trash-original-path"),
+                Some(&"/c.txt".to_owned())
+            );
+
+            // collection contents share trash directory
+            assert_eq!(request(&proxy, "DELETE", "/docs").status, 200);
+            assert_eq!(request(&proxy, "GET", "/docs/a.txt").status, 404);
+            let entries = trash(&proxy, "/docs/");
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0]["originalPath"], "/docs/a.txt");
+            assert_eq!(entries[1]["originalPath"], "/docs/b.txt");
+            assert_eq!(entries[0]["size"], 3);
+            let dir = |e: &Value| e["path"].as_str().unwrap().rsplitn(3, '/').nth(2).map(|d| {
+                d.to_owned()
+            });
+            assert_eq!(dir(&entries[0]), dir(&entries[1]));
+            assert_eq!(trash(&proxy, "/").len(), 3);
+
+            // original path is stored as is, not percent-encoded
+            request(&proxy, "PUT", "/a%20b.txt");
+            assert_eq!(request(&proxy, "DELETE", "/a%20b.txt").status, 200);
+            {
+                let stub = stub.lock().unwrap();
+                let key = stub.objects.keys().find(|k| k.ends_with("/a b.txt")).unwrap();
+                assert_eq!(
+                    stub.objects[key].metadata.get("trash-original-path"),
+                    Some(&"/a b.txt".to_owned())
+                );
+            }
+
+            assert_eq!(request(&proxy, "DELETE", "/missing.txt").status, 404);
+        }
+
+        #[test]
+        fn test_partial_delete_reports_moves() {
+            let (stub, _s3, proxy) = start();
+            testing::request(proxy.addr(), "PUT", "/docs/c.txt", &[], b"three");
+            // archived objects can't be copied
+            stub.lock().unwrap().objects.get_mut("docs/b.txt").unwrap().storage_class =
+                Some("GLACIER".to_owned());
+
+            let response = request(&proxy, "DELETE", "/docs/");
+            assert_eq!(response.status, 207);
+            let body = String::from_utf8(response.body).unwrap();
+            let keys = trashed_keys(&stub);
+            assert_eq!(keys.len(), 1);
+            assert!(keys[0].ends_with("/docs/a.txt"));
+            for status in &[
+                format!(
+                    "<D:href>/docs/a.txt</D:href><D:status>HTTP/1.1 200 OK</D:status>\
+                     <D:location><D:href>/{}</D:href></D:location>",
+                    keys[0]
+                ),
+                "<D:href>/docs/b.txt</D:href><D:status>HTTP/1.1 409 Conflict</D:status>"
+                    .to_owned(),
+                "<D:href>/docs/c.txt</D:href><D:status>HTTP/1.1 424 Failed Dependency</D:status>"
+                    .to_owned(),
+            ] {
+                assert!(body.contains(status.as_str()), "missing {} in\n{}", status, body);
+            }
+            assert_eq!(request(&proxy, "GET", "/docs/c.txt").status, 200);
+
+            // single object failure is reported as is
+            assert_eq!(request(&proxy, "DELETE", "/docs/b.txt").status, 409);
+        }
+
+        #[test]
+        fn test_delete_in_trash_is_permanent() {
+            let (stub, _s3, proxy) = start();
+
+            request(&proxy, "DELETE", "/c.txt");
+            let path = trash(&proxy, "/")[0]["path"].as_str().unwrap().to_owned();
+
+            assert_eq!(request(&proxy, "DELETE", &path).status, 200);
+            assert!(trashed_keys(&stub).is_empty());
+        }
+
+        #[test]
+        fn test_restore() {
+            let (stub, _s3, proxy) = start();
+
+            request(&proxy, "DELETE", "/c.txt");
+            let path = trash(&proxy, "/")[0]["path"].as_str().unwrap().to_owned();
+            assert_eq!(request(&proxy, "POST", &format!("{}?untrash", path)).status, 200);
+
+            assert_eq!(request(&proxy, "GET", "/c.txt").body, b"3".to_vec());
+            assert!(trashed_keys(&stub).is_empty());
+            let metadata = stub.lock().unwrap().objects["c.txt"].metadata.clone();
+            assert!(!metadata.contains_key("trash-original-path"));
+
+            // whole trash directory of deleted collection is restored at once
+            request(&proxy, "DELETE", "/docs/");
+            let path = trash(&proxy, "/")[0]["path"].as_str().unwrap().to_owned();
+            let dir = &path[..path.len() - "docs/a.txt".len()];
+            assert_eq!(request(&proxy, "POST", &format!("{}?untrash", dir)).status, 200);
+            assert_eq!(request(&proxy, "GET", "/docs/b.txt").body, b"two".to_vec());
+
+            assert_eq!(request(&proxy, "POST", "/docs/b.txt?untrash").status, 400);
+        }
+
+        #[test]
+        fn test_restore_never_overwrites() {
+            let (stub, _s3, proxy) = start();
+
+            request(&proxy, "DELETE", "/c.txt");
+            testing::request(proxy.addr(), "PUT", "/c.txt", &[], b"new");
+            let path = trash(&proxy, "/")[0]["path"].as_str().unwrap().to_owned();
+
+            assert_eq!(request(&proxy, "POST", &format!("{}?untrash", path)).status, 409);
+            assert_eq!(request(&proxy, "GET", "/c.txt").body, b"new".to_vec());
+            assert_eq!(trashed_keys(&stub).len(), 1);
+        }
+
+        #[test]
+        fn test_trash_disabled() {
+            let (stub, _s3, proxy) = testing::proxy(Stub::default(), |_| ());
+
+            testing::request(proxy.addr(), "PUT", "/c.txt", &[], b"3");
+            assert_eq!(request(&proxy, "GET", "/?trash").status, 404);
+            assert_eq!(request(&proxy, "DELETE", "/c.txt").status, 200);
+            assert!(stub.lock().unwrap().objects.is_empty());
+        }
+    }
+
+    mod listing {
+        use actix_web::test::TestServer;
+        use serde_json::{self, Value};
+        use testing::{self, Response, Stub};
+        use url::form_urlencoded;
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            let (stub, s3, proxy) = testing::proxy(Stub::default(), |_| ());
+
+            let objects = [
+                ("/docs/a.txt", "one"),
+                ("/docs/b.txt", "second"),
+                ("/docs/2018/c.txt", "3"),
+                ("/top.txt", "top"),
+            ];
+            for &(path, data) in &objects {
+                testing::request(proxy.addr(), "PUT", path, &[], data.as_bytes());
+            }
+
+            (stub, s3, proxy)
+        }
+
+        fn get(proxy: &TestServer, path: &str) -> Response {
+            testing::request(proxy.addr(), "GET", path, &[], b"")
+        }
+
+        fn get_json(proxy: &TestServer, path: &str) -> Value {
+            let accept = [("Accept", "application/json")];
+            let response = testing::request(proxy.addr(), "GET", path, &accept, b"");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("application/json"));
+
+            serde_json::from_slice(&response.body).unwrap()
+        }
+
+        fn names(listing: &Value) -> Vec<&str> {
+            listing["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| e["name"].as_str().unwrap())
+                .collect()
+        }
+
+        #[test]
+        fn test_html_listing() {
+            let (_stub, _s3, proxy) = start();
+
+            let response = get(&proxy, "/");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+            let html = String::from_utf8(response.body).unwrap();
+            assert!(html.contains("<a href=\"/docs/\">docs/</a>"));
+            assert!(html.contains("<a href=\"/top.txt\">top.txt</a>"));
+
+            let html = String::from_utf8(get(&proxy, "/docs/2018/").body).unwrap();
+            assert!(html.contains("<a href=\"/docs/\">docs</a> <a href=\"/docs/2018/\">2018</a>"));
+            assert!(html.contains("<a href=\"/docs/2018/c.txt\">c.txt</a>"));
+
+            let href = "/docs/a%23b%3Fc%25d";
+            testing::request(proxy.addr(), "PUT", href, &[], b"odd");
+            let html = String::from_utf8(get(&proxy, "/docs/").body).unwrap();
+            assert!(html.contains(&format!("<a href=\"{}\">a#b?c%d</a>", href)), "{}", html);
+            assert_eq!(get(&proxy, href).body, b"odd".to_vec());
+        }
+
+        #[test]
+        fn test_json_listing() {
+            let (_stub, _s3, proxy) = start();
+
+            let listing = get_json(&proxy, "/docs/");
+            assert_eq!(listing["path"], "/docs/");
+            assert_eq!(names(&listing), vec!["2018", "a.txt", "b.txt"]);
+            assert_eq!(listing["entries"][0]["collection"], true);
+            assert_eq!(listing["entries"][0]["path"], "/docs/2018/");
+            assert_eq!(listing["entries"][2]["size"], 6);
+            assert_eq!(listing["nextPage"], Value::Null);
+
+            let listing = get_json(&proxy, "/docs/?sort=size&order=desc");
+            assert_eq!(names(&listing), vec!["2018", "b.txt", "a.txt"]);
+            assert_eq!(get(&proxy, "/docs/?sort=owner").status, 400);
+        }
+
+        #[test]
+        fn test_pagination() {
+            let (_stub, _s3, proxy) = start();
+
+            let listing = get_json(&proxy, "/docs/?limit=2");
+            assert_eq!(names(&listing), vec!["2018", "a.txt"]);
+            let page: String =
+                form_urlencoded::byte_serialize(listing["nextPage"].as_str().unwrap().as_bytes())
+                    .collect();
+
+            let listing = get_json(&proxy, &format!("/docs/?limit=2&page={}", page));
+            assert_eq!(names(&listing), vec!["b.txt"]);
+            assert_eq!(listing["nextPage"], Value::Null);
+
+            assert_eq!(get(&proxy, "/docs/?limit=0").status, 400);
+        }
+
+        #[test]
+        fn test_missing_collection() {
+            let (_stub, _s3, proxy) = start();
+
+            let response = get(&proxy, "/docs");
+            assert_eq!(response.status, 301);
+            assert_eq!(response.header("location"), Some("/docs/"));
+
+            assert_eq!(get(&proxy, "/photos/").status, 404);
+            assert_eq!(get(&proxy, "/photos").status, 404);
+        }
+    }
+
+    mod website {
+        use actix_web::test::TestServer;
+        use env::WebsiteConfig;
+        use testing::{self, Response, Stub};
+
+        fn start(spa_fallback: Option<&'static str>) -> (Stub, TestServer, TestServer) {
+            let (stub, s3, proxy) = testing::proxy(Stub::default(), move |config| {
+                config.website = Some(WebsiteConfig {
+                    index_document: "index.html".to_owned(),
+                    error_document: Some("404.html".to_owned()),
+                    spa_fallback: spa_fallback.map(|d| d.to_owned()),
+                });
+            });
+
+            let objects = [
+                ("/index.html", "home"),
+                ("/docs/index.html", "docs"),
+                ("/404.html", "not found"),
+            ];
+            for &(path, data) in &objects {
+                testing::request(proxy.addr(), "PUT", path, &[], data.as_bytes());
+            }
+            let redirect = [("x-amz-website-redirect-location", "/docs/")];
+            testing::request(proxy.addr(), "PUT", "/old.html", &redirect, b"old");
+
+            (stub, s3, proxy)
+        }
+
+        fn get(proxy: &TestServer, path: &str) -> Response {
+            testing::request(proxy.addr(), "GET", path, &[], b"")
+        }
+
+        #[test]
+        fn test_index_documents() {
+            let (_stub, _s3, proxy) = start(None);
+
+            let response = get(&proxy, "/");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"home".to_vec());
+            assert_eq!(get(&proxy, "/docs/").body, b"docs".to_vec());
+
+            let response = get(&proxy, "/docs");
+            assert_eq!(response.status, 301);
+            assert_eq!(response.header("location"), Some("/docs/"));
+        }
+
+        #[test]
+        fn test_error_document() {
+            let (_stub, _s3, proxy) = start(None);
+
+            for path in &["/missing.html", "/photos/"] {
+                let response = get(&proxy, path);
+                assert_eq!(response.status, 404);
+                assert_eq!(response.body, b"not found".to_vec());
+            }
+        }
+
+        #[test]
+        fn test_website_redirect() {
+            let (_stub, _s3, proxy) = start(None);
+
+            let response = get(&proxy, "/old.html");
+            assert_eq!(response.status, 301);
+            assert_eq!(response.header("location"), Some("/docs/"));
+        }
+
+        #[test]
+        fn test_spa_fallback() {
+            let (_stub, _s3, proxy) = start(Some("index.html"));
+
+            let response = get(&proxy, "/users/42");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"home".to_vec());
+            assert_eq!(get(&proxy, "/404.html").body, b"not found".to_vec());
+        }
+    }
+
+    mod archive {
+        use actix_web::test::TestServer;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+        use testing::{self, Response, Stub};
+
+        fn start(max_entries: usize, max_size: u64) -> (Stub, TestServer, TestServer) {
+            let (stub, s3, proxy) = testing::proxy(Stub::default(), move |config| {
+                config.keyring = Some(testing::keyring("new"));
+                config.archive.max_entries = max_entries;
+                config.archive.max_size = max_size;
+            });
+
+            let objects = [
+                ("/docs/a.txt", "one"),
+                ("/docs/2018/c.txt", "three"),
+                ("/top.txt", "top"),
+            ];
+            for &(path, data) in &objects {
+                testing::request(proxy.addr(), "PUT", path, &[], data.as_bytes());
+            }
+
+            (stub, s3, proxy)
+        }
+
+        fn get(proxy: &TestServer, path: &str) -> Response {
+            testing::request(proxy.addr(), "GET", path, &[], b"")
+        }
+
+        fn contains(data: &[u8], part: &[u8]) -> bool {
+            data.windows(part.len()).any(|w| w == part)
+        }
+
+        #[test]
+        fn test_zip_archive() {
+            let (_stub, _s3, proxy) = start(10, 1024);
+
+            let response = get(&proxy, "/docs/?archive=zip");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("application/zip"));
+            assert_eq!(
+                response.header("content-disposition"),
+                Some("attachment; filename=\"docs.zip\"")
+            );
+
+            // objects are decrypted and stored as is, names are relative to the collection
+            let zip = response.body;
+            assert_eq!(&zip[..4], b"PK\x03\x04");
+            assert_eq!(&zip[zip.len() - 22..zip.len() - 18], b"PK\x05\x06");
+            assert!(contains(&zip, b"2018/c.txt"));
+            assert!(contains(&zip, b"three"));
+            assert!(contains(&zip, b"a.txt"));
+            assert!(!contains(&zip, b"docs/a.txt"));
+            assert!(contains(&zip, b"one"));
+            assert!(!contains(&zip, b"top"));
+
+            let response = get(&proxy, "/?archive=zip");
+            assert_eq!(response.status, 200);
+            assert_eq!(
+                response.header("content-disposition"),
+                Some("attachment; filename=\"bucket.zip\"")
+            );
+            assert!(contains(&response.body, b"docs/a.txt"));
+            assert!(contains(&response.body, b"top.txt"));
+        }
+
+        #[test]
+        fn test_tar_gz_archive() {
+            let (_stub, _s3, proxy) = start(10, 1024);
+
+            let response = get(&proxy, "/docs/?archive=tar.gz");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-type"), Some("application/gzip"));
+
+            let mut tar = Vec::new();
+            GzDecoder::new(&response.body[..]).read_to_end(&mut tar).unwrap();
+            assert_eq!(tar.len(), 512 * 6);
+            assert_eq!(&tar[..11], b"2018/c.txt\0");
+            assert_eq!(&tar[512..517], b"three");
+            assert_eq!(&tar[1024..1030], b"a.txt\0");
+            assert_eq!(&tar[1536..1539], b"one");
+        }
+
+        #[test]
+        fn test_archive_limits() {
+            let (_stub, _s3, proxy) = start(1, 1024);
+            assert_eq!(get(&proxy, "/docs/?archive=zip").status, 403);
+            assert_eq!(get(&proxy, "/docs/2018/?archive=zip").status, 200);
+
+            // limit applies to stored size, encrypted objects are larger than plaintext
+            let (_stub, _s3, proxy) = start(10, 32);
+            assert_eq!(get(&proxy, "/docs/?archive=zip").status, 403);
+        }
+
+        #[test]
+        fn test_invalid_archive_requests() {
+            let (_stub, _s3, proxy) = start(10, 1024);
+
+            assert_eq!(get(&proxy, "/docs/?archive=rar").status, 400);
+            assert_eq!(get(&proxy, "/docs/a.txt?archive=zip").status, 400);
+            assert_eq!(get(&proxy, "/music/?archive=zip").status, 404);
+        }
+    }
+
+    mod extract {
+        use actix_web::test::TestServer;
+        use aws_s3_webdav::archive::{ArchiveWriter, TarWriter};
+        use chrono::Utc;
+        use testing::{self, Response, Stub};
+
+        fn start(max_entries: usize) -> (Stub, TestServer, TestServer) {
+            testing::proxy(Stub::default(), move |config| {
+                config.archive.max_entries = max_entries
+            })
+        }
+
+        fn request(proxy: &TestServer, method: &str, path: &str, body: &[u8]) -> Response {
+            testing::request(proxy.addr(), method, path, &[], body)
+        }
+
+        fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+            let mut writer = TarWriter::new();
+            let mut tar = Vec::new();
+
+            for &(name, data) in entries {
+                tar.extend(writer.start_entry(name, data.len() as u64, &Utc::now()).unwrap());
+                tar.extend(writer.write(data).unwrap());
+                tar.extend(writer.finish_entry().unwrap());
+            }
+            tar.extend(writer.finish().unwrap());
+
+            tar
+        }
+
+        fn multistatus(response: &Response) -> String {
+            assert_eq!(response.status, 207);
+            assert_eq!(response.header("content-type"), Some("application/xml; charset=utf-8"));
+
+            String::from_utf8(response.body.clone()).unwrap()
+        }
+
+        #[test]
+        fn test_extract_downloaded_archive() {
+            for format in &["zip", "tar.gz"] {
+                let (_stub, _s3, proxy) = start(10);
+                request(&proxy, "PUT", "/docs/a.txt", b"one");
+                request(&proxy, "PUT", "/docs/2018/c.txt", b"three");
+                request(&proxy, "PUT", "/copy/a.txt", b"old");
+
+                let archive = request(&proxy, "GET", &format!("/docs/?archive={}", format), b"");
+                let path = format!("/copy/?extract={}", format);
+                let body = multistatus(&request(&proxy, "PUT", &path, &archive.body));
+
+                assert!(body.contains(
+                    "<D:response><D:href>/copy/2018/c.txt</D:href>\
+                     <D:status>HTTP/1.1 201 Created</D:status></D:response>"
+                ));
+                assert!(body.contains(
+                    "<D:response><D:href>/copy/a.txt</D:href>\
+                     <D:status>HTTP/1.1 204 No Content</D:status></D:response>"
+                ));
+                assert_eq!(request(&proxy, "GET", "/copy/2018/c.txt", b"").body, b"three");
+                assert_eq!(request(&proxy, "GET", "/copy/a.txt", b"").body, b"one");
+            }
+        }
+
+        #[test]
+        fn test_extract_unsafe_entries() {
+            let (_stub, _s3, proxy) = start(10);
+            let archive = tar(&[
+                ("../evil.txt", b"evil"),
+                ("dir/", b""),
+                ("dir/ok file.txt", b"ok"),
+            ]);
+
+            let response = request(&proxy, "POST", "/docs/?extract=tar", &archive);
+            let body = multistatus(&response);
+            assert!(body.contains(
+                "<D:response><D:href>/docs/</D:href>\
+                 <D:status>HTTP/1.1 400 Bad Request</D:status><D:responsedescription>\
+                 Entry &quot;../evil.txt&quot; escapes target collection\
+                 </D:responsedescription></D:response>"
+            ));
+            assert!(body.contains("<D:href>/docs/dir/ok%20file.txt</D:href>"));
+            assert!(!body.contains("<D:href>/docs/dir/</D:href>"));
+
+            assert_eq!(request(&proxy, "GET", "/evil.txt", b"").status, 404);
+            assert_eq!(request(&proxy, "GET", "/docs/dir/ok%20file.txt", b"").body, b"ok");
+        }
+
+        #[test]
+        fn test_extract_limits() {
+            let (_stub, _s3, proxy) = start(1);
+            let archive = tar(&[("a.txt", b"one"), ("b.txt", b"two")]);
+
+            let body = multistatus(&request(&proxy, "PUT", "/?extract=tar", &archive));
+            assert!(body.contains("<D:href>/a.txt</D:href><D:status>HTTP/1.1 201 Created"));
+            assert!(body.contains("<D:href>/</D:href><D:status>HTTP/1.1 403 Forbidden"));
+            assert_eq!(request(&proxy, "GET", "/b.txt", b"").status, 404);
+        }
+
+        #[test]
+        fn test_invalid_extract_requests() {
+            let (_stub, _s3, proxy) = start(10);
+            let archive = tar(&[("a.txt", b"one")]);
+
+            assert_eq!(request(&proxy, "PUT", "/docs/?extract=rar", &archive).status, 400);
+            assert_eq!(request(&proxy, "PUT", "/docs/a.txt?extract=tar", &archive).status, 400);
+            assert_eq!(request(&proxy, "PUT", "/docs/?extract=zip", &archive).status, 400);
+            assert_eq!(request(&proxy, "GET", "/docs/a.txt", b"").status, 404);
+
+            // entry which was being extracted when archive ended is reported
+            let response = request(&proxy, "PUT", "/docs/?extract=tar", &archive[..514]);
+            assert!(multistatus(&response).contains(
+                "<D:href>/docs/a.txt</D:href><D:status>HTTP/1.1 400 Bad Request</D:status>\
+                 <D:responsedescription>Truncated tar archive</D:responsedescription>"
+            ));
+            assert_eq!(request(&proxy, "GET", "/docs/a.txt", b"").status, 404);
+        }
+    }
+
+    mod tus {
+        use actix_web::test::TestServer;
+        use testing::{self, Response, Stub};
+        use env::TusConfig;
+
+        const MIB: usize = 1024 * 1024;
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            testing::proxy(Stub::default(), |config| {
+                config.tus = Some(TusConfig::new(".uploads"))
+            })
+        }
+
+        fn create(proxy: &TestServer, path: &str, length: usize, metadata: &str) -> Response {
+            let length = length.to_string();
+            let mut headers = vec![("Tus-Resumable", "1.0.0"), ("Upload-Length", &length)];
+            if !metadata.is_empty() {
+                headers.push(("Upload-Metadata", metadata));
+            }
+
+            testing::request(proxy.addr(), "POST", path, &headers, b"")
+        }
+
+        fn patch(proxy: &TestServer, location: &str, offset: usize, data: &[u8]) -> Response {
+            let offset = offset.to_string();
+            let headers = [
+                ("Tus-Resumable", "1.0.0"),
+                ("Content-Type", "application/offset+octet-stream"),
+                ("Upload-Offset", &offset),
+            ];
+
+            testing::request(proxy.addr(), "PATCH", location, &headers, data)
+        }
+
+        fn head(proxy: &TestServer, location: &str) -> Response {
+            testing::request(proxy.addr(), "HEAD", location, &[("Tus-Resumable", "1.0.0")], b"")
+        }
+
+        fn staged(stub: &Stub) -> usize {
+            let stub = stub.lock().unwrap();
+            stub.objects.keys().filter(|k| k.starts_with(".uploads/")).count()
+        }
+
+        #[test]
+        fn test_resumable_upload() {
+            let (stub, _s3, proxy) = start();
+            let data: Vec<u8> = (0..6 * MIB).map(|i| (i % 251) as u8).collect();
+
+            // "text/plain"
+            let metadata = "filetype dGV4dC9wbGFpbg==";
+            let response = create(&proxy, "/docs/big.bin", data.len(), metadata);
+            assert_eq!(response.status, 201);
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+            let location = response.header("location").unwrap().to_owned();
+            assert!(location.starts_with("/docs/big.bin?tus="));
+
+            let response = head(&proxy, &location);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("upload-offset"), Some("0"));
+            assert_eq!(response.header("upload-length"), Some("6291456"));
+            assert_eq!(response.header("upload-metadata"), Some(metadata));
+            assert_eq!(response.header("cache-control"), Some("no-store"));
+
+            // less than a part is staged
+            let response = patch(&proxy, &location, 0, &data[..3 * MIB]);
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("upload-offset"), Some("3145728"));
+            assert_eq!(head(&proxy, &location).header("upload-offset"), Some("3145728"));
+            assert_eq!(staged(&stub), 1);
+
+            // a whole part is uploaded, the rest staged
+            let response = patch(&proxy, &location, 3 * MIB, &data[3 * MIB..11 * MIB / 2]);
+            assert_eq!(response.header("upload-offset"), Some("5767168"));
+            assert_eq!(head(&proxy, &location).header("upload-offset"), Some("5767168"));
+            assert_eq!(stub.lock().unwrap().uploads.values().next().unwrap().parts.len(), 1);
+
+            assert_eq!(patch(&proxy, &location, 3 * MIB, &data[3 * MIB..]).status, 409);
+
+            let response = patch(&proxy, &location, 11 * MIB / 2, &data[11 * MIB / 2..]);
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("upload-offset"), Some("6291456"));
+
+            let response = testing::request(proxy.addr(), "GET", "/docs/big.bin", &[], b"");
+            assert_eq!(response.body, data);
+            assert_eq!(response.header("content-type"), Some("text/plain"));
+            assert_eq!(staged(&stub), 0);
+            assert!(stub.lock().unwrap().uploads.is_empty());
+
+            // completed upload is still reported, repeating the last PATCH is fine
+            let response = head(&proxy, &location);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("upload-offset"), Some("6291456"));
+            assert_eq!(response.header("upload-length"), Some("6291456"));
+            assert_eq!(response.header("upload-metadata"), Some(metadata));
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+            let response = patch(&proxy, &location, 6 * MIB, b"");
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("upload-offset"), Some("6291456"));
+            assert_eq!(patch(&proxy, &location, 0, b"hello").status, 409);
+        }
+
+        #[test]
+        fn test_upload_to_collection() {
+            let (stub, _s3, proxy) = start();
+
+            // "a b.txt"
+            let response = create(&proxy, "/docs/", 5, "filename YSBiLnR4dA==");
+            assert_eq!(response.status, 201);
+            let location = response.header("location").unwrap().to_owned();
+            assert!(location.starts_with("/docs/a%20b.txt?tus="));
+            assert_eq!(patch(&proxy, &location, 0, b"hello").status, 204);
+            let response = testing::request(proxy.addr(), "GET", "/docs/a%20b.txt", &[], b"");
+            assert_eq!(response.body, b"hello");
+
+            // "../x"
+            assert_eq!(create(&proxy, "/docs/", 5, "filename Li4veA==").status, 400);
+            assert_eq!(create(&proxy, "/", 5, "").status, 400);
+
+            // empty uploads are complete right away
+            assert_eq!(create(&proxy, "/empty.txt", 0, "").status, 201);
+            let response = testing::request(proxy.addr(), "GET", "/empty.txt", &[], b"");
+            assert_eq!(response.status, 200);
+            assert!(response.body.is_empty());
+            assert_eq!(staged(&stub), 0);
+        }
+
+        #[test]
+        fn test_terminate_upload() {
+            let (stub, _s3, proxy) = start();
+            let location = create(&proxy, "/a.txt", 10, "").header("location").unwrap().to_owned();
+            assert_eq!(patch(&proxy, &location, 0, b"hello").status, 204);
+
+            let headers = [("Tus-Resumable", "1.0.0")];
+            let response = testing::request(proxy.addr(), "DELETE", &location, &headers, b"");
+            assert_eq!(response.status, 204);
+            let response = head(&proxy, &location);
+            assert_eq!(response.status, 404);
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+            assert_eq!(patch(&proxy, &location, 5, b"world").status, 404);
+            assert_eq!(staged(&stub), 0);
+            assert_eq!(stub.lock().unwrap().aborted, 1);
+            assert_eq!(testing::request(proxy.addr(), "GET", "/a.txt", &[], b"").status, 404);
+        }
+
+        #[test]
+        fn test_invalid_requests() {
+            let (_stub, _s3, proxy) = start();
+            let location = create(&proxy, "/a.txt", 10, "").header("location").unwrap().to_owned();
+
+            assert_eq!(create(&proxy, "/b.txt", 6 << 40, "").status, 413);
+            assert_eq!(create(&proxy, "/b.txt", 10, "filename !!!").status, 400);
+            let headers = [("Tus-Resumable", "1.0.0")];
+            let response = testing::request(proxy.addr(), "POST", "/b.txt", &headers, b"");
+            assert_eq!(response.status, 400);
+
+            let headers = [("Tus-Resumable", "0.2.2"), ("Upload-Length", "10")];
+            let response = testing::request(proxy.addr(), "POST", "/b.txt", &headers, b"");
+            assert_eq!(response.status, 412);
+            assert_eq!(response.header("tus-version"), Some("1.0.0"));
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+
+            let headers = [("Tus-Resumable", "1.0.0"), ("Upload-Offset", "0")];
+            let response = testing::request(proxy.addr(), "PATCH", &location, &headers, b"hello");
+            assert_eq!(response.status, 415);
+            assert_eq!(patch(&proxy, &location, 0, b"hello world").status, 413);
+            assert_eq!(patch(&proxy, "/a.txt", 0, b"hello").status, 405);
+            let response = patch(&proxy, "/a.txt?tus=unknown", 0, b"hello");
+            assert_eq!(response.status, 404);
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+            let response = patch(&proxy, &location, 5, b"hello");
+            assert_eq!(response.status, 409);
+            assert_eq!(response.header("tus-resumable"), Some("1.0.0"));
+            assert_eq!(head(&proxy, &location).header("upload-offset"), Some("0"));
+        }
+
+        #[test]
+        fn test_options() {
+            let (_stub, _s3, proxy) = start();
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &[], b"");
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("tus-version"), Some("1.0.0"));
+            assert_eq!(response.header("tus-extension"), Some("creation,termination"));
+            assert_eq!(response.header("tus-max-size"), Some("5497558138880"));
+            assert!(response.header("allow").unwrap().contains("PATCH"));
+
+            let (_, _s3, proxy) = testing::proxy(Stub::default(), |_| ());
+            let response = testing::request(proxy.addr(), "OPTIONS", "/", &[], b"");
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("tus-version"), None);
+            let headers = [("Tus-Resumable", "1.0.0"), ("Upload-Length", "10")];
+            let response = testing::request(proxy.addr(), "POST", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 404);
+        }
+    }
+
+    mod form {
+        use actix_web::test::TestServer;
+        use serde_json::{self, Value};
+        use testing::{self, Response, Stub};
+
+        const CONTENT_TYPE: &str = "multipart/form-data; boundary=----boundary";
+
+        fn start() -> (Stub, TestServer, TestServer) {
+            testing::proxy(Stub::default(), |_| ())
+        }
+
+        /// Form part: field name, file name, content type and data
+        type Part<'a> = (&'a str, Option<&'a str>, Option<&'a str>, &'a [u8]);
+
+        fn form(parts: &[Part]) -> Vec<u8> {
+            let mut body = Vec::new();
+
+            for &(name, filename, content_type, data) in parts {
+                body.extend(b"------boundary\r\n");
+                let disposition = match filename {
+                    Some(filename) => format!("name=\"{}\"; filename=\"{}\"", name, filename),
+                    None => format!("name=\"{}\"", name),
+                };
+                let disposition = format!("Content-Disposition: form-data; {}\r\n", disposition);
+                body.extend(disposition.bytes());
+                if let Some(content_type) = content_type {
+                    body.extend(format!("Content-Type: {}\r\n", content_type).bytes());
+                }
+                body.extend(b"\r\n");
+                body.extend(data);
+                body.extend(b"\r\n");
+            }
+            body.extend(b"------boundary--\r\n");
+
+            body
+        }
+
+        fn post(proxy: &TestServer, path: &str, accept: &str, body: &[u8]) -> Response {
+            let headers = [("Content-Type", CONTENT_TYPE), ("Accept", accept)];
+            testing::request(proxy.addr(), "POST", path, &headers, body)
+        }
+
+        #[test]
+        fn test_upload_files() {
+            let (stub, _s3, proxy) = start();
+            testing::request(proxy.addr(), "PUT", "/docs/b.txt", &[], b"old");
+            let body = form(&[
+                ("title", None, None, b"Holidays"),
+                ("files", Some("a b.txt"), Some("text/plain"), b"hello"),
+                ("files", Some("b.txt"), None, b"world"),
+                ("more", Some(""), Some("application/octet-stream"), b""),
+            ]);
+
+            let response = post(&proxy, "/docs/", "application/json", &body);
+            assert_eq!(response.status, 200);
+            let files: Value = serde_json::from_slice(&response.body).unwrap();
+            let files = files.as_array().unwrap();
+            assert_eq!(files.len(), 2);
+            assert_eq!(files[0]["field"], "files");
+            assert_eq!(files[0]["name"], "a b.txt");
+            assert_eq!(files[0]["path"], "/docs/a%20b.txt");
+            assert_eq!(files[0]["size"], 5);
+            assert_eq!(files[0]["contentType"], "text/plain");
+            assert_eq!(files[0]["created"], true);
+            assert!(files[0]["eTag"].is_string());
+            assert_eq!(files[1]["path"], "/docs/b.txt");
+            assert_eq!(files[1]["contentType"], Value::Null);
+            assert_eq!(files[1]["created"], false);
+
+            let response = testing::request(proxy.addr(), "GET", "/docs/a%20b.txt", &[], b"");
+            assert_eq!(response.body, b"hello");
+            assert_eq!(response.header("content-type"), Some("text/plain"));
+            assert_eq!(stub.lock().unwrap().objects["docs/b.txt"].data, b"world");
+            assert_eq!(stub.lock().unwrap().objects.len(), 2);
+        }
+
+        #[test]
+        fn test_redirect() {
+            let (stub, _s3, proxy) = start();
+            let body = form(&[("file", Some("photos/cat.jpg"), Some("image/jpeg"), b"meow")]);
+            let accept = "text/html,*/*";
+
+            let response = post(&proxy, "/", accept, &body);
+            assert_eq!(response.status, 303);
+            assert_eq!(response.header("location"), Some("/"));
+            assert_eq!(stub.lock().unwrap().objects["photos/cat.jpg"].data, b"meow");
+
+            let response = post(&proxy, "/photos/?redirect=/done.html", accept, &body);
+            assert_eq!(response.status, 303);
+            assert_eq!(response.header("location"), Some("/done.html"));
+            assert!(stub.lock().unwrap().objects.contains_key("photos/photos/cat.jpg"));
+
+            for redirect in &["//evil.com/", "/%5Cevil.com", "http://evil.com/"] {
+                let path = format!("/?redirect={}", redirect);
+                assert_eq!(post(&proxy, &path, accept, &body).status, 400);
+            }
+        }
+
+        #[test]
+        fn test_invalid_forms() {
+            let (stub, _s3, proxy) = start();
+            let body = form(&[("file", Some("../evil.txt"), None, b"evil")]);
+            assert_eq!(post(&proxy, "/docs/", "application/json", &body).status, 400);
+
+            let body = form(&[("file", Some("a.txt"), None, b"hello")]);
+            assert_eq!(post(&proxy, "/docs/a.txt", "application/json", &body).status, 400);
+            assert_eq!(post(&proxy, "/docs/", "application/json", &body[..40]).status, 400);
+
+            let headers = [("Content-Type", "multipart/form-data")];
+            let response = testing::request(proxy.addr(), "POST", "/docs/", &headers, &body);
+            assert_eq!(response.status, 400);
+            assert!(stub.lock().unwrap().objects.is_empty());
+        }
+    }
+
+    mod cors {
+        use actix_web::test::TestServer;
+        use aws_s3_webdav::cors::{Policy, DEFAULT_METHODS};
+        use testing::{self, Stub};
+
+        const ORIGIN: &str = "https://app.example.com";
+
+        fn start(origins: &'static str, credentials: bool) -> (Stub, TestServer, TestServer) {
+            testing::proxy(Stub::default(), move |config| {
+                let policy =
+                    Policy::new(origins, DEFAULT_METHODS, "*", "ETag", credentials, Some(600));
+                config.cors = Some(policy.unwrap());
+            })
+        }
+
+        #[test]
+        fn test_preflight() {
+            let (_stub, _s3, proxy) = start(ORIGIN, true);
+            let headers = [
+                ("Origin", ORIGIN),
+                ("Access-Control-Request-Method", "MOVE"),
+                ("Access-Control-Request-Headers", "Destination, Overwrite"),
+            ];
+
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("access-control-allow-origin"), Some(ORIGIN));
+            assert_eq!(response.header("access-control-allow-methods"), Some(DEFAULT_METHODS));
+            assert_eq!(
+                response.header("access-control-allow-headers"),
+                Some("destination, overwrite")
+            );
+            assert_eq!(response.header("access-control-allow-credentials"), Some("true"));
+            assert_eq!(response.header("access-control-max-age"), Some("600"));
+            assert_eq!(response.header("vary"), Some("Origin"));
+
+            // at bucket root too
+            let response = testing::request(proxy.addr(), "OPTIONS", "/", &headers, b"");
+            assert_eq!(response.status, 204);
+
+            let headers = [
+                ("Origin", "https://evil.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ];
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 403);
+            assert_eq!(response.header("access-control-allow-origin"), None);
+
+            let headers = [("Origin", ORIGIN), ("Access-Control-Request-Method", "PROPFIND")];
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 204);
+
+            let headers = [("Origin", ORIGIN), ("Access-Control-Request-Method", "TRACE")];
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 403);
+
+            // not a preflight request
+            let response = testing::request(proxy.addr(), "OPTIONS", "/a.txt", &[], b"");
+            assert_eq!(response.status, 204);
+            assert!(response.header("allow").is_some());
+        }
+
+        #[test]
+        fn test_actual_requests() {
+            let origins = "https://app.example.com, https://b.example.com";
+            let (_stub, _s3, proxy) = start(origins, false);
+            let headers = [("Origin", ORIGIN)];
+
+            let response = testing::request(proxy.addr(), "PUT", "/a.txt", &headers, b"hello");
+            assert_eq!(response.status, 201);
+            assert_eq!(response.header("access-control-allow-origin"), Some(ORIGIN));
+            assert_eq!(response.header("access-control-expose-headers"), Some("ETag"));
+            assert_eq!(response.header("access-control-allow-credentials"), None);
+            assert_eq!(response.header("vary"), Some("Origin"));
+
+            // errors too, so scripts can tell what's wrong
+            let response = testing::request(proxy.addr(), "GET", "/missing.txt", &headers, b"");
+            assert_eq!(response.status, 404);
+            assert_eq!(response.header("access-control-allow-origin"), Some(ORIGIN));
+
+            let headers = [("Origin", "https://evil.com")];
+            let response = testing::request(proxy.addr(), "GET", "/a.txt", &headers, b"");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("access-control-allow-origin"), None);
+            assert_eq!(response.header("vary"), Some("Origin"));
+
+            let response = testing::request(proxy.addr(), "GET", "/a.txt", &[], b"");
+            assert_eq!(response.header("access-control-allow-origin"), None);
+        }
+
+        #[test]
+        fn test_any_origin() {
+            let (_stub, _s3, proxy) = start("*", false);
+            let headers = [("Origin", ORIGIN)];
+
+            let response = testing::request(proxy.addr(), "GET", "/", &headers, b"");
+            assert_eq!(response.header("access-control-allow-origin"), Some("*"));
+            assert_eq!(response.header("vary"), None);
+        }
+    }
 }
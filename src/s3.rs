@@ -0,0 +1,595 @@
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, InternalError,
+};
+use actix_web::{http, Error, HttpResponse};
+use aws_s3_webdav::envelope::{self, DataKey, DecryptChunks};
+use aws_s3_webdav::in_flight::OpenUpload;
+use aws_s3_webdav::retry::{self, error_code, is_retryable_error_body};
+use aws_s3_webdav::sse::CUSTOMER_ALGORITHM;
+use aws_s3_webdav::storage_class::RestoreStatus;
+use env::{AppEnv, Encryption};
+use futures::future::{self, Loop};
+use futures::{Future, IntoFuture, Stream};
+use rusoto_s3::*;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::Instant;
+use tokio_current_thread::TaskExecutor;
+
+/// Errors which may be caused by transient S3 failures
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+macro_rules! impl_retryable {
+    ($($error:ident),*) => {
+        $(
+            impl Retryable for $error {
+                fn is_retryable(&self) -> bool {
+                    match *self {
+                        $error::HttpDispatch(_) => true,
+                        $error::Unknown(ref body) => is_retryable_error_body(body),
+                        _ => false,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_retryable!(
+    AbortMultipartUploadError,
+    CompleteMultipartUploadError,
+    CopyObjectError,
+    CreateMultipartUploadError,
+    DeleteObjectError,
+    GetObjectError,
+    GetObjectTaggingError,
+    HeadObjectError,
+    ListMultipartUploadsError,
+    ListObjectVersionsError,
+    ListObjectsV2Error,
+    ListPartsError,
+    PutObjectError,
+    RestoreObjectError,
+    UploadPartCopyError,
+    UploadPartError
+);
+
+/// Encryption request fields for an object, as configured
+#[derive(Clone, Debug, Default)]
+pub struct Sse {
+    pub server_side_encryption: Option<String>,
+    pub ssekms_key_id: Option<String>,
+    pub customer_algorithm: Option<String>,
+    pub customer_key: Option<String>,
+    pub customer_key_md5: Option<String>,
+}
+
+impl Sse {
+    pub fn for_key(env: &AppEnv, key: &str) -> Sse {
+        match env.config.encryption {
+            Encryption::Default => Sse::default(),
+            Encryption::Managed {
+                ref algorithm,
+                ref kms_key_id,
+            } => Sse {
+                server_side_encryption: Some(algorithm.to_owned()),
+                ssekms_key_id: kms_key_id.to_owned(),
+                ..Sse::default()
+            },
+            Encryption::Customer(ref keys) => match keys.for_key(key) {
+                Some(customer) => Sse {
+                    customer_algorithm: Some(CUSTOMER_ALGORITHM.to_owned()),
+                    customer_key: Some(customer.key.to_owned()),
+                    customer_key_md5: Some(customer.key_md5.to_owned()),
+                    ..Sse::default()
+                },
+                None => Sse::default(),
+            },
+        }
+    }
+}
+
+/// Run S3 operation with configured retry policy, `operation` is called for every attempt.
+/// Attempts and retries are counted in metrics.
+pub fn with_retry<F, R>(
+    env: &AppEnv,
+    name: &'static str,
+    mut operation: F,
+) -> Box<Future<Item = R::Item, Error = R::Error>>
+where
+    F: FnMut() -> R + 'static,
+    R: IntoFuture + 'static,
+    R::Future: 'static,
+    R::Item: 'static,
+    R::Error: Retryable + Display + 'static,
+{
+    let metrics = env.metrics.clone();
+    let retries = env.metrics.clone();
+    let attempt = move || {
+        let metrics = metrics.clone();
+        let started = Instant::now();
+
+        operation().into_future().then(move |r| {
+            metrics.s3_request(name, started.elapsed(), r.is_err());
+            r
+        })
+    };
+
+    retry::retry(env.config.retry, attempt, move |e: &R::Error, attempt| {
+        if e.is_retryable() {
+            warn!("S3 {} attempt {} failed, retrying: {}", name, attempt, e);
+            retries.s3_retry(name);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// HEAD object, missing objects are reported as 404
+pub fn head_object(
+    env: &AppEnv,
+    bucket: &str,
+    key: &str,
+) -> Box<Future<Item=HeadObjectOutput, Error=Error>> {
+    head_object_version(env, bucket, key, None)
+}
+
+/// HEAD given version of object, latest one if `version_id` is `None`
+pub fn head_object_version(
+    env: &AppEnv,
+    bucket: &str,
+    key: &str,
+    version_id: Option<String>,
+) -> Box<Future<Item=HeadObjectOutput, Error=Error>> {
+    let state = env.clone();
+    let sse = Sse::for_key(env, key);
+    let request = HeadObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id,
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..HeadObjectRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "head_object", move || state.s3.head_object(request.clone())).map_err(
+            |e| head_object_error(e).unwrap_or_else(|| ErrorNotFound("Object not found")),
+        ),
+    )
+}
+
+/// Error of HEAD request, `None` if object is missing. HEAD responses have no body, so errors
+/// come with one standing for their status (see `StatusDispatcher`).
+pub fn head_object_error(e: HeadObjectError) -> Option<Error> {
+    match e {
+        HeadObjectError::NoSuchKey(_) => None,
+        HeadObjectError::Unknown(ref e) if error_code(e) == Some("NotFound") => None,
+        HeadObjectError::Unknown(ref e) if error_code(e) == Some("AccessDenied") => {
+            Some(ErrorForbidden("Access to object is denied"))
+        }
+        HeadObjectError::HttpDispatch(e) => Some(ErrorInternalServerError(e)),
+        HeadObjectError::Credentials(e) => Some(ErrorForbidden(e)),
+        HeadObjectError::Validation(e) => Some(ErrorBadRequest(e)),
+        HeadObjectError::Unknown(e) => Some(ErrorInternalServerError(e)),
+    }
+}
+
+/// Check if object exists, failing if that can't be told (e.g. access is denied)
+pub fn object_exists(
+    env: &AppEnv,
+    bucket: &str,
+    key: &str,
+) -> Box<Future<Item=bool, Error=Error>> {
+    let state = env.clone();
+    let sse = Sse::for_key(env, key);
+    let request = HeadObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..HeadObjectRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "head_object", move || state.s3.head_object(request.clone())).then(|r| {
+            match r {
+                Ok(_) => Ok(true),
+                Err(e) => match head_object_error(e) {
+                    Some(e) => Err(e),
+                    None => Ok(false),
+                },
+            }
+        }),
+    )
+}
+
+/// Seconds clients are asked to wait before retrying reads of objects being restored
+pub const RESTORE_RETRY_AFTER: u64 = 900;
+
+/// Error for reads of archived objects: 409 until restore is requested, then 503 with
+/// `Retry-After` while it's in progress
+pub fn not_restored(status: &RestoreStatus) -> Error {
+    let (message, mut response) = match *status {
+        RestoreStatus::InProgress => {
+            let mut response = HttpResponse::ServiceUnavailable();
+            response.header(http::header::RETRY_AFTER, RESTORE_RETRY_AFTER.to_string());
+            ("Object is being restored from archive, retry later", response)
+        }
+        _ => (
+            "Object is archived, it has to be restored with POST ?restore first",
+            HttpResponse::Conflict(),
+        ),
+    };
+    let response = response.body(message);
+
+    InternalError::from_response(message, response).into()
+}
+
+/// Restore status of object from its HEAD response
+pub fn restore_status(object: &HeadObjectOutput) -> RestoreStatus {
+    RestoreStatus::new(object.storage_class.as_deref(), object.restore.as_deref())
+}
+
+/// S3 error body for reads of objects in archive storage class
+pub fn is_not_restored_error_body(body: &str) -> bool {
+    body.contains("<Code>InvalidObjectState</Code>")
+}
+
+/// GET object, or given version of it, `None` if there's no such object. Reads of archived
+/// objects fail with `not_restored` errors.
+pub fn get_object(
+    env: &AppEnv,
+    key: &str,
+    version: Option<String>,
+) -> Box<Future<Item=Option<GetObjectOutput>, Error=Error>> {
+    let state = env.clone();
+    let sse = Sse::for_key(env, key);
+    let request = GetObjectRequest {
+        bucket: env.config.s3.bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version.to_owned(),
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..GetObjectRequest::default()
+    };
+
+    let env = env.clone();
+    let bucket = env.config.s3.bucket.to_owned();
+    let key = key.to_owned();
+
+    Box::new(
+        with_retry(&env, "get_object", move || state.s3.get_object(request.clone()))
+            .map(Some)
+            .or_else(move |e| -> Box<Future<Item=Option<GetObjectOutput>, Error=Error>> {
+                Box::new(future::err(match e {
+                    // archived objects can't be read, HEAD tells whether restore is in progress
+                    GetObjectError::Unknown(ref e) if is_not_restored_error_body(e) => {
+                        return Box::new(
+                            head_object_version(&env, &bucket, &key, version)
+                                .and_then(|object| Err(not_restored(&restore_status(&object)))),
+                        );
+                    }
+                    GetObjectError::NoSuchKey(_) => return Box::new(future::ok(None)),
+                    // http://rusoto.github.io/rusoto/rusoto_s3/enum.GetObjectError.html
+                    GetObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
+                    GetObjectError::Credentials(e) => ErrorForbidden(e),
+                    GetObjectError::Validation(e) => ErrorBadRequest(e),
+                    GetObjectError::Unknown(e) => ErrorInternalServerError(e),
+                }))
+            }),
+    )
+}
+
+/// Data key of client-side encrypted object, `None` for objects stored as is
+pub fn data_key(
+    env: &AppEnv,
+    metadata: &Option<HashMap<String, String>>,
+) -> Result<Option<DataKey>, Error> {
+    let wrapped = metadata.as_ref().and_then(|m| m.get(envelope::METADATA_KEY));
+
+    match (wrapped, &env.config.keyring) {
+        (None, _) => Ok(None),
+        (Some(wrapped), &Some(ref keyring)) => keyring.data_key(wrapped).map(Some).map_err(|e| {
+            error!("Failed to unwrap object data key: {}", e);
+            ErrorInternalServerError("Object can't be decrypted")
+        }),
+        (Some(_), &None) => Err(ErrorInternalServerError("Object is encrypted, no keyring set")),
+    }
+}
+
+/// Size of stored object as seen by clients, encrypted objects are larger than plaintext
+pub fn plaintext_length(length: i64, metadata: &Option<HashMap<String, String>>) -> i64 {
+    match metadata.as_ref().map(|m| m.contains_key(envelope::METADATA_KEY)) {
+        Some(true) => envelope::plaintext_size(length as u64).map_or(length, |l| l as i64),
+        _ => length,
+    }
+}
+
+/// Body of fetched object as seen by clients, decrypted with data key of client-side
+/// encrypted object
+pub fn plaintext_body(
+    body: StreamingBody,
+    key: Option<DataKey>,
+) -> Box<Stream<Item=Vec<u8>, Error=Error>> {
+    let body = body.map_err(|_e| {
+        ErrorInternalServerError("Something went wrong with body stream")
+    });
+
+    match key {
+        Some(key) => Box::new(DecryptChunks::new(body, key, |e| {
+            error!("Failed to decrypt object: {}", e);
+            ErrorInternalServerError("Object can't be decrypted")
+        })),
+        None => Box::new(body),
+    }
+}
+
+fn delete_object_error(e: DeleteObjectError) -> Error {
+    match e {
+        // http://rusoto.github.io/rusoto/rusoto_s3/enum.DeleteObjectError.html
+        DeleteObjectError::HttpDispatch(e) => ErrorInternalServerError(e),
+        DeleteObjectError::Credentials(e) => ErrorForbidden(e),
+        DeleteObjectError::Validation(e) => ErrorBadRequest(e),
+        DeleteObjectError::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Delete object, deleting missing objects succeeds
+pub fn delete_object(env: &AppEnv, bucket: &str, key: &str) -> Box<Future<Item=(), Error=Error>> {
+    delete_version(env, bucket, key, None)
+}
+
+/// Delete given version of object, or object itself if `version_id` is not given
+pub fn delete_version(
+    env: &AppEnv,
+    bucket: &str,
+    key: &str,
+    version_id: Option<String>,
+) -> Box<Future<Item=(), Error=Error>> {
+    let state = env.clone();
+    let request = DeleteObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        version_id: version_id,
+        ..DeleteObjectRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "delete_object", move || state.s3.delete_object(request.clone()))
+            .map_err(delete_object_error)
+            .map(|_| ()),
+    )
+}
+
+fn list_objects_error(e: ListObjectsV2Error) -> Error {
+    match e {
+        // http://rusoto.github.io/rusoto/rusoto_s3/enum.ListObjectsV2Error.html
+        ListObjectsV2Error::NoSuchBucket(e) => ErrorInternalServerError(e),
+        ListObjectsV2Error::HttpDispatch(e) => ErrorInternalServerError(e),
+        ListObjectsV2Error::Credentials(e) => ErrorForbidden(e),
+        ListObjectsV2Error::Validation(e) => ErrorBadRequest(e),
+        ListObjectsV2Error::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Single page of objects with key prefix, `continuation_token` of the next page is returned
+/// if listing is truncated. Keys are grouped by `delimiter` into common prefixes if given.
+pub fn list_objects_page(
+    env: &AppEnv,
+    prefix: &str,
+    delimiter: Option<&str>,
+    continuation_token: Option<String>,
+    max_keys: Option<i64>,
+) -> Box<Future<Item=ListObjectsV2Output, Error=Error>> {
+    let state = env.clone();
+    let request = ListObjectsV2Request {
+        bucket: env.config.s3.bucket.to_owned(),
+        prefix: Some(prefix.to_owned()),
+        delimiter: delimiter.map(|d| d.to_owned()),
+        continuation_token: continuation_token,
+        max_keys: max_keys,
+        ..ListObjectsV2Request::default()
+    };
+
+    Box::new(
+        with_retry(env, "list_objects_v2", move || state.s3.list_objects_v2(request.clone()))
+            .map_err(list_objects_error),
+    )
+}
+
+/// All objects with key prefix, in key order
+pub fn list_objects(env: &AppEnv, prefix: &str) -> Box<Future<Item=Vec<Object>, Error=Error>> {
+    let env = env.clone();
+    let prefix = prefix.to_owned();
+
+    Box::new(future::loop_fn((vec![], None), move |(mut objects, token)| {
+        list_objects_page(&env, &prefix, None, token, None).map(move |output| {
+            objects.extend(output.contents.unwrap_or_default());
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    Loop::Continue((objects, Some(token)))
+                }
+                _ => Loop::Break(objects),
+            }
+        })
+    }))
+}
+
+/// All members of collection with key prefix (ending with `/`): common prefixes of nested
+/// collections and objects, in key order
+pub fn list_collection(
+    env: &AppEnv,
+    prefix: &str,
+) -> Box<Future<Item=(Vec<CommonPrefix>, Vec<Object>), Error=Error>> {
+    let env = env.clone();
+    let prefix = prefix.to_owned();
+
+    Box::new(future::loop_fn((vec![], vec![], None), move |(mut prefixes, mut objects, token)| {
+        list_objects_page(&env, &prefix, Some("/"), token, None).map(move |output| {
+            prefixes.extend(output.common_prefixes.unwrap_or_default());
+            objects.extend(output.contents.unwrap_or_default());
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    Loop::Continue((prefixes, objects, Some(token)))
+                }
+                _ => Loop::Break((prefixes, objects)),
+            }
+        })
+    }))
+}
+
+/// All uploaded parts of multipart upload, ordered by part number
+pub fn list_parts(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+) -> Box<Future<Item=Vec<Part>, Error=ListPartsError>> {
+    let env = env.clone();
+    let request = ListPartsRequest {
+        bucket: upload.bucket.to_owned().unwrap(),
+        key: upload.key.to_owned().unwrap(),
+        upload_id: upload.upload_id.to_owned().unwrap(),
+        ..ListPartsRequest::default()
+    };
+
+    Box::new(future::loop_fn((vec![], None), move |(mut parts, marker)| {
+        let state = env.clone();
+        let request = ListPartsRequest {
+            part_number_marker: marker,
+            ..request.clone()
+        };
+
+        with_retry(&env, "list_parts", move || state.s3.list_parts(request.clone())).map(
+            move |output| {
+                parts.extend(output.parts.unwrap_or_default());
+
+                match output.next_part_number_marker {
+                    Some(marker) if output.is_truncated == Some(true) => {
+                        Loop::Continue((parts, Some(marker)))
+                    }
+                    _ => Loop::Break(parts),
+                }
+            },
+        )
+    }))
+}
+
+/// Map `ListParts` failure to HTTP error
+pub fn list_parts_error(e: ListPartsError) -> Error {
+    match e {
+        ListPartsError::HttpDispatch(e) => ErrorInternalServerError(e),
+        ListPartsError::Credentials(e) => ErrorForbidden(e),
+        ListPartsError::Validation(e) => ErrorBadRequest(e),
+        ListPartsError::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Complete multipart upload with given parts
+pub fn complete_upload(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+    parts: Vec<CompletedPart>,
+) -> Box<Future<Item=CompleteMultipartUploadOutput, Error=CompleteMultipartUploadError>> {
+    let state = env.clone();
+    let request = CompleteMultipartUploadRequest {
+        bucket: upload.bucket.to_owned().unwrap(),
+        key: upload.key.to_owned().unwrap(),
+        multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+        request_payer: None,
+        upload_id: upload.upload_id.to_owned().unwrap(),
+    };
+
+    with_retry(env, "complete_multipart_upload", move || {
+        state.s3.complete_multipart_upload(request.clone())
+    })
+}
+
+/// Map `CompleteMultipartUpload` failure to HTTP error
+pub fn complete_upload_error(e: CompleteMultipartUploadError) -> Error {
+    match e {
+        CompleteMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
+        CompleteMultipartUploadError::Credentials(e) => ErrorForbidden(e),
+        CompleteMultipartUploadError::Validation(e) => ErrorBadRequest(e),
+        CompleteMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Abort multipart upload, deleting all uploaded parts
+pub fn abort_upload(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+) -> Box<Future<Item=AbortMultipartUploadOutput, Error=AbortMultipartUploadError>> {
+    let state = env.clone();
+    let request = AbortMultipartUploadRequest {
+        bucket: upload.bucket.to_owned().unwrap(),
+        key: upload.key.to_owned().unwrap(),
+        request_payer: None,
+        upload_id: upload.upload_id.to_owned().unwrap(),
+    };
+
+    with_retry(env, "abort_multipart_upload", move || {
+        state.s3.abort_multipart_upload(request.clone())
+    })
+}
+
+/// Aborts multipart upload when dropped while still armed, this happens when request future
+/// is dropped mid-upload, e.g. on client disconnect. Armed uploads are registered as in flight,
+/// so ones left open on shutdown are aborted too.
+pub struct AbortOnDrop {
+    env: AppEnv,
+    upload: Option<CreateMultipartUploadOutput>,
+}
+
+impl AbortOnDrop {
+    pub fn new(env: &AppEnv, upload: &CreateMultipartUploadOutput) -> AbortOnDrop {
+        env.in_flight.upload_opened(OpenUpload {
+            bucket: upload.bucket.to_owned().unwrap_or_default(),
+            key: upload.key.to_owned().unwrap_or_default(),
+            upload_id: upload.upload_id.to_owned().unwrap_or_default(),
+        });
+
+        AbortOnDrop {
+            env: env.clone(),
+            upload: Some(upload.clone()),
+        }
+    }
+
+    /// Upload was completed or aborted explicitly
+    pub fn disarm(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            self.env.in_flight.upload_closed(&upload.upload_id.unwrap_or_default());
+        }
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            warn!("Upload of {:?} was cancelled, aborting", upload.key);
+
+            let env = self.env.clone();
+            let upload_id = upload.upload_id.to_owned().unwrap_or_default();
+            let abort = abort_upload(&self.env, &upload).then(move |r| {
+                match r {
+                    Ok(_) => env.in_flight.upload_closed(&upload_id),
+                    Err(e) => error!("Failed to abort cancelled upload: {}", e),
+                }
+
+                Ok(())
+            });
+
+            // executor is not available when future is dropped on shutdown
+            if TaskExecutor::current().spawn_local(Box::new(abort)).is_err() {
+                error!("Failed to abort upload of {:?}, executor is shut down", upload.key);
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use actix;
+use actix::actors::signal::{Signal, SignalType};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, System};
+use actix_web::server::{Server, StopServer};
+use env::AppEnv;
+use futures::{future, Future};
+use rusoto_s3::CreateMultipartUploadOutput;
+use s3::abort_upload;
+use std::time::{Duration, Instant};
+
+/// How often to check whether requests in flight are done
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops server gracefully on `SIGTERM` or `SIGINT`: new connections aren't accepted, requests
+/// in flight may finish within grace period, then uploads still open are aborted and system
+/// is stopped. Another signal, or `SIGQUIT`, ends grace period early.
+pub struct Shutdown {
+    server: Addr<Server>,
+    /// State sharing requests and uploads in flight with server workers
+    env: AppEnv,
+    grace: Duration,
+    deadline: Option<Instant>,
+    stopping: bool,
+}
+
+impl Shutdown {
+    pub fn new(server: Addr<Server>, env: AppEnv, grace: Duration) -> Shutdown {
+        Shutdown {
+            server: server,
+            env: env,
+            grace: grace,
+            deadline: None,
+            stopping: false,
+        }
+    }
+
+    fn poll(&mut self) {
+        let in_flight = &self.env.in_flight;
+
+        match self.deadline {
+            _ if in_flight.is_idle() => info!("Requests in flight finished"),
+            Some(deadline) if Instant::now() >= deadline => warn!(
+                "Grace period expired with {} requests in flight",
+                in_flight.requests()
+            ),
+            _ => return,
+        }
+
+        self.stop();
+    }
+
+    /// Abort uploads still open, then stop system
+    fn stop(&mut self) {
+        if self.stopping {
+            return;
+        }
+        self.stopping = true;
+
+        let uploads = self.env.in_flight.uploads();
+        if !uploads.is_empty() {
+            warn!("Aborting {} uploads still open", uploads.len());
+        }
+
+        let aborts: Vec<_> = uploads
+            .into_iter()
+            .map(|upload| {
+                let env = self.env.clone();
+                let output = CreateMultipartUploadOutput {
+                    bucket: Some(upload.bucket.to_owned()),
+                    key: Some(upload.key.to_owned()),
+                    upload_id: Some(upload.upload_id.to_owned()),
+                    ..CreateMultipartUploadOutput::default()
+                };
+
+                abort_upload(&env, &output).then(move |r| {
+                    match r {
+                        Ok(_) => env.in_flight.upload_closed(&upload.upload_id),
+                        Err(e) => error!("Failed to abort upload of {}: {}", upload.key, e),
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect();
+
+        actix::spawn(future::join_all(aborts).map(|_| System::current().stop()));
+    }
+}
+
+impl Actor for Shutdown {
+    type Context = Context<Self>;
+}
+
+impl Handler<Signal> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, ctx: &mut Context<Self>) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term if self.deadline.is_none() => {
+                info!(
+                    "Shutting down, waiting up to {} seconds for {} requests in flight",
+                    self.grace.as_secs(),
+                    self.env.in_flight.requests()
+                );
+
+                self.server.do_send(StopServer { graceful: true });
+                self.deadline = Some(Instant::now() + self.grace);
+                ctx.run_interval(POLL_INTERVAL, |shutdown, _| shutdown.poll());
+            }
+            SignalType::Int | SignalType::Term | SignalType::Quit => {
+                info!("Shutting down now");
+                self.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod shutdown {
+        use actix::actors::signal::{Signal, SignalType};
+        use actix::{self, Actor};
+        use actix_web::server::HttpServer;
+        use aws_s3_webdav::in_flight::OpenUpload;
+        use shutdown::*;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::{Duration, Instant};
+        use testing::{self, Stub, StubState, Upload};
+
+        fn upload(stub: &Stub, upload_id: &str) -> OpenUpload {
+            stub.lock().unwrap().uploads.insert(
+                upload_id.to_owned(),
+                Upload {
+                    key: "a.txt".to_owned(),
+                    ..Upload::default()
+                },
+            );
+
+            OpenUpload {
+                bucket: "bucket".to_owned(),
+                key: "a.txt".to_owned(),
+                upload_id: upload_id.to_owned(),
+            }
+        }
+
+        /// Run server until shut down after `signal`, time it took
+        fn shut_down(env: AppEnv, signal: SignalType, grace: Duration) -> Duration {
+            let sys = actix::System::new("test");
+            let server_env = env.clone();
+            let server = HttpServer::new(move || ::app(server_env.clone()))
+                .bind("127.0.0.1:0")
+                .unwrap()
+                .disable_signals()
+                .start();
+            let shutdown = Shutdown::new(server, env, grace).start();
+
+            let started = Instant::now();
+            shutdown.do_send(Signal(signal));
+            sys.run();
+
+            started.elapsed()
+        }
+
+        #[test]
+        fn test_waits_for_requests_in_flight() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let env = testing::state(s3.addr());
+            let other = upload(&stub, "other");
+
+            // request finishing its upload during grace period
+            let request = env.in_flight.request();
+            let open = upload(&stub, "open");
+            env.in_flight.upload_opened(open);
+            let in_flight = env.in_flight.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                in_flight.upload_closed("open");
+                drop(request);
+            });
+
+            let elapsed = shut_down(env.clone(), SignalType::Term, Duration::from_secs(10));
+
+            assert!(elapsed >= Duration::from_millis(300));
+            assert!(elapsed < Duration::from_secs(10));
+            assert!(env.in_flight.is_idle());
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.uploads.contains_key(&other.upload_id));
+            assert!(stub.uploads.contains_key("open"));
+        }
+
+        #[test]
+        fn test_aborts_uploads_after_grace_period() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let env = testing::state(s3.addr());
+            let other = upload(&stub, "other");
+
+            let _request = env.in_flight.request();
+            env.in_flight.upload_opened(upload(&stub, "open"));
+
+            let elapsed = shut_down(env.clone(), SignalType::Term, Duration::from_millis(500));
+
+            assert!(elapsed >= Duration::from_millis(500));
+            assert!(env.in_flight.uploads().is_empty());
+
+            let stub = stub.lock().unwrap();
+            assert!(!stub.uploads.contains_key("open"));
+            assert!(stub.uploads.contains_key(&other.upload_id));
+        }
+
+        #[test]
+        fn test_quit_aborts_uploads_immediately() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let env = testing::state(s3.addr());
+
+            env.in_flight.upload_opened(upload(&stub, "open"));
+
+            let elapsed = shut_down(env.clone(), SignalType::Quit, Duration::from_secs(10));
+
+            assert!(elapsed < Duration::from_secs(10));
+            assert!(!stub.lock().unwrap().uploads.contains_key("open"));
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use actix;
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorNotFound};
+use actix_web::Error;
+use aws_s3_webdav::trash::{self, Moved, ORIGINAL_PATH_METADATA_KEY};
+use chrono::{SecondsFormat, Utc};
+use copy::{self, ContentHeaders, MetadataChanges};
+use env::{AppEnv, TrashConfig};
+use futures::{future, stream, Future, Stream};
+use s3::{delete_object, list_objects, object_exists};
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
+
+/// Trashed object, as listed to clients
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashEntry {
+    /// Path of the object in trash, to restore it from
+    pub path: String,
+    pub original_path: String,
+    pub deleted_at: String,
+    pub size: Option<i64>,
+}
+
+fn trash_config(env: &AppEnv) -> Result<&TrashConfig, Error> {
+    env.config.trash.as_ref().ok_or_else(|| ErrorNotFound("Trash is not enabled"))
+}
+
+/// Object key of path relative to key prefix
+fn key(env: &AppEnv, path: &str) -> String {
+    match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, path),
+        None => path.to_owned(),
+    }
+}
+
+/// Path of object key relative to key prefix
+fn relative_path<'a>(env: &AppEnv, key: &'a str) -> &'a str {
+    match env.config.s3.prefix {
+        Some(ref prefix) if key.starts_with(prefix.as_str()) => &key[prefix.len()..],
+        _ => key,
+    }
+}
+
+/// Paths of objects at given path: the object itself, or all objects under it if it's a
+/// collection (ends with `/`, or there's no such object)
+fn object_paths(env: &AppEnv, path: &str) -> Box<Future<Item=Vec<String>, Error=Error>> {
+    let env = env.clone();
+    let path = path.to_owned();
+    let object: Box<Future<Item=bool, Error=Error>> = if path.ends_with('/') {
+        Box::new(future::ok(false))
+    } else {
+        object_exists(&env, &env.config.s3.bucket, &key(&env, &path))
+    };
+
+    Box::new(object.and_then(move |exists| -> Box<Future<Item=Vec<String>, Error=Error>> {
+        if exists {
+            return Box::new(future::ok(vec![path]));
+        }
+
+        let collection = if path.ends_with('/') { path } else { format!("{}/", path) };
+
+        Box::new(list_objects(&env, &key(&env, &collection)).and_then(move |objects| {
+            let paths: Vec<String> = objects
+                .into_iter()
+                .filter_map(|o| o.key)
+                .map(|k| relative_path(&env, &k).to_owned())
+                .collect();
+
+            if paths.is_empty() {
+                Err(ErrorNotFound("Object not found"))
+            } else {
+                Ok(paths)
+            }
+        }))
+    }))
+}
+
+/// Result of a move as reported in multi-status response, `None` if it wasn't attempted.
+/// Status of error response is taken from it, so error itself can't be responded with anymore.
+fn moved(path: String, destination: String, result: Option<Result<(), Error>>) -> Moved {
+    let error = match result {
+        Some(Ok(())) => None,
+        Some(Err(e)) => {
+            let status = e.as_response_error().error_response().status();
+            Some((status.to_string(), e.to_string()))
+        }
+        None => Some((
+            "424 Failed Dependency".to_owned(),
+            "Not moved after previous failure".to_owned(),
+        )),
+    };
+
+    Moved {
+        path: path,
+        destination: destination,
+        error: error,
+    }
+}
+
+/// Move objects one by one from path to destination, with user metadata changes applied.
+/// Objects moved before a failure stay at their destinations, following ones aren't moved.
+/// Resolves to result of every move, or to the error if there's a single object to move.
+fn move_all(
+    env: &AppEnv,
+    moves: Vec<(String, String, MetadataChanges)>,
+) -> Box<Future<Item=Vec<Moved>, Error=Error>> {
+    let env = env.clone();
+
+    Box::new(
+        stream::iter_ok::<_, Error>(moves)
+            .fold(Vec::new(), move |mut results: Vec<_>, (path, dest, changes)| {
+                let failed = |&(_, _, ref r): &(_, _, Option<Result<(), Error>>)| {
+                    r.as_ref().map(|r| r.is_err()) == Some(true)
+                };
+                if results.iter().any(failed) {
+                    results.push((path, dest, None));
+                    return Box::new(future::ok(results)) as Box<Future<Item=_, Error=Error>>;
+                }
+
+                let bucket = env.config.s3.bucket.to_owned();
+
+                Box::new(
+                    copy::move_object(
+                        &env,
+                        &bucket,
+                        &key(&env, &path),
+                        &key(&env, &dest),
+                        &ContentHeaders::default(),
+                        &changes,
+                    ).then(move |r| {
+                        match r {
+                            Ok(_) => info!("Moved {} to {}", path, dest),
+                            Err(ref e) => error!("Failed to move {} to {}: {}", path, dest, e),
+                        }
+                        results.push((path, dest, Some(r.map(|_| ()))));
+
+                        Ok(results)
+                    }),
+                )
+            })
+            .and_then(|mut results| {
+                if let [(_, _, Some(Err(_)))] = results[..] {
+                    if let Some((_, _, Some(Err(e)))) = results.pop() {
+                        return Err(e);
+                    }
+                }
+
+                let moved: Vec<Moved> =
+                    results.into_iter().map(|(path, dest, r)| moved(path, dest, r)).collect();
+                let done: Vec<String> = moved
+                    .iter()
+                    .filter(|m| m.error.is_none())
+                    .map(|m| format!("{} to {}", m.path, m.destination))
+                    .collect();
+                if done.len() < moved.len() {
+                    error!("Moved only {} of {} objects: {:?}", done.len(), moved.len(), done);
+                }
+
+                Ok(moved)
+            }),
+    )
+}
+
+/// Move object, or all objects of collection, to trash directory of current time, resolves
+/// to result of every move
+pub fn move_to_trash(env: &AppEnv, path: &str) -> Box<Future<Item=Vec<Moved>, Error=Error>> {
+    let trash_prefix = match trash_config(env) {
+        Ok(trash) => trash.prefix.to_owned(),
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let env = env.clone();
+    let deleted_at = Utc::now();
+
+    Box::new(object_paths(&env, path).and_then(move |paths| {
+        let moves = paths
+            .into_iter()
+            .map(|path| {
+                let mut changes = MetadataChanges::new();
+                changes.insert(ORIGINAL_PATH_METADATA_KEY.to_owned(), Some(format!("/{}", path)));
+                let dest = trash::trash_path(&trash_prefix, &deleted_at, &path);
+
+                (path, dest, changes)
+            })
+            .collect();
+
+        move_all(&env, moves)
+    }))
+}
+
+/// Move trashed object, or all objects of trash directory, back to original paths, resolves
+/// to result of every move. Objects are never overwritten, nothing is restored (409) if any
+/// of original paths exists.
+pub fn restore(env: &AppEnv, path: &str) -> Box<Future<Item=Vec<Moved>, Error=Error>> {
+    let trash_prefix = match trash_config(env) {
+        Ok(trash) if path.starts_with(trash.prefix.as_str()) => trash.prefix.to_owned(),
+        Ok(_) => return Box::new(future::err(ErrorBadRequest("Path is not in trash"))),
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let env = env.clone();
+
+    Box::new(object_paths(&env, path).and_then(move |paths| {
+        let s = env.clone();
+
+        stream::iter_ok::<_, Error>(paths)
+            .and_then(move |path| -> Box<Future<Item=_, Error=Error>> {
+                let original_path = match trash::parse(&trash_prefix, &path) {
+                    Some(trashed) => trashed.original_path.to_owned(),
+                    None => return Box::new(future::err(ErrorBadRequest("Not a trashed object"))),
+                };
+
+                Box::new(
+                    object_exists(&s, &s.config.s3.bucket, &key(&s, &original_path)).and_then(
+                        move |exists| {
+                            if exists {
+                                Err(ErrorConflict(format!("{} already exists", original_path)))
+                            } else {
+                                let mut changes = MetadataChanges::new();
+                                changes.insert(ORIGINAL_PATH_METADATA_KEY.to_owned(), None);
+
+                                Ok((path, original_path, changes))
+                            }
+                        },
+                    ),
+                )
+            })
+            .collect()
+            .and_then(move |moves| move_all(&env, moves))
+    }))
+}
+
+/// Objects in trash deleted from given path or under it, most recently deleted first
+pub fn list(env: &AppEnv, path: &str) -> Box<Future<Item=Vec<TrashEntry>, Error=Error>> {
+    let trash_prefix = match trash_config(env) {
+        Ok(trash) => trash.prefix.to_owned(),
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let env = env.clone();
+    let path = path.to_owned();
+
+    Box::new(list_objects(&env, &key(&env, &trash_prefix)).map(move |objects| {
+        let mut entries: Vec<(_, TrashEntry)> = objects
+            .into_iter()
+            .filter_map(|object| {
+                let trashed_path = relative_path(&env, object.key.as_ref()?).to_owned();
+                let trashed = trash::parse(&trash_prefix, &trashed_path)?;
+
+                if !trashed.original_path.starts_with(path.as_str()) {
+                    return None;
+                }
+
+                let entry = TrashEntry {
+                    path: format!("/{}", trashed_path),
+                    original_path: format!("/{}", trashed.original_path),
+                    deleted_at: trashed.deleted_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    size: object.size,
+                };
+
+                Some((trashed.deleted_at, entry))
+            })
+            .collect();
+
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.path.cmp(&b.1.path)));
+        entries.into_iter().map(|e| e.1).collect()
+    }))
+}
+
+/// Delete objects which were in trash longer than configured retention, resolves to the
+/// number of purged objects
+pub fn purge(env: &AppEnv) -> Box<Future<Item=usize, Error=Error>> {
+    let trash = match trash_config(env) {
+        Ok(trash) => (trash.prefix.to_owned(), trash.retention),
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let env = env.clone();
+    let now = Utc::now();
+
+    Box::new(list_objects(&env, &key(&env, &trash.0)).and_then(move |objects| {
+        let (trash_prefix, retention) = trash;
+        let expired: Vec<String> = objects
+            .into_iter()
+            .filter_map(|o| o.key)
+            .filter(|key| {
+                trash::parse(&trash_prefix, relative_path(&env, key))
+                    .map(|t| trash::is_expired(&t.deleted_at, now, retention))
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        // delete sequentially to not flood S3 with requests
+        stream::iter_ok::<_, ()>(expired)
+            .and_then(move |key| {
+                delete_object(&env, &env.config.s3.bucket, &key).then(move |r| {
+                    match r {
+                        Ok(_) => info!("Purged {} from trash", key),
+                        Err(ref e) => error!("Failed to purge {} from trash: {}", key, e),
+                    }
+
+                    Ok(r.is_ok())
+                })
+            })
+            .fold(0, |n, ok| Ok::<_, ()>(if ok { n + 1 } else { n }))
+            .then(|r| Ok(r.unwrap_or(0)))
+    }))
+}
+
+/// Periodically purge expired trash, must be called within a running actix system
+pub fn spawn(env: AppEnv, interval: Duration) {
+    actix::spawn(
+        Interval::new(Instant::now(), interval)
+            .map_err(|e| error!("Trash purge timer failed: {}", e))
+            .for_each(move |_| {
+                purge(&env).then(|r| {
+                    match r {
+                        Ok(purged) => debug!("Purged {} objects from trash", purged),
+                        Err(e) => error!("Failed to purge trash: {}", e),
+                    }
+
+                    Ok(())
+                })
+            }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    mod purge {
+        use actix;
+        use env::TrashConfig;
+        use soft_delete::*;
+        use std::sync::{Arc, Mutex};
+        use testing::{self, StoredObject, Stub, StubState};
+
+        #[test]
+        fn test_purges_only_expired_trash() {
+            let stub: Stub = Arc::new(Mutex::new(StubState::default()));
+            let s3 = testing::start(stub.clone());
+            let recent = trash::trash_path(".trash/", &Utc::now(), "new.txt");
+
+            for key in &[".trash/20180901T000000.000Z/old.txt", &recent, "kept.txt"] {
+                stub.lock().unwrap().objects.insert(key.to_string(), StoredObject::default());
+            }
+
+            let mut state = testing::state(s3.addr());
+            Arc::get_mut(&mut state).unwrap().config.trash =
+                Some(TrashConfig::new(".trash/", Duration::from_secs(7 * 24 * 3600)));
+            let purged = actix::System::new("test").block_on(purge(&state)).unwrap();
+
+            assert_eq!(purged, 1);
+
+            let stub = stub.lock().unwrap();
+            assert!(stub.objects.contains_key(&recent));
+            assert!(stub.objects.contains_key("kept.txt"));
+            assert!(!stub.objects.contains_key(".trash/20180901T000000.000Z/old.txt"));
+        }
+    }
+}
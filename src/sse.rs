@@ -0,0 +1,124 @@
+use base64;
+use md5;
+use std::cmp::Reverse;
+use toml;
+
+/// Only algorithm supported by S3 for customer provided keys
+pub const CUSTOMER_ALGORITHM: &str = "AES256";
+
+/// SSE-C key, as sent to S3: base64 encoded key and its MD5
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomerKey {
+    pub key: String,
+    pub key_md5: String,
+}
+
+impl CustomerKey {
+    /// Parse base64 encoded 256 bit key
+    pub fn from_base64(value: &str) -> Result<CustomerKey, String> {
+        let key = base64::decode(value.trim()).map_err(|_| "Key is not valid base64".to_owned())?;
+
+        if key.len() != 32 {
+            return Err(format!("Key must be 32 bytes long, got {}", key.len()));
+        }
+
+        Ok(CustomerKey {
+            key: base64::encode(&key),
+            key_md5: base64::encode(&md5::compute(&key).0),
+        })
+    }
+}
+
+/// SSE-C keys by object key prefix, default key has empty prefix
+#[derive(Clone, Debug, Default)]
+pub struct CustomerKeys {
+    /// Sorted by prefix length, longest first
+    keys: Vec<(String, CustomerKey)>,
+}
+
+impl CustomerKeys {
+    /// Parse key file with base64 encoded keys, default one and/or by object key prefix:
+    ///
+    /// ```toml
+    /// default = "default key"
+    ///
+    /// [prefixes]
+    /// "finance/" = "finance key"
+    /// ```
+    pub fn parse(content: &str) -> Result<CustomerKeys, String> {
+        let value = content.parse::<toml::Value>().map_err(|e| e.to_string())?;
+        let mut entries = vec![];
+
+        if let Some(key) = value.get("default") {
+            entries.push(("", key));
+        }
+
+        if let Some(prefixes) = value.get("prefixes") {
+            let prefixes = prefixes.as_table().ok_or("prefixes must be a table")?;
+            entries.extend(prefixes.iter().map(|(prefix, key)| (prefix.as_str(), key)));
+        }
+
+        if entries.is_empty() {
+            return Err("No keys defined".to_owned());
+        }
+
+        let mut keys = vec![];
+
+        for (prefix, key) in entries {
+            let key = key
+                .as_str()
+                .ok_or_else(|| "must be a string".to_owned())
+                .and_then(CustomerKey::from_base64)
+                .map_err(|e| format!("Key for prefix \"{}\": {}", prefix, e))?;
+
+            keys.push((prefix.to_owned(), key));
+        }
+
+        keys.sort_by_key(|k| Reverse(k.0.len()));
+
+        Ok(CustomerKeys { keys: keys })
+    }
+
+    /// Key for object, matching longest prefix
+    pub fn for_key(&self, key: &str) -> Option<&CustomerKey> {
+        self.keys.iter().find(|k| key.starts_with(k.0.as_str())).map(|k| &k.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod customer_keys {
+        use sse::*;
+
+        const DEFAULT: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        const FINANCE: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+        #[test]
+        fn test_longest_prefix() {
+            let keys = CustomerKeys::parse(&format!(
+                "default = \"{}\"\n[prefixes]\n\"finance/\" = \"{}\"\n",
+                DEFAULT, FINANCE
+            )).unwrap();
+
+            assert_eq!(keys.for_key("finance/q1.csv").unwrap().key, FINANCE);
+            let default = keys.for_key("photos/cat.jpg").unwrap();
+            assert_eq!(default.key, DEFAULT);
+            assert_eq!(default.key_md5, "cLyPS3KoaSFGi/joRB3OUQ==");
+        }
+
+        #[test]
+        fn test_no_default() {
+            let keys = CustomerKeys::parse(&format!("[prefixes]\n\"finance/\" = \"{}\"\n", FINANCE))
+                .unwrap();
+
+            assert!(keys.for_key("photos/cat.jpg").is_none());
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!(CustomerKeys::parse("").is_err());
+            assert!(CustomerKeys::parse("default = \"c2hvcnQ=\"\n").is_err());
+            assert!(CustomerKeys::parse("default = 1\n").is_err());
+        }
+    }
+}
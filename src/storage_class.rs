@@ -0,0 +1,169 @@
+use std::cmp::Reverse;
+
+/// Storage classes objects can be written with
+pub const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+];
+
+/// Storage classes which have to be restored before objects can be read
+pub const ARCHIVE_CLASSES: &[&str] = &["GLACIER", "DEEP_ARCHIVE"];
+
+/// Glacier retrieval tiers, fastest first
+pub const RESTORE_TIERS: &[&str] = &["Expedited", "Standard", "Bulk"];
+
+/// Parse storage class name, case-insensitive
+pub fn parse(value: &str) -> Result<String, String> {
+    let value = value.trim().to_uppercase();
+
+    if STORAGE_CLASSES.contains(&value.as_str()) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "Unsupported storage class \"{}\", expected one of {}",
+            value,
+            STORAGE_CLASSES.join(", ")
+        ))
+    }
+}
+
+/// Parse retrieval tier name, case-insensitive
+pub fn parse_tier(value: &str) -> Result<&'static str, String> {
+    RESTORE_TIERS
+        .iter()
+        .find(|t| t.eq_ignore_ascii_case(value.trim()))
+        .cloned()
+        .ok_or_else(|| format!("Unsupported restore tier \"{}\"", value))
+}
+
+/// Storage classes by object key prefix
+#[derive(Clone, Debug, Default)]
+pub struct StorageClassRules {
+    /// Sorted by prefix length, longest first
+    rules: Vec<(String, String)>,
+}
+
+impl StorageClassRules {
+    /// Parse `prefix=CLASS` rules, empty prefix matches all objects
+    pub fn parse<'a, I>(rules: I) -> Result<StorageClassRules, String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut parsed = vec![];
+
+        for rule in rules {
+            let mut parts = rule.rsplitn(2, '=');
+            let (class, prefix) = match (parts.next(), parts.next()) {
+                (Some(class), Some(prefix)) => (class, prefix),
+                _ => return Err(format!("Rule \"{}\" must be prefix=CLASS", rule)),
+            };
+
+            parsed.push((prefix.to_owned(), parse(class)?));
+        }
+
+        parsed.sort_by_key(|r| Reverse(r.0.len()));
+
+        Ok(StorageClassRules { rules: parsed })
+    }
+
+    /// Storage class for object, matching longest prefix
+    pub fn for_key(&self, key: &str) -> Option<&str> {
+        self.rules.iter().find(|r| key.starts_with(r.0.as_str())).map(|r| r.1.as_str())
+    }
+}
+
+/// Whether object data can be read, based on its storage class and `x-amz-restore` header
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestoreStatus {
+    /// Not in archive storage class, readable
+    NotArchived,
+    /// Has to be restored before it can be read
+    Archived,
+    /// Restore was requested, but temporary copy is not ready yet
+    InProgress,
+    /// Temporary copy is readable until expiry date
+    Restored { expiry: Option<String> },
+}
+
+impl RestoreStatus {
+    pub fn new(storage_class: Option<&str>, restore: Option<&str>) -> RestoreStatus {
+        match restore {
+            Some(restore) if restore.contains("ongoing-request=\"true\"") => {
+                RestoreStatus::InProgress
+            }
+            Some(restore) => RestoreStatus::Restored {
+                // ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"
+                expiry: restore
+                    .find("expiry-date=\"")
+                    .map(|i| &restore[i + "expiry-date=\"".len()..])
+                    .and_then(|rest| rest.find('"').map(|end| rest[..end].to_owned())),
+            },
+            None if storage_class.map(|c| ARCHIVE_CLASSES.contains(&c)) == Some(true) => {
+                RestoreStatus::Archived
+            }
+            None => RestoreStatus::NotArchived,
+        }
+    }
+
+    /// Object data can be read
+    pub fn is_readable(&self) -> bool {
+        match *self {
+            RestoreStatus::NotArchived | RestoreStatus::Restored { .. } => true,
+            RestoreStatus::Archived | RestoreStatus::InProgress => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod storage_class {
+        use storage_class::*;
+
+        #[test]
+        fn test_parse() {
+            assert_eq!(parse("glacier"), Ok("GLACIER".to_owned()));
+            assert_eq!(parse("STANDARD_IA"), Ok("STANDARD_IA".to_owned()));
+            assert!(parse("COLD").is_err());
+            assert_eq!(parse_tier("bulk"), Ok("Bulk"));
+            assert!(parse_tier("Slow").is_err());
+        }
+
+        #[test]
+        fn test_rules() {
+            let rules = vec!["=STANDARD_IA", "backups/=GLACIER", "backups/db/=deep_archive"];
+            let rules = StorageClassRules::parse(rules).unwrap();
+
+            assert_eq!(rules.for_key("backups/db/dump.sql"), Some("DEEP_ARCHIVE"));
+            assert_eq!(rules.for_key("backups/home.tar"), Some("GLACIER"));
+            assert_eq!(rules.for_key("photos/cat.jpg"), Some("STANDARD_IA"));
+            assert_eq!(StorageClassRules::default().for_key("photos/cat.jpg"), None);
+            assert!(StorageClassRules::parse(vec!["backups/"]).is_err());
+            assert!(StorageClassRules::parse(vec!["backups/=COLD"]).is_err());
+        }
+
+        #[test]
+        fn test_restore_status() {
+            assert_eq!(RestoreStatus::new(Some("STANDARD"), None), RestoreStatus::NotArchived);
+            assert_eq!(RestoreStatus::new(None, None), RestoreStatus::NotArchived);
+            assert_eq!(RestoreStatus::new(Some("GLACIER"), None), RestoreStatus::Archived);
+            assert_eq!(
+                RestoreStatus::new(Some("DEEP_ARCHIVE"), Some("ongoing-request=\"true\"")),
+                RestoreStatus::InProgress
+            );
+            let restored =
+                "ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"";
+            assert_eq!(
+                RestoreStatus::new(Some("GLACIER"), Some(restored)),
+                RestoreStatus::Restored {
+                    expiry: Some("Fri, 21 Dec 2012 00:00:00 GMT".to_owned()),
+                }
+            );
+            assert!(!RestoreStatus::Archived.is_readable());
+            assert!(RestoreStatus::Restored { expiry: None }.is_readable());
+        }
+    }
+}
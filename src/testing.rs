@@ -0,0 +1,976 @@
+//! Test helpers: in-memory S3 stand-in, implementing just enough of the S3 REST API
+//! (path-style addressing) for calls made by the proxy, and a minimal blocking HTTP client.
+
+use actix_web::http::header::HeaderValue;
+use actix_web::test::TestServer;
+use actix_web::{App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
+use aws_s3_webdav::envelope::Keyring;
+use aws_s3_webdav::in_flight::InFlight;
+use aws_s3_webdav::metrics::Metrics;
+use aws_s3_webdav::integrity::content_md5;
+use aws_s3_webdav::metadata::{DEFAULT_HEADER_PREFIX, MAX_METADATA_SIZE};
+use aws_s3_webdav::multipart::MAX_PART_SIZE;
+use aws_s3_webdav::readiness::Readiness;
+use aws_s3_webdav::retry::RetryPolicy;
+use aws_s3_webdav::sse::CustomerKeys;
+use aws_s3_webdav::storage_class::StorageClassRules;
+use aws_s3_webdav::tus::CompletedUploads;
+use chrono::Utc;
+use dispatcher::StatusDispatcher;
+use env::*;
+use futures::Future;
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::{HttpClient, Region};
+use rusoto_s3::S3Client;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
+
+#[derive(Clone, Debug, Default)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub content_type: Option<String>,
+    pub e_tag: String,
+    /// User metadata, without `x-amz-meta-` prefix
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+    pub storage_class: Option<String>,
+    /// `x-amz-server-side-encryption` value
+    pub encryption: Option<String>,
+    /// MD5 of SSE-C key, which has to be provided to read the object
+    pub customer_key_md5: Option<String>,
+    /// `x-amz-restore` value of archived object, objects in archive storage classes can only
+    /// be read once restore is complete
+    pub restore: Option<String>,
+    /// Set when bucket versioning is enabled
+    pub version_id: Option<String>,
+    pub website_redirect_location: Option<String>,
+}
+
+impl StoredObject {
+    fn in_archive_class(&self) -> bool {
+        match self.storage_class {
+            Some(ref class) => class == "GLACIER" || class == "DEEP_ARCHIVE",
+            None => false,
+        }
+    }
+
+    /// Object data can't be read
+    fn is_archived(&self) -> bool {
+        let restored = self.restore.as_ref().map(|r| r.contains("\"false\""));
+
+        self.in_archive_class() && restored != Some(true)
+    }
+}
+
+const RESTORE_IN_PROGRESS: &str = "ongoing-request=\"true\"";
+
+#[derive(Default)]
+pub struct Upload {
+    pub key: String,
+    /// Upload initiation time, as returned by `ListMultipartUploads`
+    pub initiated: String,
+    pub content_type: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+    pub storage_class: Option<String>,
+    pub encryption: Option<String>,
+    pub customer_key_md5: Option<String>,
+    pub website_redirect_location: Option<String>,
+    pub parts: BTreeMap<i64, Vec<u8>>,
+}
+
+/// Noncurrent object version, or delete marker if there's no object
+pub struct NoncurrentVersion {
+    pub key: String,
+    pub version_id: String,
+    pub object: Option<StoredObject>,
+}
+
+/// Injected failure, returned instead of handling next matching operation
+pub struct Failure {
+    pub operation: &'static str,
+    pub status: u16,
+    /// S3 error code, HTML page (e.g. of load balancer) is returned instead of S3 error if empty
+    pub code: &'static str,
+}
+
+#[derive(Default)]
+pub struct StubState {
+    /// Stored objects by key (without bucket)
+    pub objects: HashMap<String, StoredObject>,
+    /// In-progress multipart uploads by upload id
+    pub uploads: HashMap<String, Upload>,
+    /// Number of aborted multipart uploads
+    pub aborted: usize,
+    /// Operations handled, in order
+    pub calls: Vec<&'static str>,
+    pub failures: Vec<Failure>,
+    /// Keep previous versions of overwritten and deleted objects
+    pub versioning: bool,
+    /// Noncurrent versions, oldest first
+    pub versions: Vec<NoncurrentVersion>,
+    next_upload_id: usize,
+    next_version_id: usize,
+}
+
+impl StubState {
+    fn next_version_id(&mut self) -> String {
+        self.next_version_id += 1;
+        format!("v{}", self.next_version_id)
+    }
+
+    /// Make current version of the object noncurrent
+    fn keep_version(&mut self, key: &str) {
+        if let Some(object) = self.objects.remove(key) {
+            self.versions.push(NoncurrentVersion {
+                key: key.to_owned(),
+                version_id: object.version_id.clone().unwrap_or_default(),
+                object: Some(object),
+            });
+        }
+    }
+
+    /// Store object, keeping previous one as noncurrent version if versioning is enabled
+    fn store(&mut self, key: String, mut object: StoredObject) {
+        if self.versioning {
+            object.version_id = Some(self.next_version_id());
+            self.keep_version(&key);
+        }
+
+        self.objects.insert(key, object);
+    }
+
+    /// Delete object, with delete marker if versioning is enabled
+    fn delete(&mut self, key: &str) {
+        if self.versioning {
+            let version_id = self.next_version_id();
+            self.keep_version(key);
+            self.versions.push(NoncurrentVersion {
+                key: key.to_owned(),
+                version_id: version_id,
+                object: None,
+            });
+        }
+
+        self.objects.remove(key);
+    }
+
+    /// Delete noncurrent version of the object
+    fn delete_version(&mut self, key: &str, version_id: &str) {
+        self.versions.retain(|v| v.key != key || v.version_id != version_id);
+    }
+
+    /// Object version, current one if `version_id` is not given
+    fn object(&self, key: &str, version_id: Option<&String>) -> Option<&StoredObject> {
+        match version_id {
+            Some(version_id) => self.objects
+                .get(key)
+                .filter(|o| o.version_id.as_ref() == Some(version_id))
+                .or_else(|| {
+                    self.versions
+                        .iter()
+                        .find(|v| v.key == key && v.version_id == *version_id)
+                        .and_then(|v| v.object.as_ref())
+                }),
+            None => self.objects.get(key),
+        }
+    }
+}
+
+/// Fake modification time of object version, later versions are newer
+fn version_time(version_id: &str) -> String {
+    let n: u32 = version_id.trim_left_matches('v').parse().unwrap_or(0);
+
+    format!("2018-10-01T00:{:02}:{:02}.000Z", n / 60, n % 60)
+}
+
+pub type Stub = Arc<Mutex<StubState>>;
+
+pub fn e_tag(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn error(status: u16, code: &str) -> HttpResponse {
+    if code.is_empty() {
+        return HttpResponse::build(::actix_web::http::StatusCode::from_u16(status).unwrap())
+            .content_type("text/html")
+            .body(format!("<html><body><h1>{}</h1></body></html>", status));
+    }
+
+    HttpResponse::build(::actix_web::http::StatusCode::from_u16(status).unwrap())
+        .content_type("application/xml")
+        .body(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <Error><Code>{}</Code><Message>{}</Message></Error>",
+            code, code
+        ))
+}
+
+fn xml(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}", body))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn operation(req: &HttpRequest<Stub>) -> &'static str {
+    let query = req.query();
+    let method = req.method().as_str();
+    let copy = req.headers().contains_key("x-amz-copy-source");
+
+    match method {
+        // path is "/bucket", without key
+        "HEAD" if !req.path().trim_matches('/').contains('/') => "head_bucket",
+        "HEAD" => "head_object",
+        "GET" if query.contains_key("uploads") => "list_multipart_uploads",
+        "GET" if query.contains_key("uploadId") => "list_parts",
+        "GET" if query.contains_key("tagging") => "get_object_tagging",
+        "GET" if query.contains_key("versions") => "list_object_versions",
+        "GET" if query.contains_key("list-type") => "list_objects_v2",
+        "GET" => "get_object",
+        "PUT" if query.contains_key("partNumber") && copy => "upload_part_copy",
+        "PUT" if query.contains_key("partNumber") => "upload_part",
+        "PUT" if copy => "copy_object",
+        "PUT" => "put_object",
+        "POST" if query.contains_key("uploads") => "create_multipart_upload",
+        "POST" if query.contains_key("uploadId") => "complete_multipart_upload",
+        "POST" if query.contains_key("restore") => "restore_object",
+        "DELETE" if query.contains_key("uploadId") => "abort_multipart_upload",
+        "DELETE" => "delete_object",
+        _ => "unknown",
+    }
+}
+
+/// Check ETags listed in `CompleteMultipartUpload` request body match uploaded parts
+fn valid_completed_parts(state: &StubState, upload_id: &str, body: &[u8]) -> bool {
+    let upload = match state.uploads.get(upload_id) {
+        Some(upload) => upload,
+        None => return true,
+    };
+    let body = String::from_utf8_lossy(body).replace("&quot;", "\"");
+
+    body.split("<Part>").skip(1).all(|part| {
+        let value = |tag: &str| {
+            let start = part.find(&format!("<{}>", tag))? + tag.len() + 2;
+            let end = part.find(&format!("</{}>", tag))?;
+            Some(part[start..end].to_owned())
+        };
+
+        match (value("PartNumber").and_then(|n| n.parse().ok()), value("ETag")) {
+            (Some(number), Some(tag)) => upload.parts.get(&number).map(|d| e_tag(d)) == Some(tag),
+            _ => false,
+        }
+    })
+}
+
+/// Source key and version of copy requests, `x-amz-copy-source` is URL-encoded "bucket/key"
+/// optionally followed by "?versionId=version"
+fn copy_source(req: &HttpRequest<Stub>) -> (String, Option<String>) {
+    let source = req.headers()["x-amz-copy-source"].to_str().unwrap();
+    let mut parts = source.splitn(2, "?versionId=");
+    let key = parts.next().unwrap().trim_left_matches('/').splitn(2, '/').nth(1).unwrap_or("");
+    let key = percent_decode(key.as_bytes()).decode_utf8().unwrap();
+
+    (key.into_owned(), parts.next().map(|v| v.to_owned()))
+}
+
+fn handle(req: &HttpRequest<Stub>) -> FutureResponse<HttpResponse> {
+    let req = req.clone();
+
+    req.body()
+        .limit(usize::max_value())
+        .from_err()
+        .map(move |body| {
+            let operation = operation(&req);
+            let mut state = req.state().lock().unwrap();
+            state.calls.push(operation);
+
+            if let Some(i) = state.failures.iter().position(|f| f.operation == operation) {
+                let failure = state.failures.remove(i);
+                return error(failure.status, failure.code);
+            }
+
+            // path is "/bucket/key"
+            let key = req.path().trim_left_matches('/').splitn(2, '/').nth(1).unwrap_or("");
+            let key = key.to_owned();
+            let query = req.query().clone();
+            let content_type = req.headers()
+                .get("content-type")
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_owned());
+            let metadata: BTreeMap<String, String> = req.headers()
+                .iter()
+                .filter(|&(name, _)| name.as_str().starts_with("x-amz-meta-"))
+                .map(|(name, value)| {
+                    let name = &name.as_str()["x-amz-meta-".len()..];
+                    (name.to_owned(), value.to_str().unwrap().to_owned())
+                })
+                .collect();
+
+            let header = |name: &str| {
+                req.headers().get(name).and_then(|h| h.to_str().ok()).map(|h| h.to_owned())
+            };
+            let tags: BTreeMap<String, String> = header("x-amz-tagging")
+                .map(|t| form_urlencoded::parse(t.as_bytes()).into_owned().collect())
+                .unwrap_or_default();
+            let storage_class = header("x-amz-storage-class");
+            let encryption = header("x-amz-server-side-encryption");
+            let customer_key_md5 = header("x-amz-server-side-encryption-customer-key-md5");
+            let source_customer_key_md5 =
+                header("x-amz-copy-source-server-side-encryption-customer-key-md5");
+            let website_redirect_location = header("x-amz-website-redirect-location");
+
+            // like S3, reject data not matching provided digest
+            if let Some(md5) = req.headers().get("content-md5") {
+                if md5.to_str().ok() != Some(content_md5(&body).as_str()) {
+                    return error(400, "BadDigest");
+                }
+            }
+
+            if operation == "complete_multipart_upload"
+                && !valid_completed_parts(&state, &query["uploadId"], &body)
+            {
+                return error(400, "InvalidPart");
+            }
+
+            match operation {
+                "head_object" | "get_object" => match state.object(&key, query.get("versionId")) {
+                    // like S3, SSE-C objects can only be read with the same key
+                    Some(object) if object.customer_key_md5 != customer_key_md5 => {
+                        if operation == "head_object" {
+                            HttpResponse::BadRequest().finish()
+                        } else {
+                            error(400, "InvalidRequest")
+                        }
+                    }
+                    Some(object) if operation == "get_object" && object.is_archived() => {
+                        error(403, "InvalidObjectState")
+                    }
+                    Some(object) => {
+                        let mut response = HttpResponse::Ok();
+                        response.header("ETag", object.e_tag.as_str());
+
+                        if let Some(ref content_type) = object.content_type {
+                            response.header("Content-Type", content_type.as_str());
+                        }
+
+                        if let Some(ref storage_class) = object.storage_class {
+                            response.header("x-amz-storage-class", storage_class.as_str());
+                        }
+
+                        if let Some(ref encryption) = object.encryption {
+                            response.header("x-amz-server-side-encryption", encryption.as_str());
+                        }
+
+                        if let Some(ref restore) = object.restore {
+                            response.header("x-amz-restore", restore.as_str());
+                        }
+
+                        if let Some(ref version_id) = object.version_id {
+                            response.header("x-amz-version-id", version_id.as_str());
+                        }
+
+                        if let Some(ref location) = object.website_redirect_location {
+                            response.header("x-amz-website-redirect-location", location.as_str());
+                        }
+
+                        for (name, value) in &object.metadata {
+                            let name = format!("x-amz-meta-{}", name);
+                            response.header(name.as_str(), value.as_str());
+                        }
+
+                        if operation == "head_object" {
+                            response
+                                .header("Content-Length", object.data.len().to_string())
+                                .finish()
+                        } else {
+                            response.body(object.data.clone())
+                        }
+                    }
+                    None if operation == "head_object" => HttpResponse::NotFound().finish(),
+                    None => error(404, "NoSuchKey"),
+                },
+                "put_object" => {
+                    let e_tag = e_tag(&body);
+                    state.store(
+                        key,
+                        StoredObject {
+                            data: body.to_vec(),
+                            content_type: content_type,
+                            e_tag: e_tag.clone(),
+                            metadata: metadata,
+                            tags: tags,
+                            storage_class: storage_class,
+                            encryption: encryption,
+                            customer_key_md5: customer_key_md5,
+                            website_redirect_location: website_redirect_location,
+                            ..StoredObject::default()
+                        },
+                    );
+
+                    HttpResponse::Ok().header("ETag", e_tag).finish()
+                }
+                "create_multipart_upload" => {
+                    state.next_upload_id += 1;
+                    let upload_id = format!("upload-{}", state.next_upload_id);
+                    state.uploads.insert(
+                        upload_id.clone(),
+                        Upload {
+                            key: key.clone(),
+                            initiated: Utc::now().to_rfc3339(),
+                            content_type: content_type,
+                            metadata: metadata,
+                            tags: tags,
+                            storage_class: storage_class,
+                            encryption: encryption,
+                            customer_key_md5: customer_key_md5,
+                            website_redirect_location: website_redirect_location,
+                            parts: BTreeMap::new(),
+                        },
+                    );
+
+                    xml(format!(
+                        "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                         <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                        key, upload_id
+                    ))
+                }
+                "upload_part" => {
+                    let part_number: i64 = query["partNumber"].parse().unwrap();
+
+                    match state.uploads.get_mut(&query["uploadId"]) {
+                        Some(ref upload) if upload.customer_key_md5 != customer_key_md5 => {
+                            error(400, "InvalidRequest")
+                        }
+                        Some(upload) => {
+                            upload.parts.insert(part_number, body.to_vec());
+                            HttpResponse::Ok().header("ETag", e_tag(&body)).finish()
+                        }
+                        None => error(404, "NoSuchUpload"),
+                    }
+                }
+                "get_object_tagging" => match state.objects.get(&key) {
+                    Some(object) => {
+                        let tags: String = object
+                            .tags
+                            .iter()
+                            .map(|(k, v)| {
+                                format!(
+                                    "<Tag><Key>{}</Key><Value>{}</Value></Tag>",
+                                    escape(k),
+                                    escape(v)
+                                )
+                            })
+                            .collect();
+
+                        xml(format!("<Tagging><TagSet>{}</TagSet></Tagging>", tags))
+                    }
+                    None => error(404, "NoSuchKey"),
+                },
+                "copy_object" => {
+                    let (source, version_id) = copy_source(&req);
+
+                    match state.object(&source, version_id.as_ref()).cloned() {
+                        Some(ref object) if object.customer_key_md5 != source_customer_key_md5 => {
+                            error(400, "InvalidRequest")
+                        }
+                        Some(ref object) if object.is_archived() => {
+                            error(403, "ObjectNotInActiveTierError")
+                        }
+                        Some(mut object) => {
+                            if header("x-amz-metadata-directive") == Some("REPLACE".to_owned()) {
+                                object.content_type = content_type;
+                                object.metadata = metadata;
+                                object.website_redirect_location = website_redirect_location;
+                            }
+
+                            if header("x-amz-tagging-directive") == Some("REPLACE".to_owned()) {
+                                object.tags = tags;
+                            }
+
+                            // storage class and encryption are not copied, copy is never restored
+                            object.storage_class = storage_class;
+                            object.restore = None;
+                            object.encryption = encryption;
+                            object.customer_key_md5 = customer_key_md5;
+
+                            let result = format!(
+                                "<CopyObjectResult><ETag>{}</ETag><LastModified>{}</LastModified>\
+                                 </CopyObjectResult>",
+                                object.e_tag.replace("\"", "&quot;"),
+                                Utc::now().to_rfc3339()
+                            );
+                            state.store(key, object);
+
+                            xml(result)
+                        }
+                        None => error(404, "NoSuchKey"),
+                    }
+                }
+                "upload_part_copy" => {
+                    let part_number: i64 = query["partNumber"].parse().unwrap();
+                    let (source, version_id) = copy_source(&req);
+                    let source = state.object(&source, version_id.as_ref());
+
+                    if source.map(|o| o.customer_key_md5 != source_customer_key_md5) == Some(true) {
+                        return error(400, "InvalidRequest");
+                    }
+
+                    let data = source.map(|object| {
+                        match req.headers().get("x-amz-copy-source-range") {
+                            Some(range) => {
+                                // "bytes=first-last"
+                                let range = range.to_str().unwrap().trim_left_matches("bytes=");
+                                let bounds: Vec<usize> =
+                                    range.split('-').map(|b| b.parse().unwrap()).collect();
+
+                                object.data[bounds[0]..bounds[1] + 1].to_vec()
+                            }
+                            None => object.data.clone(),
+                        }
+                    });
+
+                    match (data, state.uploads.get_mut(&query["uploadId"])) {
+                        (Some(data), Some(upload)) => {
+                            let e_tag = e_tag(&data);
+                            upload.parts.insert(part_number, data);
+
+                            xml(format!(
+                                "<CopyPartResult><ETag>{}</ETag><LastModified>{}</LastModified>\
+                                 </CopyPartResult>",
+                                e_tag.replace("\"", "&quot;"),
+                                Utc::now().to_rfc3339()
+                            ))
+                        }
+                        (None, _) => error(404, "NoSuchKey"),
+                        (_, None) => error(404, "NoSuchUpload"),
+                    }
+                }
+                "list_parts" => match state.uploads.get(&query["uploadId"]) {
+                    Some(upload) => {
+                        let parts: String = upload
+                            .parts
+                            .iter()
+                            .map(|(number, data)| {
+                                format!(
+                                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag>\
+                                     <Size>{}</Size></Part>",
+                                    number,
+                                    e_tag(data).replace("\"", "&quot;"),
+                                    data.len()
+                                )
+                            })
+                            .collect();
+
+                        xml(format!(
+                            "<ListPartsResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                             <UploadId>{}</UploadId><IsTruncated>false</IsTruncated>{}\
+                             </ListPartsResult>",
+                            upload.key, query["uploadId"], parts
+                        ))
+                    }
+                    None => error(404, "NoSuchUpload"),
+                },
+                "complete_multipart_upload" => match state.uploads.remove(&query["uploadId"]) {
+                    Some(upload) => {
+                        let data: Vec<u8> = upload.parts.values().flat_map(|p| p.clone()).collect();
+                        let e_tag = e_tag(&data);
+                        state.store(
+                            upload.key.clone(),
+                            StoredObject {
+                                data: data,
+                                content_type: upload.content_type,
+                                e_tag: e_tag.clone(),
+                                metadata: upload.metadata,
+                                tags: upload.tags,
+                                storage_class: upload.storage_class,
+                                encryption: upload.encryption,
+                                customer_key_md5: upload.customer_key_md5,
+                                website_redirect_location: upload.website_redirect_location,
+                                ..StoredObject::default()
+                            },
+                        );
+
+                        let mut response = xml(format!(
+                            "<CompleteMultipartUploadResult><Location>http://stub/bucket/{}\
+                             </Location><Bucket>bucket</Bucket><Key>{}</Key><ETag>{}</ETag>\
+                             </CompleteMultipartUploadResult>",
+                            upload.key,
+                            upload.key,
+                            e_tag.replace("\"", "&quot;")
+                        ));
+                        if let Some(version_id) = state.objects[&upload.key].version_id.clone() {
+                            response.headers_mut().insert(
+                                "x-amz-version-id",
+                                HeaderValue::from_str(&version_id).unwrap(),
+                            );
+                        }
+
+                        response
+                    }
+                    None => error(404, "NoSuchUpload"),
+                },
+                "list_multipart_uploads" => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let uploads: String = state
+                        .uploads
+                        .iter()
+                        .filter(|&(_, upload)| upload.key.starts_with(&prefix))
+                        .map(|(id, upload)| {
+                            format!(
+                                "<Upload><Key>{}</Key><UploadId>{}</UploadId>\
+                                 <Initiated>{}</Initiated></Upload>",
+                                upload.key, id, upload.initiated
+                            )
+                        })
+                        .collect();
+
+                    xml(format!(
+                        "<ListMultipartUploadsResult><Bucket>bucket</Bucket>\
+                         <IsTruncated>false</IsTruncated>{}</ListMultipartUploadsResult>",
+                        uploads
+                    ))
+                }
+                "abort_multipart_upload" => {
+                    state.uploads.remove(&query["uploadId"]);
+                    state.aborted += 1;
+
+                    HttpResponse::NoContent().finish()
+                }
+                "restore_object" => match state.objects.get_mut(&key) {
+                    Some(ref object) if !object.in_archive_class() => {
+                        error(403, "ObjectAlreadyInActiveTierError")
+                    }
+                    Some(ref object) if object.restore == Some(RESTORE_IN_PROGRESS.to_owned()) => {
+                        error(409, "RestoreAlreadyInProgress")
+                    }
+                    // restored copy only gets its expiry extended
+                    Some(ref object) if object.restore.is_some() => HttpResponse::Ok().finish(),
+                    Some(object) => {
+                        object.restore = Some(RESTORE_IN_PROGRESS.to_owned());
+
+                        HttpResponse::Accepted().finish()
+                    }
+                    None => error(404, "NoSuchKey"),
+                },
+                "list_object_versions" => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let mut entries: Vec<(String, String, Option<&StoredObject>)> = state
+                        .versions
+                        .iter()
+                        .map(|v| (v.key.clone(), v.version_id.clone(), v.object.as_ref()))
+                        .chain(state.objects.iter().map(|(key, object)| {
+                            let version_id = object.version_id.clone().unwrap_or_default();
+                            (key.clone(), version_id, Some(object))
+                        }))
+                        .filter(|e| e.0.starts_with(&prefix))
+                        .collect();
+                    // newest first within key, delete marker is latest if no current object
+                    entries.sort_by_key(|e| (e.0.clone(), Reverse(version_time(&e.1))));
+                    let mut seen = vec![];
+                    let listed: String = entries
+                        .into_iter()
+                        .map(|(key, version_id, object)| {
+                            let latest = !seen.contains(&key);
+                            seen.push(key.clone());
+
+                            match object {
+                                Some(object) => format!(
+                                    "<Version><Key>{}</Key><VersionId>{}</VersionId>\
+                                     <IsLatest>{}</IsLatest><LastModified>{}</LastModified>\
+                                     <ETag>{}</ETag><Size>{}</Size></Version>",
+                                    key,
+                                    version_id,
+                                    latest,
+                                    version_time(&version_id),
+                                    object.e_tag.replace("\"", "&quot;"),
+                                    object.data.len()
+                                ),
+                                None => format!(
+                                    "<DeleteMarker><Key>{}</Key><VersionId>{}</VersionId>\
+                                     <IsLatest>{}</IsLatest><LastModified>{}</LastModified>\
+                                     </DeleteMarker>",
+                                    key,
+                                    version_id,
+                                    latest,
+                                    version_time(&version_id)
+                                ),
+                            }
+                        })
+                        .collect();
+
+                    xml(format!(
+                        "<ListVersionsResult><Name>bucket</Name><Prefix>{}</Prefix>\
+                         <IsTruncated>false</IsTruncated>{}</ListVersionsResult>",
+                        prefix, listed
+                    ))
+                }
+                "list_objects_v2" => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let delimiter = query.get("delimiter").cloned().unwrap_or_default();
+                    let max_keys: usize =
+                        query.get("max-keys").map(|m| m.parse().unwrap()).unwrap_or(1000);
+                    // continuation token is the last listed key or common prefix
+                    let after = query.get("continuation-token").cloned().unwrap_or_default();
+                    let mut keys: Vec<&String> = state.objects.keys().collect();
+                    keys.sort();
+
+                    let mut entries: Vec<(String, Option<&StoredObject>)> = vec![];
+                    for key in keys.into_iter().filter(|k| k.starts_with(&prefix)) {
+                        let rest = &key[prefix.len()..];
+                        let common = rest.find(&delimiter).filter(|_| !delimiter.is_empty());
+                        let entry = match common {
+                            Some(i) => {
+                                let end = prefix.len() + i + delimiter.len();
+                                (key[..end].to_owned(), None)
+                            }
+                            None => (key.clone(), state.objects.get(key)),
+                        };
+
+                        if entry.0 > after && entries.last().map(|e| &e.0) != Some(&entry.0) {
+                            entries.push(entry);
+                        }
+                    }
+
+                    let truncated = entries.len() > max_keys;
+                    entries.truncate(max_keys);
+                    let next = match entries.last() {
+                        Some(last) if truncated => format!(
+                            "<NextContinuationToken>{}</NextContinuationToken>",
+                            last.0
+                        ),
+                        _ => String::new(),
+                    };
+                    let listed: String = entries
+                        .iter()
+                        .map(|&(ref key, object)| match object {
+                            Some(object) => format!(
+                                "<Contents><Key>{}</Key><LastModified>{}</LastModified>\
+                                 <ETag>{}</ETag><Size>{}</Size><StorageClass>{}</StorageClass>\
+                                 </Contents>",
+                                escape(key),
+                                version_time(object.version_id.as_ref().map_or("", |v| v)),
+                                object.e_tag.replace("\"", "&quot;"),
+                                object.data.len(),
+                                object.storage_class.as_ref().map_or("STANDARD", |c| c)
+                            ),
+                            None => format!(
+                                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                                escape(key)
+                            ),
+                        })
+                        .collect();
+
+                    xml(format!(
+                        "<ListBucketResult><Name>bucket</Name><Prefix>{}</Prefix>\
+                         <KeyCount>{}</KeyCount><IsTruncated>{}</IsTruncated>{}{}\
+                         </ListBucketResult>",
+                        escape(&prefix),
+                        entries.len(),
+                        truncated,
+                        next,
+                        listed
+                    ))
+                }
+                "delete_object" => {
+                    match query.get("versionId") {
+                        Some(version_id) => state.delete_version(&key, version_id),
+                        None => state.delete(&key),
+                    }
+
+                    HttpResponse::NoContent().finish()
+                }
+                "head_bucket" => HttpResponse::Ok().finish(),
+                _ => error(501, "NotImplemented"),
+            }
+        })
+        .responder()
+}
+
+/// Start S3 stub server
+pub fn start(stub: Stub) -> TestServer {
+    TestServer::with_factory(move || {
+        App::with_state(stub.clone()).default_resource(|r| r.f(handle))
+    })
+}
+
+/// Start S3 stub server for `stub`, and proxy using it with config changed by `configure`
+pub fn proxy<F>(stub: Stub, configure: F) -> (Stub, TestServer, TestServer)
+where
+    F: Fn(&mut AppConfig) + Clone + Send + Sync + 'static,
+{
+    let s3 = start(stub.clone());
+    let s3_addr = s3.addr();
+    let proxy = TestServer::with_factory(move || {
+        let mut state = state(s3_addr);
+        configure(&mut Arc::get_mut(&mut state).unwrap().config);
+        ::app(state)
+    });
+
+    (stub, s3, proxy)
+}
+
+/// Application state using S3 stub listening at given address
+pub fn state(s3_addr: SocketAddr) -> AppEnv {
+    Arc::new(AppState {
+        s3: client(s3_addr),
+        config: AppConfig {
+            aws: AwsConfig::new(&Region::UsEast1),
+            s3: S3Config::new("bucket", None),
+            upload: UploadConfig::new(
+                5 * 1024 * 1024,
+                MAX_PART_SIZE,
+                Duration::from_millis(300),
+            ),
+            copy: CopyConfig::new(5 * 1024 * 1024, 2),
+            metadata: MetadataConfig::new(DEFAULT_HEADER_PREFIX, MAX_METADATA_SIZE),
+            encryption: Encryption::Default,
+            storage_class: StorageClassRules::default(),
+            keyring: None,
+            trash: None,
+            website: None,
+            archive: ArchiveConfig::new(1000, 1024 * 1024),
+            tus: None,
+            cors: None,
+            retry: RetryPolicy::none(),
+            readiness: ReadinessConfig::new(None, Duration::from_secs(10)),
+        },
+        in_flight: InFlight::default(),
+        metrics: Metrics::default(),
+        readiness: Readiness::default(),
+        completed_uploads: CompletedUploads::default(),
+    })
+}
+
+/// MD5 of the key returned by `customer_keys`, as stored with SSE-C objects
+pub const CUSTOMER_KEY_MD5: &str = "cLyPS3KoaSFGi/joRB3OUQ==";
+
+/// SSE-C config with single all-zeros key used for all objects
+pub fn customer_keys() -> Encryption {
+    let keys = "default = \"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\"\n";
+
+    Encryption::Customer(CustomerKeys::parse(keys).unwrap())
+}
+
+/// Client-side encryption keyring with `old` and `new` keys, `current` one used for new objects
+pub fn keyring(current: &str) -> Keyring {
+    let keys = format!(
+        "current = \"{}\"\n[keys]\nold = \"{}\"\nnew = \"{}\"\n",
+        current,
+        "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="
+    );
+
+    Keyring::parse(&keys).unwrap()
+}
+
+/// S3 client talking to stub server listening at given address
+pub fn client(addr: SocketAddr) -> S3Client {
+    S3Client::new_with(
+        StatusDispatcher(HttpClient::new().unwrap()),
+        StaticProvider::new_minimal("access".to_owned(), "secret".to_owned()),
+        Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint: format!("http://{}", addr),
+        },
+    )
+}
+
+/// Response received by `request`
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+/// Send HTTP/1.1 request over a fresh connection and read the whole response
+pub fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Response {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+
+    for &(name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw).unwrap();
+
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&raw[..split]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| {
+            let mut kv = l.splitn(2, ':');
+            Some((kv.next()?.trim().to_owned(), kv.next()?.trim().to_owned()))
+        })
+        .collect();
+
+    let mut response = Response {
+        status: status,
+        headers: headers,
+        body: raw[split + 4..].to_vec(),
+    };
+
+    if response.header("transfer-encoding") == Some("chunked") {
+        response.body = dechunk(&response.body);
+    }
+
+    response
+}
+
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size_line = String::from_utf8_lossy(&data[..line_end]).into_owned();
+        let size = usize::from_str_radix(size_line.split(';').next().unwrap().trim(), 16).unwrap();
+
+        if size == 0 {
+            return body;
+        }
+
+        body.extend_from_slice(&data[line_end + 2..line_end + 2 + size]);
+        data = &data[line_end + 4 + size..];
+    }
+}
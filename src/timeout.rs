@@ -0,0 +1,136 @@
+use futures::{Async, Future, Poll, Stream};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// Stream adapter failing with error built by `on_timeout` if inner stream doesn't produce
+/// any item for `timeout`, used to detect stalled or silently disconnected clients. Only time
+/// spent waiting for inner stream counts, not time consumer takes before polling for more.
+pub struct IdleTimeout<S, F> {
+    inner: S,
+    timeout: Duration,
+    /// Started on first poll
+    delay: Option<Delay>,
+    /// Inner stream wasn't ready when last polled
+    waiting: bool,
+    on_timeout: F,
+}
+
+impl<S, F> IdleTimeout<S, F>
+where
+    S: Stream,
+    F: Fn() -> S::Error,
+{
+    pub fn new(inner: S, timeout: Duration, on_timeout: F) -> IdleTimeout<S, F> {
+        IdleTimeout {
+            inner: inner,
+            timeout: timeout,
+            delay: None,
+            waiting: false,
+            on_timeout: on_timeout,
+        }
+    }
+}
+
+impl<S, F> Stream for IdleTimeout<S, F>
+where
+    S: Stream,
+    F: Fn() -> S::Error,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        // first poll, or consumer is back for more: waiting for inner stream starts now
+        if !self.waiting {
+            let deadline = Instant::now() + self.timeout;
+
+            match self.delay {
+                Some(ref mut delay) => delay.reset(deadline),
+                None => self.delay = Some(Delay::new(deadline)),
+            }
+        }
+
+        match self.inner.poll()? {
+            Async::Ready(item) => {
+                self.waiting = false;
+                Ok(Async::Ready(item))
+            }
+            Async::NotReady => {
+                self.waiting = true;
+
+                match self.delay.as_mut().map(|delay| delay.poll()) {
+                    Some(Ok(Async::NotReady)) => Ok(Async::NotReady),
+                    // timer errors are treated as timeouts, they only happen on shutdown
+                    _ => Err((self.on_timeout)()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod idle_timeout {
+        use futures::{stream, task, Async, Future, Stream};
+        use std::thread;
+        use std::time::{Duration, Instant};
+        use timeout::*;
+        use tokio::runtime::current_thread::Runtime;
+        use tokio_timer::Delay;
+
+        /// Stream of 1 and 2, not ready once before each of them
+        fn slow() -> Box<Stream<Item = u32, Error = &'static str>> {
+            let mut next = 0;
+            let mut ready = false;
+
+            Box::new(stream::poll_fn(move || {
+                if !ready {
+                    ready = true;
+                    task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+
+                ready = false;
+                next += 1;
+                Ok(Async::Ready(if next <= 2 { Some(next) } else { None }))
+            }))
+        }
+
+        #[test]
+        fn test_passes_items() {
+            let s = stream::iter_ok::<_, ()>(vec![1, 2, 3]);
+            let s = IdleTimeout::new(s, Duration::from_secs(1), || ());
+
+            assert_eq!(Runtime::new().unwrap().block_on(s.collect()), Ok(vec![1, 2, 3]));
+        }
+
+        #[test]
+        fn test_fails_on_idle() {
+            // stream which never completes after first item
+            let pending = stream::poll_fn(|| Ok(Async::NotReady));
+            let s = stream::iter_ok::<_, &str>(vec![1]).chain(pending);
+            let s = IdleTimeout::new(s, Duration::from_millis(10), || "idle");
+
+            assert_eq!(Runtime::new().unwrap().block_on(s.collect()), Err("idle"));
+        }
+
+        #[test]
+        fn test_starts_on_first_poll() {
+            let s = IdleTimeout::new(slow(), Duration::from_millis(10), || "idle");
+            thread::sleep(Duration::from_millis(30));
+
+            assert_eq!(Runtime::new().unwrap().block_on(s.collect()), Ok(vec![1, 2]));
+        }
+
+        #[test]
+        fn test_consumer_stalls() {
+            let s = IdleTimeout::new(slow(), Duration::from_millis(10), || "idle");
+            // consumer takes longer than timeout with every item
+            let s = s.and_then(|n| {
+                Delay::new(Instant::now() + Duration::from_millis(30)).then(move |_| Ok(n))
+            });
+
+            assert_eq!(Runtime::new().unwrap().block_on(s.collect()), Ok(vec![1, 2]));
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use actix::actors::signal::{Signal, SignalType};
+use actix::{Actor, Context, Handler};
+use actix_web::server::{AcceptorService, IoStream, RustlsAcceptor, ServerFlags};
+use actix_web::Extensions;
+use aws_s3_webdav::x509;
+use futures::{Future, Poll};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+    DistinguishedNames, NoClientAuth, PrivateKey, ResolvesServerCert, RootCertStore,
+    ServerConfig, Session, SignatureScheme, TLSError,
+};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_io::{AsyncRead, AsyncWrite};
+use webpki;
+
+/// Principal of client certificate verified when connection was accepted, in extensions of
+/// requests made on the connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal(pub String);
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Cannot read {}: {}", path, e))
+}
+
+/// Certificates of PEM file, in file order
+fn certs(pem: &[u8]) -> Result<Vec<Certificate>, String> {
+    match pemfile::certs(&mut &pem[..]) {
+        Ok(ref certs) if certs.is_empty() => Err("No certificates found".to_owned()),
+        Ok(certs) => Ok(certs),
+        Err(_) => Err("Invalid PEM certificate".to_owned()),
+    }
+}
+
+/// First PKCS#8 or RSA private key of PEM file
+fn private_key(pem: &[u8]) -> Result<PrivateKey, String> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+
+    pkcs8.into_iter().chain(rsa).next().ok_or_else(|| "No private key found".to_owned())
+}
+
+/// Server certificate chain (end-entity certificate first) with its private key
+fn certified_key(cert: &[u8], key: &[u8]) -> Result<CertifiedKey, String> {
+    let key = sign::any_supported_type(&private_key(key)?)
+        .map_err(|_| "Unsupported private key type".to_owned())?;
+
+    Ok(CertifiedKey::new(certs(cert)?, Arc::new(key)))
+}
+
+/// Trust anchors client certificates are verified with
+fn client_roots(pem: &[u8]) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in certs(pem)? {
+        roots.add(&cert).map_err(|e| format!("Invalid client CA certificate: {:?}", e))?;
+    }
+
+    Ok(roots)
+}
+
+/// Resolves every handshake to the same certificate, swapped on reload
+struct CertResolver(RwLock<CertifiedKey>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        _server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// Requires client certificates issued by client CA, maps their subjects to principals and,
+/// if there's a list of allowed principals, rejects clients not on it
+struct ClientVerifier {
+    /// Verifies certificate chain, swapped on reload
+    chain: RwLock<Arc<ClientCertVerifier>>,
+    allowed: Option<Vec<String>>,
+}
+
+impl ClientVerifier {
+    fn chain(&self) -> Arc<ClientCertVerifier> {
+        self.chain.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.chain().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(&self, certs: &[Certificate]) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.chain().verify_client_cert(certs)?;
+        let subject = x509::subject(&certs[0].0).map_err(TLSError::General)?;
+        let principal = subject.principal();
+
+        if let Some(ref allowed) = self.allowed {
+            if !allowed.contains(&principal) {
+                debug!("Client certificate rejected, {} is not allowed ({})", principal, subject);
+                return Err(TLSError::General(format!("Client {} is not allowed", principal)));
+            }
+        }
+
+        debug!("Client certificate accepted, principal {} ({})", principal, subject);
+
+        Ok(verified)
+    }
+}
+
+/// TLS connection with extensions its requests get, see `Principal`
+pub struct Connection<S> {
+    stream: S,
+    extensions: Rc<Extensions>,
+}
+
+impl<S: Read> Read for Connection<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Connection<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Connection<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Connection<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+impl<S: IoStream> IoStream for Connection<S> {
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        IoStream::shutdown(&mut self.stream, how)
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    fn set_linger(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_linger(dur)
+    }
+
+    fn extensions(&self) -> Option<Rc<Extensions>> {
+        Some(self.extensions.clone())
+    }
+}
+
+/// Accepts TLS connections, with principal of client certificate if there's one
+#[derive(Clone)]
+pub struct Acceptor(RustlsAcceptor);
+
+impl<Io: IoStream> AcceptorService<Io> for Acceptor {
+    type Accepted = Connection<<RustlsAcceptor as AcceptorService<Io>>::Accepted>;
+    type Future = Box<Future<Item = Self::Accepted, Error = io::Error>>;
+
+    fn accept(&self, io: Io) -> Self::Future {
+        Box::new(self.0.accept(io).map(|stream| {
+            let mut extensions = Extensions::new();
+            let certs = stream.get_ref().1.get_peer_certificates().unwrap_or_default();
+
+            // client certificate was verified already, so its subject is valid
+            if let Some(subject) = certs.first().and_then(|c| x509::subject(&c.0).ok()) {
+                extensions.insert(Principal(subject.principal()));
+            }
+
+            Connection {
+                stream: stream,
+                extensions: Rc::new(extensions),
+            }
+        }))
+    }
+
+    fn scheme(&self) -> &'static str {
+        "https"
+    }
+}
+
+/// TLS termination: server certificate chain and private key, and optionally CA certificates
+/// client certificates must be issued by, all read from PEM files and reloaded from them
+pub struct Tls {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+    resolver: Arc<CertResolver>,
+    verifier: Option<Arc<ClientVerifier>>,
+}
+
+impl Tls {
+    /// Load certificates from files, `allowed_clients` are principals (certificate common
+    /// names, or whole subjects if they have none) allowed to connect, any if not set
+    pub fn new(
+        cert: &str,
+        key: &str,
+        client_ca: Option<&str>,
+        allowed_clients: Option<Vec<String>>,
+    ) -> Result<Tls, String> {
+        let resolver = CertResolver(RwLock::new(certified_key(&read(cert)?, &read(key)?)?));
+        let verifier = match client_ca {
+            Some(client_ca) => Some(Arc::new(ClientVerifier {
+                chain: RwLock::new(AllowAnyAuthenticatedClient::new(client_roots(&read(
+                    client_ca,
+                )?)?)),
+                allowed: allowed_clients,
+            })),
+            None => None,
+        };
+
+        Ok(Tls {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            client_ca: client_ca.map(|c| c.to_owned()),
+            resolver: Arc::new(resolver),
+            verifier: verifier,
+        })
+    }
+
+    /// Server configuration resolving certificates loaded last
+    pub fn server_config(&self) -> ServerConfig {
+        let verifier: Arc<ClientCertVerifier> = match self.verifier {
+            Some(ref verifier) => verifier.clone(),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = self.resolver.clone();
+
+        config
+    }
+
+    /// Acceptor of HTTP/1.1 connections with server configuration
+    pub fn acceptor(&self) -> Acceptor {
+        Acceptor(RustlsAcceptor::with_flags(self.server_config(), ServerFlags::HTTP1))
+    }
+
+    /// Read certificates again, for new connections. Ones in use are kept if any fails to load.
+    pub fn reload(&self) -> Result<(), String> {
+        let key = certified_key(&read(&self.cert)?, &read(&self.key)?)?;
+        let roots = match self.client_ca {
+            Some(ref client_ca) => Some(client_roots(&read(client_ca)?)?),
+            None => None,
+        };
+
+        *self.resolver.0.write().unwrap() = key;
+
+        if let (Some(verifier), Some(roots)) = (self.verifier.as_ref(), roots) {
+            *verifier.chain.write().unwrap() = AllowAnyAuthenticatedClient::new(roots);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reloads certificates on `SIGHUP`
+pub struct Reloader(pub Arc<Tls>);
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+}
+
+impl Handler<Signal> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Context<Self>) {
+        if let SignalType::Hup = msg.0 {
+            match self.0.reload() {
+                Ok(()) => info!("Reloaded TLS certificates"),
+                Err(e) => error!("Failed to reload TLS certificates, keeping old ones: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod tls {
+        use actix;
+        use actix_web::test::TestServer;
+        use actix_web::{server, App, HttpRequest};
+        use middleware::ClientPrincipal;
+        use rustls::internal::pemfile;
+        use rustls::{
+            ClientConfig, ClientSession, ServerConfig, ServerSession, Session, Stream, TLSError,
+        };
+        use std::env;
+        use std::fs;
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::sync::{mpsc, Arc};
+        use std::thread;
+        use tls::*;
+        use webpki;
+
+        const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/");
+
+        fn fixture(name: &str) -> String {
+            format!("{}{}", FIXTURES, name)
+        }
+
+        /// Client trusting `root`, presenting certificate `client` (e.g. `client` for
+        /// client.pem and client.key) if set
+        fn client(root: &str, client: Option<&str>) -> ClientSession {
+            let mut config = ClientConfig::new();
+            let root = fs::read(fixture(root)).unwrap();
+            config.root_store.add_pem_file(&mut &root[..]).unwrap();
+
+            if let Some(client) = client {
+                let cert = fs::read(fixture(&format!("{}.pem", client))).unwrap();
+                let key = fs::read(fixture(&format!("{}.key", client))).unwrap();
+                config.set_single_client_cert(
+                    pemfile::certs(&mut &cert[..]).unwrap(),
+                    pemfile::pkcs8_private_keys(&mut &key[..]).unwrap().remove(0),
+                );
+            }
+
+            let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+            ClientSession::new(&Arc::new(config), name)
+        }
+
+        /// Run handshake in memory, length of server certificate chain on success
+        fn handshake(
+            config: &Arc<ServerConfig>,
+            mut client: ClientSession,
+        ) -> Result<usize, TLSError> {
+            let mut server = ServerSession::new(config);
+            let mut buf = Vec::new();
+
+            for _ in 0..10 {
+                if !client.is_handshaking() && !server.is_handshaking() {
+                    return Ok(client.get_peer_certificates().unwrap().len());
+                }
+
+                while client.wants_write() {
+                    buf.clear();
+                    client.write_tls(&mut buf).unwrap();
+                    server.read_tls(&mut &buf[..]).unwrap();
+                    server.process_new_packets()?;
+                }
+
+                while server.wants_write() {
+                    buf.clear();
+                    server.write_tls(&mut buf).unwrap();
+                    client.read_tls(&mut &buf[..]).unwrap();
+                    client.process_new_packets()?;
+                }
+            }
+
+            panic!("Handshake didn't complete");
+        }
+
+        fn server_config(client_ca: Option<&str>, allowed: Option<&[&str]>) -> Arc<ServerConfig> {
+            let client_ca = client_ca.map(fixture);
+            let tls = Tls::new(
+                &fixture("server.pem"),
+                &fixture("server.key"),
+                match client_ca {
+                    Some(ref client_ca) => Some(client_ca.as_str()),
+                    None => None,
+                },
+                allowed.map(|a| a.iter().map(|p| p.to_string()).collect()),
+            ).unwrap();
+
+            Arc::new(tls.server_config())
+        }
+
+        #[test]
+        fn test_server_certificate() {
+            let config = server_config(None, None);
+
+            assert_eq!(handshake(&config, client("ca.pem", None)), Ok(1));
+            assert!(handshake(&config, client("other.pem", None)).is_err());
+
+            assert!(Tls::new(&fixture("server.pem"), &fixture("ca.pem"), None, None).is_err());
+            let missing = fixture("missing.pem");
+            assert!(Tls::new(&missing, &fixture("server.key"), None, None).is_err());
+        }
+
+        #[test]
+        fn test_client_certificates() {
+            let config = server_config(Some("ca.pem"), None);
+
+            assert!(handshake(&config, client("ca.pem", Some("client"))).is_ok());
+            assert!(handshake(&config, client("ca.pem", Some("bob"))).is_ok());
+            assert!(handshake(&config, client("ca.pem", Some("other"))).is_err());
+            assert!(handshake(&config, client("ca.pem", None)).is_err());
+
+            let allowed = server_config(Some("ca.pem"), Some(&["alice"]));
+
+            assert!(handshake(&allowed, client("ca.pem", Some("client"))).is_ok());
+            assert_eq!(
+                handshake(&allowed, client("ca.pem", Some("bob"))),
+                Err(TLSError::General("Client bob is not allowed".to_owned()))
+            );
+        }
+
+        #[test]
+        fn test_client_principal() {
+            let tls = Tls::new(
+                &fixture("server.pem"),
+                &fixture("server.key"),
+                Some(&fixture("ca.pem")),
+                None,
+            ).unwrap();
+            let acceptor = tls.acceptor();
+            let addr = TestServer::unused_addr();
+            let (started, start) = mpsc::channel();
+
+            thread::spawn(move || {
+                let sys = actix::System::new("tls");
+                server::new(|| {
+                    App::new().middleware(ClientPrincipal).resource("/", |r| {
+                        r.f(|req: &HttpRequest| {
+                            let principal = req.extensions().get::<Principal>().cloned();
+                            principal.map(|p| p.0).unwrap_or_default()
+                        })
+                    })
+                }).workers(1)
+                    .disable_signals()
+                    .bind_with(addr, acceptor)
+                    .unwrap()
+                    .start();
+                started.send(()).unwrap();
+                sys.run();
+            });
+            start.recv().unwrap();
+
+            for &(cert, principal) in &[("client", "alice"), ("bob", "bob")] {
+                let mut session = client("ca.pem", Some(cert));
+                let mut socket = TcpStream::connect(addr).unwrap();
+                let mut stream = Stream::new(&mut session, &mut socket);
+                let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+                stream.write_all(request.as_bytes()).unwrap();
+
+                // connection may be closed without TLS close notification
+                let mut response = String::new();
+                let _ = stream.read_to_string(&mut response);
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+                assert!(response.ends_with(&format!("\r\n\r\n{}", principal)), "{}", response);
+            }
+        }
+
+        #[test]
+        fn test_reload() {
+            let dir = env::temp_dir().join(format!("aws-s3-webdav-tls-{}", ::std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let (cert, key) = (dir.join("tls.pem"), dir.join("tls.key"));
+            fs::copy(fixture("server.pem"), &cert).unwrap();
+            fs::copy(fixture("server.key"), &key).unwrap();
+
+            let tls = Tls::new(cert.to_str().unwrap(), key.to_str().unwrap(), None, None).unwrap();
+            let config = Arc::new(tls.server_config());
+            assert!(handshake(&config, client("ca.pem", None)).is_ok());
+
+            // server keeps its configuration, which resolves reloaded certificate
+            fs::copy(fixture("other.pem"), &cert).unwrap();
+            fs::copy(fixture("other.key"), &key).unwrap();
+            tls.reload().unwrap();
+            assert!(handshake(&config, client("ca.pem", None)).is_err());
+            assert!(handshake(&config, client("other.pem", None)).is_ok());
+
+            fs::write(&key, "not a key").unwrap();
+            assert!(tls.reload().is_err());
+            assert!(handshake(&config, client("other.pem", None)).is_ok());
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, TimeZone, Utc};
+use listing::{encode_path, escape};
+use std::time::Duration;
+
+/// User metadata key with original path of trashed object
+pub const ORIGINAL_PATH_METADATA_KEY: &str = "trash-original-path";
+
+/// Deletion time, as first path segment under trash prefix
+const DELETED_AT_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Trashed object path, relative to trash prefix
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrashedPath<'a> {
+    pub deleted_at: DateTime<Utc>,
+    /// Path the object was deleted from
+    pub original_path: &'a str,
+}
+
+/// Path of trashed object: `<trash prefix><deleted at>/<original path>`, so objects deleted
+/// together (collection contents) share the same trash directory
+pub fn trash_path(trash_prefix: &str, deleted_at: &DateTime<Utc>, path: &str) -> String {
+    format!("{}{}/{}", trash_prefix, deleted_at.format(DELETED_AT_FORMAT), path)
+}
+
+/// Parse path of trashed object, `None` if it's not under trash prefix or doesn't look like
+/// one created by `trash_path`
+pub fn parse<'a>(trash_prefix: &str, path: &'a str) -> Option<TrashedPath<'a>> {
+    if !path.starts_with(trash_prefix) {
+        return None;
+    }
+
+    let mut parts = path[trash_prefix.len()..].splitn(2, '/');
+    let deleted_at = Utc.datetime_from_str(parts.next()?, DELETED_AT_FORMAT).ok()?;
+
+    Some(TrashedPath {
+        deleted_at: deleted_at,
+        original_path: parts.next()?,
+    })
+}
+
+/// Check if object deleted at given time was in trash longer than `retention`
+pub fn is_expired(deleted_at: &DateTime<Utc>, now: DateTime<Utc>, retention: Duration) -> bool {
+    match ::chrono::Duration::from_std(retention) {
+        Ok(retention) => *deleted_at + retention < now,
+        Err(_) => false,
+    }
+}
+
+/// Result of moving one object to or from trash
+#[derive(Clone, Debug, PartialEq)]
+pub struct Moved {
+    /// Path the object was moved from
+    pub path: String,
+    /// Path the object was, or should have been, moved to
+    pub destination: String,
+    /// Status (e.g. `403 Forbidden`) and description of failure, if the object wasn't moved
+    pub error: Option<(String, String)>,
+}
+
+/// `207 Multi-Status` body with result of every move, moved objects are reported with their
+/// destination, so collections moved only partially can be recovered
+pub fn multistatus(moved: &[Moved]) -> String {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+
+    for m in moved {
+        let (status, description) = match m.error {
+            Some((ref status, ref description)) => (status.as_str(), description.as_str()),
+            None => ("200 OK", "Moved"),
+        };
+
+        body.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:status>HTTP/1.1 {}</D:status>\
+             <D:location><D:href>{}</D:href></D:location>\
+             <D:responsedescription>{}</D:responsedescription></D:response>\n",
+            escape(&encode_path(&format!("/{}", m.path))),
+            status,
+            escape(&encode_path(&format!("/{}", m.destination))),
+            escape(description)
+        ));
+    }
+
+    body.push_str("</D:multistatus>\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    mod trash {
+        use chrono::{TimeZone, Utc};
+        use std::time::Duration;
+        use trash::*;
+
+        #[test]
+        fn test_trash_path() {
+            let deleted_at = Utc.ymd(2018, 10, 1).and_hms_milli(12, 30, 5, 42);
+            let path = trash_path(".trash/", &deleted_at, "docs/a.txt");
+
+            assert_eq!(path, ".trash/20181001T123005.042Z/docs/a.txt");
+            assert_eq!(
+                parse(".trash/", &path),
+                Some(TrashedPath {
+                    deleted_at: deleted_at,
+                    original_path: "docs/a.txt",
+                })
+            );
+        }
+
+        #[test]
+        fn test_parse_invalid() {
+            assert_eq!(parse(".trash/", "docs/a.txt"), None);
+            assert_eq!(parse(".trash/", ".trash/a.txt"), None);
+            assert_eq!(parse(".trash/", ".trash/yesterday/a.txt"), None);
+            assert_eq!(parse(".trash/", ".trash/20181001T123005.042Z"), None);
+        }
+
+        #[test]
+        fn test_is_expired() {
+            let now = Utc.ymd(2018, 10, 8).and_hms(12, 0, 0);
+            let week = Duration::from_secs(7 * 24 * 3600);
+
+            assert!(is_expired(&Utc.ymd(2018, 10, 1).and_hms(11, 59, 59), now, week));
+            assert!(!is_expired(&Utc.ymd(2018, 10, 1).and_hms(12, 0, 1), now, week));
+        }
+
+        #[test]
+        fn test_multistatus() {
+            let moved = vec![
+                Moved {
+                    path: "docs/a b.txt".to_owned(),
+                    destination: ".trash/20181001T123005.042Z/docs/a b.txt".to_owned(),
+                    error: None,
+                },
+                Moved {
+                    path: "docs/<b>.txt".to_owned(),
+                    destination: ".trash/20181001T123005.042Z/docs/<b>.txt".to_owned(),
+                    error: Some(("403 Forbidden".to_owned(), "Access denied".to_owned())),
+                },
+            ];
+            let body = multistatus(&moved);
+
+            assert!(body.contains(
+                "<D:response><D:href>/docs/a%20b.txt</D:href>\
+                 <D:status>HTTP/1.1 200 OK</D:status><D:location>\
+                 <D:href>/.trash/20181001T123005.042Z/docs/a%20b.txt</D:href></D:location>"
+            ), "{}", body);
+            assert!(body.contains(
+                "<D:href>/docs/%3Cb%3E.txt</D:href><D:status>HTTP/1.1 403 Forbidden</D:status>"
+            ), "{}", body);
+            assert!(body.contains("Access denied</D:responsedescription>"), "{}", body);
+        }
+    }
+}
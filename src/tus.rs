@@ -0,0 +1,182 @@
+use base64;
+use hex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Protocol version, sent in `Tus-Resumable` header of every request and response
+pub const VERSION: &str = "1.0.0";
+
+/// Supported protocol extensions, besides the core protocol
+pub const EXTENSIONS: &str = "creation,termination";
+
+/// Largest upload, S3 objects can't be larger
+pub const MAX_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+/// Content type of `PATCH` requests
+pub const PATCH_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Staging object metadata: upload length, raw `Upload-Metadata` and number of parts
+/// uploaded before staged data
+pub const LENGTH_METADATA_KEY: &str = "tus-length";
+pub const UPLOAD_METADATA_KEY: &str = "tus-metadata";
+pub const PARTS_METADATA_KEY: &str = "tus-parts";
+
+/// Completed uploads are reported for this long, so clients which missed the response to the
+/// final `PATCH` find out the upload is complete
+pub const COMPLETED_GRACE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Upload which received all of its data
+#[derive(Clone, Debug, PartialEq)]
+pub struct Completed {
+    pub length: u64,
+    /// `Upload-Metadata` given on creation
+    pub metadata: Option<String>,
+    completed_at: Instant,
+}
+
+/// Uploads completed within `COMPLETED_GRACE_PERIOD`, by upload id, shared by all workers.
+/// Staging object and multipart upload are gone once upload completes, so it's only known here.
+#[derive(Clone, Debug, Default)]
+pub struct CompletedUploads(Arc<Mutex<HashMap<String, Completed>>>);
+
+impl CompletedUploads {
+    /// Upload was completed at `now`, uploads completed before grace period are forgotten
+    pub fn record(&self, upload_id: &str, length: u64, metadata: Option<String>, now: Instant) {
+        let mut uploads = self.0.lock().unwrap();
+        uploads.retain(|_, c| now.duration_since(c.completed_at) <= COMPLETED_GRACE_PERIOD);
+        uploads.insert(
+            upload_id.to_owned(),
+            Completed {
+                length: length,
+                metadata: metadata,
+                completed_at: now,
+            },
+        );
+    }
+
+    /// Upload completed within grace period before `now`
+    pub fn get(&self, upload_id: &str, now: Instant) -> Option<Completed> {
+        match self.0.lock().unwrap().get(upload_id) {
+            Some(c) if now.duration_since(c.completed_at) <= COMPLETED_GRACE_PERIOD => {
+                Some(c.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parse `Upload-Length` or `Upload-Offset` header value, a non-negative number
+pub fn parse_size(value: &str, header: &str) -> Result<u64, String> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("Invalid {} header", header));
+    }
+
+    value.parse().map_err(|_| format!("Invalid {} header", header))
+}
+
+/// Parse `Upload-Metadata` header: comma separated keys with optional base64 encoded values
+pub fn parse_metadata(value: &str) -> Result<Vec<(String, Option<String>)>, String> {
+    value
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, ' ');
+            let key = parts.next().unwrap_or_default().to_owned();
+            let value = match parts.next() {
+                Some(encoded) => {
+                    let decoded = base64::decode(encoded.trim())
+                        .map_err(|_| format!("Invalid base64 value of {} metadata", key))?;
+                    let decoded = String::from_utf8(decoded)
+                        .map_err(|_| format!("Value of {} metadata is not UTF-8", key))?;
+
+                    Some(decoded)
+                }
+                None => None,
+            };
+
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Value of metadata key, `None` if it's missing or has no value
+pub fn metadata_value<'a>(metadata: &'a [(String, Option<String>)], key: &str) -> Option<&'a str> {
+    metadata
+        .iter()
+        .find(|&&(ref k, _)| k == key)
+        .and_then(|&(_, ref v)| v.as_ref().map(|v| v.as_str()))
+}
+
+/// Key of object staging data which doesn't fill a whole part yet, named after upload id
+pub fn staging_key(prefix: &str, upload_id: &str) -> String {
+    format!("{}{}", prefix, hex::encode(Sha256::digest(upload_id.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    mod tus {
+        use std::time::{Duration, Instant};
+        use tus::*;
+
+        #[test]
+        fn test_parse_size() {
+            assert_eq!(parse_size("0", "Upload-Offset"), Ok(0));
+            assert_eq!(parse_size("5368709120", "Upload-Length"), Ok(5_368_709_120));
+            assert!(parse_size("", "Upload-Length").is_err());
+            assert!(parse_size("-1", "Upload-Length").is_err());
+            assert!(parse_size("+1", "Upload-Length").is_err());
+            assert!(parse_size("1.5", "Upload-Length").is_err());
+            assert!(parse_size("99999999999999999999999", "Upload-Length").is_err());
+        }
+
+        #[test]
+        fn test_parse_metadata() {
+            let metadata =
+                parse_metadata("filename d29ybGRfZG9taW5hdGlvbi5wZGY=,is_confidential").unwrap();
+            assert_eq!(
+                metadata,
+                vec![
+                    ("filename".to_owned(), Some("world_domination.pdf".to_owned())),
+                    ("is_confidential".to_owned(), None),
+                ]
+            );
+            assert_eq!(metadata_value(&metadata, "filename"), Some("world_domination.pdf"));
+            assert_eq!(metadata_value(&metadata, "is_confidential"), None);
+            assert_eq!(metadata_value(&metadata, "filetype"), None);
+
+            assert_eq!(parse_metadata(""), Ok(vec![]));
+            assert!(parse_metadata("filename !!!").is_err());
+            assert!(parse_metadata("filename /w==").is_err());
+        }
+
+        #[test]
+        fn test_staging_key() {
+            let key = staging_key(".uploads/", "upload-1");
+            assert!(key.starts_with(".uploads/"));
+            assert_eq!(key.len(), ".uploads/".len() + 64);
+            assert_eq!(key, staging_key(".uploads/", "upload-1"));
+            assert_ne!(key, staging_key(".uploads/", "upload-2"));
+        }
+
+        #[test]
+        fn test_completed_uploads() {
+            let completed = CompletedUploads::default();
+            let now = Instant::now();
+            assert_eq!(completed.get("a", now), None);
+
+            completed.clone().record("a", 10, Some("filename YS50eHQ=".to_owned()), now);
+            let upload = completed.get("a", now + COMPLETED_GRACE_PERIOD).unwrap();
+            assert_eq!(upload.length, 10);
+            assert_eq!(upload.metadata, Some("filename YS50eHQ=".to_owned()));
+
+            let later = now + COMPLETED_GRACE_PERIOD + Duration::from_secs(1);
+            assert_eq!(completed.get("a", later), None);
+            completed.record("b", 0, None, later);
+            assert_eq!(completed.0.lock().unwrap().len(), 1);
+        }
+    }
+}
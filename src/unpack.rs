@@ -0,0 +1,290 @@
+use actix_web::error::{ErrorBadRequest, ErrorForbidden};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpResponse};
+use aws_s3_webdav::archive::{self, Format};
+use aws_s3_webdav::extract::{Entries, EntryKind, Event};
+use aws_s3_webdav::integrity::ExpectedDigests;
+use aws_s3_webdav::listing::escape;
+use bytes::Bytes;
+use env::AppEnv;
+use futures::future::{self, Loop};
+use futures::{Async, Future, Poll, Stream};
+use s3::object_exists;
+use std::cell::RefCell;
+use std::rc::Rc;
+use upload::{upload_object, BodyStream, ObjectHeaders};
+
+/// Outcome of extracting one entry, reported as `response` element of Multi-Status
+pub struct EntryResult {
+    /// Absolute path of extracted object, or of target collection for archive errors
+    pub href: String,
+    pub status: StatusCode,
+    pub description: Option<String>,
+}
+
+impl EntryResult {
+    fn error(href: &str, e: &Error) -> EntryResult {
+        EntryResult {
+            href: href.to_owned(),
+            status: e.as_response_error().error_response().status(),
+            description: Some(e.to_string()),
+        }
+    }
+}
+
+/// Archive events shared by entry bodies, which are read one after another
+struct Reader {
+    events: Box<Stream<Item=Event, Error=Error>>,
+    /// Data of current entry is being read
+    in_entry: bool,
+    /// Total size of extracted data, checked against archive limits
+    size: u64,
+    max_size: u64,
+    /// Archive limits were exceeded, extraction stops
+    exceeded: bool,
+}
+
+type SharedReader = Rc<RefCell<Reader>>;
+
+type ExtractLoop = Box<Future<Item=Loop<Vec<EntryResult>, Vec<EntryResult>>, Error=Error>>;
+
+impl Reader {
+    /// Next entry, data of current one is skipped
+    fn poll_entry(&mut self) -> Poll<Option<(String, EntryKind)>, Error> {
+        loop {
+            match self.events.poll()? {
+                Async::Ready(Some(Event::Entry(name, kind))) => {
+                    self.in_entry = true;
+                    return Ok(Async::Ready(Some((name, kind))));
+                }
+                Async::Ready(Some(_)) => {}
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Data of current entry, it ends with the entry
+struct EntryBody {
+    reader: SharedReader,
+}
+
+impl Stream for EntryBody {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        let mut reader = self.reader.borrow_mut();
+
+        if !reader.in_entry {
+            return Ok(Async::Ready(None));
+        }
+
+        match reader.events.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(Some(Event::Data(data))) => {
+                reader.size += data.len() as u64;
+
+                if reader.size > reader.max_size {
+                    reader.exceeded = true;
+                    return Err(ErrorForbidden(format!(
+                        "Archive is larger than {} bytes, it can't be extracted",
+                        reader.max_size
+                    )));
+                }
+
+                Ok(Async::Ready(Some(Bytes::from(data))))
+            }
+            _ => {
+                reader.in_entry = false;
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Skip the rest of current entry
+fn skip_entry(reader: &SharedReader) -> Box<Future<Item=(), Error=Error>> {
+    let body = EntryBody {
+        reader: reader.clone(),
+    };
+
+    Box::new(body.for_each(|_| Ok(())))
+}
+
+/// Skip the rest of current entry and continue with the next one, unless archive limits
+/// were exceeded or archive is invalid
+fn next_entry(reader: &SharedReader, mut results: Vec<EntryResult>, href: String) -> ExtractLoop {
+    if reader.borrow().exceeded {
+        return Box::new(future::ok(Loop::Break(results)));
+    }
+
+    Box::new(skip_entry(reader).then(move |r| match r {
+        Ok(_) => Ok(Loop::Continue(results)),
+        Err(e) => {
+            results.push(EntryResult::error(&href, &e));
+            Ok(Loop::Break(results))
+        }
+    }))
+}
+
+/// Extract file entry into object at `key`, reporting `201 Created` for new objects and
+/// `204 No Content` for overwritten ones
+fn extract_file(
+    env: &AppEnv,
+    reader: &SharedReader,
+    key: String,
+    href: String,
+) -> Box<Future<Item=EntryResult, Error=Error>> {
+    let env = env.clone();
+    let body: BodyStream = Box::new(EntryBody {
+        reader: reader.clone(),
+    });
+    let headers = ObjectHeaders {
+        storage_class: env.config.storage_class.for_key(&key).map(|c| c.to_owned()),
+        ..ObjectHeaders::default()
+    };
+
+    Box::new(
+        object_exists(&env, &env.config.s3.bucket, &key)
+            .and_then(move |existed| {
+                upload_object(&env, &key, headers, body, None, ExpectedDigests::default())
+                    .map(move |_| existed)
+            })
+            .then(move |r| {
+                Ok(match r {
+                    Ok(existed) => EntryResult {
+                        href: href,
+                        status: if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED },
+                        description: None,
+                    },
+                    Err(e) => EntryResult::error(&href, &e),
+                })
+            }),
+    )
+}
+
+/// Extract archive streamed in request body into collection at `path` (relative to key
+/// prefix, empty or ending with `/`), `href` is its absolute path. Every file becomes an
+/// object uploaded as it's read, directories are skipped as S3 has none. Entries which names
+/// would escape the collection, links and special files aren't extracted. Failures of single
+/// entries are reported in results, archive errors end extraction.
+pub fn extract(
+    env: &AppEnv,
+    path: &str,
+    href: &str,
+    format: Format,
+    body: BodyStream,
+) -> Box<Future<Item=Vec<EntryResult>, Error=Error>> {
+    let env = env.clone();
+    let prefix = match env.config.s3.prefix {
+        Some(ref prefix) => format!("{}{}", prefix, path),
+        None => path.to_owned(),
+    };
+    let href = href.to_owned();
+    let reader = Rc::new(RefCell::new(Reader {
+        events: Box::new(Entries::new(body, format, ErrorBadRequest)),
+        in_entry: false,
+        size: 0,
+        max_size: env.config.archive.max_size,
+        exceeded: false,
+    }));
+    let max_entries = env.config.archive.max_entries;
+
+    Box::new(future::loop_fn(Vec::new(), move |mut results: Vec<EntryResult>| {
+        let (env, prefix, href) = (env.clone(), prefix.clone(), href.clone());
+        let (reader, next) = (reader.clone(), reader.clone());
+
+        future::poll_fn(move || next.borrow_mut().poll_entry()).then(
+            move |entry| -> ExtractLoop {
+                let (name, kind) = match entry {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => return Box::new(future::ok(Loop::Break(results))),
+                    // nothing was extracted, so it's not an archive at all
+                    Err(e) if results.is_empty() => return Box::new(future::err(e)),
+                    Err(e) => {
+                        results.push(EntryResult::error(&href, &e));
+                        return Box::new(future::ok(Loop::Break(results)));
+                    }
+                };
+
+                if kind == EntryKind::Directory {
+                    return next_entry(&reader, results, href);
+                }
+
+                if results.len() >= max_entries {
+                    let e = ErrorForbidden(format!(
+                        "Archive has more than {} entries, the rest isn't extracted",
+                        max_entries
+                    ));
+                    results.push(EntryResult::error(&href, &e));
+                    return Box::new(future::ok(Loop::Break(results)));
+                }
+
+                let result: Box<Future<Item=EntryResult, Error=Error>> =
+                    match archive::safe_path(&name) {
+                        None => Box::new(future::ok(EntryResult {
+                            href: href.to_owned(),
+                            status: StatusCode::BAD_REQUEST,
+                            description: Some(format!(
+                                "Entry \"{}\" escapes target collection",
+                                name
+                            )),
+                        })),
+                        Some(ref name) if kind == EntryKind::Unsupported => {
+                            Box::new(future::ok(EntryResult {
+                                href: format!("{}{}", href, archive::entry_path(name)),
+                                status: StatusCode::UNPROCESSABLE_ENTITY,
+                                description: Some(
+                                    "Links and special files can't be extracted".to_owned(),
+                                ),
+                            }))
+                        }
+                        Some(name) => {
+                            let path = archive::entry_path(&name);
+                            let key = format!("{}{}", prefix, path);
+
+                            extract_file(&env, &reader, key, format!("{}{}", href, path))
+                        }
+                    };
+
+                Box::new(result.and_then(move |result| {
+                    results.push(result);
+                    next_entry(&reader, results, href)
+                }))
+            },
+        )
+    }))
+}
+
+/// `207 Multi-Status` response with results of extracted entries
+pub fn multistatus(results: &[EntryResult]) -> HttpResponse {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+
+    for result in results {
+        body.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:status>HTTP/1.1 {} {}</D:status>",
+            escape(&result.href),
+            result.status.as_u16(),
+            result.status.canonical_reason().unwrap_or(""),
+        ));
+
+        if let Some(ref description) = result.description {
+            body.push_str(&format!(
+                "<D:responsedescription>{}</D:responsedescription>",
+                escape(description)
+            ));
+        }
+
+        body.push_str("</D:response>\n");
+    }
+
+    body.push_str("</D:multistatus>\n");
+
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(body)
+}
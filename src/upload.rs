@@ -0,0 +1,471 @@
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use aws_s3_webdav::envelope::{self, EncryptChunks, Keyring};
+use aws_s3_webdav::integrity::{self, Digests, ExpectedDigests, Hasher, SHA256_METADATA_KEY};
+use aws_s3_webdav::multipart::{self, PartChunks, PartSize, MAX_COPY_OBJECT_SIZE, MAX_PARTS};
+use bytes::Bytes;
+use env::AppEnv;
+use futures::{future, Future, Stream};
+use rusoto_s3::*;
+use s3::{
+    abort_upload, complete_upload, complete_upload_error, delete_version, head_object, with_retry,
+    AbortOnDrop, Sse,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+/// Headers of uploaded object, stored with it
+#[derive(Clone, Debug, Default)]
+pub struct ObjectHeaders {
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+    pub content_encoding: Option<String>,
+    pub content_language: Option<String>,
+    pub content_type: Option<String>,
+    pub expires: Option<String>,
+    pub storage_class: Option<String>,
+    pub tagging: Option<String>,
+    pub website_redirect_location: Option<String>,
+    /// User metadata, without `x-amz-meta-` prefix
+    pub metadata: HashMap<String, String>,
+}
+
+/// Upload single part of multipart upload, part data is kept buffered, so failed part can be
+/// re-sent. Every attempt sends its own copy of the data, so part takes twice its size in
+/// memory while it's sent.
+pub fn upload_part(
+    env: &AppEnv,
+    upload: &CreateMultipartUploadOutput,
+    part_number: i64,
+    data: Vec<u8>,
+) -> Box<Future<Item=CompletedPart, Error=Error>> {
+    let state = env.clone();
+    let bucket = upload.bucket.to_owned().unwrap();
+    let key = upload.key.to_owned().unwrap();
+    let upload_id = upload.upload_id.to_owned().unwrap();
+    let sse = Sse::for_key(env, &key);
+    let content_md5 = integrity::content_md5(&data);
+
+    Box::new(
+        with_retry(env, "upload_part", move || {
+            state.s3.upload_part(UploadPartRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                part_number: part_number,
+                body: Some(StreamingBody::from(data.clone())),
+                content_md5: Some(content_md5.to_owned()),
+                sse_customer_algorithm: sse.customer_algorithm.to_owned(),
+                sse_customer_key: sse.customer_key.to_owned(),
+                sse_customer_key_md5: sse.customer_key_md5.to_owned(),
+                ..UploadPartRequest::default()
+            })
+        }).map_err(upload_part_error)
+            .map(move |output| CompletedPart {
+                e_tag: output.e_tag,
+                part_number: Some(part_number),
+            }),
+    )
+}
+
+/// Upload body in parts, resolves to uploaded parts, size of uploaded data and its digests
+fn upload_parts(
+    body_stream: Box<Stream<Item=Bytes, Error=Error>>,
+    state: AppEnv,
+    upload: &CreateMultipartUploadOutput,
+    part_size: PartSize,
+) -> Box<Future<Item=(Vec<CompletedPart>, u64, Digests), Error=Error>> {
+    let upload = upload.clone();
+
+    Box::new(
+        // Buffer into parts of at least 5Mb, AWS doesn't allow smaller parts (except last one)
+        // body errors are passed as is, they tell why reading body failed
+        PartChunks::new(body_stream, part_size)
+            .and_then(|(part_number, data)| {
+                if part_number as u64 > MAX_PARTS {
+                    // S3 would reject it after all parts are uploaded
+                    return Err(too_large());
+                }
+
+                Ok((part_number, data))
+            })
+            .fold(
+                (vec![], 0, Hasher::new()),
+                move |(mut parts, size, mut hasher), (part_number, data)| {
+                    // parts come in order, so whole body digests are computed on the way
+                    hasher.update(&data);
+                    let size = size + data.len() as u64;
+
+                    upload_part(&state, &upload, part_number, data).map(move |part| {
+                        parts.push(part);
+                        (parts, size, hasher)
+                    })
+                },
+            )
+            .map(|(parts, size, hasher)| (parts, size, hasher.finish())),
+    )
+}
+
+/// Error for uploads which don't fit into `MAX_PARTS` parts
+fn too_large() -> Error {
+    InternalError::new("Upload is too large", StatusCode::PAYLOAD_TOO_LARGE).into()
+}
+
+fn upload_part_error(e: UploadPartError) -> Error {
+    match e {
+        UploadPartError::HttpDispatch(e) => ErrorInternalServerError(e),
+        UploadPartError::Credentials(e) => ErrorForbidden(e),
+        UploadPartError::Validation(e) => ErrorBadRequest(e),
+        UploadPartError::Unknown(e) => ErrorInternalServerError(e),
+    }
+}
+
+/// Metadata stored with uploaded object, with SHA-256 of the data if it's known
+fn upload_metadata(
+    user: &HashMap<String, String>,
+    sha256: Option<&Vec<u8>>,
+) -> Option<HashMap<String, String>> {
+    let mut metadata = user.clone();
+
+    if let Some(sha256) = sha256 {
+        metadata.insert(SHA256_METADATA_KEY.to_owned(), integrity::sha256_hex(sha256));
+    }
+
+    if metadata.is_empty() {
+        None
+    } else {
+        Some(metadata)
+    }
+}
+
+/// ETag of stored object
+type ETagFuture = Box<Future<Item=Option<String>, Error=Error>>;
+
+/// Store SHA-256 with completed multipart upload of given `size`, metadata can only be set
+/// when upload is created, so object is copied in place with all its metadata replaced. In
+/// versioned buckets the version created by upload is deleted, so PUT leaves one version.
+/// Resolves to ETag of the object, failures are only logged, as object is complete.
+fn store_sha256(
+    env: &AppEnv,
+    key: &str,
+    headers: &ObjectHeaders,
+    metadata: Option<HashMap<String, String>>,
+    size: u64,
+    upload: CompleteMultipartUploadOutput,
+) -> ETagFuture {
+    let CompleteMultipartUploadOutput { e_tag, version_id, .. } = upload;
+    if size > MAX_COPY_OBJECT_SIZE {
+        warn!("SHA-256 of {} isn't stored, it's too large to be copied", key);
+        return Box::new(future::ok(e_tag));
+    }
+
+    let state = env.clone();
+    let bucket = env.config.s3.bucket.to_owned();
+    let sse = Sse::for_key(env, key);
+    let request = CopyObjectRequest {
+        bucket: bucket.to_owned(),
+        copy_source: util::encode_key(format!("{}/{}", bucket, key)),
+        key: key.to_owned(),
+        metadata_directive: Some("REPLACE".to_owned()),
+        tagging_directive: Some("COPY".to_owned()),
+        cache_control: headers.cache_control.to_owned(),
+        content_disposition: headers.content_disposition.to_owned(),
+        content_encoding: headers.content_encoding.to_owned(),
+        content_language: headers.content_language.to_owned(),
+        content_type: headers.content_type.to_owned(),
+        expires: headers.expires.to_owned(),
+        metadata: metadata,
+        storage_class: headers.storage_class.to_owned(),
+        website_redirect_location: headers.website_redirect_location.to_owned(),
+        server_side_encryption: sse.server_side_encryption.to_owned(),
+        ssekms_key_id: sse.ssekms_key_id.to_owned(),
+        copy_source_sse_customer_algorithm: sse.customer_algorithm.to_owned(),
+        copy_source_sse_customer_key: sse.customer_key.to_owned(),
+        copy_source_sse_customer_key_md5: sse.customer_key_md5.to_owned(),
+        sse_customer_algorithm: sse.customer_algorithm,
+        sse_customer_key: sse.customer_key,
+        sse_customer_key_md5: sse.customer_key_md5,
+        ..CopyObjectRequest::default()
+    };
+    let s = env.clone();
+    let key = key.to_owned();
+
+    Box::new(
+        with_retry(env, "copy_object", move || state.s3.copy_object(request.clone()))
+            .then(move |r| -> ETagFuture {
+                if let Err(e) = r {
+                    error!("Failed to store SHA-256 of {}: {}", key, e);
+                    return Box::new(future::ok(e_tag));
+                }
+
+                let deleted: Box<Future<Item=(), Error=Error>> = match version_id {
+                    Some(version_id) => delete_replaced_version(&s, &key, version_id),
+                    None => Box::new(future::ok(())),
+                };
+
+                // rusoto doesn't parse `CopyObject` response body, so new ETag has to be read
+                Box::new(deleted.and_then(move |_| {
+                    head_object(&s, &bucket, &key).then(move |r| match r {
+                        Ok(object) => Ok(object.e_tag),
+                        Err(e) => {
+                            error!("Stored SHA-256 of {}, but can't read its ETag: {}", key, e);
+                            Ok(None)
+                        }
+                    })
+                }))
+            }),
+    )
+}
+
+/// Delete version of uploaded object replaced by its copy, failures are only logged
+fn delete_replaced_version(
+    env: &AppEnv,
+    key: &str,
+    version_id: String,
+) -> Box<Future<Item=(), Error=Error>> {
+    let key = key.to_owned();
+
+    Box::new(
+        delete_version(env, &env.config.s3.bucket, &key, Some(version_id.to_owned())).or_else(
+            move |e| {
+                warn!("Failed to delete replaced version {} of {}: {}", version_id, key, e);
+                Ok(())
+            },
+        ),
+    )
+}
+
+/// Request body as streamed to S3
+pub type BodyStream = Box<Stream<Item=Bytes, Error=Error>>;
+
+/// Encrypted body stream, wrapped data key and plaintext hasher
+type EncryptedBody = (BodyStream, String, Rc<RefCell<Hasher>>);
+
+/// Encrypt request body with a new data key, returned with its metadata value. Uploaded parts
+/// are hashed after encryption, so plaintext digests are computed on the way.
+fn encrypt_body(body: BodyStream, keyring: &Keyring) -> Result<EncryptedBody, Error> {
+    let (key, wrapped) = keyring.new_data_key().map_err(ErrorInternalServerError)?;
+    let hasher = Rc::new(RefCell::new(Hasher::new()));
+    let h = hasher.clone();
+    let plaintext = body.map(move |chunk| {
+        h.borrow_mut().update(&chunk);
+        chunk
+    });
+
+    Ok((Box::new(EncryptChunks::new(plaintext, key).map(Bytes::from)), wrapped, hasher))
+}
+
+/// Uploaded object ETag and computed body digests
+pub type UploadResult = Box<Future<Item=(Option<String>, Digests), Error=Error>>;
+
+/// Upload object with multipart upload, parts are sent one by one as body is read, so memory
+/// used by each upload is bounded by twice the max part size (buffered part and its copy
+/// being sent). Uploads which don't fit into `MAX_PARTS` parts fail with 413 Payload Too
+/// Large. Objects are encrypted if keyring is set. Upload is aborted if it
+/// fails or body doesn't match expected digests, empty bodies are stored with PUT. SHA-256
+/// of the data is stored with the object, unless it's too large to be copied.
+pub fn upload_object(
+    env: &AppEnv,
+    key: &str,
+    headers: ObjectHeaders,
+    body_stream: BodyStream,
+    content_length: Option<u64>,
+    expected: ExpectedDigests,
+) -> UploadResult {
+    let state = env.clone();
+    let bucket = env.config.s3.bucket.to_owned();
+    let key = key.to_owned();
+    let sse = Sse::for_key(&state, &key);
+    let object_headers = headers.clone();
+    let ObjectHeaders {
+        cache_control,
+        content_disposition,
+        content_encoding,
+        content_language,
+        content_type,
+        expires,
+        storage_class,
+        tagging,
+        website_redirect_location,
+        metadata: mut user_metadata,
+    } = headers;
+
+    let (body_stream, plaintext, content_length) = match state.config.keyring {
+        Some(ref keyring) => match encrypt_body(body_stream, keyring) {
+            Ok((body_stream, wrapped, hasher)) => {
+                user_metadata.insert(envelope::METADATA_KEY.to_owned(), wrapped);
+                (body_stream, Some(hasher), content_length.map(envelope::encrypted_size))
+            }
+            Err(e) => return Box::new(future::err(e)),
+        },
+        None => (body_stream, None, content_length),
+    };
+    let max_part_size = state.config.upload.max_part_size;
+    if content_length.map(|l| l > multipart::max_upload_size(max_part_size)) == Some(true) {
+        return Box::new(future::err(too_large()));
+    }
+    let part_size =
+        PartSize::with_max(state.config.upload.part_size, max_part_size, content_length);
+
+    let create_request = CreateMultipartUploadRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        cache_control: cache_control.to_owned(),
+        content_disposition: content_disposition.to_owned(),
+        content_encoding: content_encoding.to_owned(),
+        content_language: content_language.to_owned(),
+        content_type: content_type.to_owned(),
+        expires: expires.to_owned(),
+        metadata: upload_metadata(&user_metadata, expected.sha256.as_ref()),
+        storage_class: storage_class.to_owned(),
+        tagging: tagging.to_owned(),
+        website_redirect_location: website_redirect_location.to_owned(),
+        server_side_encryption: sse.server_side_encryption.to_owned(),
+        ssekms_key_id: sse.ssekms_key_id.to_owned(),
+        sse_customer_algorithm: sse.customer_algorithm.to_owned(),
+        sse_customer_key: sse.customer_key.to_owned(),
+        sse_customer_key_md5: sse.customer_key_md5.to_owned(),
+        ..CreateMultipartUploadRequest::default()
+    };
+
+    let upload = {
+        let state = state.clone();
+        let s = state.clone();
+
+        with_retry(&state, "create_multipart_upload", move || {
+            s.s3.create_multipart_upload(create_request.clone())
+        })
+            .map_err(|e| match e {
+                CreateMultipartUploadError::HttpDispatch(e) => ErrorInternalServerError(e),
+                CreateMultipartUploadError::Credentials(e) => ErrorForbidden(e),
+                CreateMultipartUploadError::Validation(e) => ErrorBadRequest(e),
+                CreateMultipartUploadError::Unknown(e) => ErrorInternalServerError(e),
+            })
+            .and_then(move |upload| {
+                let mut guard = AbortOnDrop::new(&state, &upload);
+
+                upload_parts(body_stream, state.to_owned(), &upload, part_size)
+                    .map(move |(parts, size, digests)| match plaintext {
+                        Some(hasher) => {
+                            let digests = mem::replace(&mut *hasher.borrow_mut(), Hasher::new());
+                            (parts, size, digests.finish())
+                        }
+                        None => (parts, size, digests),
+                    })
+                    .then(move |parts_r| -> UploadResult {
+                        if let Ok((_, _, ref digests)) = parts_r {
+                            if let Err(e) = expected.verify(digests) {
+                                // never complete upload with corrupted data
+                                return Box::new(
+                                    abort_upload(&state, &upload)
+                                        .then(move |_| Err(ErrorBadRequest(e))),
+                                );
+                            }
+                        }
+
+                        match parts_r {
+                            Ok((ref parts, _, ref digests)) if parts.is_empty() => {
+                                // no parts upload - file is empty
+                                let digests = digests.clone();
+
+                                Box::new(abort_upload(&state, &upload).then(move |_| {
+                                    let s = state.clone();
+                                    let content_md5 = integrity::content_md5(&[]);
+                                    let metadata =
+                                        upload_metadata(&user_metadata, Some(&digests.sha256));
+
+                                    with_retry(&state, "put_object", move || {
+                                        s.s3.put_object(PutObjectRequest {
+                                            bucket: bucket.to_owned(),
+                                            key: key.to_owned(),
+                                            body: Some(StreamingBody::from(vec![])),
+                                            cache_control: cache_control.to_owned(),
+                                            content_disposition: content_disposition.to_owned(),
+                                            content_encoding: content_encoding.to_owned(),
+                                            content_language: content_language.to_owned(),
+                                            content_md5: Some(content_md5.to_owned()),
+                                            content_type: content_type.to_owned(),
+                                            expires: expires.to_owned(),
+                                            metadata: metadata.to_owned(),
+                                            storage_class: storage_class.to_owned(),
+                                            tagging: tagging.to_owned(),
+                                            website_redirect_location: website_redirect_location
+                                                .to_owned(),
+                                            server_side_encryption: sse
+                                                .server_side_encryption
+                                                .to_owned(),
+                                            ssekms_key_id: sse.ssekms_key_id.to_owned(),
+                                            sse_customer_algorithm: sse
+                                                .customer_algorithm
+                                                .to_owned(),
+                                            sse_customer_key: sse.customer_key.to_owned(),
+                                            sse_customer_key_md5: sse
+                                                .customer_key_md5
+                                                .to_owned(),
+                                            ..PutObjectRequest::default()
+                                        })
+                                    })
+                                        .map_err(|e| match e {
+                                            PutObjectError::HttpDispatch(e) => {
+                                                ErrorInternalServerError(e)
+                                            }
+                                            PutObjectError::Credentials(e) => ErrorForbidden(e),
+                                            PutObjectError::Validation(e) => ErrorBadRequest(e),
+                                            PutObjectError::Unknown(e) => {
+                                                ErrorInternalServerError(e)
+                                            }
+                                        })
+                                        .map(move |output| (output.e_tag, digests))
+                                }))
+                            }
+                            Ok((parts, size, digests)) => {
+                                let s = state.clone();
+                                // provided SHA-256 was stored when upload was created
+                                let stored = expected.sha256.is_some();
+                                let metadata =
+                                    upload_metadata(&user_metadata, Some(&digests.sha256));
+
+                                Box::new(
+                                    complete_upload(&state, &upload, parts)
+                                        .or_else(move |e| {
+                                            // do not leave parts behind if upload can't be
+                                            // completed
+                                            abort_upload(&s, &upload)
+                                                .then(move |_| Err(complete_upload_error(e)))
+                                        })
+                                        .and_then(move |output| -> ETagFuture {
+                                            if stored {
+                                                return Box::new(future::ok(output.e_tag));
+                                            }
+
+                                            store_sha256(
+                                                &state,
+                                                &key,
+                                                &object_headers,
+                                                metadata,
+                                                size,
+                                                output,
+                                            )
+                                        })
+                                        .map(move |e_tag| (e_tag, digests)),
+                                )
+                            }
+                            Err(e) => Box::new(
+                                abort_upload(&state, &upload)
+                                    .then(|_| Err(e)),
+                            ),
+                        }
+                    })
+                    .then(move |r| {
+                        guard.disarm();
+                        r
+                    })
+            })
+    };
+
+    Box::new(upload)
+}
@@ -0,0 +1,125 @@
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError};
+use actix_web::Error;
+use env::AppEnv;
+use futures::future::{self, Loop};
+use futures::Future;
+use rusoto_s3::*;
+use s3::with_retry;
+use std::cmp::Reverse;
+
+/// Object version or delete marker, as listed to clients
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Version {
+    pub version_id: String,
+    pub is_latest: bool,
+    pub delete_marker: bool,
+    pub last_modified: Option<String>,
+    pub size: Option<i64>,
+    pub e_tag: Option<String>,
+    pub storage_class: Option<String>,
+}
+
+/// Versions of the key listed in a page, with markers for the next page if listing is
+/// truncated and may contain more versions of the key
+type VersionsPage = (Vec<Version>, Option<(Option<String>, Option<String>)>);
+
+fn list_versions_page(
+    env: &AppEnv,
+    key: &str,
+    key_marker: Option<String>,
+    version_id_marker: Option<String>,
+) -> Box<Future<Item=VersionsPage, Error=ListObjectVersionsError>> {
+    let state = env.clone();
+    let key = key.to_owned();
+    let request = ListObjectVersionsRequest {
+        bucket: env.config.s3.bucket.to_owned(),
+        prefix: Some(key.to_owned()),
+        key_marker: key_marker,
+        version_id_marker: version_id_marker,
+        ..ListObjectVersionsRequest::default()
+    };
+
+    Box::new(
+        with_retry(env, "list_object_versions", move || {
+            state.s3.list_object_versions(request.clone())
+        }).map(move |output| {
+            // prefix matches longer keys too, which are listed after the key itself
+            let next = match output.next_key_marker {
+                Some(ref marker) if output.is_truncated == Some(true) && *marker == key => {
+                    Some((output.next_key_marker.clone(), output.next_version_id_marker))
+                }
+                _ => None,
+            };
+            let versions = output.versions.unwrap_or_default().into_iter().filter_map(|v| {
+                if v.key.as_ref() != Some(&key) {
+                    return None;
+                }
+
+                Some(Version {
+                    version_id: v.version_id.unwrap_or_default(),
+                    is_latest: v.is_latest == Some(true),
+                    delete_marker: false,
+                    last_modified: v.last_modified,
+                    size: v.size,
+                    e_tag: v.e_tag,
+                    storage_class: v.storage_class,
+                })
+            });
+            let markers = output.delete_markers.unwrap_or_default().into_iter().filter_map(|m| {
+                if m.key.as_ref() != Some(&key) {
+                    return None;
+                }
+
+                Some(Version {
+                    version_id: m.version_id.unwrap_or_default(),
+                    is_latest: m.is_latest == Some(true),
+                    delete_marker: true,
+                    last_modified: m.last_modified,
+                    size: None,
+                    e_tag: None,
+                    storage_class: None,
+                })
+            });
+
+            (versions.chain(markers).collect(), next)
+        }),
+    )
+}
+
+/// All versions and delete markers of the key, newest first
+pub fn list_versions(env: &AppEnv, key: &str) -> Box<Future<Item=Vec<Version>, Error=Error>> {
+    let env = env.clone();
+    let key = key.to_owned();
+
+    Box::new(
+        future::loop_fn(
+            (vec![], None, None),
+            move |(mut versions, key_marker, version_id_marker)| {
+                list_versions_page(&env, &key, key_marker, version_id_marker).map(
+                    move |(page, next)| {
+                        versions.extend(page);
+
+                        match next {
+                            Some((key_marker, version_id_marker)) => {
+                                Loop::Continue((versions, key_marker, version_id_marker))
+                            }
+                            None => Loop::Break(versions),
+                        }
+                    },
+                )
+            },
+        ).map_err(|e| match e {
+            // http://rusoto.github.io/rusoto/rusoto_s3/enum.ListObjectVersionsError.html
+            ListObjectVersionsError::HttpDispatch(e) => ErrorInternalServerError(e),
+            ListObjectVersionsError::Credentials(e) => ErrorForbidden(e),
+            ListObjectVersionsError::Validation(e) => ErrorBadRequest(e),
+            ListObjectVersionsError::Unknown(e) => ErrorInternalServerError(e),
+        })
+            .map(|mut versions: Vec<Version>| {
+                // ISO 8601 timestamps sort chronologically
+                versions.sort_by_key(|v| Reverse(v.last_modified.clone()));
+                versions
+            }),
+    )
+}
@@ -0,0 +1,203 @@
+use std::cmp;
+use std::fmt;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const BMP_STRING: u8 = 0x1e;
+/// Explicit `[0]` tag of certificate version
+const VERSION: u8 = 0xa0;
+
+/// Short names of common attribute types, by DER encoded OID
+const ATTRIBUTE_NAMES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01], "emailAddress"),
+];
+
+/// Certificate subject: attribute types and values, in certificate order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subject(pub Vec<(String, String)>);
+
+impl Subject {
+    /// Value of the last (most specific) attribute of given type
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().rev().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v.as_str())
+    }
+
+    /// Principal the certificate was issued to: its common name, or the whole subject if it
+    /// has none
+    pub fn principal(&self) -> String {
+        self.get("CN").map_or_else(|| self.to_string(), |cn| cn.to_owned())
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let attributes: Vec<String> =
+            self.0.iter().map(|&(ref n, ref v)| format!("{}={}", n, v)).collect();
+
+        write!(f, "{}", attributes.join(", "))
+    }
+}
+
+/// DER element: tag, contents and the rest of input
+fn element(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let truncated = || "Truncated certificate".to_owned();
+
+    if input.len() < 2 {
+        return Err(truncated());
+    }
+
+    let (length, header) = match input[1] {
+        length if length < 0x80 => (length as usize, 2),
+        0x81..=0x84 => {
+            let size = (input[1] & 0x7f) as usize;
+            let bytes = input.get(2..2 + size).ok_or_else(truncated)?;
+
+            (bytes.iter().fold(0, |length, b| length << 8 | *b as usize), 2 + size)
+        }
+        _ => return Err("Invalid certificate length".to_owned()),
+    };
+    let contents = input.get(header..header + length).ok_or_else(truncated)?;
+
+    Ok((input[0], contents, &input[header + length..]))
+}
+
+/// DER element with expected tag
+fn expect(input: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    match element(input)? {
+        (t, contents, rest) if t == tag => Ok((contents, rest)),
+        _ => Err("Invalid certificate".to_owned()),
+    }
+}
+
+/// Dotted form of object identifier, for attribute types without short names
+fn dotted_oid(oid: &[u8]) -> String {
+    let mut arcs: Vec<u64> = Vec::new();
+    let mut arc: u64 = 0;
+
+    for b in oid {
+        arc = arc << 7 | u64::from(b & 0x7f);
+
+        if b & 0x80 == 0 {
+            // first subidentifier encodes the first two arcs
+            if arcs.is_empty() {
+                let first = cmp::min(arc / 40, 2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+
+    arcs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// String value of attribute, BMP strings are UTF-16, others are treated as UTF-8
+fn attribute_value(tag: u8, value: &[u8]) -> String {
+    if tag == BMP_STRING {
+        let units: Vec<u16> = value
+            .chunks(2)
+            .map(|c| u16::from(c[0]) << 8 | u16::from(*c.get(1).unwrap_or(&0)))
+            .collect();
+
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(value).into_owned()
+    }
+}
+
+/// Subject of DER encoded certificate
+pub fn subject(der: &[u8]) -> Result<Subject, String> {
+    let (certificate, _) = expect(der, SEQUENCE)?;
+    let (tbs, _) = expect(certificate, SEQUENCE)?;
+    let mut rest = match element(tbs)? {
+        (VERSION, _, rest) => rest,
+        _ => tbs,
+    };
+
+    // serial number, signature algorithm, issuer and validity precede subject
+    for _ in 0..4 {
+        rest = element(rest)?.2;
+    }
+
+    let (mut names, _) = expect(rest, SEQUENCE)?;
+    let mut attributes = Vec::new();
+
+    while !names.is_empty() {
+        let (mut set, next) = expect(names, SET)?;
+        names = next;
+
+        while !set.is_empty() {
+            let (attribute, next) = expect(set, SEQUENCE)?;
+            set = next;
+
+            let (oid, value) = expect(attribute, OBJECT_IDENTIFIER)?;
+            let (tag, value, _) = element(value)?;
+            let name = ATTRIBUTE_NAMES
+                .iter()
+                .find(|&&(o, _)| o == oid)
+                .map_or_else(|| dotted_oid(oid), |&(_, name)| name.to_owned());
+
+            attributes.push((name, attribute_value(tag, value)));
+        }
+    }
+
+    Ok(Subject(attributes))
+}
+
+#[cfg(test)]
+mod tests {
+    mod x509 {
+        use base64;
+        use x509::*;
+
+        const ALICE: &str = "\
+MIIBsjCCAVigAwIBAgIUOqdwaZDISCNgyQRJg5s94nGCM5wwCgYIKoZIzj0EAwIwEjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0y\
+NjEwMTgyMjQyMzFaGA8yMTI2MDkyNDIyNDIzMVowRTELMAkGA1UEBhMCREUxFDASBgNVBAoMC0V4YW1wbGUgT3JnMRAwDgYD\
+VQQLDAdCYWNrdXBzMQ4wDAYDVQQDDAVhbGljZTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABLdhMmGgxY2/0K3BBKCJX266\
+RirsH5WXoOYGuQgQxNWzPa8YomIduFDXowoVQhua0awRxin49WSXWC0/t3QVSGWjVzBVMBMGA1UdJQQMMAoGCCsGAQUFBwMC\
+MB0GA1UdDgQWBBTWWuDfQW7obVnSwOCNd9Tr9M8hPjAfBgNVHSMEGDAWgBQhlT5DCgbj/z+9Odp469ABMqd6XDAKBggqhkjO\
+PQQDAgNIADBFAiEAwC6BhKuPJgKiL1q6hMrZ7whRcfx8hGjWb1x1iTU2de4CIEnrt+2zb2Ip6X7opM1vVvyHEV+QHQOyOenw\
+/X1QPMZt";
+
+        #[test]
+        fn test_subject() {
+            let der = base64::decode(ALICE).unwrap();
+            let alice = subject(&der).unwrap();
+
+            assert_eq!(alice.to_string(), "C=DE, O=Example Org, OU=Backups, CN=alice");
+            assert_eq!(alice.get("O"), Some("Example Org"));
+            assert_eq!(alice.principal(), "alice");
+
+            assert!(subject(&der[..100]).is_err());
+            assert!(subject(b"not a certificate").is_err());
+        }
+
+        #[test]
+        fn test_principal_without_common_name() {
+            let subject = Subject(vec![
+                ("O".to_owned(), "Example Org".to_owned()),
+                ("0.9.2342.19200300.100.1.1".to_owned(), "alice".to_owned()),
+            ]);
+
+            assert_eq!(subject.principal(), "O=Example Org, 0.9.2342.19200300.100.1.1=alice");
+        }
+
+        #[test]
+        fn test_dotted_oid() {
+            assert_eq!(dotted_oid(&[0x55, 0x04, 0x03]), "2.5.4.3");
+            assert_eq!(
+                dotted_oid(&[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01]),
+                "0.9.2342.19200300.100.1.1"
+            );
+        }
+    }
+}